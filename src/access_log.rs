@@ -0,0 +1,121 @@
+//! Access log of a reflector, for accounting of who measures against it
+//!
+//! Every interval, a JSON line per source address and client ID probing in
+//! it: `{"time":…,"client":"192.0.2.1","client_id":"web-01","probes":60,"rate":1.0}`,
+//! `client_id` being `null` for probes without one. The probes since the
+//! last line are written when the log is dropped.
+
+use crate::{clock::Timestamp, protocol::ClientId};
+use anyhow::{ensure, Context, Result};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Access log settings of the reflector
+#[derive(Clone, Debug)]
+pub struct AccessLogConfig {
+    /// File the lines are appended to
+    pub path: PathBuf,
+    /// How often the lines are written
+    pub interval: Duration,
+}
+
+/// Probes of every client since the lines were last written
+#[derive(Debug)]
+struct Interval {
+    probes: HashMap<(IpAddr, Option<ClientId>), u64>,
+    start: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    interval: Mutex<Interval>,
+}
+
+impl AccessLog {
+    /// Append to the file of `config`
+    pub fn open(config: &AccessLogConfig) -> Result<Self> {
+        ensure!(
+            !config.interval.is_zero(),
+            "the access log interval must be positive"
+        );
+        let path = &config.path;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open the access log {}", path.display()))?;
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(file),
+            interval: Mutex::new(Interval {
+                probes: HashMap::new(),
+                start: Instant::now(),
+            }),
+        })
+    }
+
+    /// Count an answered probe of `client`
+    pub fn record(&self, client: IpAddr, id: Option<ClientId>) {
+        // Dual-stack sockets see IPv4 clients as v4-mapped addresses
+        let key = (client.to_canonical(), id);
+        *self.interval.lock().unwrap().probes.entry(key).or_default() += 1;
+    }
+
+    /// Write the lines of the clients since the previous call, failures
+    /// being logged
+    pub fn flush(&self) {
+        if let Err(e) = self.write() {
+            warn!(
+                "Writing the access log {} failed: {:#}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        let (probes, elapsed) = {
+            let mut interval = self.interval.lock().unwrap();
+            let elapsed = interval.start.elapsed().as_secs_f64();
+            interval.start = Instant::now();
+            (std::mem::take(&mut interval.probes), elapsed)
+        };
+        if probes.is_empty() {
+            return Ok(());
+        }
+        let time = Timestamp::now()?.to_rfc3339(None);
+        let mut probes: Vec<_> = probes.into_iter().collect();
+        probes.sort_by_key(|((client, id), _)| (*client, id.map(|id| id.to_string())));
+        let mut lines = String::new();
+        for ((client, id), count) in probes {
+            let line = json!({
+                "time": time,
+                "client": client.to_string(),
+                "client_id": id.map(|id| id.to_string()),
+                "probes": count,
+                "rate": count as f64 / elapsed,
+            });
+            lines.push_str(&line.to_string());
+            lines.push('\n');
+        }
+        self.file.lock().unwrap().write_all(lines.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -0,0 +1,187 @@
+//! Alerts on targets whose filtered offset stays beyond a threshold, by
+//! running a command or posting to a webhook
+//!
+//! An alert fires once the threshold is exceeded by a number of consecutive
+//! samples, and again only after the offset got back within it.
+
+use crate::{analysis::Sample, clock::Timestamp, http::Endpoint};
+use anyhow::{bail, Context, Result};
+use std::{collections::HashMap, process, sync::mpsc, thread};
+use tracing::{info, warn};
+
+pub struct AlertConfig {
+    /// Filtered offset magnitude alerted on (seconds)
+    pub threshold: f64,
+    /// Consecutive samples beyond the threshold that raise an alert
+    pub samples: u32,
+    /// Shell command run on alerts, with the values in `CO_*` variables
+    pub command: Option<String>,
+    /// `http://` URL alerts are posted to as JSON
+    pub url: Option<String>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 0.1,
+            samples: 3,
+            command: None,
+            url: None,
+        }
+    }
+}
+
+/// Values of the sample raising an alert
+struct Alert {
+    target: String,
+    /// Receive time of the reply
+    time: Timestamp,
+    offset: f64,
+    filtered_offset: f64,
+    rtt: f64,
+}
+
+impl Alert {
+    fn to_json(&self, hooks: &Hooks) -> String {
+        format!(
+            "{{\"target\":{},\"time\":{},\"offset\":{:.9},\"filtered_offset\":{:.9},\"rtt\":{:.9},\"threshold\":{:.9},\"samples\":{}}}",
+            serde_json::Value::from(self.target.as_str()),
+            self.time,
+            self.offset,
+            self.filtered_offset,
+            self.rtt,
+            hooks.threshold,
+            hooks.samples
+        )
+    }
+
+    fn run(&self, command: &str, hooks: &Hooks) -> Result<()> {
+        #[cfg(unix)]
+        let mut shell = process::Command::new("sh");
+        #[cfg(unix)]
+        shell.arg("-c");
+        #[cfg(not(unix))]
+        let mut shell = process::Command::new("cmd");
+        #[cfg(not(unix))]
+        shell.arg("/C");
+        let status = shell
+            .arg(command)
+            .env("CO_TARGET", &self.target)
+            .env("CO_TIME", self.time.to_string())
+            .env("CO_OFFSET", format!("{:.9}", self.offset))
+            .env("CO_FILTERED_OFFSET", format!("{:.9}", self.filtered_offset))
+            .env("CO_RTT", format!("{:.9}", self.rtt))
+            .env("CO_THRESHOLD", format!("{:.9}", hooks.threshold))
+            .env("CO_SAMPLES", hooks.samples.to_string())
+            .status()
+            .context("failed to run the alert command")?;
+        if !status.success() {
+            bail!("alert command exited with {}", status);
+        }
+        Ok(())
+    }
+}
+
+/// Watches the filtered offsets of the targets, firing the alert hooks from
+/// a background thread, so that slow hooks do not hold up measuring
+pub struct Alerter {
+    threshold: f64,
+    samples: u32,
+    /// Consecutive samples beyond the threshold by target
+    streaks: HashMap<String, u32>,
+    alerts: Option<mpsc::Sender<Alert>>,
+}
+
+/// What the hook thread needs of the config
+struct Hooks {
+    threshold: f64,
+    samples: u32,
+    command: Option<String>,
+    endpoint: Option<Endpoint>,
+}
+
+impl Alerter {
+    pub fn new(config: AlertConfig) -> Result<Self> {
+        let hooks = Hooks {
+            threshold: config.threshold,
+            samples: config.samples.max(1),
+            command: config.command,
+            endpoint: config
+                .url
+                .as_deref()
+                .map(Endpoint::parse)
+                .transpose()
+                .context("invalid alert URL")?,
+        };
+        let alerts = if hooks.command.is_some() || hooks.endpoint.is_some() {
+            let (alerts, rx) = mpsc::channel();
+            thread::Builder::new()
+                .name("alert".to_owned())
+                .spawn(move || run_hooks(hooks, rx))
+                .context("failed to start the alert hooks")?;
+            Some(alerts)
+        } else {
+            None
+        };
+        Ok(Self {
+            threshold: config.threshold,
+            samples: config.samples.max(1),
+            streaks: HashMap::new(),
+            alerts,
+        })
+    }
+
+    /// Account for an accepted sample of `target`, alerting if it makes the
+    /// required run of samples beyond the threshold
+    pub fn update(&mut self, target: &str, sample: &Sample) {
+        let offset = sample.filtered_offset;
+        let streak = self.streaks.entry(target.to_owned()).or_default();
+        if offset.abs() <= self.threshold {
+            if *streak >= self.samples {
+                info!(
+                    "Offset {:.9} to {} is back within the alert threshold",
+                    offset, target
+                );
+            }
+            *streak = 0;
+            return;
+        }
+        *streak += 1;
+        if *streak != self.samples {
+            return;
+        }
+        warn!(
+            "Offset {:.9} to {} exceeded the alert threshold of {} seconds for {} samples",
+            offset, target, self.threshold, self.samples
+        );
+        if let Some(alerts) = &self.alerts {
+            let m = &sample.measurement;
+            let alert = Alert {
+                target: target.to_owned(),
+                time: m.t4,
+                offset: m.offset,
+                filtered_offset: offset,
+                rtt: m.rtt,
+            };
+            if alerts.send(alert).is_err() {
+                warn!("Alert hooks stopped");
+            }
+        }
+    }
+}
+
+fn run_hooks(hooks: Hooks, rx: mpsc::Receiver<Alert>) {
+    while let Ok(alert) = rx.recv() {
+        if let Some(command) = &hooks.command {
+            if let Err(e) = alert.run(command, &hooks) {
+                warn!("Alert for {} failed: {:#}", alert.target, e);
+            }
+        }
+        if let Some(endpoint) = &hooks.endpoint {
+            let body = alert.to_json(&hooks);
+            if let Err(e) = endpoint.post("", "application/json", &body) {
+                warn!("Alert post to {} failed: {:#}", endpoint.authority, e);
+            }
+        }
+    }
+}
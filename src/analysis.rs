@@ -0,0 +1,169 @@
+//! Per-target processing of raw measurements into derived estimates
+
+use crate::{
+    clock_filter::ClockFilter,
+    drift::DriftEstimator,
+    kernel_state::KernelState,
+    measurement::Measurement,
+    outlier::OutlierFilter,
+    path_change::PathChangeDetector,
+    smoothing::{Smoother, SmoothingFilter},
+    step::StepDetector,
+};
+use std::fmt;
+
+/// Analysis settings
+#[derive(Clone, Debug)]
+pub struct AnalyzerConfig {
+    /// Number of samples in the drift regression window
+    pub drift_window: usize,
+    /// Number of samples the minimum-delay clock filter selects from
+    pub clock_filter_size: usize,
+    /// Discard samples with RTT above this many seconds
+    pub max_rtt: Option<f64>,
+    /// Discard samples with RTT this many scaled MADs above the recent median
+    pub mad_threshold: Option<f64>,
+    /// Produce a smoothed offset estimate with this filter
+    pub smoothing: Option<SmoothingFilter>,
+    /// Flag clock steps, offset changes this many times above the recent noise
+    /// floor, and restart the estimators there
+    pub step_threshold: Option<f64>,
+    /// Flag path changes when the minimum delay of the recent samples moves
+    /// by more than this many seconds, besides when the TTL of replies changes,
+    /// and restart the estimators there, as on clock steps
+    pub path_change_delay: Option<f64>,
+}
+
+impl Default for AnalyzerConfig {
+    fn default() -> Self {
+        Self {
+            drift_window: 64,
+            clock_filter_size: 8,
+            max_rtt: None,
+            mad_threshold: None,
+            smoothing: None,
+            step_threshold: Some(10.0),
+            path_change_delay: None,
+        }
+    }
+}
+
+/// Markers attached to a sample
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags {
+    /// Rejected as an outlier and not fed into the estimators
+    pub discarded: bool,
+    /// First sample after a clock step, the estimators restart from it
+    pub step: bool,
+    /// First sample over an apparently new network path, the estimators
+    /// restart from it
+    pub path_change: bool,
+}
+
+impl Flags {
+    /// Names of the set flags
+    pub fn markers(&self) -> Vec<&'static str> {
+        [
+            (self.discarded, "discarded"),
+            (self.step, "step"),
+            (self.path_change, "path_change"),
+        ]
+        .iter()
+        .filter_map(|&(set, marker)| set.then_some(marker))
+        .collect()
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.markers().join("|"))
+    }
+}
+
+/// Measurement together with the estimates derived from it and its predecessors
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub measurement: Measurement,
+    /// Local clock frequency error in ppm
+    pub drift_ppm: Option<f64>,
+    /// Offset of the minimum-delay sample among the recent ones
+    pub filtered_offset: f64,
+    /// Smoothed offset, if a smoothing filter is configured
+    pub offset_est: Option<f64>,
+    /// Kernel NTP state at the time the sample was reported, if queried
+    pub kernel: Option<KernelState>,
+    pub flags: Flags,
+}
+
+/// Derives estimates from the measurements of a single target
+#[derive(Debug)]
+pub struct Analyzer {
+    drift: DriftEstimator,
+    clock_filter: ClockFilter,
+    outliers: OutlierFilter,
+    smoother: Option<Smoother>,
+    smoothing: Option<SmoothingFilter>,
+    steps: Option<StepDetector>,
+    paths: PathChangeDetector,
+}
+
+impl Analyzer {
+    pub fn new(config: &AnalyzerConfig) -> Self {
+        Self {
+            drift: DriftEstimator::new(config.drift_window),
+            clock_filter: ClockFilter::new(config.clock_filter_size),
+            outliers: OutlierFilter::new(config.max_rtt, config.mad_threshold, config.drift_window),
+            smoother: config.smoothing.map(Smoother::new),
+            smoothing: config.smoothing,
+            steps: config
+                .step_threshold
+                .map(|threshold| StepDetector::new(threshold, config.drift_window)),
+            paths: PathChangeDetector::new(config.path_change_delay),
+        }
+    }
+
+    pub fn process(&mut self, measurement: Measurement) -> Sample {
+        let mut flags = Flags::default();
+        // Before the outlier filter, which may reject the delays of a new path
+        if self.paths.is_change(&measurement) {
+            flags.path_change = true;
+            self.restart();
+            // The offset shift of the new path is not a clock step
+            if let Some(steps) = &mut self.steps {
+                steps.reset();
+            }
+        }
+        if self.outliers.is_outlier(&measurement) {
+            flags.discarded = true;
+        } else {
+            if self.steps.as_mut().is_some_and(|s| s.is_step(&measurement)) {
+                flags.step = true;
+                self.restart();
+            }
+            self.drift.add(measurement.t1, measurement.offset);
+            self.clock_filter.add(measurement);
+            if let Some(smoother) = &mut self.smoother {
+                smoother.add(&measurement);
+            }
+        }
+
+        Sample {
+            measurement,
+            drift_ppm: self.drift.ppm(),
+            filtered_offset: self
+                .clock_filter
+                .best()
+                .map_or(measurement.offset, |best| best.offset),
+            offset_est: self.smoother.as_ref().and_then(Smoother::estimate),
+            kernel: None,
+            flags,
+        }
+    }
+
+    /// Forget the samples from before a clock step or path change
+    fn restart(&mut self) {
+        self.drift.reset();
+        self.clock_filter.reset();
+        self.smoother = self.smoothing.map(Smoother::new);
+    }
+}
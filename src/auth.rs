@@ -0,0 +1,166 @@
+//! Packet authentication and encryption with a pre-shared key
+//!
+//! Authenticated packets have [`protocol::FLAG_AUTHENTICATED`] set and carry a
+//! truncated HMAC-SHA256 over the whole packet, header included, at the end.
+//!
+//! Encrypted packets also have [`protocol::FLAG_ENCRYPTED`] set: everything
+//! after the header is sealed with XChaCha20-Poly1305 under a key derived from
+//! the pre-shared one, the header being authenticated along, and the random
+//! nonce and the tag follow. Timestamps are then hidden from the path as well.
+
+use crate::{protocol, random};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use chacha20poly1305::{aead::AeadInPlace, KeyInit, Tag, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{borrow::Cow, fmt, str::FromStr};
+
+/// Size of the MAC appended to authenticated packets
+pub const MAC_SIZE: usize = 16;
+const MIN_KEY_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// Context of the derived encryption key, so it differs from the HMAC key
+const ENCRYPTION_CONTEXT: &[u8] = b"co packet encryption";
+
+/// Pre-shared key
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+    bytes: Vec<u8>,
+    encrypt: bool,
+}
+
+impl Key {
+    pub fn new(bytes: Vec<u8>) -> Result<Self> {
+        ensure!(
+            bytes.len() >= MIN_KEY_SIZE,
+            "key must be at least {} bytes long",
+            MIN_KEY_SIZE
+        );
+        Ok(Self {
+            bytes,
+            encrypt: false,
+        })
+    }
+
+    /// Encrypt packets rather than only authenticate them, and require
+    /// received packets to be encrypted
+    pub fn encrypting(self) -> Self {
+        Self {
+            encrypt: true,
+            ..self
+        }
+    }
+
+    /// Bytes added to packets by [`Key::sign`]
+    pub fn overhead(&self) -> usize {
+        match self.encrypt {
+            true => NONCE_SIZE + TAG_SIZE,
+            false => MAC_SIZE,
+        }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        <Hmac<Sha256> as Mac>::new_from_slice(&self.bytes).expect("HMAC accepts keys of any size")
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        let mut mac = self.mac();
+        mac.update(ENCRYPTION_CONTEXT);
+        XChaCha20Poly1305::new(&mac.finalize().into_bytes())
+    }
+
+    /// Flag `packet` as authenticated and append its MAC, or encrypt it
+    pub fn sign(&self, mut packet: Vec<u8>) -> Result<Vec<u8>> {
+        if self.encrypt {
+            return self.seal(packet);
+        }
+        packet[protocol::FLAGS_OFFSET] |= protocol::FLAG_AUTHENTICATED;
+        let mut mac = self.mac();
+        mac.update(&packet);
+        packet.extend_from_slice(&mac.finalize().into_bytes()[..MAC_SIZE]);
+        Ok(packet)
+    }
+
+    /// Check the MAC of an authenticated packet, returning the packet without
+    /// it, or decrypt an encrypted one
+    pub fn verify<'a>(&self, packet: &'a [u8]) -> Result<Cow<'a, [u8]>> {
+        if !protocol::is_authenticated(packet) {
+            bail!("unauthenticated packet");
+        }
+        match (self.encrypt, protocol::is_encrypted(packet)) {
+            (true, true) => return self.open(packet).map(Cow::Owned),
+            (true, false) => bail!("unencrypted packet"),
+            (false, true) => bail!("encrypted packet, but encryption is not enabled"),
+            (false, false) => {}
+        }
+        ensure!(
+            packet.len() >= protocol::HEADER_SIZE + MAC_SIZE,
+            "authenticated packet too short"
+        );
+
+        let (data, tag) = packet.split_at(packet.len() - MAC_SIZE);
+        let mut mac = self.mac();
+        mac.update(data);
+        if mac.verify_truncated_left(tag).is_err() {
+            bail!("authentication failed");
+        }
+        Ok(Cow::Borrowed(data))
+    }
+
+    fn seal(&self, mut packet: Vec<u8>) -> Result<Vec<u8>> {
+        packet[protocol::FLAGS_OFFSET] |= protocol::FLAG_AUTHENTICATED | protocol::FLAG_ENCRYPTED;
+        let mut nonce = XNonce::default();
+        random::fill(&mut nonce).context("failed to generate a nonce")?;
+        let (header, payload) = packet.split_at_mut(protocol::HEADER_SIZE);
+        let tag = self
+            .cipher()
+            .encrypt_in_place_detached(&nonce, header, payload)
+            .expect("packets are far below the size limit");
+        packet.extend_from_slice(&nonce);
+        packet.extend_from_slice(&tag);
+        Ok(packet)
+    }
+
+    fn open(&self, packet: &[u8]) -> Result<Vec<u8>> {
+        ensure!(
+            packet.len() >= protocol::HEADER_SIZE + NONCE_SIZE + TAG_SIZE,
+            "encrypted packet too short"
+        );
+        let (data, rest) = packet.split_at(packet.len() - NONCE_SIZE - TAG_SIZE);
+        let (nonce, tag) = rest.split_at(NONCE_SIZE);
+        let mut data = data.to_vec();
+        let (header, payload) = data.split_at_mut(protocol::HEADER_SIZE);
+        self.cipher()
+            .decrypt_in_place_detached(
+                XNonce::from_slice(nonce),
+                header,
+                payload,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| anyhow!("decryption failed"))?;
+        Ok(data)
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    /// Parse a hex-encoded key
+    fn from_str(s: &str) -> Result<Self> {
+        ensure!(s.len().is_multiple_of(2), "odd number of hex digits in key");
+        let bytes = (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(s.get(i..i + 2).unwrap_or_default(), 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("key is not valid hex"))?;
+        Self::new(bytes)
+    }
+}
+
+// Keep the key out of logs
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Key(..)")
+    }
+}
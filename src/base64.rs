@@ -0,0 +1,53 @@
+//! Standard base64 with padding (RFC 4648), for handshake headers and public keys
+
+use anyhow::{anyhow, ensure, Result};
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+pub fn decode(s: &str) -> Result<Vec<u8>> {
+    let s = s.as_bytes();
+    ensure!(
+        s.len().is_multiple_of(4),
+        "base64 length is not a multiple of 4"
+    );
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    for (i, chunk) in s.chunks(4).enumerate() {
+        let last = i == s.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        ensure!(
+            padding <= 2 && (padding == 0 || last),
+            "invalid base64 padding"
+        );
+        let mut n = 0u32;
+        for &c in &chunk[..4 - padding] {
+            let value = ALPHABET
+                .iter()
+                .position(|&a| a == c)
+                .ok_or_else(|| anyhow!("invalid base64 character '{}'", c as char))?;
+            n = n << 6 | value as u32;
+        }
+        n <<= 6 * padding;
+        out.extend_from_slice(&n.to_be_bytes()[1..4 - padding]);
+    }
+    Ok(out)
+}
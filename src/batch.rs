@@ -0,0 +1,196 @@
+//! Batched datagram I/O with `recvmmsg()` and `sendmmsg()` (Linux)
+
+use crate::{
+    clock::Clock,
+    socket::{self, PacketInfo, Received},
+    timestamping::TimestampSource,
+};
+use anyhow::{anyhow, Result};
+use nix::sys::socket::{SockaddrLike, SockaddrStorage};
+use std::{io, mem, net::SocketAddr, os::unix::io::AsRawFd};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Receive buffers for up to a batch of datagrams
+pub struct RecvBatch {
+    bufs: Vec<[u8; 2048]>, // should be enough for MTU 1500
+    names: Vec<libc::sockaddr_storage>,
+    controls: Vec<[u64; 64]>,
+    received: Vec<Received>,
+}
+
+impl RecvBatch {
+    pub fn new(size: usize) -> Self {
+        Self {
+            bufs: vec![[0; 2048]; size],
+            names: vec![unsafe { mem::zeroed() }; size],
+            controls: vec![[0; 64]; size],
+            received: Vec::with_capacity(size),
+        }
+    }
+
+    /// Wait for datagrams and read as many as are queued, up to the batch size
+    ///
+    /// Each datagram keeps its own kernel timestamp; without one, all of them
+    /// get the same receive time read from `clock` after the call.
+    pub async fn recv(&mut self, socket: &UdpSocket, clock: &Clock) -> Result<()> {
+        let messages = loop {
+            socket.readable().await?;
+            match socket.try_io(Interest::READABLE, || self.recvmmsg(socket)) {
+                Ok(messages) => break messages,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        self.received.clear();
+        let mut now = None;
+        for msg in messages {
+            let (timestamp, source) = match msg.timestamp {
+                Some(timestamp) => timestamp,
+                None => match now {
+                    Some(now) => (now, TimestampSource::Userspace),
+                    None => {
+                        let timestamp = clock.now()?;
+                        now = Some(timestamp);
+                        (timestamp, TimestampSource::Userspace)
+                    }
+                },
+            };
+            self.received.push(Received {
+                len: msg.len,
+                from: msg
+                    .from
+                    .ok_or_else(|| anyhow!("recvmmsg() returned no source address"))?,
+                timestamp,
+                source,
+                dest: msg.dest,
+                ttl: msg.ttl,
+                tos: msg.tos,
+            });
+        }
+        Ok(())
+    }
+
+    /// Datagrams of the latest `recv()`
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], &Received)> {
+        self.bufs
+            .iter()
+            .zip(&self.received)
+            .map(|(buf, received)| (&buf[..received.len], received))
+    }
+
+    /// Non-async `recvmmsg()`
+    fn recvmmsg(&mut self, socket: &UdpSocket) -> io::Result<Vec<socket::Message>> {
+        let mut iovs: Vec<libc::iovec> = self
+            .bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut hdrs: Vec<libc::mmsghdr> = iovs
+            .iter_mut()
+            .zip(&mut self.names)
+            .zip(&mut self.controls)
+            .map(|((iov, name), control)| {
+                let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+                hdr.msg_hdr.msg_name = name as *mut _ as *mut libc::c_void;
+                hdr.msg_hdr.msg_namelen = mem::size_of_val(name) as libc::socklen_t;
+                hdr.msg_hdr.msg_iov = iov;
+                hdr.msg_hdr.msg_iovlen = 1;
+                hdr.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_hdr.msg_controllen = mem::size_of_val(control) as _;
+                hdr
+            })
+            .collect();
+
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                hdrs.as_mut_ptr(),
+                hdrs.len() as _,
+                libc::MSG_DONTWAIT,
+                std::ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(hdrs[..count as usize]
+            .iter()
+            .zip(&self.names)
+            .map(|(hdr, name)| unsafe {
+                socket::parse_message(&hdr.msg_hdr, name, hdr.msg_len as usize)
+            })
+            .collect())
+    }
+}
+
+/// Datagram to send
+pub struct Outgoing {
+    pub buf: Vec<u8>,
+    pub to: SocketAddr,
+    /// Local source address, the routing choice if `None`
+    pub from: Option<PacketInfo>,
+}
+
+/// Send all `datagrams` with as few `sendmmsg()` calls as the socket buffer allows
+pub async fn send(socket: &UdpSocket, datagrams: &[Outgoing]) -> Result<()> {
+    let mut sent = 0;
+    while sent < datagrams.len() {
+        socket.writable().await?;
+        match socket.try_io(Interest::WRITABLE, || sendmmsg(socket, &datagrams[sent..])) {
+            Ok(count) => sent += count,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Non-async `sendmmsg()`, returning the number of datagrams sent
+fn sendmmsg(socket: &UdpSocket, datagrams: &[Outgoing]) -> io::Result<usize> {
+    let names: Vec<SockaddrStorage> = datagrams
+        .iter()
+        .map(|datagram| SockaddrStorage::from(datagram.to))
+        .collect();
+    let mut controls = vec![[0u64; 8]; datagrams.len()];
+    let mut iovs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|datagram| libc::iovec {
+            iov_base: datagram.buf.as_ptr() as *mut libc::c_void,
+            iov_len: datagram.buf.len(),
+        })
+        .collect();
+    let mut hdrs: Vec<libc::mmsghdr> = datagrams
+        .iter()
+        .zip(&names)
+        .zip(iovs.iter_mut().zip(&mut controls))
+        .map(|((datagram, name), (iov, control))| {
+            let mut hdr: libc::mmsghdr = unsafe { mem::zeroed() };
+            hdr.msg_hdr.msg_name = name.as_ptr() as *mut libc::c_void;
+            hdr.msg_hdr.msg_namelen = name.len();
+            hdr.msg_hdr.msg_iov = iov;
+            hdr.msg_hdr.msg_iovlen = 1;
+            if let Some(from) = datagram.from {
+                hdr.msg_hdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                unsafe { socket::write_pktinfo(&mut hdr.msg_hdr, from) };
+            }
+            hdr
+        })
+        .collect();
+
+    let count = unsafe {
+        libc::sendmmsg(
+            socket.as_raw_fd(),
+            hdrs.as_mut_ptr(),
+            hdrs.len() as _,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if count < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(count as usize)
+}
@@ -0,0 +1,67 @@
+//! Startup calibration of the local timestamping overhead
+//!
+//! Userspace timestamps are taken before the send and after the receive
+//! syscalls, so every exchange includes the local send and receive paths.
+//! Timing datagrams over loopback, where the wire takes no time, measures
+//! that constant, which can then be taken off the offset bounds.
+
+use crate::clock::{nsec_to_sec, Clock};
+use anyhow::{Context, Result};
+use std::{fmt, net::Ipv4Addr};
+use tokio::net::UdpSocket;
+
+/// Clock reads and loopback datagrams timed
+const ROUNDS: usize = 1000;
+
+/// Local timestamping overhead, the lowest ones seen over the rounds
+#[derive(Clone, Copy, Debug)]
+pub struct Calibration {
+    /// Cost of reading the clock (seconds)
+    pub clock_read: f64,
+    /// Userspace send and receive path of a datagram: from the timestamp
+    /// before sending to the one after receiving it over loopback (seconds)
+    pub send_receive: f64,
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "clock read {:.0} ns, send/receive path {:.1} us",
+            self.clock_read * 1e9,
+            self.send_receive * 1e6
+        )
+    }
+}
+
+/// Time reads of `clock` and datagrams over loopback
+pub async fn calibrate(clock: &Clock) -> Result<Calibration> {
+    let mut clock_read = i128::MAX;
+    let mut previous = clock.now()?;
+    for _ in 0..ROUNDS {
+        let now = clock.now()?;
+        clock_read = clock_read.min(now.total_nsec() - previous.total_nsec());
+        previous = now;
+    }
+
+    let bind = || UdpSocket::bind((Ipv4Addr::LOCALHOST, 0));
+    let (sender, receiver) = (bind().await?, bind().await?);
+    sender
+        .connect(receiver.local_addr()?)
+        .await
+        .context("failed to connect the calibration socket")?;
+    let mut send_receive = i128::MAX;
+    let mut buf = [0; 64];
+    for _ in 0..ROUNDS {
+        let t1 = clock.now()?;
+        sender.send(&buf[..48]).await?;
+        receiver.recv(&mut buf).await?;
+        let t4 = clock.now()?;
+        send_receive = send_receive.min(t4.total_nsec() - t1.total_nsec());
+    }
+
+    Ok(Calibration {
+        clock_read: nsec_to_sec(clock_read.max(0)),
+        send_receive: nsec_to_sec(send_receive.max(0)),
+    })
+}
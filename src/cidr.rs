@@ -0,0 +1,82 @@
+use anyhow::{anyhow, ensure, Context, Result};
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// IP network in CIDR notation, e.g. `192.0.2.0/24` or `2001:db8::/32`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max_len = max_prefix_len(&network);
+        ensure!(
+            prefix_len <= max_len,
+            "prefix length {} exceeds {}",
+            prefix_len,
+            max_len
+        );
+        Ok(Self {
+            network: mask(network, prefix_len),
+            prefix_len,
+        })
+    }
+
+    /// Whether `addr` is in the network; IPv4-mapped IPv6 addresses match
+    /// both IPv4 networks and IPv6 ones such as `::ffff:192.0.2.0/120`
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let matches = |addr: IpAddr| {
+            addr.is_ipv4() == self.network.is_ipv4() && mask(addr, self.prefix_len) == self.network
+        };
+        matches(*addr) || matches(addr.to_canonical())
+    }
+}
+
+fn max_prefix_len(addr: &IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V4((u32::from(v4) & mask).into())
+        }
+        IpAddr::V6(v6) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            IpAddr::V6((u128::from(v6) & mask).into())
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    /// Parse `addr/len`, a bare address is a single-host network
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .with_context(|| format!("invalid network address in {}", s))?;
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix length in {}", s))?,
+            None => max_prefix_len(&network),
+        };
+        Self::new(network, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
@@ -0,0 +1,118 @@
+//! Per-client statistics of a reflector, for passive monitoring of client clocks
+
+use crate::clock::{nsec_to_sec, Timestamp};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    time::Instant,
+};
+
+/// Number of tracked clients above which idle ones are forgotten
+const MAX_CLIENTS: usize = 4096;
+/// Clients silent for this many seconds count as idle
+const IDLE_SECONDS: u64 = 3600;
+/// Recent probes the apparent offset is taken from
+const OFFSET_WINDOW: usize = 8;
+
+/// What the reflector has seen of one client
+#[derive(Clone, Debug)]
+pub struct ClientStats {
+    /// Probes answered
+    pub packets: u64,
+    /// Local receive time of the latest probe
+    pub last_seen: Timestamp,
+    /// Client send time minus local receive time of the latest probe
+    pub last_offset: f64,
+    /// Highest `last_offset` of the recent probes: a lower bound on the client
+    /// clock offset, off by the smallest forward delay
+    pub apparent_offset: f64,
+    recent: VecDeque<f64>,
+    updated: Instant,
+}
+
+/// Statistics of the clients a reflector answered
+#[derive(Debug, Default)]
+pub struct ClientTable {
+    clients: HashMap<IpAddr, ClientStats>,
+}
+
+impl ClientTable {
+    /// Record a probe sent by `client` at its time `t1` and received at local time `t2`
+    pub fn add(&mut self, client: IpAddr, t1: Timestamp, t2: Timestamp) {
+        // Dual-stack sockets see IPv4 clients as v4-mapped addresses
+        let client = client.to_canonical();
+        let now = Instant::now();
+        if self.clients.len() >= MAX_CLIENTS && !self.clients.contains_key(&client) {
+            self.clients
+                .retain(|_, stats| now.duration_since(stats.updated).as_secs() < IDLE_SECONDS);
+            if self.clients.len() >= MAX_CLIENTS {
+                return;
+            }
+        }
+
+        let offset = nsec_to_sec(t1.total_nsec() - t2.total_nsec());
+        let stats = self.clients.entry(client).or_insert_with(|| ClientStats {
+            packets: 0,
+            last_seen: t2,
+            last_offset: offset,
+            apparent_offset: offset,
+            recent: VecDeque::with_capacity(OFFSET_WINDOW),
+            updated: now,
+        });
+        if stats.recent.len() == OFFSET_WINDOW {
+            stats.recent.pop_front();
+        }
+        stats.recent.push_back(offset);
+        stats.packets += 1;
+        stats.last_seen = t2;
+        stats.last_offset = offset;
+        stats.apparent_offset = stats.recent.iter().copied().fold(f64::MIN, f64::max);
+        stats.updated = now;
+    }
+
+    /// Number of clients tracked
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Clients ordered by address
+    pub fn snapshot(&self) -> Vec<(IpAddr, ClientStats)> {
+        let mut clients: Vec<_> = self
+            .clients
+            .iter()
+            .map(|(addr, stats)| (*addr, stats.clone()))
+            .collect();
+        clients.sort_by_key(|(addr, _)| *addr);
+        clients
+    }
+}
+
+/// Table of clients, one per line
+pub struct ClientReport(pub Vec<(IpAddr, ClientStats)>);
+
+impl fmt::Display for ClientReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<39} {:>10} {:>16} {:>16}  last seen",
+            "client", "packets", "apparent offset", "last offset"
+        )?;
+        for (addr, stats) in &self.0 {
+            writeln!(
+                f,
+                "{:<39} {:>10} {:>16.9} {:>16.9}  {}",
+                addr.to_string(),
+                stats.packets,
+                stats.apparent_offset,
+                stats.last_offset,
+                stats.last_seen
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,422 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{fmt, fs::File, path::PathBuf, str::FromStr, sync::Arc, time::Duration};
+
+const NANOSECONDS_IN_SECOND: i128 = 1000000000;
+
+/// Point in time as seconds and nanoseconds since the epoch
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub sec: i64,
+    pub nsec: i64,
+}
+
+impl Timestamp {
+    pub fn new(sec: i64, nsec: i64) -> Self {
+        Self { sec, nsec }
+    }
+
+    /// Current system (wall clock) time
+    pub fn now() -> Result<Self> {
+        sys::now(&Clock::Realtime)
+    }
+
+    pub fn from_nsec(nsec: i128) -> Self {
+        Self::new(
+            nsec.div_euclid(NANOSECONDS_IN_SECOND) as i64,
+            nsec.rem_euclid(NANOSECONDS_IN_SECOND) as i64,
+        )
+    }
+
+    pub fn total_nsec(&self) -> i128 {
+        self.sec as i128 * NANOSECONDS_IN_SECOND + self.nsec as i128
+    }
+
+    pub fn to_le_bytes(self) -> [u8; 16] {
+        let mut bytes = [0; 16];
+        bytes[..8].copy_from_slice(&self.sec.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.nsec.to_le_bytes());
+        bytes
+    }
+
+    pub fn from_le_bytes(bytes: &[u8]) -> Result<Self> {
+        let sec = i64::from_le_bytes(bytes[..8].try_into()?);
+        let nsec = i64::from_le_bytes(bytes[8..16].try_into()?);
+        Ok(Self::new(sec, nsec))
+    }
+
+    /// RFC 3339 date and time with nanoseconds, `offset` seconds east of UTC,
+    /// or in UTC (`Z`) without one
+    pub fn to_rfc3339(&self, offset: Option<i64>) -> String {
+        let t = DateTime::from_unix(self.sec + offset.unwrap_or(0));
+        let zone = match offset {
+            None => "Z".to_owned(),
+            Some(offset) => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                let minutes = offset.abs() / 60;
+                format!("{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+            }
+        };
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}{}",
+            t.year, t.month, t.day, t.hour, t.minute, t.second, self.nsec, zone
+        )
+    }
+
+    /// Parse an RFC 3339 date and time, such as written by [`Timestamp::to_rfc3339`]
+    fn from_rfc3339(s: &str) -> Option<Self> {
+        let number = |s: &str| {
+            s.bytes()
+                .all(|b| b.is_ascii_digit())
+                .then(|| s.parse::<i64>().ok())
+                .flatten()
+        };
+        let (date, time) = s.split_once(['T', 't', ' '])?;
+        let mut date = date.splitn(3, '-');
+        let (year, month, day) = (
+            number(date.next()?)?,
+            number(date.next()?)?,
+            number(date.next()?)?,
+        );
+        let (time, offset) = match time.strip_suffix(['Z', 'z']) {
+            Some(time) => (time, 0),
+            None => {
+                let i = time.rfind(['+', '-'])?;
+                let (hours, minutes) = time[i + 1..].split_once(':')?;
+                let offset = number(hours)? * 3600 + number(minutes)? * 60;
+                (
+                    &time[..i],
+                    if &time[i..=i] == "-" { -offset } else { offset },
+                )
+            }
+        };
+        let (time, frac) = time.split_once('.').unwrap_or((time, "0"));
+        if frac.is_empty() || frac.len() > 9 {
+            return None;
+        }
+        let mut time = time.splitn(3, ':');
+        let (hours, minutes, seconds) = (
+            number(time.next()?)?,
+            number(time.next()?)?,
+            number(time.next()?)?,
+        );
+        if !(1..=12).contains(&month)
+            || !(1..=31).contains(&day)
+            || hours > 23
+            || minutes > 59
+            || seconds > 60
+        {
+            return None;
+        }
+        let t = DateTime {
+            year,
+            month: month as u32,
+            day: day as u32,
+            hour: hours as u32,
+            minute: minutes as u32,
+            second: seconds as u32,
+        };
+        Some(Self::new(
+            t.to_unix() - offset,
+            number(&format!("{:0<9}", frac))?,
+        ))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:09}", self.sec, self.nsec)
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
+
+    /// Parse the `sec.nsec` form written by `Display`, without rounding, or
+    /// an RFC 3339 date and time
+    fn from_str(s: &str) -> Result<Self> {
+        if s.contains(['T', 't', ' ']) {
+            return Self::from_rfc3339(s).ok_or_else(|| anyhow!("invalid timestamp {}", s));
+        }
+        let (sec, frac) = s.split_once('.').unwrap_or((s, "0"));
+        if frac.is_empty() || frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(anyhow!("invalid timestamp {}", s));
+        }
+        let sec: i64 = sec
+            .parse()
+            .with_context(|| format!("invalid timestamp {}", s))?;
+        let nsec = format!("{:0<9}", frac).parse()?;
+        Ok(Self::new(sec, nsec))
+    }
+}
+
+/// Local clock the probes and replies are timestamped with
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Clock {
+    /// System wall clock, `CLOCK_REALTIME`
+    #[default]
+    Realtime,
+    /// International Atomic Time, `CLOCK_TAI`, free of leap seconds (Linux)
+    Tai,
+    /// Time since an unspecified start, not affected by clock steps
+    Monotonic,
+    /// Monotonic time including suspend, `CLOCK_BOOTTIME` (Linux)
+    Boottime,
+    /// PTP hardware clock of a NIC (Linux)
+    Phc(Arc<Phc>),
+}
+
+impl Clock {
+    /// Current time of this clock
+    pub fn now(&self) -> Result<Timestamp> {
+        sys::now(self)
+    }
+
+    /// Fail unless timestamps taken by the kernel are comparable to this clock
+    ///
+    /// Software timestamps are `CLOCK_REALTIME`, raw hardware timestamps are
+    /// in the time of the NIC's PHC, which must then be the one timestamped with.
+    pub fn check_kernel_timestamps(&self, hardware: bool) -> Result<()> {
+        match self {
+            Clock::Realtime => Ok(()),
+            Clock::Phc(_) if hardware => Ok(()),
+            Clock::Phc(_) => bail!("the {} clock requires hardware timestamps", self),
+            _ => bail!(
+                "kernel and hardware timestamps require the realtime clock, not {}",
+                self
+            ),
+        }
+    }
+}
+
+/// Open PTP hardware clock device, like `/dev/ptp0`
+#[derive(Debug)]
+pub struct Phc {
+    path: PathBuf,
+    /// Kept open, the clock id is derived from the descriptor
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    device: File,
+}
+
+impl Phc {
+    #[cfg(target_os = "linux")]
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let device =
+            File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(Self { path, device })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(_path: PathBuf) -> Result<Self> {
+        bail!("PTP hardware clocks are only supported on Linux")
+    }
+
+    /// Dynamic POSIX clock id of the device, `FD_TO_CLOCKID()` of the kernel
+    #[cfg(target_os = "linux")]
+    fn clock_id(&self) -> libc::clockid_t {
+        use std::os::unix::io::AsRawFd;
+        (!self.device.as_raw_fd() << 3) | 3
+    }
+}
+
+impl PartialEq for Phc {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+
+impl Eq for Phc {}
+
+impl fmt::Display for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Clock::Realtime => "realtime",
+            Clock::Tai => "tai",
+            Clock::Monotonic => "monotonic",
+            Clock::Boottime => "boottime",
+            Clock::Phc(phc) => return write!(f, "phc:{}", phc.path.display()),
+        })
+    }
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    /// A clock name, or `phc:<device>` opening the device
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(path) = s.strip_prefix("phc:") {
+            let clock = Clock::Phc(Arc::new(Phc::open(path.into())?));
+            // Reject devices that are not clocks right away
+            clock.now()?;
+            return Ok(clock);
+        }
+        match s {
+            "realtime" => Ok(Clock::Realtime),
+            "tai" => Ok(Clock::Tai),
+            "monotonic" => Ok(Clock::Monotonic),
+            "boottime" => Ok(Clock::Boottime),
+            _ => bail!(
+                "unknown clock '{}', expected realtime, tai, monotonic, boottime or phc:<device>",
+                s
+            ),
+        }
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::{Clock, Timestamp};
+    use anyhow::{Context, Result};
+    use nix::time::{clock_gettime, ClockId};
+
+    pub fn now(clock: &Clock) -> Result<Timestamp> {
+        let id = match clock {
+            Clock::Realtime => ClockId::CLOCK_REALTIME,
+            Clock::Monotonic => ClockId::CLOCK_MONOTONIC,
+            #[cfg(target_os = "linux")]
+            Clock::Tai => ClockId::CLOCK_TAI,
+            #[cfg(target_os = "linux")]
+            Clock::Boottime => ClockId::CLOCK_BOOTTIME,
+            #[cfg(target_os = "linux")]
+            Clock::Phc(phc) => ClockId::from_raw(phc.clock_id()),
+            #[cfg(not(target_os = "linux"))]
+            Clock::Tai | Clock::Boottime | Clock::Phc(_) => {
+                anyhow::bail!("the {} clock is only available on Linux", clock)
+            }
+        };
+        let time =
+            clock_gettime(id).with_context(|| format!("clock_gettime() of {} failed", clock))?;
+        Ok(Timestamp::new(time.tv_sec(), time.tv_nsec()))
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use super::{Clock, Timestamp, NANOSECONDS_IN_SECOND};
+    use anyhow::{bail, Result};
+
+    /// 100 ns intervals between 1601-01-01 and the Unix epoch
+    const FILETIME_UNIX_EPOCH: i128 = 116444736000000000;
+
+    #[repr(C)]
+    struct FileTime {
+        low: u32,
+        high: u32,
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetSystemTimePreciseAsFileTime(time: *mut FileTime);
+    }
+
+    /// `GetSystemTimePreciseAsFileTime()` time, in 100 ns resolution
+    pub fn now(clock: &Clock) -> Result<Timestamp> {
+        if *clock != Clock::Realtime {
+            bail!("only the realtime clock is available on this platform");
+        }
+        let mut time = FileTime { low: 0, high: 0 };
+        unsafe { GetSystemTimePreciseAsFileTime(&mut time) };
+        let ticks = ((time.high as i128) << 32 | time.low as i128) - FILETIME_UNIX_EPOCH;
+        Ok(Timestamp::from_nsec(
+            ticks * (NANOSECONDS_IN_SECOND / 10_000_000),
+        ))
+    }
+}
+
+pub fn nsec_to_sec(nsec: i128) -> f64 {
+    nsec as f64 * 1e-9
+}
+
+/// Parse a duration like `90`, `0.25`, `250ms`, `1.5s`, `10m`, `2h`, `1d` or
+/// `1h30m`; bare numbers are seconds, and units go down to `us` and `ns`
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let invalid = || format!("invalid duration {}", s);
+    if s.is_empty() {
+        bail!("empty duration");
+    }
+    let mut seconds = 0.0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let unit_start = rest.find(|c: char| c.is_alphabetic()).unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(unit_start);
+        let unit_end = tail
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_end);
+        // Only a duration of a single number can do without a unit
+        let unit = match unit {
+            "" if rest.len() == s.len() => "s",
+            "" => bail!("missing unit in duration {}", s),
+            unit => unit,
+        };
+        let multiplier = match unit {
+            "ns" => 1e-9,
+            "us" | "µs" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            _ => return Err(anyhow!("unknown unit '{}' in duration {}", unit, s)),
+        };
+        let value: f64 = number.parse().with_context(invalid)?;
+        seconds += value * multiplier;
+        rest = tail;
+    }
+    Duration::try_from_secs_f64(seconds).with_context(invalid)
+}
+
+/// Calendar date and time of day
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl DateTime {
+    /// UTC date and time of `sec` seconds since the epoch
+    pub fn from_unix(sec: i64) -> Self {
+        let days = sec.div_euclid(86400);
+        let secs_of_day = sec.rem_euclid(86400) as u32;
+
+        // Howard Hinnant's civil_from_days
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: secs_of_day / 3600,
+            minute: secs_of_day / 60 % 60,
+            second: secs_of_day % 60,
+        }
+    }
+
+    /// Seconds since the epoch of this UTC date and time
+    pub fn to_unix(&self) -> i64 {
+        // Howard Hinnant's days_from_civil
+        let year = if self.month <= 2 {
+            self.year - 1
+        } else {
+            self.year
+        };
+        let era = year.div_euclid(400);
+        let yoe = year.rem_euclid(400);
+        let mp = (self.month as i64 + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146097 + doe - 719468;
+        days * 86400 + (self.hour * 3600 + self.minute * 60 + self.second) as i64
+    }
+}
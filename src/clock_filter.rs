@@ -0,0 +1,39 @@
+use crate::measurement::Measurement;
+use std::collections::VecDeque;
+
+/// NTP-style clock filter: of the last N samples, trust the one with minimum delay
+///
+/// Queueing only ever adds delay, so the fastest exchange is the one least
+/// affected by it.
+#[derive(Debug)]
+pub struct ClockFilter {
+    size: usize,
+    samples: VecDeque<Measurement>,
+}
+
+impl ClockFilter {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size: size.max(1),
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, m: Measurement) {
+        if self.samples.len() == self.size {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(m);
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Minimum-delay sample in the filter
+    pub fn best(&self) -> Option<&Measurement> {
+        self.samples
+            .iter()
+            .min_by(|a, b| a.delay.total_cmp(&b.delay))
+    }
+}
@@ -0,0 +1,170 @@
+//! Parquet files of the output columns, for loading long runs straight into
+//! dataframe libraries
+//!
+//! Rows are buffered per column and written out as a row group every
+//! [`ROW_GROUP_ROWS`] rows, the footer when the file is closed; a file
+//! that is not closed can not be read.
+
+use parquet::{
+    data_type::{ByteArray, ByteArrayType, DoubleType, Int64Type},
+    file::{properties::WriterProperties, writer::SerializedFileWriter},
+    schema::parser::parse_message_type,
+};
+use std::{fs::File, io, path::Path, sync::Arc};
+
+/// Rows per row group
+const ROW_GROUP_ROWS: usize = 65536;
+
+/// Physical and logical type of a column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Text,
+    Count,
+    /// Nanoseconds since the epoch
+    Time,
+    Double,
+}
+
+/// Value of a column in a row
+pub(crate) enum Cell {
+    Text(String),
+    Int(i64),
+    Double(f64),
+    Null,
+}
+
+enum Values {
+    Text(Vec<ByteArray>),
+    Int(Vec<i64>),
+    Double(Vec<f64>),
+}
+
+struct Column {
+    values: Values,
+    /// Definition levels: 1 for a value, 0 for a null
+    levels: Vec<i16>,
+}
+
+pub(crate) struct ParquetFile {
+    writer: Option<SerializedFileWriter<File>>,
+    columns: Vec<Column>,
+    rows: usize,
+}
+
+impl ParquetFile {
+    /// Create (or truncate) the file at `path` with these columns
+    pub fn create(path: &Path, columns: &[(&str, Kind)]) -> io::Result<Self> {
+        let fields: Vec<_> = columns
+            .iter()
+            .map(|(name, kind)| match kind {
+                Kind::Text => format!("OPTIONAL BYTE_ARRAY {} (STRING);", name),
+                Kind::Count => format!("OPTIONAL INT64 {} (INTEGER(64,false));", name),
+                Kind::Time => format!("OPTIONAL INT64 {} (TIMESTAMP(NANOS,true));", name),
+                Kind::Double => format!("OPTIONAL DOUBLE {};", name),
+            })
+            .collect();
+        let schema = parse_message_type(&format!("message sample {{ {} }}", fields.join(" ")))
+            .map_err(io::Error::other)?;
+        let file = File::create(path)?;
+        let properties = Arc::new(WriterProperties::builder().build());
+        let writer = SerializedFileWriter::new(file, Arc::new(schema), properties)
+            .map_err(io::Error::other)?;
+        let columns = columns
+            .iter()
+            .map(|(_, kind)| Column {
+                values: match kind {
+                    Kind::Text => Values::Text(Vec::new()),
+                    Kind::Count | Kind::Time => Values::Int(Vec::new()),
+                    Kind::Double => Values::Double(Vec::new()),
+                },
+                levels: Vec::new(),
+            })
+            .collect();
+        Ok(Self {
+            writer: Some(writer),
+            columns,
+            rows: 0,
+        })
+    }
+
+    /// Add a row of one cell per column, of the column kind or null
+    pub fn push(&mut self, row: Vec<Cell>) -> io::Result<()> {
+        for (column, cell) in self.columns.iter_mut().zip(row) {
+            let defined = match (&mut column.values, cell) {
+                (Values::Text(values), Cell::Text(text)) => {
+                    values.push(text.into_bytes().into());
+                    true
+                }
+                (Values::Int(values), Cell::Int(value)) => {
+                    values.push(value);
+                    true
+                }
+                (Values::Double(values), Cell::Double(value)) => {
+                    values.push(value);
+                    true
+                }
+                _ => false,
+            };
+            column.levels.push(defined as i16);
+        }
+        self.rows += 1;
+        if self.rows == ROW_GROUP_ROWS {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the buffered rows and the footer; later rows are dropped
+    pub fn close(&mut self) -> io::Result<()> {
+        self.write_row_group()?;
+        if let Some(writer) = self.writer.take() {
+            writer.close().map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+
+    fn write_row_group(&mut self) -> io::Result<()> {
+        let Some(writer) = &mut self.writer else {
+            return Ok(());
+        };
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut group = writer.next_row_group().map_err(io::Error::other)?;
+        let mut columns = self.columns.iter_mut();
+        while let Some(mut writer) = group.next_column().map_err(io::Error::other)? {
+            let Some(column) = columns.next() else {
+                break;
+            };
+            let levels = Some(column.levels.as_slice());
+            match &mut column.values {
+                Values::Text(values) => {
+                    writer
+                        .typed::<ByteArrayType>()
+                        .write_batch(values, levels, None)
+                        .map_err(io::Error::other)?;
+                    values.clear();
+                }
+                Values::Int(values) => {
+                    writer
+                        .typed::<Int64Type>()
+                        .write_batch(values, levels, None)
+                        .map_err(io::Error::other)?;
+                    values.clear();
+                }
+                Values::Double(values) => {
+                    writer
+                        .typed::<DoubleType>()
+                        .write_batch(values, levels, None)
+                        .map_err(io::Error::other)?;
+                    values.clear();
+                }
+            }
+            column.levels.clear();
+            writer.close().map_err(io::Error::other)?;
+        }
+        group.close().map_err(io::Error::other)?;
+        self.rows = 0;
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+//! Comparing two local clocks without the network
+
+use crate::{clock::Clock, measurement::Measurement};
+use anyhow::Result;
+
+/// Reads two local clocks back to back, as if the second one were a reflector
+///
+/// Each sample reads the local clock, the reference clock and the local clock
+/// again, so `t1`/`t4` bracket `t2` = `t3` and the usual offset bounds apply.
+pub struct Comparator {
+    local: Clock,
+    reference: Clock,
+    /// Reads per sample, of which the one with the shortest delay is kept
+    reads: usize,
+    seq: u64,
+}
+
+impl Comparator {
+    pub fn new(local: Clock, reference: Clock, reads: usize) -> Self {
+        Self {
+            local,
+            reference,
+            reads: reads.max(1),
+            seq: 0,
+        }
+    }
+
+    pub fn reference(&self) -> &Clock {
+        &self.reference
+    }
+
+    /// Take the lowest-delay of a tight loop of reads
+    pub fn sample(&mut self) -> Result<Measurement> {
+        let mut best: Option<Measurement> = None;
+        for _ in 0..self.reads {
+            let t1 = self.local.now()?;
+            let t2 = self.reference.now()?;
+            let t4 = self.local.now()?;
+            let m = Measurement::new(self.seq, t1, t2, t2, t4);
+            if best.as_ref().is_none_or(|best| m.delay < best.delay) {
+                best = Some(m);
+            }
+        }
+        self.seq += 1;
+        Ok(best.expect("at least one read"))
+    }
+}
@@ -0,0 +1,255 @@
+//! Options from a TOML configuration file (`--config`), a table per command
+//! of long option names and their values:
+//!
+//! ```toml
+//! [measure]
+//! targets = ["ntp1.example.com", "ntp2.example.com"]
+//! interval = 0.5
+//! mad-threshold = 3.0
+//! output = "/var/log/co/offsets.csv"
+//! rotate = "daily"
+//!
+//! [reflect]
+//! allow = ["10.0.0.0/8", "192.168.0.0/16"]
+//! rate-limit = 10
+//! ntp = true
+//! ```
+//!
+//! Flags are set with `true`, repeatable options take arrays and the
+//! positional arguments are named as in the usage, such as `targets`. The
+//! options become command line arguments, skipping those given on the actual
+//! command line or conflicting with them, so that the command line overrides
+//! the file and both are validated the same way.
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{App, Arg, ArgSettings};
+use std::{collections::HashSet, ffi::OsString, fs, path::PathBuf};
+use toml::{Table, Value};
+
+const CONFIG: &str = "--config";
+
+/// `args` with the options of its command from the file given with
+/// `--config` added; `app` describes the commands
+pub fn expand_args(app: &App, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let table: Table = contents
+        .parse()
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    let position = command_position(&args);
+    let mut sections = Vec::new();
+    for (name, section) in &table {
+        let command = app
+            .get_subcommands()
+            .find(|command| command.get_name() == name)
+            .ok_or_else(|| anyhow!("unknown command [{}] in {}", name, path.display()))?;
+        let Value::Table(section) = section else {
+            bail!("{} in {} is not a table of options", name, path.display());
+        };
+        let settings = settings(command, section)
+            .with_context(|| format!("invalid [{}] in {}", name, path.display()))?;
+        sections.push((command, settings));
+    }
+    // Without a command clap reports the error
+    let Some(position) = position else {
+        return Ok(args);
+    };
+    let Some((command, settings)) = sections
+        .into_iter()
+        .find(|(command, _)| args[position] == *command.get_name())
+    else {
+        return Ok(args);
+    };
+
+    let (command_line, rest) = args[position + 1..].split_at(
+        args[position + 1..]
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(args.len() - position - 1),
+    );
+    let given: Vec<&Arg> = given(command, &args[position + 1..]).collect();
+    let overridden = |arg: &Arg| {
+        given.iter().any(|given| {
+            given.get_name() == arg.get_name()
+                || conflicts(command, arg).contains(&given.get_name())
+                || conflicts(command, given).contains(&arg.get_name())
+        })
+    };
+
+    // Positional values go first and options last, so that neither is taken
+    // as the value of an option
+    let mut expanded = args[..=position].to_vec();
+    let mut options = Vec::new();
+    for (arg, values) in settings {
+        if overridden(arg) {
+            continue;
+        }
+        if arg.is_positional() {
+            expanded.extend(values.into_iter().map(OsString::from));
+        } else {
+            options.extend(values.into_iter().map(OsString::from));
+        }
+    }
+    expanded.extend_from_slice(command_line);
+    expanded.extend(options);
+    expanded.extend_from_slice(rest);
+    Ok(expanded)
+}
+
+/// Value of the last `--config` before a `--`
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut path = None;
+    let mut args = args.iter().skip(1).take_while(|arg| *arg != "--");
+    while let Some(arg) = args.next() {
+        if arg == CONFIG {
+            path = args.next().map(PathBuf::from);
+        } else if let Some(value) = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix(CONFIG)?.strip_prefix('='))
+        {
+            path = Some(PathBuf::from(value));
+        }
+    }
+    path
+}
+
+/// Index of the command in `args`, the first argument but `--config`
+fn command_position(args: &[OsString]) -> Option<usize> {
+    let mut i = 1;
+    while i < args.len() {
+        match &args[i] {
+            arg if arg == CONFIG => i += 2,
+            arg if arg.to_string_lossy().starts_with("--config=") => i += 1,
+            _ => return Some(i),
+        }
+    }
+    None
+}
+
+/// The arguments standing for the options of `section`, with their values
+fn settings<'a, 'help>(
+    command: &'a App<'help>,
+    section: &Table,
+) -> Result<Vec<(&'a Arg<'help>, Vec<String>)>> {
+    section
+        .iter()
+        .map(|(name, value)| {
+            let name = name.replace('_', "-");
+            let arg = command
+                .get_arguments()
+                .find(|arg| match arg.get_long() {
+                    Some(long) => long == name,
+                    None => arg.get_name() == name,
+                })
+                .ok_or_else(|| anyhow!("unknown option `{}`", name))?;
+            let values = arguments(arg, value).with_context(|| format!("invalid `{}`", name))?;
+            Ok((arg, values))
+        })
+        .collect()
+}
+
+/// Command line arguments setting `arg` to `value`
+fn arguments(arg: &Arg, value: &Value) -> Result<Vec<String>> {
+    let option = match (arg.get_long(), arg.get_short()) {
+        (Some(long), _) => format!("--{}", long),
+        (None, Some(short)) => format!("-{}", short),
+        (None, None) => String::new(),
+    };
+    let values = match value {
+        Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+    };
+    let mut arguments = Vec::new();
+    for value in values {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Integer(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            // Also a bare option taking an optional value, such as `--ntp`
+            Value::Boolean(true) if !arg.is_positional() => {
+                arguments.push(option.clone());
+                continue;
+            }
+            Value::Boolean(false) if !arg.is_positional() => continue,
+            _ => bail!("expected a string or a number"),
+        };
+        if arg.is_positional() {
+            arguments.push(value);
+        } else {
+            ensure!(
+                arg.is_set(ArgSettings::TakesValue),
+                "a flag, expected true or false"
+            );
+            arguments.push(format!("{}={}", option, value));
+        }
+    }
+    Ok(arguments)
+}
+
+/// The arguments of `command` given in `args`, all positional ones if any
+/// positional value is given
+fn given<'a, 'help>(
+    command: &'a App<'help>,
+    args: &[OsString],
+) -> impl Iterator<Item = &'a Arg<'help>> {
+    let takes_value = |arg: &Arg| arg.is_set(ArgSettings::TakesValue);
+    let mut names = HashSet::new();
+    let mut positional = false;
+    let mut args = args.iter().map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        let needs_value = if arg == "--" {
+            positional |= args.next().is_some();
+            break;
+        } else if let Some(long) = arg.strip_prefix("--") {
+            let (long, inline) = match long.split_once('=') {
+                Some((long, _)) => (long, true),
+                None => (long, false),
+            };
+            match command.get_arguments().find(|a| a.get_long() == Some(long)) {
+                Some(a) => {
+                    names.insert(a.get_name());
+                    takes_value(a) && !inline
+                }
+                None => long == &CONFIG[2..] && !inline,
+            }
+        } else if let Some(shorts) = arg.strip_prefix('-').filter(|s| !s.is_empty()) {
+            let mut needs_value = false;
+            for (i, c) in shorts.char_indices() {
+                let Some(a) = command.get_arguments().find(|a| a.get_short() == Some(c)) else {
+                    break;
+                };
+                names.insert(a.get_name());
+                if takes_value(a) {
+                    // The rest of the argument is the value, if any
+                    needs_value = i + c.len_utf8() == shorts.len();
+                    break;
+                }
+            }
+            needs_value
+        } else {
+            positional = true;
+            false
+        };
+        if needs_value {
+            let mut peek = args.clone();
+            if peek.next().is_some_and(|next| !next.starts_with('-')) {
+                args.next();
+            }
+        }
+    }
+    command
+        .get_arguments()
+        .filter(move |arg| names.contains(arg.get_name()) || (positional && arg.is_positional()))
+}
+
+/// Names of the arguments `arg` conflicts with
+fn conflicts<'help>(command: &App<'help>, arg: &Arg) -> Vec<&'help str> {
+    command
+        .get_arg_conflicts_with(arg)
+        .into_iter()
+        .map(|arg| arg.get_name())
+        .collect()
+}
@@ -0,0 +1,111 @@
+//! Combining offset intervals from several reflectors
+
+use crate::measurement::Measurement;
+use std::collections::HashMap;
+
+/// Offset interval agreed on by the largest number of sources
+#[derive(Clone, Debug)]
+pub struct Consensus {
+    pub offset_min: f64,
+    pub offset_max: f64,
+    /// Midpoint of the agreed interval
+    pub offset: f64,
+    /// Indices of sources whose intervals contain the agreed interval
+    pub truechimers: Vec<usize>,
+    /// Indices of sources disagreeing with the majority
+    pub falsetickers: Vec<usize>,
+}
+
+impl Consensus {
+    /// Whether more than half of the sources agree
+    pub fn has_majority(&self) -> bool {
+        self.truechimers.len() * 2 > self.truechimers.len() + self.falsetickers.len()
+    }
+}
+
+/// Marzullo's algorithm: find the interval consistent with most `(min, max)` source intervals
+pub fn marzullo(intervals: &[(f64, f64)]) -> Option<Consensus> {
+    if intervals.is_empty() {
+        return None;
+    }
+
+    // Interval starts sort before ends at the same offset, so touching intervals intersect
+    let mut edges: Vec<(f64, i32)> = intervals
+        .iter()
+        .flat_map(|&(min, max)| [(min, -1), (max, 1)])
+        .collect();
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let mut best = 0;
+    let mut count = 0;
+    let (mut best_min, mut best_max) = (0.0, 0.0);
+    for (i, &(offset, edge)) in edges.iter().enumerate() {
+        count -= edge;
+        if count > best {
+            best = count;
+            best_min = offset;
+            best_max = edges[i + 1].0;
+        }
+    }
+
+    let (truechimers, falsetickers) = (0..intervals.len())
+        .partition(|&i| intervals[i].0 <= best_max && intervals[i].1 >= best_min);
+
+    Some(Consensus {
+        offset_min: best_min,
+        offset_max: best_max,
+        offset: (best_min + best_max) / 2.0,
+        truechimers,
+        falsetickers,
+    })
+}
+
+/// Collects the latest measurement per source and forms a consensus once per round
+///
+/// A round is complete when every source that reported before has reported
+/// again, or when some source reports twice because another one went quiet.
+#[derive(Debug, Default)]
+pub struct ConsensusTracker {
+    latest: HashMap<String, Measurement>,
+    in_round: Vec<String>,
+}
+
+impl ConsensusTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a measurement, returning the consensus of the last round if it just completed
+    ///
+    /// The returned source names are in the order of the consensus indices.
+    pub fn add(&mut self, source: &str, m: Measurement) -> Option<(Vec<String>, Consensus)> {
+        let mut result = None;
+        if self.in_round.iter().any(|s| s == source) {
+            result = self.finish_round();
+        }
+
+        self.latest.insert(source.to_owned(), m);
+        self.in_round.push(source.to_owned());
+
+        if result.is_none() && self.in_round.len() == self.latest.len() && self.latest.len() > 1 {
+            result = self.finish_round();
+        }
+        result
+    }
+
+    fn finish_round(&mut self) -> Option<(Vec<String>, Consensus)> {
+        let sources = std::mem::take(&mut self.in_round);
+        if sources.len() < 2 {
+            return None;
+        }
+
+        let intervals: Vec<_> = sources
+            .iter()
+            .map(|s| {
+                let m = &self.latest[s];
+                (m.offset_min, m.offset_max)
+            })
+            .collect();
+        marzullo(&intervals).map(|consensus| (sources, consensus))
+    }
+}
@@ -0,0 +1,183 @@
+//! Runtime control of `measure`: commands on a Unix domain socket, one per
+//! line, or over a small HTTP API, both answered with JSON
+//!
+//! The commands are `status`, `pause [TARGET]`, `resume [TARGET]`,
+//! `set-interval SECONDS [TARGET]`, `add-target TARGET` and
+//! `remove-target TARGET`; without a target, pausing, resuming and setting
+//! the interval apply to all targets. Over HTTP the status is `GET /status`
+//! and the other commands are POSTs with query parameters, such as
+//! `POST /set-interval?seconds=0.5&target=host:55555`.
+
+use crate::transport::UnixListener;
+use anyhow::{bail, ensure, Context, Result};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, path::Path, str::FromStr, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+use tracing::warn;
+
+/// Longest HTTP request head read
+const MAX_REQUEST_SIZE: usize = 4096;
+
+/// Control command; targets are named as in the output
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Settings and statistics of all targets
+    Status,
+    /// Stop sending probes, to all targets if `None`
+    Pause(Option<String>),
+    Resume(Option<String>),
+    /// Change the probe interval, the shortest one if it adapts
+    SetInterval(Duration, Option<String>),
+    AddTarget(String),
+    RemoveTarget(String),
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        let target = |i: usize| words.get(i).map(|target| target.to_string());
+        let command = match words.as_slice() {
+            ["status"] => Command::Status,
+            ["pause"] | ["pause", _] => Command::Pause(target(1)),
+            ["resume"] | ["resume", _] => Command::Resume(target(1)),
+            ["set-interval", seconds] | ["set-interval", seconds, _] => {
+                let seconds: f64 = seconds.parse().context("invalid interval")?;
+                ensure!(
+                    seconds.is_finite() && seconds > 0.0,
+                    "the interval must be positive"
+                );
+                Command::SetInterval(Duration::from_secs_f64(seconds), target(2))
+            }
+            ["add-target", target] => Command::AddTarget(target.to_string()),
+            ["remove-target", target] => Command::RemoveTarget(target.to_string()),
+            [] => bail!("empty command"),
+            [name, ..] => bail!("unknown command or arguments of '{}'", name),
+        };
+        Ok(command)
+    }
+}
+
+impl Command {
+    /// Command of an HTTP request for `path`, with the arguments in its query
+    fn from_http(method: &str, path: &str) -> Result<Self> {
+        let (name, query) = path.split_once('?').unwrap_or((path, ""));
+        let param = |key: &str| {
+            query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix(key)?.strip_prefix('='))
+        };
+        let name = name.trim_start_matches('/');
+        let expected = if name == "status" { "GET" } else { "POST" };
+        ensure!(method == expected, "{} /{} expected", expected, name);
+        let words: Vec<_> = [Some(name), param("seconds"), param("target")]
+            .into_iter()
+            .flatten()
+            .collect();
+        words.join(" ").parse()
+    }
+}
+
+/// Command with where to send its answer
+pub type Request = (Command, oneshot::Sender<Result<Value>>);
+
+/// Answer commands on connections to the Unix domain socket at `path`
+pub async fn serve_unix(path: &Path, requests: mpsc::Sender<Request>) -> Result<()> {
+    let listener = UnixListener::bind(path)?;
+    loop {
+        let stream = listener.accept_stream().await?;
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            let (read, mut write) = tokio::io::split(stream);
+            let mut lines = BufReader::new(read).lines();
+            loop {
+                let line = match lines.next_line().await {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Control connection failed: {}", e);
+                        return;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let answer = answer(line.parse(), &requests).await;
+                let written = write.write_all(format!("{}\n", answer).as_bytes()).await;
+                if let Err(e) = written {
+                    warn!("Control connection failed: {}", e);
+                    return;
+                }
+            }
+        });
+    }
+}
+
+/// Answer the HTTP API on `addr`
+pub async fn serve_http(addr: SocketAddr, requests: mpsc::Sender<Request>) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind the control API to {}", addr))?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_http(stream, &requests).await {
+                warn!("Control API request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_http(mut stream: TcpStream, requests: &mpsc::Sender<Request>) -> Result<()> {
+    // Only the request line matters, headers and body are ignored
+    let mut buf = [0; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < buf.len() {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let answer = answer(Command::from_http(method, path), requests).await;
+    let status = match answer.get("error") {
+        Some(_) => "400 Bad Request",
+        None => "200 OK",
+    };
+    let body = format!("{}\n", answer);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Have `command` executed, answering `{"error": ...}` if it fails
+async fn answer(command: Result<Command>, requests: &mpsc::Sender<Request>) -> Value {
+    let executed = async {
+        let (tx, rx) = oneshot::channel();
+        requests
+            .send((command?, tx))
+            .await
+            .context("measuring has finished")?;
+        rx.await.context("measuring has finished")?
+    };
+    executed
+        .await
+        .unwrap_or_else(|e: anyhow::Error| json!({ "error": format!("{:#}", e) }))
+}
@@ -0,0 +1,62 @@
+//! Stateless return reachability cookies
+
+use crate::{
+    protocol::{Cookie, COOKIE_SIZE},
+    random,
+};
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Cookies are accepted for one to two lifetimes after they are issued
+const COOKIE_LIFETIME_SECS: u64 = 60;
+
+/// Issues and checks cookies bound to a source address, keyed by a secret
+/// generated at startup
+pub(crate) struct CookieJar {
+    secret: [u8; 32],
+}
+
+impl CookieJar {
+    pub fn new() -> Result<Self> {
+        let mut secret = [0; 32];
+        random::fill(&mut secret).context("failed to generate cookie secret")?;
+        Ok(Self { secret })
+    }
+
+    pub fn issue(&self, source: &SocketAddr) -> Cookie {
+        let mut cookie = [0; COOKIE_SIZE];
+        cookie.copy_from_slice(&self.mac(source, epoch()).finalize().into_bytes()[..COOKIE_SIZE]);
+        cookie
+    }
+
+    pub fn check(&self, source: &SocketAddr, cookie: &Cookie) -> bool {
+        let epoch = epoch();
+        [epoch, epoch.saturating_sub(1)].iter().any(|&epoch| {
+            self.mac(source, epoch)
+                .verify_truncated_left(cookie)
+                .is_ok()
+        })
+    }
+
+    fn mac(&self, source: &SocketAddr, epoch: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("any key size is valid");
+        mac.update(&epoch.to_le_bytes());
+        match source.ip() {
+            IpAddr::V4(ip) => mac.update(&ip.octets()),
+            IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&source.port().to_le_bytes());
+        mac
+    }
+}
+
+fn epoch() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs() / COOKIE_LIFETIME_SECS)
+}
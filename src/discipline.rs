@@ -0,0 +1,146 @@
+//! Correcting the local clock toward the measured offsets
+
+use crate::clock::Timestamp;
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// Discipline settings
+#[derive(Clone, Debug)]
+pub struct DisciplineConfig {
+    /// Step the clock for offsets above this many seconds, slew it otherwise
+    pub step_threshold: f64,
+    /// Refuse to correct offsets above this many seconds, which are more
+    /// likely a broken reference than a broken local clock
+    pub max_offset: f64,
+    /// Only print the corrections that would be made
+    pub dry_run: bool,
+}
+
+impl Default for DisciplineConfig {
+    fn default() -> Self {
+        Self {
+            step_threshold: 0.128,
+            max_offset: 1000.0,
+            dry_run: false,
+        }
+    }
+}
+
+/// Change made to the local clock, in seconds added to it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Correction {
+    /// Gradual adjustment by the kernel, at most 500 ppm
+    Slew(f64),
+    /// Immediate jump
+    Step(f64),
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Correction::Slew(seconds) => write!(f, "slew by {:+.9} s", seconds),
+            Correction::Step(seconds) => write!(f, "step by {:+.9} s", seconds),
+        }
+    }
+}
+
+/// Steers the system clock (`CLOCK_REALTIME`) toward the reference
+pub struct Discipline {
+    config: DisciplineConfig,
+    /// Clock time right after the latest step; older measurements are stale
+    stepped_at: Option<Timestamp>,
+}
+
+impl Discipline {
+    pub fn new(config: DisciplineConfig) -> Result<Self> {
+        if !cfg!(target_os = "linux") && !config.dry_run {
+            bail!("clock discipline is only supported on Linux");
+        }
+        Ok(Self {
+            config,
+            stepped_at: None,
+        })
+    }
+
+    pub fn dry_run(&self) -> bool {
+        self.config.dry_run
+    }
+
+    /// Correct the clock for a fresh `offset` measurement (local − remote)
+    /// of a probe sent at local time `sent`, `None` if it predates a step
+    ///
+    /// Each slew replaces the one still in progress, so `offset` must reflect
+    /// the clock as it is now rather than an older filtered estimate.
+    pub fn update(&mut self, sent: Timestamp, offset: f64) -> Result<Option<Correction>> {
+        if self.stepped_at.is_some_and(|stepped_at| sent < stepped_at) {
+            return Ok(None);
+        }
+        if offset.abs() > self.config.max_offset {
+            bail!(
+                "offset {:.9} s exceeds the {} s limit, not correcting",
+                offset,
+                self.config.max_offset
+            );
+        }
+        let correction = if offset.abs() > self.config.step_threshold {
+            Correction::Step(-offset)
+        } else {
+            Correction::Slew(-offset)
+        };
+        if !self.config.dry_run {
+            match correction {
+                Correction::Slew(seconds) => sys::slew(seconds)?,
+                Correction::Step(seconds) => {
+                    sys::step(seconds)?;
+                    self.stepped_at = Some(Timestamp::now()?);
+                }
+            }
+        }
+        Ok(Some(correction))
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use crate::clock::Timestamp;
+    use anyhow::{Context, Result};
+    use nix::{
+        sys::time::TimeSpec,
+        time::{clock_settime, ClockId},
+    };
+    use std::{io, mem};
+
+    /// Start an `adjtime()`-style slew, replacing any one in progress
+    pub fn slew(seconds: f64) -> Result<()> {
+        let mut tx: libc::timex = unsafe { mem::zeroed() };
+        tx.modes = libc::ADJ_OFFSET_SINGLESHOT;
+        tx.offset = (seconds * 1e6) as _;
+        if unsafe { libc::adjtimex(&mut tx) } < 0 {
+            return Err(io::Error::last_os_error()).context("adjtimex() call failed");
+        }
+        Ok(())
+    }
+
+    pub fn step(seconds: f64) -> Result<()> {
+        let now = Timestamp::now()?;
+        let time = Timestamp::from_nsec(now.total_nsec() + (seconds * 1e9) as i128);
+        clock_settime(
+            ClockId::CLOCK_REALTIME,
+            TimeSpec::new(time.sec, time.nsec as _),
+        )
+        .context("clock_settime() call failed")
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use anyhow::Result;
+
+    pub fn slew(_seconds: f64) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn step(_seconds: f64) -> Result<()> {
+        Ok(())
+    }
+}
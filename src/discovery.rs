@@ -0,0 +1,135 @@
+//! Finding reflectors on the local network
+//!
+//! Discovery requests go to the [`GROUP`] multicast group and the IPv4
+//! broadcast address on the discovery port. Reflectors that opted in answer
+//! with an announcement of their probe port and version, to the address the
+//! request came from.
+
+use crate::{
+    measurement::Measurement,
+    protocol::{self, Announcement},
+    random::Rng,
+    socket,
+};
+use anyhow::{ensure, Context, Result};
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+use tracing::warn;
+
+/// Port reflectors answer discovery requests on by default
+pub const DEFAULT_PORT: u16 = 55556;
+/// Administratively scoped multicast group the requests go to
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 55, 55);
+
+/// Reflector that announced itself
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reflector {
+    /// Address the announcement came from, with the probe port
+    pub addr: SocketAddr,
+    pub version: String,
+}
+
+/// Socket answering discovery requests on `port` of all IPv4 addresses
+pub fn bind(port: u16) -> Result<UdpSocket> {
+    // Several reflectors of a host all get the multicast and broadcast requests
+    let socket = socket::bind_udp(
+        (Ipv4Addr::UNSPECIFIED, port).into(),
+        cfg!(target_os = "linux"),
+    )
+    .with_context(|| format!("failed to bind the discovery port {}", port))?;
+    socket
+        .join_multicast_v4(GROUP, Ipv4Addr::UNSPECIFIED)
+        .context("failed to join the discovery group")?;
+    Ok(socket)
+}
+
+/// Send discovery requests to `port` and collect the reflectors announcing
+/// themselves within `wait`, once each in the order they answered
+pub async fn discover(port: u16, wait: Duration) -> Result<Vec<Reflector>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let nonce = Rng::new()?.next_u64();
+    let request = protocol::encode_discover(nonce);
+    let mut sent = 0;
+    for addr in [GROUP, Ipv4Addr::BROADCAST] {
+        match socket.send_to(&request, (addr, port)).await {
+            Ok(_) => sent += 1,
+            Err(e) => warn!("Sending the discovery request to {} failed: {}", addr, e),
+        }
+    }
+    ensure!(sent > 0, "no discovery request could be sent");
+
+    let deadline = Instant::now() + wait;
+    let mut reflectors = Vec::new();
+    let mut buf = [0; 2048];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        let announcement = match protocol::decode_announcement(&buf[..len]) {
+            Ok(announcement) if announcement.nonce == nonce => announcement,
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("Invalid announcement from {} discarded: {}", from, e);
+                continue;
+            }
+        };
+        let reflector = Reflector {
+            addr: SocketAddr::new(from.ip(), announcement.port),
+            version: announcement.version,
+        };
+        if !reflectors.contains(&reflector) {
+            reflectors.push(reflector);
+        }
+    }
+    Ok(reflectors)
+}
+
+/// Announcement answering a discovery request in `packet`, for a reflector
+/// taking probes on `port`
+pub fn announce(packet: &[u8], port: u16) -> Result<Vec<u8>> {
+    let nonce = protocol::decode_discover(packet)?;
+    Ok(protocol::encode_announcement(&Announcement {
+        nonce,
+        port,
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+    }))
+}
+
+/// Table of the discovered reflectors with the best measurement of a burst
+/// to each, if any
+pub struct DiscoveryReport(pub Vec<(Reflector, Option<Measurement>)>);
+
+impl fmt::Display for DiscoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<39} {:>5} {:<16} {:>14}   uncertainty",
+            "address", "port", "version", "offset"
+        )?;
+        for (reflector, m) in &self.0 {
+            write!(
+                f,
+                "{:<39} {:>5} {:<16}",
+                reflector.addr.ip().to_string(),
+                reflector.addr.port(),
+                reflector.version
+            )?;
+            match m {
+                Some(m) => writeln!(
+                    f,
+                    " {:>14.9} \u{b1} {:.9}",
+                    m.offset,
+                    (m.offset_max - m.offset_min) / 2.0
+                )?,
+                None => writeln!(f, " {:>14}", "no replies")?,
+            }
+        }
+        Ok(())
+    }
+}
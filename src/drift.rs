@@ -0,0 +1,58 @@
+use crate::clock::Timestamp;
+use std::collections::VecDeque;
+
+/// Frequency offset estimate from a linear fit of offset against local time
+#[derive(Debug)]
+pub struct DriftEstimator {
+    window: usize,
+    /// Local time and offset of the samples in the window
+    samples: VecDeque<(Timestamp, f64)>,
+}
+
+impl DriftEstimator {
+    /// Fit over the last `window` samples
+    pub fn new(window: usize) -> Self {
+        Self {
+            window: window.max(2),
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn add(&mut self, time: Timestamp, offset: f64) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((time, offset));
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Drift of the local clock relative to the remote one in ppm (positive: local runs fast)
+    pub fn ppm(&self) -> Option<f64> {
+        let (base, _) = *self.samples.front()?;
+        let points: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .map(|(time, offset)| {
+                (
+                    (time.total_nsec() - base.total_nsec()) as f64 * 1e-9,
+                    *offset,
+                )
+            })
+            .collect();
+
+        let n = points.len() as f64;
+        let mean_t = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_o = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let (cov, var) = points.iter().fold((0.0, 0.0), |(cov, var), (t, o)| {
+            (
+                cov + (t - mean_t) * (o - mean_o),
+                var + (t - mean_t).powi(2),
+            )
+        });
+
+        (points.len() >= 2 && var > 0.0).then(|| cov / var * 1e6)
+    }
+}
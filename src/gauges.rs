@@ -0,0 +1,189 @@
+//! Per-target gauges pushed to StatsD (over UDP) or Graphite (plaintext
+//! protocol over TCP) with every accepted sample
+//!
+//! Gauges are named `co.<target>.<name>`, with the dots and colons of the
+//! target replaced by underscores: `offset`, `filtered_offset`, `rtt`,
+//! `loss_ratio` and, once estimated, `drift_ppm`.
+
+use crate::{analysis::Sample, clock::Timestamp};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fmt::Write as _,
+    io::Write,
+    net::{SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// Root of the gauge names
+const PREFIX: &str = "co";
+/// Longest wait for Graphite to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where gauges are pushed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    Statsd,
+    Graphite,
+}
+
+impl Protocol {
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::Statsd => "StatsD",
+            Protocol::Graphite => "Graphite",
+        }
+    }
+}
+
+/// Gauge values of one sample
+struct Gauges {
+    target: String,
+    time: Timestamp,
+    values: Vec<(&'static str, f64)>,
+}
+
+impl Gauges {
+    fn path(&self, name: &str) -> String {
+        let target: String = self
+            .target
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' => c,
+                _ => '_',
+            })
+            .collect();
+        format!("{}.{}.{}", PREFIX, target, name)
+    }
+
+    /// StatsD lines; negative gauges are set from zero, as a sign otherwise
+    /// changes the gauge by the value
+    fn to_statsd(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in &self.values {
+            let path = self.path(name);
+            if *value < 0.0 {
+                let _ = writeln!(text, "{}:0|g", path);
+            }
+            let _ = writeln!(text, "{}:{:.9}|g", path, value);
+        }
+        text
+    }
+
+    fn to_graphite(&self) -> String {
+        let mut text = String::new();
+        for (name, value) in &self.values {
+            let _ = writeln!(text, "{} {:.9} {}", self.path(name), value, self.time.sec);
+        }
+        text
+    }
+}
+
+/// Pushes gauges from a background thread, so that a slow or unreachable
+/// server does not hold up measuring
+pub struct GaugeSink {
+    gauges: mpsc::Sender<Gauges>,
+}
+
+impl GaugeSink {
+    /// Push to the server at `addr` (`host:port`), resolved once
+    pub fn new(protocol: Protocol, addr: &str) -> Result<Self> {
+        let addr = addr
+            .to_socket_addrs()
+            .with_context(|| format!("invalid {} address '{}'", protocol.name(), addr))?
+            .next()
+            .ok_or_else(|| anyhow!("no address found for {}", addr))?;
+        let (gauges, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name(protocol.name().to_lowercase())
+            .spawn(move || match protocol {
+                Protocol::Statsd => push_statsd(addr, rx),
+                Protocol::Graphite => push_graphite(addr, rx),
+            })
+            .with_context(|| format!("failed to start pushing to {}", protocol.name()))?;
+        Ok(Self { gauges })
+    }
+
+    /// Push the gauges of an accepted sample of `target`
+    pub fn update(&self, target: &str, sample: &Sample) {
+        let m = &sample.measurement;
+        let mut values = vec![
+            ("offset", m.offset),
+            ("filtered_offset", sample.filtered_offset),
+            ("rtt", m.rtt),
+            ("loss_ratio", m.lost as f64 / (m.seq + 1) as f64),
+        ];
+        if let Some(drift) = sample.drift_ppm {
+            values.push(("drift_ppm", drift));
+        }
+        let gauges = Gauges {
+            target: target.to_owned(),
+            time: m.t4,
+            values,
+        };
+        // The thread only stops if it failed to start
+        let _ = self.gauges.send(gauges);
+    }
+}
+
+fn push_statsd(addr: SocketAddr, rx: mpsc::Receiver<Gauges>) {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = match UdpSocket::bind(local).and_then(|s| s.connect(addr).map(|()| s)) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Pushing to StatsD at {} failed: {}", addr, e);
+            return;
+        }
+    };
+    let mut failing = false;
+    while let Ok(gauges) = rx.recv() {
+        match socket.send(gauges.to_statsd().as_bytes()) {
+            Ok(_) if failing => {
+                info!("Pushing to StatsD at {} works again", addr);
+                failing = false;
+            }
+            Ok(_) => {}
+            // Reported once until it recovers, not with every sample
+            Err(e) if !failing => {
+                warn!("Pushing to StatsD at {} failed: {}", addr, e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn push_graphite(addr: SocketAddr, rx: mpsc::Receiver<Gauges>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut failing = false;
+    while let Ok(gauges) = rx.recv() {
+        // Reconnects with the next sample after a failure
+        let result = match &mut stream {
+            Some(stream) => stream.write_all(gauges.to_graphite().as_bytes()),
+            None => TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).and_then(|mut s| {
+                s.write_all(gauges.to_graphite().as_bytes())?;
+                stream = Some(s);
+                Ok(())
+            }),
+        };
+        match result {
+            Ok(()) if failing => {
+                info!("Pushing to Graphite at {} works again", addr);
+                failing = false;
+            }
+            Ok(()) => {}
+            Err(e) => {
+                stream = None;
+                if !failing {
+                    warn!("Pushing to Graphite at {} failed: {}", addr, e);
+                    failing = true;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Address family of dual-stack targets, in the spirit of Happy Eyeballs
+//! (RFC 8305): a hostname with both IPv4 and IPv6 addresses is probed
+//! briefly over both, and measured over the one of lower delay
+
+use crate::{
+    measurer::MeasurerConfig,
+    sweep,
+    target::{Family, Target},
+    transport::Transport,
+};
+use anyhow::Result;
+use std::time::Duration;
+use tracing::info;
+
+/// Probes sent over each family
+const PROBES: u64 = 4;
+const INTERVAL: Duration = Duration::from_millis(50);
+
+/// Family to measure `target` with `config` over: that of the config unless
+/// it allows any and the target has addresses of both, which go to the
+/// family of lower delay, IPv6 on ties
+///
+/// Families without replies lose; without replies over either, any address
+/// is left to be measured.
+pub async fn select_family(target: &Target, config: &MeasurerConfig) -> Result<Family> {
+    if config.family != Family::Any || config.transport != Transport::Udp || target.is_address() {
+        return Ok(config.family);
+    }
+    let addrs = target.resolve_all(Family::Any).await?;
+    if !addrs.iter().any(|addr| addr.is_ipv4()) || !addrs.iter().any(|addr| addr.is_ipv6()) {
+        return Ok(Family::Any);
+    }
+    let probe = |family| {
+        let config = MeasurerConfig {
+            family,
+            interval: INTERVAL,
+            interval_max: None,
+            jitter: 0.0,
+            burst: 1,
+            count: Some(PROBES),
+            warmup: 0,
+            duration: None,
+            backoff_max: None,
+            ..config.clone()
+        };
+        sweep::best(target.clone(), config)
+    };
+    let (v4, v6) = tokio::join!(probe(Family::V4), probe(Family::V6));
+    let family = match (v4, v6) {
+        (Ok(v4), Ok(v6)) => {
+            let family = if v6.delay <= v4.delay {
+                Family::V6
+            } else {
+                Family::V4
+            };
+            info!(
+                "Measuring over {}, the delay being {:.9} over IPv4 and {:.9} over IPv6",
+                family, v4.delay, v6.delay
+            );
+            family
+        }
+        (Ok(_), Err(e)) => {
+            info!("Measuring over IPv4, IPv6 failing: {:#}", e);
+            Family::V4
+        }
+        (Err(e), Ok(_)) => {
+            info!("Measuring over IPv6, IPv4 failing: {:#}", e);
+            Family::V6
+        }
+        (Err(_), Err(_)) => Family::Any,
+    };
+    Ok(family)
+}
@@ -0,0 +1,156 @@
+//! HDR-style histograms with bounded relative error and constant memory per decade
+
+use std::fmt;
+
+/// Sub-buckets per power of two, as a power of two; gives under 1% relative error
+const SUB_BUCKET_BITS: u32 = 7;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+
+/// Percentiles reported by [`Percentiles`]
+pub const QUANTILES: [f64; 4] = [0.5, 0.9, 0.99, 0.999];
+
+/// Counts of non-negative integer values in log-linear buckets
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, value: u64) {
+        let index = bucket_index(value);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+        self.total += 1;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.total == 0
+    }
+
+    /// Representative value of the `rank`-th smallest recorded value (0-based)
+    pub fn value_at_rank(&self, rank: u64) -> Option<u64> {
+        let mut seen = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen > rank {
+                return Some(bucket_value(index));
+            }
+        }
+        None
+    }
+
+    /// Nearest-rank quantile, `q` in `[0, 1]`
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        self.value_at_rank(nearest_rank(q, self.total)?)
+    }
+}
+
+fn nearest_rank(q: f64, total: u64) -> Option<u64> {
+    if total == 0 {
+        return None;
+    }
+    let rank = (q * total as f64).ceil() as u64;
+    Some(rank.clamp(1, total) - 1)
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() - SUB_BUCKET_BITS;
+    let group = shift as u64 + 1;
+    let sub = (value >> shift) - SUB_BUCKETS;
+    (group * SUB_BUCKETS + sub) as usize
+}
+
+/// Midpoint of the values falling into bucket `index`
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    let group = index >> SUB_BUCKET_BITS;
+    let sub = index & (SUB_BUCKETS - 1);
+    if group == 0 {
+        return sub;
+    }
+    let shift = group - 1;
+    let lower = (SUB_BUCKETS + sub) << shift;
+    lower + ((1 << shift) >> 1)
+}
+
+/// Histogram of signed durations in seconds, kept at nanosecond resolution
+#[derive(Clone, Debug, Default)]
+pub struct DurationHistogram {
+    negative: Histogram,
+    positive: Histogram,
+}
+
+impl DurationHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, seconds: f64) {
+        let nsec = (seconds.abs() * 1e9).round() as u64;
+        if seconds < 0.0 {
+            self.negative.record(nsec);
+        } else {
+            self.positive.record(nsec);
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.negative.len() + self.positive.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Nearest-rank quantile in seconds, `q` in `[0, 1]`
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        let rank = nearest_rank(q, self.len())?;
+        let negatives = self.negative.len();
+        // Negative values sort by descending magnitude
+        let nsec = if rank < negatives {
+            -(self.negative.value_at_rank(negatives - 1 - rank)? as f64)
+        } else {
+            self.positive.value_at_rank(rank - negatives)? as f64
+        };
+        Some(nsec * 1e-9)
+    }
+
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(Percentiles(
+            QUANTILES.map(|q| self.quantile(q).unwrap_or_default()),
+        ))
+    }
+}
+
+/// Values at the [`QUANTILES`]
+#[derive(Clone, Copy, Debug)]
+pub struct Percentiles(pub [f64; 4]);
+
+impl Percentiles {
+    /// Names of the values, in `Display` order
+    pub const LABEL: &'static str = "p50/p90/p99/p99.9";
+}
+
+impl fmt::Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [p50, p90, p99, p999] = self.0;
+        write!(f, "{:.9}/{:.9}/{:.9}/{:.9}", p50, p90, p99, p999)
+    }
+}
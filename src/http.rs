@@ -0,0 +1,76 @@
+//! Minimal HTTP/1.1 client for posting to `http://` endpoints
+
+use anyhow::{anyhow, bail, Result};
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `http://host[:port]/path?query` split into its parts
+#[derive(Clone, Debug)]
+pub(crate) struct Endpoint {
+    pub authority: String,
+    path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("only http:// URLs are supported: {}", url))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => bail!("missing path in URL {}", url),
+        };
+        if authority.is_empty() {
+            bail!("missing host in URL {}", url);
+        }
+        Ok(Self {
+            authority: authority.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr = if self.authority.ends_with(']') || !self.authority.contains(':') {
+            format!("{}:80", self.authority)
+        } else {
+            self.authority.clone()
+        };
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+        TcpStream::connect_timeout(&addr, TIMEOUT)
+    }
+
+    /// POST `body` of `content_type`, with the extra `headers` (each ending
+    /// with CRLF), failing unless the response status is 2xx
+    pub fn post(&self, headers: &str, content_type: &str, body: &str) -> Result<()> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\n{}Content-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.authority,
+            headers,
+            content_type,
+            body.len(),
+            body
+        )?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let status = status_line.split(' ').nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            bail!("server responded {}", status_line.trim_end());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! ICMP errors for sent probes, read from the socket error queue
+
+use crate::timestamping::TxTimestamp;
+use anyhow::Result;
+use std::{fmt, io, net::IpAddr};
+use tokio::net::UdpSocket;
+
+/// What went wrong with a sent packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IcmpErrorKind {
+    /// Nothing listens on the port: the reflector is down
+    PortUnreachable,
+    /// No route to the host or network
+    Unreachable { code: u8 },
+    /// The TTL or hop limit ran out on the way
+    TtlExceeded,
+    /// The packet is larger than the path MTU
+    FragmentationNeeded { mtu: u32 },
+    /// Any other type and code
+    Other { kind: u8, code: u8 },
+}
+
+/// ICMP error reported for a sent packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IcmpError {
+    pub kind: IcmpErrorKind,
+    /// Host that sent the error, `None` for errors raised locally
+    pub from: Option<IpAddr>,
+}
+
+impl fmt::Display for IcmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            IcmpErrorKind::PortUnreachable => write!(f, "ICMP port unreachable")?,
+            IcmpErrorKind::Unreachable { code } => {
+                write!(f, "ICMP destination unreachable (code {})", code)?
+            }
+            IcmpErrorKind::TtlExceeded => write!(f, "ICMP TTL exceeded")?,
+            IcmpErrorKind::FragmentationNeeded { mtu } => {
+                write!(f, "ICMP fragmentation needed (MTU {})", mtu)?
+            }
+            IcmpErrorKind::Other { kind, code } => {
+                write!(f, "ICMP error type {} code {}", kind, code)?
+            }
+        }
+        match self.from {
+            Some(from) => write!(f, " from {}", from),
+            None => write!(f, " (local)"),
+        }
+    }
+}
+
+/// Entries drained from the socket error queue
+#[derive(Debug, Default)]
+pub struct ErrorQueue {
+    pub tx_timestamps: Vec<TxTimestamp>,
+    pub icmp_errors: Vec<IcmpError>,
+}
+
+/// Whether a receive error is the socket reporting an ICMP error, which the
+/// error queue has the details of
+pub fn is_icmp_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+        ) || e.raw_os_error() == Some(EMSGSIZE)
+    })
+}
+
+#[cfg(unix)]
+const EMSGSIZE: i32 = libc::EMSGSIZE;
+/// `WSAEMSGSIZE`
+#[cfg(not(unix))]
+const EMSGSIZE: i32 = 10040;
+
+/// Queue ICMP errors of sent packets on the socket error queue, so they can be
+/// told apart; otherwise only port unreachable errors show, without details
+#[cfg(target_os = "linux")]
+pub fn enable(socket: &UdpSocket) -> Result<()> {
+    use crate::socket;
+
+    if socket.local_addr()?.is_ipv6() {
+        socket::set_int_option(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVERR,
+            1,
+            "IPV6_RECVERR",
+        )?;
+    }
+    // Also covers IPv4 packets of dual-stack sockets
+    socket::set_int_option(socket, libc::IPPROTO_IP, libc::IP_RECVERR, 1, "IP_RECVERR")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable(_socket: &UdpSocket) -> Result<()> {
+    Ok(())
+}
+
+/// Drain the transmit timestamps and ICMP errors queued on the socket error queue
+#[cfg(target_os = "linux")]
+pub fn read_error_queue(socket: &UdpSocket) -> ErrorQueue {
+    use crate::socket;
+
+    let mut buf = [0; 256];
+    let mut queue = ErrorQueue::default();
+
+    while let Ok(msg) = socket::recvmsg(socket, &mut buf, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) {
+        let Some(err) = msg.extended_err else {
+            continue;
+        };
+        let kind = match (err.ee_origin, err.ee_type, err.ee_code) {
+            (libc::SO_EE_ORIGIN_TIMESTAMPING, _, _) => {
+                if let Some((timestamp, source)) = msg.timestamp {
+                    queue.tx_timestamps.push(TxTimestamp {
+                        key: err.ee_data,
+                        timestamp,
+                        source,
+                    });
+                }
+                continue;
+            }
+            (libc::SO_EE_ORIGIN_ICMP, 3, 3) | (libc::SO_EE_ORIGIN_ICMP6, 1, 4) => {
+                IcmpErrorKind::PortUnreachable
+            }
+            (libc::SO_EE_ORIGIN_ICMP, 3, 4) | (libc::SO_EE_ORIGIN_ICMP6, 2, _) => {
+                IcmpErrorKind::FragmentationNeeded { mtu: err.ee_info }
+            }
+            (libc::SO_EE_ORIGIN_ICMP, 3, code) | (libc::SO_EE_ORIGIN_ICMP6, 1, code) => {
+                IcmpErrorKind::Unreachable { code }
+            }
+            (libc::SO_EE_ORIGIN_ICMP, 11, _) | (libc::SO_EE_ORIGIN_ICMP6, 3, _) => {
+                IcmpErrorKind::TtlExceeded
+            }
+            (libc::SO_EE_ORIGIN_ICMP | libc::SO_EE_ORIGIN_ICMP6, kind, code) => {
+                IcmpErrorKind::Other { kind, code }
+            }
+            // Sending a packet larger than the known path MTU
+            (libc::SO_EE_ORIGIN_LOCAL, _, _) if err.ee_errno == libc::EMSGSIZE as u32 => {
+                IcmpErrorKind::FragmentationNeeded { mtu: err.ee_info }
+            }
+            _ => continue,
+        };
+        let from = match err.ee_origin {
+            libc::SO_EE_ORIGIN_LOCAL => None,
+            _ => msg.offender,
+        };
+        queue.icmp_errors.push(IcmpError { kind, from });
+    }
+
+    queue
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read_error_queue(_socket: &UdpSocket) -> ErrorQueue {
+    ErrorQueue::default()
+}
@@ -0,0 +1,68 @@
+//! Writing line protocol directly to an InfluxDB HTTP endpoint
+
+use crate::http::Endpoint;
+use anyhow::{Context, Result};
+use std::{
+    io::{self, Write},
+    sync::mpsc,
+    thread,
+};
+use tracing::warn;
+
+/// Line protocol sink that posts complete lines to InfluxDB from a background thread
+///
+/// Lines are batched while a request is in flight. Failed writes are logged and dropped.
+pub struct InfluxClient {
+    lines: mpsc::Sender<String>,
+    partial: Vec<u8>,
+}
+
+impl InfluxClient {
+    /// Write to `url`, e.g. `http://localhost:8086/write?db=clock` or a v2 `/api/v2/write` URL
+    pub fn new(url: &str, token: Option<String>) -> Result<Self> {
+        let endpoint = Endpoint::parse(url).context("invalid InfluxDB URL")?;
+        let (lines, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("influx".to_owned())
+            .spawn(move || post_lines(endpoint, token, rx))
+            .context("failed to start InfluxDB writer")?;
+
+        Ok(Self {
+            lines,
+            partial: Vec::new(),
+        })
+    }
+}
+
+impl Write for InfluxClient {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.partial.extend_from_slice(buf);
+        while let Some(end) = self.partial.iter().position(|&b| b == b'\n') {
+            let line: Vec<_> = self.partial.drain(..=end).collect();
+            self.lines
+                .send(String::from_utf8_lossy(&line).into_owned())
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::BrokenPipe, "InfluxDB writer stopped")
+                })?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn post_lines(endpoint: Endpoint, token: Option<String>, rx: mpsc::Receiver<String>) {
+    let authorization = token
+        .map(|token| format!("Authorization: Token {}\r\n", token))
+        .unwrap_or_default();
+    while let Ok(line) = rx.recv() {
+        let mut body = line;
+        body.extend(rx.try_iter());
+
+        if let Err(e) = endpoint.post(&authorization, "text/plain; charset=utf-8", &body) {
+            warn!("InfluxDB write to {} failed: {:#}", endpoint.authority, e);
+        }
+    }
+}
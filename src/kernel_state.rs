@@ -0,0 +1,43 @@
+//! Reading the kernel NTP state of the local clock
+
+use anyhow::Result;
+
+/// Clock state the kernel reports through `adjtimex()`, as set by ntpd or chronyd
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KernelState {
+    /// Frequency correction applied to the clock
+    pub freq_ppm: f64,
+    /// Maximum error estimate, in seconds
+    pub maxerror: f64,
+    /// Estimated error, in seconds
+    pub esterror: f64,
+    /// Whether the clock is marked synchronized
+    pub synced: bool,
+}
+
+impl KernelState {
+    /// Query the state without changing it
+    #[cfg(target_os = "linux")]
+    pub fn read() -> Result<Self> {
+        use anyhow::Context;
+        use std::{io, mem};
+
+        let mut tx: libc::timex = unsafe { mem::zeroed() };
+        let state = unsafe { libc::adjtimex(&mut tx) };
+        if state < 0 {
+            return Err(io::Error::last_os_error()).context("adjtimex() call failed");
+        }
+        Ok(Self {
+            // In ppm with a 16-bit fractional part
+            freq_ppm: tx.freq as f64 / 65536.0,
+            maxerror: tx.maxerror as f64 * 1e-6,
+            esterror: tx.esterror as f64 * 1e-6,
+            synced: state != libc::TIME_ERROR && tx.status & libc::STA_UNSYNC == 0,
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn read() -> Result<Self> {
+        anyhow::bail!("reading the kernel clock state is only supported on Linux")
+    }
+}
@@ -0,0 +1,94 @@
+//! UDP-based naive clock offset measurement
+//!
+//! A [`Reflector`] answers probes with its receive and transmit timestamps,
+//! a [`Measurer`] probes a reflector and yields a [`Measurement`] per reply.
+//! A [`Comparator`] measures between two local clocks the same way.
+
+pub mod access_log;
+pub mod alert;
+pub mod analysis;
+pub mod auth;
+mod base64;
+#[cfg(target_os = "linux")]
+mod batch;
+pub mod calibration;
+mod cidr;
+pub mod clients;
+pub mod clock;
+mod clock_filter;
+mod columnar;
+mod compare;
+pub mod config_file;
+pub mod consensus;
+pub mod control;
+mod cookie;
+pub mod discipline;
+pub mod discovery;
+mod drift;
+pub mod gauges;
+pub mod happy_eyeballs;
+pub mod histogram;
+mod http;
+pub mod icmp;
+pub mod influx;
+pub mod kernel_state;
+pub mod logging;
+pub mod mdns;
+mod measurement;
+mod measurer;
+pub mod mesh;
+pub mod metrics;
+pub mod mqtt;
+pub mod mtu;
+pub mod ntp;
+pub mod otlp;
+mod outlier;
+pub mod output;
+mod path_change;
+mod poll;
+pub mod protocol;
+pub mod quic;
+mod random;
+mod ratelimit;
+pub mod record;
+pub mod refclock;
+mod reflector;
+pub mod rotate;
+pub mod roughtime;
+pub mod scheduling;
+mod sequence;
+pub mod smoothing;
+mod socket;
+pub mod stability;
+mod step;
+pub mod store;
+pub mod summary;
+pub mod sweep;
+pub mod systemd;
+mod target;
+pub mod timestamping;
+mod transport;
+pub mod tui;
+mod uring;
+pub mod web;
+mod websocket;
+
+pub use analysis::{Analyzer, AnalyzerConfig, Flags, Sample};
+pub use cidr::Cidr;
+pub use clock::{Clock, Timestamp};
+pub use clock_filter::ClockFilter;
+pub use compare::Comparator;
+pub use drift::DriftEstimator;
+pub use measurement::{Asymmetry, BurstStats, LostProbe, Measurement};
+pub use measurer::{Control, Event, Measurer, MeasurerConfig, MissedTicks, Protocol};
+pub use outlier::OutlierFilter;
+pub use path_change::PathChangeDetector;
+pub use ratelimit::RateLimiter;
+pub use reflector::{Reflector, ReflectorConfig};
+pub use sequence::SequenceTracker;
+pub use socket::{Dscp, Ecn, SocketOptions};
+pub use step::StepDetector;
+pub use target::{Family, Target};
+pub use timestamping::{TimestampSource, Timestamping};
+pub use transport::Transport;
+pub use uring::IoBackend;
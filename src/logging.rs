@@ -0,0 +1,113 @@
+//! Diagnostics on stderr through `tracing`, kept apart from the measurement
+//! data on stdout
+
+use anyhow::{bail, Result};
+use std::{
+    collections::VecDeque,
+    io::{self, IsTerminal, Write},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+pub use tracing_subscriber::filter::LevelFilter;
+
+/// Log format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with the fields of the enclosing spans
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format '{}', expected text or json", s),
+        }
+    }
+}
+
+/// Log events of `level` and above to stderr
+pub fn init(level: LevelFilter, format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(level)
+        .with_target(false);
+    match format {
+        LogFormat::Text => builder.with_ansi(io::stderr().is_terminal()).init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Latest log lines, kept for display while the terminal is taken by the
+/// dashboard rather than written to stderr
+#[derive(Clone)]
+pub struct LogBuffer(Arc<Mutex<Captured>>);
+
+struct Captured {
+    lines: VecDeque<String>,
+    capacity: usize,
+    /// Lines go to stderr again
+    released: bool,
+}
+
+impl LogBuffer {
+    /// The latest `count` lines, oldest first
+    pub fn latest(&self, count: usize) -> Vec<String> {
+        let captured = self.0.lock().unwrap();
+        let skip = captured.lines.len().saturating_sub(count);
+        captured.lines.iter().skip(skip).cloned().collect()
+    }
+
+    /// Write the kept lines to stderr, as well as every later one
+    pub fn release(&self) {
+        let mut captured = self.0.lock().unwrap();
+        captured.released = true;
+        let mut stderr = io::stderr().lock();
+        for line in captured.lines.drain(..) {
+            let _ = writeln!(stderr, "{}", line);
+        }
+    }
+}
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut captured = self.0.lock().unwrap();
+        if captured.released {
+            return io::stderr().write(buf);
+        }
+        for line in String::from_utf8_lossy(buf).lines() {
+            if captured.lines.len() == captured.capacity {
+                captured.lines.pop_front();
+            }
+            captured.lines.push_back(line.to_owned());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Log events of `level` and above as text into a buffer of the latest
+/// `capacity` lines
+pub fn init_buffered(level: LevelFilter, capacity: usize) -> LogBuffer {
+    let buffer = LogBuffer(Arc::new(Mutex::new(Captured {
+        lines: VecDeque::new(),
+        capacity,
+        released: false,
+    })));
+    let writer = buffer.clone();
+    tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_max_level(level)
+        .with_target(false)
+        .with_ansi(false)
+        .init();
+    buffer
+}
@@ -1,144 +1,2368 @@
-use anyhow::{Context, Result};
-use clap::Parser;
-use nix::time::{clock_gettime, ClockId};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{App, Args, FromArgMatches, IntoApp, Parser, Subcommand};
+use co::{
+    access_log::AccessLogConfig,
+    alert::{AlertConfig, Alerter},
+    auth::Key,
+    calibration,
+    clients::ClientReport,
+    clock::parse_duration,
+    config_file,
+    consensus::{Consensus, ConsensusTracker},
+    control::{self, Request},
+    discipline::{Correction, Discipline, DisciplineConfig},
+    discovery::{self, DiscoveryReport},
+    gauges::{self, GaugeSink},
+    happy_eyeballs,
+    influx::InfluxClient,
+    kernel_state::KernelState,
+    logging::{self, LevelFilter, LogBuffer, LogFormat},
+    mdns,
+    mesh::Mesh,
+    metrics::{self, Metrics},
+    mqtt::{self, MqttPublisher},
+    mtu,
+    ntp::{self, NtpConfig},
+    otlp::OtlpExporter,
+    output::{Field, Format, OutputWriter, TimeFormat, TimeZone},
+    protocol::ClientId,
+    quic::QuicConfig,
+    record,
+    refclock::{Refclock, RefclockSpec},
+    rotate::Rotation,
+    roughtime,
+    scheduling::SchedulingConfig,
+    smoothing::SmoothingFilter,
+    stability::{self, Stability},
+    store::{self, Store, StoreSpec},
+    summary::Summary,
+    sweep::{self, Survey},
+    systemd,
+    tui::Dashboard,
+    web::WebUi,
+    Analyzer, AnalyzerConfig, Asymmetry, Cidr, Clock, ClockFilter, Comparator,
+    Control as MeasurerControl, Dscp, Event, Family, IoBackend, LostProbe, Measurement, Measurer,
+    MeasurerConfig, MissedTicks, Protocol, Reflector, ReflectorConfig, Sample, SocketOptions,
+    Target, Timestamp, Timestamping, Transport,
+};
+use serde_json::{json, Value};
 use std::{
-    sync::Arc,
-    net::{Ipv4Addr, SocketAddrV4}
+    collections::HashMap,
+    env,
+    fmt::Write,
+    fs, future, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    process,
 };
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::{
-    net::UdpSocket,
-    time::{sleep, Duration}
+    sync::{mpsc, watch},
+    task::JoinHandle,
+    time::{self, Duration, Instant, Interval, MissedTickBehavior},
 };
+use tracing::{error, error_span, info, warn, Instrument};
 
-const PAYLOAD_SIZE: usize = 16;
-const REFLECTED_PAYLOAD_SIZE: usize = 32;
+const DEFAULT_PORT: u16 = 55555;
+/// Number of probes sent by `--oneshot`
+const ONESHOT_PROBES: u64 = 8;
+const ONESHOT_INTERVAL: Duration = Duration::from_millis(50);
+/// Targets browsed for over mDNS
+const MDNS_PREFIX: &str = "mdns:";
+/// Time to collect mDNS responses for
+const MDNS_WAIT: Duration = Duration::from_secs(1);
+/// Control commands waiting to be executed
+const CONTROL_QUEUE: usize = 16;
+/// How often `--tui` redraws the dashboard
+const DASHBOARD_INTERVAL: Duration = Duration::from_millis(250);
+/// Log lines kept while the dashboard is shown
+const DASHBOARD_LOG_LINES: usize = 1000;
 
 /// UDP-based naive clock offset measurement tool
 #[derive(Parser, Debug)]
-struct Args {
-    /// Stream timestamps to host
-    remote_ip: Option<String>,
+struct Cli {
+    /// Read options from this TOML file, a table per command such as [measure];
+    /// options given on the command line override it
+    #[clap(long, value_name = "PATH", global = true)]
+    #[allow(dead_code)] // Read by config_file::expand_args before parsing
+    config: Option<PathBuf>,
 
-    /// Port to listen for incoming timestamps on
-    #[clap(short, long, default_value_t = 55555)]
-    port: u16,
+    #[clap(flatten)]
+    log: LogArgs,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+/// Diagnostics on stderr, apart from the measurement data
+#[derive(Args, Debug)]
+struct LogArgs {
+    /// Lowest level of messages to log: error, warn, info, debug or trace
+    #[clap(long, value_name = "LEVEL", default_value = "info", global = true)]
+    log_level: LevelFilter,
+
+    /// Log format: text or json (one object per line, with the target measured in its span)
+    #[clap(long, value_name = "FORMAT", default_value = "text", global = true)]
+    log_format: LogFormat,
+
+    /// Log nothing, leaving only the measurement data
+    #[clap(short, long, global = true, conflicts_with = "log-level")]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Stream timestamps to reflectors and report the clock offsets; SIGHUP
+    /// reloads the targets and output options, SIGUSR1 logs the statistics so
+    /// far and SIGUSR2 resets them
+    Measure(Box<MeasureArgs>),
+    /// Answer probes with receive and transmit timestamps
+    Reflect(ReflectArgs),
+    /// Re-run the analysis over a recorded CSV or JSON log and print a report
+    Analyze(AnalyzeArgs),
+    /// Reflect probes and measure peers at the same time (symmetric mode), so
+    /// that two hosts running `peer` against each other both get offset series
+    Peer(Box<PeerArgs>),
+    /// Compare two local clocks back to back, without the network
+    Compare(CompareArgs),
+    /// Query NTP servers with SNTP client requests and report the offsets like `measure`
+    Ntp(Box<MeasureArgs>),
+    /// Measure a group of reflectors from this host, one short burst each, and
+    /// print the offsets between all of them and the hosts inconsistent with the group
+    Mesh(Box<SweepArgs>),
+    /// Measure many reflectors at once, one short burst each, and print their
+    /// offsets sorted, a quick check of whether a fleet is in sync
+    Survey(Box<SweepArgs>),
+    /// List the reflectors on the local network that answer discovery
+    /// (reflect --discoverable), with their offsets from a short burst each
+    Discover(DiscoverArgs),
+    /// Find the largest probe a reflector reflects with the don't-fragment flag set, sending
+    /// padded probes and asking for padded replies, to learn the MTU of the path
+    Mtu(Box<MtuArgs>),
+    /// Query Roughtime servers, verifying the signed server times, and report
+    /// the offsets like `measure`, widened by the uncertainty the servers give
+    Roughtime(Box<RoughtimeArgs>),
+}
+
+/// Options of both sides of the exchange
+#[derive(Args, Debug)]
+struct CommonArgs {
+    /// Port the reflector listens on, default port of targets [default: 55555, 123 for ntp, 2002 for roughtime]
+    #[clap(short, long)]
+    port: Option<u16>,
+
+    /// Use the original headerless 16/32-byte packet format (reflector: also accept it)
+    #[clap(long, conflicts_with = "key")]
+    legacy: bool,
+
+    /// Pre-shared key (hex, at least 16 bytes) to authenticate probes and replies with
+    #[clap(long, value_name = "HEX")]
+    key: Option<Key>,
+
+    /// Encrypt probes and replies with the key (XChaCha20-Poly1305) rather than only
+    /// authenticate them, hiding the timestamps on hostile networks; both sides need it
+    #[clap(long, requires = "key")]
+    encrypt: bool,
+
+    /// Take receive timestamps from the kernel (SO_TIMESTAMPNS, SO_TIMESTAMP outside Linux) instead of userspace
+    #[clap(long, conflicts_with = "hw-timestamps")]
+    kernel_timestamps: bool,
+
+    /// Use NIC hardware timestamps on interface (NIC clock must be synced to the system clock)
+    #[clap(long, value_name = "IFACE")]
+    hw_timestamps: Option<String>,
+
+    /// Local clock to timestamp with: realtime, tai, monotonic, boottime or phc:<device> (e.g. phc:/dev/ptp0)
+    #[clap(long, default_value = "realtime")]
+    clock: Clock,
+
+    /// Send and receive through the tokio reactor or io_uring (Linux only): tokio or uring
+    #[clap(long, value_name = "BACKEND", default_value = "tokio")]
+    io_backend: IoBackend,
+
+    /// Serve Prometheus metrics over HTTP on this address (e.g. 0.0.0.0:9100)
+    #[clap(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Mark sent packets with this DSCP: a name such as EF, AF41 or CS6, or a number (0-63)
+    #[clap(long, value_name = "DSCP")]
+    dscp: Option<Dscp>,
+
+    /// Set SO_PRIORITY of the socket, selecting the queueing discipline band (Linux only)
+    #[clap(long, value_name = "N")]
+    so_priority: Option<u32>,
+
+    /// IPv4 TTL or IPv6 hop limit of sent packets
+    #[clap(long, value_name = "N")]
+    ttl: Option<u32>,
+
+    /// Set the don't-fragment flag on sent packets, so that those larger than the path MTU
+    /// are lost rather than fragmented (Linux only)
+    #[clap(long)]
+    df: bool,
+
+    #[clap(flatten)]
+    scheduling: SchedulingArgs,
+}
+
+impl CommonArgs {
+    fn key(&self) -> Option<Key> {
+        let key = self.key.clone()?;
+        Some(match self.encrypt {
+            true => key.encrypting(),
+            false => key,
+        })
+    }
+
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+
+    fn timestamping(&self) -> Timestamping {
+        if let Some(interface) = &self.hw_timestamps {
+            Timestamping::Hardware {
+                interface: interface.clone(),
+            }
+        } else if self.kernel_timestamps {
+            Timestamping::Kernel
+        } else {
+            Timestamping::Userspace
+        }
+    }
+
+    fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            dscp: self.dscp,
+            priority: self.so_priority,
+            interface: None,
+            ttl: self.ttl,
+            dont_fragment: self.df,
+        }
+    }
+
+    /// Start the metrics exporter if requested
+    fn metrics(&self) -> Option<Metrics> {
+        self.metrics_addr.map(|addr| {
+            let metrics = Metrics::new();
+            let exporter = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(addr, exporter).await {
+                    error!("Metrics exporter failed: {:#}", e);
+                }
+            });
+            metrics
+        })
+    }
+}
+
+/// Scheduling options of the process threads (Linux only)
+#[derive(Args, Debug)]
+struct SchedulingArgs {
+    /// Run with SCHED_FIFO at this priority (1-99), so other tasks do not delay timestamps
+    #[clap(long, value_name = "N")]
+    rt_priority: Option<i32>,
+
+    /// Pin the threads to this CPU (repeatable)
+    #[clap(long, value_name = "CORE", multiple_occurrences = true)]
+    cpu: Vec<usize>,
+
+    /// Lock the process memory with mlockall() to avoid page faults
+    #[clap(long)]
+    mlockall: bool,
+}
+
+impl SchedulingArgs {
+    fn config(&self) -> SchedulingConfig {
+        SchedulingConfig {
+            rt_priority: self.rt_priority,
+            cpus: self.cpu.clone(),
+            lock_memory: self.mlockall,
+        }
+    }
+}
+
+/// Filtering and estimation options
+#[derive(Args, Debug)]
+struct AnalysisArgs {
+    /// Number of samples in the drift (frequency error) regression window
+    #[clap(long, default_value_t = 64)]
+    drift_window: usize,
+
+    /// Number of recent samples the minimum-delay clock filter selects from
+    #[clap(long, default_value_t = 8)]
+    clock_filter: usize,
+
+    /// Discard samples with round-trip time above this many seconds
+    #[clap(long, value_name = "SECONDS")]
+    max_rtt: Option<f64>,
+
+    /// Discard samples with round-trip time more than K scaled MADs above the recent median
+    #[clap(long, value_name = "K")]
+    mad_threshold: Option<f64>,
+
+    /// Add a smoothed `offset_est` column: `ewma:ALPHA` (e.g. ewma:0.1) or `kalman`
+    #[clap(long, value_name = "FILTER")]
+    filter: Option<SmoothingFilter>,
+
+    /// Flag offset jumps of more than K times the recent noise floor as clock
+    /// steps and restart the drift estimate there, 0 to disable
+    #[clap(long, value_name = "K", default_value_t = 10.0)]
+    step_threshold: f64,
+
+    /// Flag network path changes where the minimum delay of the recent samples moves by more
+    /// than this many seconds, and restart the drift estimate, clock filter and smoothing
+    /// there; path changes are also flagged when the TTL of replies changes (--ttl-ecn)
+    #[clap(long, value_name = "SECONDS")]
+    path_change_delay: Option<f64>,
+}
+
+impl AnalysisArgs {
+    fn config(&self) -> AnalyzerConfig {
+        AnalyzerConfig {
+            drift_window: self.drift_window,
+            clock_filter_size: self.clock_filter,
+            max_rtt: self.max_rtt,
+            mad_threshold: self.mad_threshold,
+            smoothing: self.filter,
+            step_threshold: (self.step_threshold > 0.0).then_some(self.step_threshold),
+            path_change_delay: self.path_change_delay,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+struct MeasureArgs {
+    /// Hosts to stream timestamps to (`host`, `host:port`, `ipv6` or `[ipv6]:port`), or
+    /// reflectors advertised over mDNS (reflect --mdns): `mdns:` for all, `mdns:NAME` for one
+    #[clap(value_name = "REMOTE", required_unless_present = "targets-file")]
+    targets: Vec<String>,
+
+    /// Read additional targets from file, one per line
+    #[clap(long, value_name = "PATH")]
+    targets_file: Option<PathBuf>,
+
+    #[clap(flatten)]
+    common: CommonArgs,
+
+    /// Timestamp sending interval, e.g. 1, 0.5, 250ms or 2s (seconds without a unit)
+    #[clap(short, long, value_name = "DURATION", default_value = "1", parse(try_from_str = parse_duration))]
+    interval: Duration,
+
+    /// Adapt the interval between --interval and this one: longer while the
+    /// offset is stable, shorter after steps or loss
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    interval_max: Option<Duration>,
+
+    /// Randomize each send time within this fraction of the interval either way, e.g. 0.1
+    #[clap(long, value_name = "FRACTION", default_value_t = 0.0)]
+    jitter: f64,
+
+    /// When probes fall behind schedule: send the missed ones at once (burst),
+    /// restart the schedule from the late probe (delay) or drop them (skip)
+    #[clap(long, value_name = "BEHAVIOR", default_value = "skip")]
+    missed_ticks: MissedTicks,
+
+    /// Only use IPv4 addresses of the remote host
+    #[clap(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// Only use IPv6 addresses of the remote host
+    #[clap(short = '6')]
+    ipv6: bool,
+
+    /// Send probes from this local address (`addr`, `addr:port` or `[ipv6]:port`),
+    /// also selecting the address family of hostnames
+    #[clap(long, value_name = "ADDR", parse(try_from_str = parse_source))]
+    source: Option<SocketAddr>,
+
+    /// Measure every address of hostname targets, each as a target of its own
+    /// named by the address, rather than the address family of lower delay
+    #[clap(long)]
+    all_addresses: bool,
+
+    /// Report the TTL (IPv6 hop limit) and ECN code point of every reply in the ttl and ecn
+    /// columns, to tell path changes and congestion marking (Linux only, UDP)
+    #[clap(long)]
+    ttl_ecn: bool,
+
+    /// Send probes out of this network interface only (SO_BINDTODEVICE, Linux only)
+    #[clap(long, value_name = "NAME")]
+    interface: Option<String>,
+
+    /// Exchange probes as UDP datagrams (udp), over a TCP connection where UDP
+    /// is blocked (tcp, reflector: --tcp), as QUIC datagrams, encrypted and
+    /// authenticated (quic, reflector: --quic-port, needs the quic feature) or
+    /// over WebSocket where only web traffic passes (websocket, reflector:
+    /// --websocket-port); TCP and WebSocket results are noisier, but the offset
+    /// is still bounded by the RTT interval. unix:PATH connects to the Unix
+    /// domain socket of a reflector on the same host (reflector: --unix PATH),
+    /// measuring the tool's own overhead with a real offset of zero
+    #[clap(long, value_name = "TRANSPORT", default_value = "udp")]
+    transport: Transport,
+
+    /// PEM certificates to verify QUIC reflectors with, such as their own self-signed one
+    #[clap(long, value_name = "PATH")]
+    tls_ca: Option<PathBuf>,
+
+    /// Open WebSocket connections through the HTTP proxy at this address, with CONNECT
+    #[clap(long, value_name = "HOST:PORT", parse(try_from_str = parse_proxy))]
+    proxy: Option<SocketAddr>,
+
+    /// Remote hostname re-resolution interval, e.g. 300 or 5m, 0 to resolve only once
+    #[clap(long, value_name = "DURATION", default_value = "5m", parse(try_from_str = parse_duration))]
+    resolve_interval: Duration,
+
+    /// While a target does not answer, back off the probe interval
+    /// exponentially to at most this one, e.g. 64 or 10m, 0 to keep probing
+    /// at the full rate
+    #[clap(long, value_name = "DURATION", default_value = "64", parse(try_from_str = parse_duration))]
+    backoff_max: Duration,
+
+    /// Send this many back-to-back probes each interval and report the lowest-RTT reply
+    #[clap(long, value_name = "K", default_value_t = 1)]
+    burst: usize,
+
+    /// Stop after sending this many probes to each target, not counting --warmup
+    #[clap(short = 'c', long, value_name = "N")]
+    count: Option<u64>,
+
+    /// Send this many probes first and discard them, so that address resolution,
+    /// route caches and CPU frequency ramp-up do not skew statistics and filters
+    #[clap(
+        long,
+        value_name = "N",
+        default_value_t = 0,
+        conflicts_with = "oneshot"
+    )]
+    warmup: u64,
+
+    /// Correct offsets for a known path asymmetry: forward minus return delay with
+    /// a unit (e.g. 2ms, -300us) or the forward share of the delay (e.g. 0.6);
+    /// the applied shift is written in an asymmetry_correction column
+    #[clap(long, value_name = "ASYMMETRY", allow_hyphen_values = true)]
+    asymmetry: Option<Asymmetry>,
+
+    /// Time the local send and receive path over loopback at startup, report it
+    /// and take it off the offset bounds of userspace timestamps
+    #[clap(long)]
+    calibrate: bool,
+
+    /// Pad probes to this many bytes of UDP payload (at most 2048), to measure
+    /// with the serialization delay of real packets
+    #[clap(long, value_name = "BYTES", conflicts_with = "legacy")]
+    size: Option<usize>,
+
+    /// Ask the reflector to pad replies to the probe size too
+    #[clap(long, requires = "size")]
+    pad_replies: bool,
+
+    /// Leave out the random nonce replies have to echo, for reflectors of older versions;
+    /// without it or a key, off-path hosts guessing the port can fake replies
+    #[clap(long)]
+    no_nonce: bool,
+
+    /// Name this client in every probe (up to 32 bytes), for the access log of
+    /// the reflector; reflectors of older versions do not answer such probes
+    #[clap(long, value_name = "ID", conflicts_with = "legacy")]
+    client_id: Option<ClientId>,
+
+    /// Report probes unanswered after this long as lost, e.g. 2 or 500ms
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    timeout: Option<Duration>,
+
+    /// Stop after this long, e.g. 90, 30s, 10m or 1h30m
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+
+    /// Send a short burst to a single target and print only the minimum-delay offset
+    #[clap(long, conflicts_with_all = &["count", "duration"])]
+    oneshot: bool,
+
+    /// Also reflect probes on the port, with the defaults of the reflector
+    /// options that `peer` takes
+    #[clap(long)]
+    also_reflect: bool,
+
+    /// Show a live dashboard of the targets instead of printing samples, which
+    /// still go to --output or --influx-url; logs are shown on it too
+    #[clap(long, conflicts_with = "oneshot")]
+    tui: bool,
+
+    /// With --oneshot: exit with status 2 if the offset magnitude exceeds this many seconds
+    #[clap(long, value_name = "SECONDS", requires = "oneshot")]
+    threshold: Option<f64>,
+
+    /// Take probe send times from kernel transmit timestamps (implied by --hw-timestamps)
+    #[clap(long)]
+    tx_timestamps: bool,
+
+    /// Combine the offset intervals of all targets into a consensus estimate (printed to stderr)
+    #[clap(long)]
+    consensus: bool,
+
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+
+    /// Print the Allan and time deviation of each target to stderr this often
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    stability_interval: Option<Duration>,
+
+    /// Print offset and round-trip time percentiles of each target to stderr this often
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    percentiles_interval: Option<Duration>,
+
+    /// At exit, write the summary of every target as JSON to this file: samples,
+    /// loss, offset and rtt percentiles, drift and clock steps
+    #[clap(long, value_name = "PATH")]
+    summary_file: Option<PathBuf>,
+
+    /// Do not print discarded samples
+    #[clap(long)]
+    hide_discarded: bool,
+
+    /// Alert when the filtered offset magnitude exceeds this many seconds for
+    /// --alert-samples consecutive samples
+    #[clap(long, value_name = "SECONDS")]
+    alert_threshold: Option<f64>,
+
+    /// Consecutive samples beyond --alert-threshold that raise an alert
+    #[clap(long, value_name = "N", default_value_t = 3)]
+    alert_samples: u32,
 
-    /// Timestamp sending interval (seconds)
-    #[clap(short, long, default_value_t = 1.0)]
-    interval: f64
+    /// Run this shell command on alerts, with CO_TARGET, CO_TIME, CO_OFFSET,
+    /// CO_FILTERED_OFFSET, CO_RTT, CO_THRESHOLD and CO_SAMPLES set
+    #[clap(long, value_name = "CMD", requires = "alert-threshold")]
+    alert_cmd: Option<String>,
+
+    /// POST alerts as JSON to this http:// URL
+    #[clap(long, value_name = "URL", requires = "alert-threshold")]
+    alert_url: Option<String>,
+
+    /// Output format: csv, json (one object per line), influx (line protocol),
+    /// parquet (needs --output, written when measuring ends) or chrony
+    /// (chronyd's measurements.log)
+    #[clap(long, default_value = "csv")]
+    format: Format,
+
+    /// Append results to file instead of printing them to stdout
+    #[clap(short, long, value_name = "PATH", conflicts_with = "influx-url")]
+    output: Option<PathBuf>,
+
+    /// Output file rotation: hourly, daily or size (e.g. 100M)
+    #[clap(long, requires = "output")]
+    rotate: Option<Rotation>,
+
+    /// Write only these columns, in this order (e.g. offset,rtt,seq)
+    #[clap(long, value_name = "FIELDS", use_delimiter = true)]
+    fields: Vec<Field>,
+
+    /// Separator of csv columns, \t for a tab
+    #[clap(long, value_name = "STRING", default_value = ", ", parse(from_str = parse_delimiter))]
+    delimiter: String,
+
+    /// Leave out the csv header line
+    #[clap(long)]
+    no_header: bool,
+
+    /// Write the t1-t4 timestamps as epoch seconds or iso8601 (RFC 3339) date and time
+    #[clap(long, value_name = "FORMAT", default_value = "epoch")]
+    timestamps: TimeFormat,
+
+    /// Time zone of iso8601 timestamps: utc, local or an offset such as +02:00
+    #[clap(
+        long,
+        value_name = "ZONE",
+        default_value = "utc",
+        allow_hyphen_values = true
+    )]
+    timezone: TimeZone,
+
+    /// Post line protocol to InfluxDB instead of printing it (e.g. http://localhost:8086/write?db=clock)
+    #[clap(long, value_name = "URL")]
+    influx_url: Option<String>,
+
+    /// InfluxDB API token, sent as `Authorization: Token`
+    #[clap(long, value_name = "TOKEN", requires = "influx-url")]
+    influx_token: Option<String>,
+
+    /// Push offset, rtt and loss gauges of every target to StatsD at this
+    /// host:port with each accepted sample
+    #[clap(long, value_name = "ADDR")]
+    statsd: Option<String>,
+
+    /// Push the gauges to Graphite at this host:port, in the plaintext protocol
+    #[clap(long, value_name = "ADDR")]
+    graphite: Option<String>,
+
+    /// Publish every sample as a JSON message to the MQTT broker at this
+    /// mqtt://[USER[:PASSWORD]@]HOST[:PORT] URL
+    #[clap(long, value_name = "URL")]
+    mqtt: Option<String>,
+
+    /// MQTT topic of the samples, {target} standing for the target name
+    #[clap(long, value_name = "TOPIC", default_value = mqtt::DEFAULT_TOPIC)]
+    mqtt_topic: String,
+
+    /// Export the offset, rtt and loss gauges as OpenTelemetry metrics to the
+    /// OTLP/HTTP collector at this URL (e.g. http://localhost:4318)
+    #[clap(long, value_name = "URL")]
+    otlp_endpoint: Option<String>,
+
+    /// Also store every sample, with the targets, the command line and the
+    /// version, in a database: sqlite:PATH (needs the sqlite feature)
+    #[clap(long, value_name = "STORE")]
+    store: Option<StoreSpec>,
+
+    /// Feed offsets to chronyd/ntpd as a reference clock: shm:<unit> or sock:<path> (chrony)
+    #[clap(long, value_name = "SPEC")]
+    refclock: Option<RefclockSpec>,
+
+    /// Correct the system clock toward the single target (Linux, needs CAP_SYS_TIME)
+    #[clap(long, conflicts_with = "refclock")]
+    discipline: bool,
+
+    /// With --discipline: step the clock for offsets above this many seconds, slew it below
+    #[clap(long, value_name = "SECONDS", default_value_t = 0.128)]
+    discipline_step: f64,
+
+    /// With --discipline: leave offsets above this many seconds uncorrected
+    #[clap(long, value_name = "SECONDS", default_value_t = 1000.0)]
+    discipline_max: f64,
+
+    /// With --discipline: only print the corrections that would be made
+    #[clap(long, requires = "discipline")]
+    dry_run: bool,
+
+    /// Add the kernel's frequency correction, error estimates and sync status
+    /// (adjtimex, Linux) at the time of each sample as kernel_* columns
+    #[clap(long)]
+    kernel_state: bool,
+
+    /// Accept control commands (status, pause, resume, set-interval, add-target,
+    /// remove-target) on a Unix domain socket at this path, one per line
+    #[clap(long, value_name = "PATH")]
+    control: Option<PathBuf>,
+
+    /// Serve the control commands as an HTTP API on this address (e.g. 127.0.0.1:9200)
+    #[clap(long, value_name = "ADDR")]
+    api_addr: Option<SocketAddr>,
+
+    /// Serve a page plotting the offsets and round-trip times live on this
+    /// address (e.g. 127.0.0.1:8080)
+    #[clap(long, value_name = "ADDR")]
+    web: Option<SocketAddr>,
+}
+
+#[derive(Args, Debug)]
+struct ReflectArgs {
+    #[clap(flatten)]
+    common: CommonArgs,
+
+    #[clap(flatten)]
+    reflector: ReflectorArgs,
+}
+
+/// Options of the reflecting side
+#[derive(Args, Debug)]
+struct ReflectorArgs {
+    /// Address to listen on; `::` also accepts IPv4 where dual-stack is supported
+    #[clap(short, long, default_value = "::")]
+    listen: IpAddr,
+
+    /// Only answer probes from this network (CIDR, repeatable)
+    #[clap(long, value_name = "CIDR", multiple_occurrences = true)]
+    allow: Vec<Cidr>,
+
+    /// Maximum replies per second to a single source address
+    #[clap(long, value_name = "PPS")]
+    rate_limit: Option<f64>,
+
+    /// Maximum replies per second overall
+    #[clap(long, value_name = "PPS")]
+    max_pps: Option<f64>,
+
+    /// Answer new sources with a small challenge until they prove they receive replies
+    #[clap(long, conflicts_with = "legacy")]
+    challenge: bool,
+
+    /// Print the clients and their apparent clock offsets to stderr this often
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    clients_interval: Option<Duration>,
+
+    /// Print the packet and byte counters, the number of clients and the probe
+    /// rate to stderr this often
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    stats_interval: Option<Duration>,
+
+    /// Append a JSON line per client address and client ID to this file every
+    /// --access-log-interval, with the number and rate of its probes
+    #[clap(long, value_name = "PATH")]
+    access_log: Option<PathBuf>,
+
+    /// How often to write the access log
+    #[clap(long, value_name = "DURATION", default_value = "1m", parse(try_from_str = parse_duration))]
+    access_log_interval: Duration,
+
+    /// Sockets sharing the port with SO_REUSEPORT, each served by its own task (Linux only)
+    #[clap(long, value_name = "N", default_value = "1")]
+    workers: usize,
+
+    /// Datagrams to read and answer per recvmmsg()/sendmmsg() call (Linux only)
+    #[clap(long, value_name = "N", default_value = "1")]
+    batch: usize,
+
+    /// Also accept probes over TCP connections on the same port (measure --transport tcp)
+    #[clap(long)]
+    tcp: bool,
+
+    /// Also accept probes as QUIC datagrams on this UDP port (measure --transport quic)
+    #[clap(long, value_name = "PORT", requires_all = &["tls-cert", "tls-key"])]
+    quic_port: Option<u16>,
+
+    /// PEM certificate chain to present to QUIC clients
+    #[clap(long, value_name = "PATH", requires = "quic-port")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[clap(long, value_name = "PATH", requires = "quic-port")]
+    tls_key: Option<PathBuf>,
+
+    /// Also accept probes over WebSocket connections on this TCP port (measure --transport websocket)
+    #[clap(long, value_name = "PORT")]
+    websocket_port: Option<u16>,
+
+    /// Also accept probes over connections to a Unix domain socket at this path
+    /// (measure --transport unix:PATH)
+    #[clap(long, value_name = "PATH")]
+    unix: Option<PathBuf>,
+
+    /// Also answer NTP clients such as chronyd on this UDP port, 123 if not given;
+    /// the leap indicator and root dispersion follow the kernel clock state (Linux)
+    #[clap(
+        long,
+        value_name = "PORT",
+        min_values = 0,
+        default_missing_value = "123"
+    )]
+    ntp: Option<u16>,
+
+    /// Stratum announced to NTP clients, one more than that of the host's own time source
+    #[clap(long, value_name = "N", default_value_t = 3, parse(try_from_str = parse_stratum))]
+    ntp_stratum: u8,
+
+    /// Answer discovery requests (co discover) on --discovery-port, multicast
+    /// to 239.255.55.55 or broadcast over IPv4
+    #[clap(long)]
+    discoverable: bool,
+
+    /// Port to answer discovery requests on
+    #[clap(long, value_name = "PORT", default_value_t = discovery::DEFAULT_PORT)]
+    discovery_port: u16,
+
+    /// Advertise the reflector over mDNS as a _clockoffset._udp service, for
+    /// measuring it as mdns:NAME
+    #[clap(long)]
+    mdns: bool,
+
+    /// Instance name to advertise, the host name by default
+    #[clap(long, value_name = "NAME", requires = "mdns")]
+    mdns_name: Option<String>,
+
+    /// Serve the sockets systemd passes (LISTEN_FDS) instead of binding --listen and --port:
+    /// UDP sockets as workers and a TCP listener as with --tcp
+    #[clap(long, conflicts_with = "workers")]
+    socket_activation: bool,
+}
+
+impl ReflectorArgs {
+    fn config(&self, common: &CommonArgs, metrics: Option<Metrics>) -> ReflectorConfig {
+        ReflectorConfig {
+            legacy: common.legacy,
+            clock: common.clock.clone(),
+            timestamping: common.timestamping(),
+            io_backend: common.io_backend,
+            socket: common.socket_options(),
+            key: common.key(),
+            allow: self.allow.clone(),
+            rate_limit: self.rate_limit,
+            max_pps: self.max_pps,
+            challenge: self.challenge,
+            // The statistics come from the counters, exported or not
+            metrics: metrics.or_else(|| self.stats_interval.map(|_| Metrics::new())),
+            workers: self.workers,
+            batch: self.batch,
+            tcp: self.tcp,
+            quic: self.quic_port.map(|port| QuicConfig {
+                port,
+                cert: self.tls_cert.clone().unwrap_or_default(),
+                key: self.tls_key.clone().unwrap_or_default(),
+            }),
+            websocket_port: self.websocket_port,
+            unix: self.unix.clone(),
+            ntp: self.ntp.map(|port| NtpConfig {
+                port,
+                stratum: self.ntp_stratum,
+            }),
+            socket_activation: self.socket_activation,
+            discovery_port: self.discoverable.then_some(self.discovery_port),
+            mdns: self.mdns.then(|| {
+                self.mdns_name
+                    .clone()
+                    .unwrap_or_else(mdns::default_instance)
+            }),
+            access_log: self.access_log.clone().map(|path| AccessLogConfig {
+                path,
+                interval: self.access_log_interval,
+            }),
+        }
+    }
+
+    fn addr(&self, common: &CommonArgs) -> SocketAddr {
+        SocketAddr::new(self.listen, common.port())
+    }
+
+    /// Reflector options left at their defaults
+    fn defaults() -> Self {
+        // Along with the common options some of them refer to
+        let app = ReflectArgs::augment_args(App::new("reflect"));
+        ReflectArgs::from_arg_matches(&app.get_matches_from(["reflect"]))
+            .expect("the reflector options have defaults")
+            .reflector
+    }
+}
+
+#[derive(Args, Debug)]
+struct PeerArgs {
+    #[clap(flatten)]
+    measure: MeasureArgs,
+
+    #[clap(flatten)]
+    reflector: ReflectorArgs,
+}
+
+#[derive(Args, Debug)]
+struct MtuArgs {
+    /// Smallest probe size to try, in bytes of UDP payload; probes of this size must be reflected
+    #[clap(long, value_name = "BYTES", default_value_t = 64)]
+    min_size: usize,
+
+    /// Largest probe size to try
+    #[clap(long, value_name = "BYTES", default_value_t = mtu::MAX_SIZE)]
+    max_size: usize,
+
+    #[clap(flatten)]
+    measure: MeasureArgs,
+}
+
+#[derive(Args, Debug)]
+struct SweepArgs {
+    /// Measure this many hosts at a time
+    #[clap(long, value_name = "N", default_value_t = 64)]
+    parallel: usize,
+
+    #[clap(flatten)]
+    measure: MeasureArgs,
+}
+
+#[derive(Args, Debug)]
+struct RoughtimeArgs {
+    /// Long-term Ed25519 public key of the servers (base64), as published by their operators
+    #[clap(long, value_name = "BASE64")]
+    public_key: roughtime::PublicKey,
+
+    #[clap(flatten)]
+    measure: MeasureArgs,
 }
 
-fn time_realtime() -> Result<(i64, i64)> {
-    let time = clock_gettime(ClockId::CLOCK_REALTIME).context("clock_gettime() call failed")?;
-    Ok((time.tv_sec(), time.tv_nsec()))
+#[derive(Args, Debug)]
+struct DiscoverArgs {
+    /// How long to wait for reflectors to answer, e.g. 1, 500ms or 2s
+    #[clap(long, value_name = "DURATION", default_value = "1", parse(try_from_str = parse_duration))]
+    wait: Duration,
+
+    /// Port reflectors answer discovery requests on
+    #[clap(long, value_name = "PORT", default_value_t = discovery::DEFAULT_PORT)]
+    discovery_port: u16,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+#[derive(Args, Debug)]
+struct CompareArgs {
+    /// Clock taking the place of the measuring side: realtime, tai, monotonic, boottime or phc:<device>
+    clock: Clock,
+
+    /// Clock taking the place of the reflector, the offset being clock minus reference
+    reference: Clock,
+
+    /// Sampling interval, e.g. 1, 100ms or 1m (seconds without a unit)
+    #[clap(short, long, value_name = "DURATION", default_value = "1", parse(try_from_str = parse_duration))]
+    interval: Duration,
+
+    /// Read the clocks this many times per sample and keep the lowest-delay read
+    #[clap(long, value_name = "N", default_value_t = 5)]
+    reads: usize,
+
+    /// Stop after this many samples
+    #[clap(short = 'c', long, value_name = "N")]
+    count: Option<u64>,
+
+    /// Stop after this long, e.g. 90, 30s, 10m or 2h
+    #[clap(long, value_name = "DURATION", parse(try_from_str = parse_duration))]
+    duration: Option<Duration>,
+
+    #[clap(flatten)]
+    scheduling: SchedulingArgs,
+
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+
+    /// Do not print discarded samples
+    #[clap(long)]
+    hide_discarded: bool,
+
+    /// Output format: csv, json (one object per line), influx (line protocol),
+    /// parquet (needs --output, written when measuring ends) or chrony
+    /// (chronyd's measurements.log)
+    #[clap(long, default_value = "csv")]
+    format: Format,
+
+    /// Append results to file instead of printing them to stdout
+    #[clap(short, long, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Output file rotation: hourly, daily or size (e.g. 100M)
+    #[clap(long, requires = "output")]
+    rotate: Option<Rotation>,
+
+    /// Write only these columns, in this order (e.g. offset,rtt,seq)
+    #[clap(long, value_name = "FIELDS", use_delimiter = true)]
+    fields: Vec<Field>,
+
+    /// Separator of csv columns, \t for a tab
+    #[clap(long, value_name = "STRING", default_value = ", ", parse(from_str = parse_delimiter))]
+    delimiter: String,
+
+    /// Leave out the csv header line
+    #[clap(long)]
+    no_header: bool,
+
+    /// Write the t1-t4 timestamps as epoch seconds or iso8601 (RFC 3339) date and time
+    #[clap(long, value_name = "FORMAT", default_value = "epoch")]
+    timestamps: TimeFormat,
+
+    /// Time zone of iso8601 timestamps: utc, local or an offset such as +02:00
+    #[clap(
+        long,
+        value_name = "ZONE",
+        default_value = "utc",
+        allow_hyphen_values = true
+    )]
+    timezone: TimeZone,
+}
+
+#[derive(Args, Debug)]
+struct AnalyzeArgs {
+    /// Log written by `measure` in the csv or json format, or a store written
+    /// with --store, such as sqlite:offsets.db
+    file: PathBuf,
+
+    /// Run of the store to analyze, by default the latest one
+    #[clap(long, value_name = "ID")]
+    run: Option<i64>,
+
+    #[clap(flatten)]
+    analysis: AnalysisArgs,
+}
+
+fn main() -> Result<()> {
+    let args = config_file::expand_args(&Cli::into_app(), env::args_os().collect())?;
+    let cli = Cli::parse_from(args);
+    let level = match cli.log.quiet {
+        true => LevelFilter::OFF,
+        false => cli.log.log_level,
+    };
+    let tui = match &cli.command {
+        Command::Measure(args) | Command::Ntp(args) => args.tui,
+        Command::Peer(args) => args.measure.tui,
+        Command::Roughtime(args) => args.measure.tui,
+        _ => false,
+    };
+    // The dashboard takes the terminal, so it shows the logs
+    let log = match tui {
+        true => Some(logging::init_buffered(level, DASHBOARD_LOG_LINES)),
+        false => {
+            logging::init(level, cli.log.log_format);
+            None
+        }
+    };
+    let command = cli.command;
+    // Before the runtime starts, so its threads inherit the settings
+    let scheduling = match &command {
+        Command::Measure(args) => Some(&args.common.scheduling),
+        Command::Reflect(args) => Some(&args.common.scheduling),
+        Command::Analyze(_) | Command::Discover(_) => None,
+        Command::Peer(args) => Some(&args.measure.common.scheduling),
+        Command::Compare(args) => Some(&args.scheduling),
+        Command::Ntp(args) => Some(&args.common.scheduling),
+        Command::Mesh(args) | Command::Survey(args) => Some(&args.measure.common.scheduling),
+        Command::Mtu(args) => Some(&args.measure.common.scheduling),
+        Command::Roughtime(args) => Some(&args.measure.common.scheduling),
+    };
+    if let Some(scheduling) = scheduling {
+        scheduling.config().apply()?;
+    }
 
-    if let Some(remote_ip) = args.remote_ip {
-        measure(SocketAddrV4::new(remote_ip.parse()?, args.port), args.interval).await
+    tokio::runtime::Runtime::new()?.block_on(async {
+        match command {
+            Command::Measure(args) => run_measure(*args, None, Protocol::Native, log).await,
+            Command::Reflect(args) => run_reflect(args).await,
+            Command::Analyze(args) => run_analyze(args),
+            Command::Peer(args) => {
+                run_measure(args.measure, Some(args.reflector), Protocol::Native, log).await
+            }
+            Command::Compare(args) => run_compare(args).await,
+            Command::Ntp(args) => run_measure(*args, None, Protocol::Ntp, log).await,
+            Command::Mesh(args) => run_mesh(*args).await,
+            Command::Survey(args) => run_survey(*args).await,
+            Command::Discover(args) => run_discover(args).await,
+            Command::Mtu(args) => run_mtu(*args).await,
+            Command::Roughtime(args) => {
+                let protocol = Protocol::Roughtime(args.public_key);
+                run_measure(args.measure, None, protocol, log).await
+            }
+        }
+    })
+}
+
+/// Measure the targets, also reflecting their probes in peer mode
+async fn run_measure(
+    args: MeasureArgs,
+    peer: Option<ReflectorArgs>,
+    protocol: Protocol,
+    log: Option<LogBuffer>,
+) -> Result<()> {
+    let peer = match (peer, args.also_reflect) {
+        (None, true) => {
+            ensure!(
+                matches!(protocol, Protocol::Native),
+                "--also-reflect only reflects probes of this tool"
+            );
+            Some(ReflectorArgs::defaults())
+        }
+        (peer, _) => peer,
+    };
+    let common = &args.common;
+    let port = match protocol {
+        Protocol::Native => common.port(),
+        Protocol::Ntp => common.port.unwrap_or(ntp::PORT),
+        Protocol::Roughtime(_) => common.port.unwrap_or(roughtime::PORT),
+    };
+    let mut targets = targets(&args, port).await?;
+
+    let config = measurer_config(&args, protocol).await?;
+    if args.oneshot {
+        ensure!(peer.is_none(), "--oneshot does not combine with peer mode");
+        ensure!(targets.len() == 1, "--oneshot takes exactly one target");
+        let config = MeasurerConfig {
+            interval: ONESHOT_INTERVAL,
+            interval_max: None,
+            jitter: 0.0,
+            count: Some(ONESHOT_PROBES),
+            ..config
+        };
+        let target = targets.remove(0);
+        let span = error_span!("measure", remote = %target);
+        return oneshot(target, config, args.threshold)
+            .instrument(span)
+            .await;
+    }
+
+    let analysis = args.analysis.config();
+    let mut fields = Field::ALL.to_vec();
+    if args.burst > 1 {
+        fields.extend(Field::BURST);
+    }
+    if analysis.smoothing.is_some() {
+        fields.push(Field::OffsetEst);
+    }
+    if args.asymmetry.is_some() {
+        fields.push(Field::AsymmetryCorrection);
+    }
+    // Which address of a hostname is measured
+    if args.all_addresses || targets.iter().any(|target| !target.is_address()) {
+        fields.push(Field::Address);
+    }
+    if args.ttl_ecn {
+        fields.extend([Field::Ttl, Field::Ecn]);
+    }
+    if args.kernel_state {
+        // Fail early where the state can not be read
+        KernelState::read()?;
+        fields.extend(Field::KERNEL);
+    }
+    let output = output_writer(&args, fields.clone())?;
+    ensure!(
+        args.refclock.is_none() || common.clock == Clock::Realtime,
+        "--refclock requires the realtime clock"
+    );
+    if args.discipline {
+        ensure!(
+            common.clock == Clock::Realtime,
+            "--discipline requires the realtime clock"
+        );
+        ensure!(targets.len() == 1, "--discipline takes exactly one target");
+    }
+    let metrics = common.metrics();
+    let reflector = match &peer {
+        Some(peer) => {
+            let config = peer.config(common, metrics.clone());
+            let reflector = Reflector::bind(peer.addr(common), config).await?;
+            info!("Reflecting packets on {}...", reflector.local_addr()?);
+            Some(reflector)
+        }
+        None => None,
+    };
+    let report = Report {
+        output,
+        metrics,
+        refclock: args.refclock.as_ref().map(Refclock::open).transpose()?,
+        kernel_state: args.kernel_state,
+        discipline: args
+            .discipline
+            .then(|| {
+                Discipline::new(DisciplineConfig {
+                    step_threshold: args.discipline_step,
+                    max_offset: args.discipline_max,
+                    dry_run: args.dry_run,
+                })
+            })
+            .transpose()?,
+        consensus: args.consensus,
+        hide_discarded: args.hide_discarded,
+        tracker: ConsensusTracker::new(),
+        summary: Summary::with_warmup(args.warmup),
+        stability_interval: args.stability_interval,
+        percentiles_interval: args.percentiles_interval,
+        summary_file: args.summary_file.clone(),
+        alerter: args
+            .alert_threshold
+            .map(|threshold| {
+                Alerter::new(AlertConfig {
+                    threshold,
+                    samples: args.alert_samples,
+                    command: args.alert_cmd.clone(),
+                    url: args.alert_url.clone(),
+                })
+            })
+            .transpose()?,
+        gauges: [
+            (gauges::Protocol::Statsd, &args.statsd),
+            (gauges::Protocol::Graphite, &args.graphite),
+        ]
+        .into_iter()
+        .filter_map(|(protocol, addr)| Some(GaugeSink::new(protocol, addr.as_deref()?)))
+        .collect::<Result<_>>()?,
+        mqtt: args
+            .mqtt
+            .as_deref()
+            .map(|url| {
+                let fields = match args.fields.is_empty() {
+                    true => fields.clone(),
+                    false => args.fields.clone(),
+                };
+                MqttPublisher::new(url, &args.mqtt_topic, fields)
+            })
+            .transpose()?,
+        otlp: args
+            .otlp_endpoint
+            .as_deref()
+            .map(OtlpExporter::new)
+            .transpose()?,
+        store: match &args.store {
+            Some(spec) => {
+                let names: Vec<_> = targets.iter().map(Target::to_string).collect();
+                let store = Store::create(spec, &names)?;
+                info!("Storing samples in {} as run {}", spec, store.run());
+                Some(store)
+            }
+            None => None,
+        },
+        web: match args.web {
+            Some(addr) => Some(WebUi::bind(addr).await?),
+            None => None,
+        },
+        dashboard: log.map(Dashboard::start).transpose()?,
+    };
+    let requests = control_requests(&args);
+    let reload = Reload { port, fields };
+    let measuring = measure(targets, config, analysis, report, reload, requests);
+    match (reflector, peer) {
+        (Some(reflector), Some(peer)) => tokio::select! {
+            result = measuring => result,
+            result = serve(&reflector, peer.clients_interval, peer.stats_interval) => result.context("reflector failed"),
+        },
+        _ => measuring.await,
+    }
+}
+
+/// Measurer settings of the measure options, checking them
+async fn measurer_config(args: &MeasureArgs, protocol: Protocol) -> Result<MeasurerConfig> {
+    let common = &args.common;
+    let family = family(args);
+    ensure!(!args.interval.is_zero(), "--interval must be positive");
+    if let Some(interval_max) = args.interval_max {
+        ensure!(
+            interval_max >= args.interval,
+            "--interval-max must not be shorter than --interval"
+        );
+    }
+    // Receive buffers are sized for an MTU of 1500
+    ensure!(
+        args.size.is_none_or(|size| size <= 2048),
+        "--size must be at most 2048 bytes"
+    );
+    ensure!(
+        (0.0..1.0).contains(&args.jitter),
+        "--jitter must be at least 0 and less than 1"
+    );
+    let overhead = if args.calibrate {
+        let calibration = calibration::calibrate(&common.clock).await?;
+        info!("Timestamping overhead: {}", calibration);
+        Some(calibration.send_receive)
+    } else {
+        None
+    };
+    Ok(MeasurerConfig {
+        interval: args.interval,
+        interval_max: args.interval_max,
+        jitter: args.jitter,
+        missed_ticks: args.missed_ticks,
+        family,
+        resolve_interval: (!args.resolve_interval.is_zero()).then_some(args.resolve_interval),
+        legacy: common.legacy,
+        protocol,
+        clock: common.clock.clone(),
+        timestamping: common.timestamping(),
+        io_backend: common.io_backend,
+        socket: SocketOptions {
+            interface: args.interface.clone(),
+            ..common.socket_options()
+        },
+        source: args.source,
+        transport: args.transport.clone(),
+        tls_ca: args.tls_ca.clone(),
+        proxy: args.proxy,
+        tx_timestamps: args.tx_timestamps,
+        key: common.key(),
+        burst: args.burst,
+        count: args.count.map(|count| count + args.warmup),
+        warmup: args.warmup,
+        duration: args.duration,
+        timeout: args.timeout,
+        backoff_max: (!args.backoff_max.is_zero()).then_some(args.backoff_max),
+        asymmetry: args.asymmetry,
+        overhead,
+        size: args.size,
+        pad_replies: args.pad_replies,
+        nonce: !args.no_nonce,
+        client_id: args.client_id,
+        ttl_ecn: args.ttl_ecn,
+    })
+}
+
+/// Measure the hosts of a mesh and print the offsets between them
+async fn run_mesh(args: SweepArgs) -> Result<()> {
+    let mesh = Mesh::new(run_sweep(&args).await?);
+    print!("{}", mesh);
+    let Some(consensus) = mesh.consensus() else {
+        return Ok(());
+    };
+    let hosts: Vec<_> = mesh
+        .most_inconsistent()
+        .into_iter()
+        .map(|(name, deviation)| format!("{} ({:+.9})", name, deviation))
+        .collect();
+    let majority = if consensus.has_majority() {
+        ""
     } else {
-        reflect(args.port).await
+        " (no majority)"
+    };
+    match consensus.falsetickers.is_empty() {
+        true => info!(
+            "All {} hosts are consistent with the group{}, deviating most: {}",
+            consensus.truechimers.len(),
+            majority,
+            hosts.join(", ")
+        ),
+        false => warn!(
+            "Inconsistent with {} of {} hosts{}: {}",
+            consensus.truechimers.len(),
+            consensus.truechimers.len() + consensus.falsetickers.len(),
+            majority,
+            hosts.join(", ")
+        ),
     }
+    Ok(())
 }
 
-async fn reflect(port: u16) -> Result<()> {
-    let sockaddr = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port);
-    eprintln!("Reflecting packets on {}...", sockaddr);
+/// Survey the hosts and print their offsets, sorted
+async fn run_survey(args: SweepArgs) -> Result<()> {
+    let survey = Survey::new(run_sweep(&args).await?);
+    print!("{}", survey);
+    match survey.spread() {
+        Some((min, max)) => info!(
+            "{} of {} hosts replied, their offsets spanning {:.9} from {:.9} to {:.9}",
+            survey.replied(),
+            survey.len(),
+            max - min,
+            min,
+            max
+        ),
+        None => warn!("None of the {} hosts replied", survey.len()),
+    }
+    Ok(())
+}
 
-    let socket = UdpSocket::bind(sockaddr).await?;
-    let mut buf = [0; 2048];  // should be enough for MTU 1500
+/// Probe the path MTU to every target
+async fn run_mtu(args: MtuArgs) -> Result<()> {
+    let measure = &args.measure;
+    ensure!(!measure.common.legacy, "legacy probes can not be padded");
+    let config = measurer_config(measure, Protocol::Native).await?;
+    for target in targets(measure, measure.common.port()).await? {
+        match mtu::probe(&target, &config, args.min_size, args.max_size).await {
+            Ok(mtu) => info!("{}: {}", target, mtu),
+            Err(e) => warn!("Probing the path MTU to {} failed: {:#}", target, e),
+        }
+    }
+    Ok(())
+}
 
-    loop {
-        let (len, addr) = socket.recv_from(&mut buf).await?;
-        if len != PAYLOAD_SIZE {
-            eprintln!("Invalid packet discarded: payload size {} != {}", len, PAYLOAD_SIZE);
+/// List the reflectors answering discovery with a short burst to each
+async fn run_discover(args: DiscoverArgs) -> Result<()> {
+    let reflectors = discovery::discover(args.discovery_port, args.wait).await?;
+    if reflectors.is_empty() {
+        warn!("No reflectors answered");
+        return Ok(());
+    }
+    let targets: Vec<_> = reflectors.iter().map(|r| Target::from(r.addr)).collect();
+    let config = MeasurerConfig {
+        interval: ONESHOT_INTERVAL,
+        count: Some(ONESHOT_PROBES),
+        ..MeasurerConfig::default()
+    };
+    let results = sweep::sweep(&targets, &config, targets.len()).await?;
+    let mut report = Vec::with_capacity(reflectors.len());
+    for (reflector, result) in reflectors.into_iter().zip(results) {
+        let m = match result {
+            Ok(m) => Some(m),
+            Err(e) => {
+                warn!("Measuring {} failed: {:#}", reflector.addr, e);
+                None
+            }
+        };
+        report.push((reflector, m));
+    }
+    report.sort_by_key(|(reflector, _)| reflector.addr);
+    print!("{}", DiscoveryReport(report));
+    Ok(())
+}
+
+/// Measure each target briefly, as `--oneshot` does, warning of those that
+/// fail
+async fn run_sweep(args: &SweepArgs) -> Result<Vec<(String, Option<Measurement>)>> {
+    let measure = &args.measure;
+    let targets = targets(measure, measure.common.port()).await?;
+    let config = MeasurerConfig {
+        interval: ONESHOT_INTERVAL,
+        interval_max: None,
+        jitter: 0.0,
+        count: Some(measure.count.unwrap_or(ONESHOT_PROBES) + measure.warmup),
+        ..measurer_config(measure, Protocol::Native).await?
+    };
+    let results = sweep::sweep(&targets, &config, args.parallel).await?;
+    let measurements = targets
+        .iter()
+        .zip(results)
+        .map(|(target, result)| {
+            let m = match result {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    warn!("Measuring {} failed: {:#}", target, e);
+                    None
+                }
+            };
+            (target.to_string(), m)
+        })
+        .collect();
+    Ok(measurements)
+}
+
+/// Start the control socket and API if requested, receiving their commands
+fn control_requests(args: &MeasureArgs) -> Option<mpsc::Receiver<Request>> {
+    if args.control.is_none() && args.api_addr.is_none() {
+        return None;
+    }
+    let (tx, rx) = mpsc::channel(CONTROL_QUEUE);
+    if let Some(path) = args.control.clone() {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = control::serve_unix(&path, tx).await {
+                error!("Control socket failed: {:#}", e);
+            }
+        });
+    }
+    if let Some(addr) = args.api_addr {
+        tokio::spawn(async move {
+            if let Err(e) = control::serve_http(addr, tx).await {
+                error!("Control API failed: {:#}", e);
+            }
+        });
+    }
+    Some(rx)
+}
+
+/// `format` with timestamps in `zone`
+fn time_format(format: TimeFormat, zone: TimeZone) -> TimeFormat {
+    match format {
+        TimeFormat::Iso8601(_) => TimeFormat::Iso8601(zone),
+        format => format,
+    }
+}
+
+/// Address family of hostname targets
+fn family(args: &MeasureArgs) -> Family {
+    if args.ipv4 {
+        Family::V4
+    } else if args.ipv6 {
+        Family::V6
+    } else {
+        match args.source {
+            Some(source) if source.is_ipv4() => Family::V4,
+            Some(_) => Family::V6,
+            None => Family::Any,
+        }
+    }
+}
+
+/// Targets of the command line and targets file, hostnames in place of
+/// their addresses with `--all-addresses`
+async fn targets(args: &MeasureArgs, port: u16) -> Result<Vec<Target>> {
+    let (browsed, remotes): (Vec<_>, Vec<_>) = args
+        .targets
+        .iter()
+        .partition(|remote| remote.starts_with(MDNS_PREFIX));
+    let mut targets = remotes
+        .into_iter()
+        .map(|remote| Target::parse(remote, port))
+        .collect::<Result<Vec<_>>>()?;
+    if !browsed.is_empty() {
+        targets.extend(mdns_targets(&browsed).await?);
+    }
+    if let Some(path) = &args.targets_file {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        targets.extend(Target::parse_list(&contents, port)?);
+    }
+    ensure!(!targets.is_empty(), "no targets to measure");
+    if !args.all_addresses {
+        return Ok(targets);
+    }
+    let mut addresses = Vec::new();
+    for target in targets {
+        if target.is_address() {
+            addresses.push(target);
             continue;
         }
+        let addrs = target.resolve_all(family(args)).await?;
+        let names: Vec<_> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+        info!("Measuring {} at {}", target, names.join(", "));
+        addresses.extend(addrs.into_iter().map(Target::from));
+    }
+    Ok(addresses)
+}
 
-        let (sec, nsec) = time_realtime()?;
-        let reply = [&buf[..PAYLOAD_SIZE], &sec.to_le_bytes(), &nsec.to_le_bytes()].concat();
-        socket.send_to(&reply, &addr).await?;
+/// Reflectors advertised over mDNS: every one for `mdns:`, the instance
+/// `NAME` for `mdns:NAME`
+async fn mdns_targets(remotes: &[&String]) -> Result<Vec<Target>> {
+    let instances = mdns::browse(MDNS_WAIT).await?;
+    let mut targets = Vec::new();
+    for remote in remotes {
+        let name = &remote[MDNS_PREFIX.len()..];
+        let found: Vec<_> = instances
+            .iter()
+            .filter(|instance| name.is_empty() || instance.name.eq_ignore_ascii_case(name))
+            .collect();
+        match (found.is_empty(), name.is_empty()) {
+            (true, true) => warn!("No reflectors advertised over mDNS"),
+            (true, false) => bail!("no reflector advertised over mDNS as {}", name),
+            (false, _) => {}
+        }
+        for instance in found {
+            info!("Measuring {} at {}", instance.name, instance.addr);
+            targets.push(Target::from(instance.addr));
+        }
     }
+    Ok(targets)
 }
 
-async fn measure(remote: SocketAddrV4, interval: f64) -> Result<()> {
-    eprintln!("Sending timestamps to {} every {} seconds...", remote, interval);
+fn output_writer(args: &MeasureArgs, fields: Vec<Field>) -> Result<OutputWriter> {
+    let mut output = match (&args.output, &args.influx_url) {
+        (_, Some(url)) => OutputWriter::new(
+            Format::Influx,
+            Box::new(InfluxClient::new(url, args.influx_token.clone())?),
+        ),
+        (Some(path), None) => {
+            OutputWriter::file(args.format, path, args.rotate.unwrap_or(Rotation::Never))?
+        }
+        (None, None) if args.format == Format::Parquet => bail!("--format parquet needs --output"),
+        (None, None) if args.tui => OutputWriter::new(args.format, Box::new(io::sink())),
+        (None, None) => OutputWriter::stdout(args.format),
+    };
+    output.set_fields(if args.fields.is_empty() {
+        fields
+    } else {
+        args.fields.clone()
+    });
+    output.set_time_format(time_format(args.timestamps, args.timezone));
+    output.set_delimiter(args.delimiter.clone());
+    if args.no_header {
+        output.no_header();
+    }
+    Ok(output)
+}
 
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    socket.connect(remote).await?;
-    let socket_receive = Arc::new(socket);
-    let socket_send = socket_receive.clone();
+async fn run_reflect(args: ReflectArgs) -> Result<()> {
+    let common = &args.common;
+    let config = args.reflector.config(common, common.metrics());
+    reflect(
+        args.reflector.addr(common),
+        config,
+        args.reflector.clients_interval,
+        args.reflector.stats_interval,
+    )
+    .await
+}
 
-    tokio::spawn(async move {
-        receive(socket_receive).await
+async fn run_compare(args: CompareArgs) -> Result<()> {
+    let analysis = args.analysis.config();
+    let mut output = match &args.output {
+        Some(path) => {
+            OutputWriter::file(args.format, path, args.rotate.unwrap_or(Rotation::Never))?
+        }
+        None if args.format == Format::Parquet => bail!("--format parquet needs --output"),
+        None => OutputWriter::stdout(args.format),
+    };
+    let mut fields = Field::ALL.to_vec();
+    if analysis.smoothing.is_some() {
+        fields.push(Field::OffsetEst);
+    }
+    output.set_fields(if args.fields.is_empty() {
+        fields
+    } else {
+        args.fields.clone()
     });
+    output.set_time_format(time_format(args.timestamps, args.timezone));
+    output.set_delimiter(args.delimiter.clone());
+    if args.no_header {
+        output.no_header();
+    }
+    let mut report = Report {
+        output,
+        metrics: None,
+        refclock: None,
+        kernel_state: false,
+        discipline: None,
+        consensus: false,
+        hide_discarded: args.hide_discarded,
+        tracker: ConsensusTracker::new(),
+        summary: Summary::new(),
+        stability_interval: None,
+        percentiles_interval: None,
+        summary_file: None,
+        alerter: None,
+        gauges: Vec::new(),
+        mqtt: None,
+        otlp: None,
+        store: None,
+        web: None,
+        dashboard: None,
+    };
+
+    let mut comparator = Comparator::new(args.clock.clone(), args.reference, args.reads);
+    let mut analyzer = Analyzer::new(&analysis);
+    let target = comparator.reference().to_string();
+    ensure!(!args.interval.is_zero(), "--interval must be positive");
+    info!(
+        "Comparing {} to {} every {} seconds...",
+        args.clock,
+        target,
+        args.interval.as_secs_f64()
+    );
+    let mut timer = time::interval(args.interval);
+    timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let finish = args.duration.map(|duration| Instant::now() + duration);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    for _ in 0..args.count.unwrap_or(u64::MAX) {
+        tokio::select! {
+            _ = timer.tick() => {}
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+        }
+        if finish.is_some_and(|finish| Instant::now() >= finish) {
+            break;
+        }
+        let sample = analyzer.process(comparator.sample()?);
+        report.add_sample(&target, sample, true)?;
+    }
 
-    send(socket_send, Duration::from_secs_f64(interval)).await
+    info!("Summary:\n{}", report.summary.to_string().trim_end());
+    Ok(())
 }
 
-async fn receive(socket: Arc<UdpSocket>) -> Result<()> {
-    println!("t1, tau2, t3, offset_min, offset_max, offset");
+fn run_analyze(args: AnalyzeArgs) -> Result<()> {
+    let path = &args.file;
+    let records = match path.to_string_lossy().parse::<StoreSpec>() {
+        Ok(spec) => {
+            let records = store::load(&spec, args.run)?;
+            ensure!(!records.is_empty(), "no measurements in {}", spec);
+            records
+        }
+        Err(_) => {
+            ensure!(args.run.is_none(), "--run only applies to stores");
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let records = record::parse_log(&contents)
+                .with_context(|| format!("failed to parse {}", path.display()))?;
+            ensure!(!records.is_empty(), "no measurements in {}", path.display());
+            records
+        }
+    };
 
-    let mut buf = [0; 2048];
+    let config = args.analysis.config();
+    let mut analyzers = HashMap::new();
+    let mut summary = Summary::new();
+    for record in records {
+        let analyzer = analyzers
+            .entry(record.target.clone())
+            .or_insert_with(|| Analyzer::new(&config));
+        let sample = analyzer.process(record.measurement);
+        summary.add(&record.target, &sample);
+    }
 
-    loop {
-        let len = socket.recv(&mut buf).await?;
-        if len != REFLECTED_PAYLOAD_SIZE {
-            eprintln!(
-                "Invalid packet discarded: payload size {} != {}",
-                len,
-                REFLECTED_PAYLOAD_SIZE
-            );
+    print!("{}", summary);
+    for (target, t) in summary.targets() {
+        let Some(tau0) = stability::sample_interval(&t.times) else {
             continue;
+        };
+        print!("{} stability:\n{}", target, Stability::of(&t.offsets, tau0));
+    }
+    Ok(())
+}
+
+async fn reflect(
+    addr: SocketAddr,
+    config: ReflectorConfig,
+    clients_interval: Option<Duration>,
+    stats_interval: Option<Duration>,
+) -> Result<()> {
+    let reflector = Reflector::bind(addr, config).await?;
+    info!("Reflecting packets on {}...", reflector.local_addr()?);
+    notify_systemd("READY=1");
+
+    tokio::select! {
+        result = serve(&reflector, clients_interval, stats_interval) => result,
+        result = shutdown_signal() => result,
+    }
+}
+
+/// Reflect packets forever, printing the client table every `clients_interval`
+/// and the statistics every `stats_interval`
+async fn serve(
+    reflector: &Reflector,
+    clients_interval: Option<Duration>,
+    stats_interval: Option<Duration>,
+) -> Result<()> {
+    let running = reflector.run();
+    tokio::pin!(running);
+    let mut clients_timer = periodic(clients_interval);
+    let mut stats_timer = periodic(stats_interval);
+    let mut last_received = 0;
+    let mut watchdog_timer = periodic(systemd::watchdog_interval());
+    loop {
+        tokio::select! {
+            result = &mut running => return result,
+            _ = tick(&mut clients_timer) => {
+                info!("{}", ClientReport(reflector.clients()).to_string().trim_end())
+            }
+            _ = tick(&mut stats_timer) => {
+                let counters = reflector.counters().unwrap_or_default();
+                let interval = stats_interval.unwrap_or_default().as_secs_f64();
+                info!(
+                    "Reflector {}, {} clients; {:.1} packets/s since the last report",
+                    counters,
+                    reflector.client_count(),
+                    (counters.received - last_received) as f64 / interval
+                );
+                last_received = counters.received;
+            }
+            _ = tick(&mut watchdog_timer) => notify_systemd("WATCHDOG=1"),
         }
+    }
+}
 
-        let (sec3, nsec3) = time_realtime()?;
-        let sec1 = i64::from_le_bytes(buf[..8].try_into()?);
-        let nsec1 = i64::from_le_bytes(buf[8..16].try_into()?);
-        let sec2 = i64::from_le_bytes(buf[16..24].try_into()?);
-        let nsec2 = i64::from_le_bytes(buf[24..32].try_into()?);
-
-        let t1 = total_nsec(sec1, nsec1);
-        let tau2 = total_nsec(sec2, nsec2);  // reference time
-        let t3 = total_nsec(sec3, nsec3);
-
-        let offset_min = t1 - tau2;
-        let offset_max = t3 - tau2;
-        let offset = (t1 + t3) / 2 - tau2;
-
-        println!(
-            "{}.{:09}, {}.{:09}, {}.{:09}, {:.9}, {:.9}, {:.9}",
-            sec1, nsec1,
-            sec2, nsec2,
-            sec3, nsec3,
-            nsec_to_sec(offset_min),
-            nsec_to_sec(offset_max),
-            nsec_to_sec(offset)
+/// Tell the service manager about the state of the service, if run by systemd
+fn notify_systemd(state: &str) {
+    if let Err(e) = systemd::notify(state) {
+        warn!("Notifying systemd failed: {:#}", e);
+    }
+}
+
+/// Wait for SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+/// Wait for Ctrl-C
+#[cfg(not(unix))]
+async fn shutdown_signal() -> Result<()> {
+    Ok(tokio::signal::ctrl_c().await?)
+}
+
+/// Signal handled while measuring, which never comes outside Unix
+#[cfg(unix)]
+struct UnixSignal(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl UnixSignal {
+    fn hangup() -> Result<Self> {
+        Ok(Self(signal(SignalKind::hangup())?))
+    }
+
+    fn user_defined1() -> Result<Self> {
+        Ok(Self(signal(SignalKind::user_defined1())?))
+    }
+
+    fn user_defined2() -> Result<Self> {
+        Ok(Self(signal(SignalKind::user_defined2())?))
+    }
+
+    async fn recv(&mut self) {
+        self.0.recv().await;
+    }
+}
+
+#[cfg(not(unix))]
+struct UnixSignal;
+
+#[cfg(not(unix))]
+impl UnixSignal {
+    fn hangup() -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn user_defined1() -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn user_defined2() -> Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        future::pending().await
+    }
+}
+
+/// What SIGHUP reloads from the command line and the configuration and
+/// targets files: the targets and where results go
+struct Reload {
+    /// Default port of the targets
+    port: u16,
+    fields: Vec<Field>,
+}
+
+impl Reload {
+    /// The targets and the output, unless that is a Parquet file, which can
+    /// only be written in one go
+    async fn load(&self) -> Result<(Vec<Target>, Option<OutputWriter>)> {
+        let args = config_file::expand_args(&Cli::into_app(), env::args_os().collect())?;
+        let args = match Cli::try_parse_from(args)?.command {
+            Command::Measure(args) | Command::Ntp(args) => *args,
+            Command::Peer(args) => args.measure,
+            Command::Roughtime(args) => args.measure,
+            _ => unreachable!("reloading a command that does not measure"),
+        };
+        let output = match args.format {
+            Format::Parquet => None,
+            _ => Some(output_writer(&args, self.fields.clone())?),
+        };
+        Ok((targets(&args, self.port).await?, output))
+    }
+}
+
+/// Print the offset of the minimum-delay sample of a short burst
+///
+/// Exits with status 2 if the offset magnitude exceeds `threshold`.
+async fn oneshot(target: Target, config: MeasurerConfig, threshold: Option<f64>) -> Result<()> {
+    let mut measurer = Measurer::connect(target.clone(), config).await?;
+    let mut filter = ClockFilter::new(ONESHOT_PROBES as usize);
+    while let Some(m) = measurer.next_measurement().await? {
+        filter.add(m);
+    }
+
+    let best = filter
+        .best()
+        .ok_or_else(|| anyhow!("no replies from {}", target))?;
+    println!("{:.9}", best.offset);
+
+    if threshold.is_some_and(|threshold| best.offset.abs() > threshold) {
+        warn!(
+            "Offset {:.9} to {} exceeds the threshold",
+            best.offset, target
         );
+        process::exit(2);
     }
+    Ok(())
+}
+
+/// Where measurement results go
+struct Report {
+    output: OutputWriter,
+    metrics: Option<Metrics>,
+    refclock: Option<Refclock>,
+    /// Attach the kernel NTP state to every sample
+    kernel_state: bool,
+    /// Steers the system clock toward the single target
+    discipline: Option<Discipline>,
+    /// Print the consensus of all targets to stderr
+    consensus: bool,
+    hide_discarded: bool,
+    tracker: ConsensusTracker,
+    summary: Summary,
+    /// How often to print the stability of the offsets
+    stability_interval: Option<Duration>,
+    /// How often to print the offset and round-trip time percentiles
+    percentiles_interval: Option<Duration>,
+    /// Where to write the summary as JSON at exit
+    summary_file: Option<PathBuf>,
+    alerter: Option<Alerter>,
+    gauges: Vec<GaugeSink>,
+    mqtt: Option<MqttPublisher>,
+    otlp: Option<OtlpExporter>,
+    store: Option<Store>,
+    web: Option<WebUi>,
+    dashboard: Option<Dashboard>,
 }
 
-fn total_nsec(sec: i64, nsec: i64) -> i128 {
-    const NANOSECONDS_IN_SECOND: i128 = 1000000000;
+impl Report {
+    /// Handle a sample; `single_target` tells the reference clock to follow it
+    /// directly rather than the consensus
+    fn add_sample(&mut self, target: &str, mut sample: Sample, single_target: bool) -> Result<()> {
+        if self.kernel_state {
+            sample.kernel = KernelState::read()
+                .map_err(|e| warn!("Reading the kernel clock state failed: {:#}", e))
+                .ok();
+        }
+        self.summary.add(target, &sample);
+        if let Some(metrics) = &self.metrics {
+            metrics.record_sample(target, &sample);
+        }
+        if let Some(store) = &mut self.store {
+            store.add_sample(target, &sample)?;
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.publish(target, &sample);
+        }
+        if let Some(web) = &self.web {
+            web.publish(target, &sample);
+        }
+        if !(self.hide_discarded && sample.flags.discarded) {
+            self.output.write_sample(target, &sample)?;
+        }
+        if sample.flags.discarded {
+            return Ok(());
+        }
+        if let Some(alerter) = &mut self.alerter {
+            alerter.update(target, &sample);
+        }
+        for sink in &self.gauges {
+            sink.update(target, &sample);
+        }
+        if let Some(otlp) = &self.otlp {
+            otlp.update(target, &sample);
+        }
+        if let (Some(refclock), true) = (&mut self.refclock, single_target) {
+            update_refclock(refclock, sample.measurement.t4, sample.filtered_offset);
+        }
+        // Only the latest sample tells how the clock is now, and only the
+        // minimum-delay ones are trusted
+        let m = &sample.measurement;
+        let fresh = sample.filtered_offset == m.offset;
+        if let (Some(discipline), true) = (&mut self.discipline, fresh) {
+            match discipline.update(m.t1, m.offset) {
+                // Slews are only printed in a dry run, they happen every interval
+                Ok(Some(correction @ Correction::Step(_))) => info!("Clock {}", correction),
+                Ok(Some(correction)) if discipline.dry_run() => info!("Clock {}", correction),
+                Ok(_) => {}
+                Err(e) => error!("Clock discipline failed: {:#}", e),
+            }
+        }
+        if self.consensus || (self.refclock.is_some() && !single_target) {
+            if let Some((sources, c)) = self.tracker.add(target, sample.measurement) {
+                if self.consensus {
+                    print_consensus(&sources, &c);
+                }
+                if let (Some(refclock), true) = (&mut self.refclock, c.has_majority()) {
+                    update_refclock(refclock, Timestamp::now()?, c.offset);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the stability of the accepted offsets so far, sampled at their
+    /// median spacing, as the interval may adapt or back off
+    fn print_stability(&self) {
+        for (target, t) in self.summary.targets() {
+            let Some(tau0) = stability::sample_interval(&t.times) else {
+                continue;
+            };
+            let stability = Stability::of(&t.offsets, tau0);
+            if !stability.points.is_empty() {
+                info!(
+                    "{} stability:\n{}",
+                    target,
+                    stability.to_string().trim_end()
+                );
+            }
+        }
+    }
+
+    fn add_lost(&mut self, target: &str, probe: &LostProbe) -> Result<()> {
+        self.summary.add_lost(target, probe);
+        self.output.write_lost(target, probe)?;
+        if let Some(store) = &mut self.store {
+            store.add_lost(target, probe)?;
+        }
+        Ok(())
+    }
+
+    fn draw_dashboard(&mut self) -> Result<()> {
+        if let Some(dashboard) = &mut self.dashboard {
+            dashboard.draw(&self.summary)?;
+        }
+        Ok(())
+    }
 
-    sec as i128 * NANOSECONDS_IN_SECOND + nsec as i128
+    fn print_percentiles(&self) -> Result<()> {
+        let mut text = String::new();
+        for (target, t) in self.summary.targets() {
+            writeln!(
+                text,
+                "{}: {}/{} probes lost ({:.1}%)",
+                target,
+                t.lost,
+                t.sent,
+                t.loss_ratio() * 100.0
+            )?;
+            t.write_percentiles(&mut text)?;
+        }
+        info!("{}", text.trim_end());
+        Ok(())
+    }
 }
 
-fn nsec_to_sec(nsec: i128) -> f64 {
-    nsec as f64 * 1e-9
+/// Measure all targets until done or interrupted, then print a summary to stderr
+async fn measure(
+    targets: Vec<Target>,
+    config: MeasurerConfig,
+    analysis: AnalyzerConfig,
+    mut report: Report,
+    reload: Reload,
+    mut requests: Option<mpsc::Receiver<Request>>,
+) -> Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let spawn = |target: Target| {
+        let analyzer = Analyzer::new(&analysis);
+        let (control, settings) = watch::channel(MeasurerControl {
+            paused: false,
+            interval: config.interval,
+        });
+        // At the error level, so that filtering by level keeps the target in the messages
+        let span = error_span!("measure", remote = %target);
+        let measuring = measure_target(target, config.clone(), analyzer, settings, tx.clone());
+        Task {
+            handle: tokio::spawn(measuring.instrument(span)),
+            control,
+        }
+    };
+    // Tasks by target name, until they finish
+    let mut tasks: HashMap<_, _> = targets
+        .into_iter()
+        .map(|target| (target.to_string(), spawn(target)))
+        .collect();
+    let mut started = tasks.len();
+
+    let mut failed = 0;
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut hangup = UnixSignal::hangup()?;
+    let mut snapshot = UnixSignal::user_defined1()?;
+    let mut reset = UnixSignal::user_defined2()?;
+    let mut stability_timer = periodic(report.stability_interval);
+    let mut percentiles_timer = periodic(report.percentiles_interval);
+    let mut watchdog_timer = periodic(systemd::watchdog_interval());
+    let mut dashboard_timer = periodic(report.dashboard.is_some().then_some(DASHBOARD_INTERVAL));
+    // The service is ready once the first valid reply arrives
+    let mut ready = false;
+    while !tasks.is_empty() {
+        // With several targets the reference clock is fed their consensus
+        let single_target = tasks.len() == 1;
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some((target, Ok(Outcome::Sample(sample)))) => {
+                    if !ready && !sample.flags.discarded {
+                        notify_systemd("READY=1");
+                        ready = true;
+                    }
+                    report.add_sample(&target.to_string(), *sample, single_target)?
+                }
+                Some((target, Ok(Outcome::Lost(probe)))) => {
+                    report.add_lost(&target.to_string(), &probe)?
+                }
+                Some((target, Ok(Outcome::Finished))) => {
+                    tasks.remove(&target.to_string());
+                }
+                Some((target, Err(e))) => {
+                    error!("Measuring {} failed: {:#}", target, e);
+                    tasks.remove(&target.to_string());
+                    failed += 1;
+                }
+                None => break,
+            },
+            _ = tick(&mut stability_timer) => report.print_stability(),
+            _ = tick(&mut percentiles_timer) => report.print_percentiles()?,
+            _ = tick(&mut watchdog_timer) => notify_systemd("WATCHDOG=1"),
+            _ = tick(&mut dashboard_timer) => report.draw_dashboard()?,
+            quit = dashboard_input(&mut report.dashboard) => match quit {
+                true => break,
+                false => report.draw_dashboard()?,
+            },
+            _ = snapshot.recv() => info!("Statistics:\n{}", report.summary.to_string().trim_end()),
+            _ = reset.recv() => {
+                report.summary.reset();
+                info!("Statistics reset");
+            }
+            _ = hangup.recv() => match reload.load().await {
+                Ok((targets, _)) if report.discipline.is_some() && targets.len() != 1 => {
+                    error!("Reload failed, --discipline takes exactly one target");
+                }
+                Ok((targets, output)) => {
+                    // Unchanged targets keep measuring with their state
+                    let targets: HashMap<_, _> =
+                        targets.into_iter().map(|target| (target.to_string(), target)).collect();
+                    tasks.retain(|name, task| {
+                        let keep = targets.contains_key(name);
+                        if !keep {
+                            task.handle.abort();
+                            info!("Stopped measuring {}", name);
+                        }
+                        keep
+                    });
+                    for (name, target) in targets {
+                        tasks.entry(name).or_insert_with(|| {
+                            started += 1;
+                            spawn(target)
+                        });
+                    }
+                    if let Some(output) = output {
+                        report.output = output;
+                    }
+                    info!("Reloaded the targets and output options");
+                }
+                Err(e) => error!("Reload failed, keeping the previous settings: {:#}", e),
+            },
+            Some((command, answer)) = next_request(&mut requests) => {
+                let name = |remote: &str| Target::parse(remote, reload.port).map(|t| t.to_string());
+                let executed = match command {
+                    control::Command::Status => Ok(()),
+                    control::Command::Pause(target) => {
+                        update_tasks(&tasks, target.as_deref(), name, |c| c.paused = true)
+                    }
+                    control::Command::Resume(target) => {
+                        update_tasks(&tasks, target.as_deref(), name, |c| c.paused = false)
+                    }
+                    control::Command::SetInterval(interval, target) => {
+                        update_tasks(&tasks, target.as_deref(), name, |c| c.interval = interval)
+                    }
+                    control::Command::AddTarget(remote) => match Target::parse(&remote, reload.port) {
+                        Ok(_) if report.discipline.is_some() => {
+                            Err(anyhow!("--discipline takes exactly one target"))
+                        }
+                        Ok(target) if tasks.contains_key(&target.to_string()) => {
+                            Err(anyhow!("{} is already measured", target))
+                        }
+                        Ok(target) => {
+                            info!("Started measuring {}", target);
+                            started += 1;
+                            tasks.insert(target.to_string(), spawn(target));
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    },
+                    control::Command::RemoveTarget(remote) => {
+                        match name(&remote).ok().and_then(|name| tasks.remove_entry(&name)) {
+                            Some((name, task)) => {
+                                task.handle.abort();
+                                info!("Stopped measuring {}", name);
+                                Ok(())
+                            }
+                            None => Err(anyhow!("{} is not measured", remote)),
+                        }
+                    }
+                };
+                let _ = answer.send(executed.map(|()| status(&tasks, &report.summary)));
+            }
+            result = &mut shutdown => {
+                result?;
+                break;
+            }
+        }
+    }
+
+    // Give the terminal back for the summary
+    report.dashboard = None;
+    if !report.summary.targets().is_empty() {
+        info!("Summary:\n{}", report.summary.to_string().trim_end());
+    }
+    if let Some(path) = &report.summary_file {
+        let json = serde_json::to_string_pretty(&report.summary.to_json())?;
+        fs::write(path, json + "\n")
+            .with_context(|| format!("failed to write the summary to {}", path.display()))?;
+    }
+    if config.high_rate() && config.interval_max.is_none() {
+        let requested = config.burst as f64 / config.interval.as_secs_f64();
+        for (target, t) in report.summary.targets() {
+            if let Some(rate) = t.probe_rate() {
+                info!(
+                    "Sent {:.1} probes/s to {}, {:.1} requested",
+                    rate, target, requested
+                );
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("measuring {} of {} targets failed", failed, started);
+    }
+    Ok(())
+}
+
+/// Stratum of a synchronized server, 1 being a reference clock itself
+fn parse_stratum(s: &str) -> Result<u8> {
+    let stratum = s.parse()?;
+    ensure!((1..=15).contains(&stratum), "stratum must be 1 to 15");
+    Ok(stratum)
+}
+
+/// Local address with an optional port, 0 letting the system pick one
+fn parse_source(s: &str) -> Result<SocketAddr> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    let ip: IpAddr = s
+        .parse()
+        .with_context(|| format!("invalid source address '{}'", s))?;
+    Ok(SocketAddr::new(ip, 0))
+}
+
+/// Separator given on the command line, with `\t` standing for a tab
+fn parse_delimiter(s: &str) -> String {
+    s.replace("\\t", "\t")
+}
+
+/// Resolve a `host:port` proxy address once, at startup
+fn parse_proxy(s: &str) -> Result<SocketAddr> {
+    s.to_socket_addrs()
+        .with_context(|| format!("invalid proxy address '{}'", s))?
+        .next()
+        .ok_or_else(|| anyhow!("no address found for proxy {}", s))
+}
+
+/// Timer firing every `period` starting one period from now, if any
+fn periodic(period: Option<Duration>) -> Option<Interval> {
+    period.map(|period| time::interval_at(Instant::now() + period, period))
+}
+
+/// Wait for the next tick of `timer`, forever if there is none
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => future::pending().await,
+    }
 }
 
-async fn send(socket: Arc<UdpSocket>, interval: Duration) -> Result<()> {
+/// Measuring of a target
+struct Task {
+    handle: JoinHandle<()>,
+    /// Settings changed by control commands
+    control: watch::Sender<MeasurerControl>,
+}
+
+/// Change the settings of the target `remote`, named with `name`, or of all
+/// targets without one
+fn update_tasks(
+    tasks: &HashMap<String, Task>,
+    remote: Option<&str>,
+    name: impl Fn(&str) -> Result<String>,
+    update: impl Fn(&mut MeasurerControl),
+) -> Result<()> {
+    match remote {
+        Some(remote) => {
+            let task = name(remote)
+                .ok()
+                .and_then(|name| tasks.get(&name))
+                .ok_or_else(|| anyhow!("{} is not measured", remote))?;
+            task.control.send_modify(update);
+        }
+        None => tasks
+            .values()
+            .for_each(|task| task.control.send_modify(&update)),
+    }
+    Ok(())
+}
+
+/// Settings and statistics of the targets, answering control commands
+fn status(tasks: &HashMap<String, Task>, summary: &Summary) -> Value {
+    let targets: serde_json::Map<_, _> = tasks
+        .iter()
+        .map(|(name, task)| {
+            let control = *task.control.borrow();
+            let summary = summary
+                .targets()
+                .iter()
+                .find(|(target, _)| target == name)
+                .map(|(_, t)| t);
+            let status = json!({
+                "paused": control.paused,
+                "interval": control.interval.as_secs_f64(),
+                "sent": summary.map_or(0, |t| t.sent),
+                "lost": summary.map_or(0, |t| t.lost),
+                "samples": summary.map_or(0, |t| t.samples),
+                "filtered_offset": summary.and_then(|t| t.filtered_offset),
+                "drift_ppm": summary.and_then(|t| t.drift_ppm),
+            });
+            (name.clone(), status)
+        })
+        .collect();
+    json!({ "targets": targets })
+}
+
+/// Next control command, never without a control socket or API
+async fn next_request(requests: &mut Option<mpsc::Receiver<Request>>) -> Option<Request> {
+    match requests {
+        Some(requests) => requests.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// Whether the key pressed on the dashboard quits, never without one
+async fn dashboard_input(dashboard: &mut Option<Dashboard>) -> bool {
+    match dashboard {
+        Some(dashboard) => dashboard.input().await,
+        None => future::pending().await,
+    }
+}
+
+/// What a target reports per probe
+enum Outcome {
+    Sample(Box<Sample>),
+    Lost(LostProbe),
+    /// The last probe was answered or lost
+    Finished,
+}
+
+/// Stream measurements against one target until done or an error occurs
+async fn measure_target(
+    target: Target,
+    config: MeasurerConfig,
+    mut analyzer: Analyzer,
+    control: watch::Receiver<MeasurerControl>,
+    tx: mpsc::UnboundedSender<(Target, Result<Outcome>)>,
+) {
+    let interval = match config.interval_max {
+        Some(max) => format!("{} to {}", config.interval.as_secs_f64(), max.as_secs_f64()),
+        None => config.interval.as_secs_f64().to_string(),
+    };
+    let connecting = async {
+        let family = happy_eyeballs::select_family(&target, &config).await?;
+        Measurer::connect(target.clone(), MeasurerConfig { family, ..config }).await
+    };
+    let mut measurer = match connecting.await {
+        Ok(measurer) => measurer,
+        Err(e) => {
+            let _ = tx.send((target, Err(e)));
+            return;
+        }
+    };
+    measurer.control(control);
+    info!(
+        "Sending timestamps to {} ({}) every {} seconds...",
+        target,
+        measurer.remote(),
+        interval
+    );
+
     loop {
-        let (sec, nsec) = time_realtime()?;
-        let payload = [sec.to_le_bytes(), nsec.to_le_bytes()].concat();
-        assert_eq!(payload.len(), PAYLOAD_SIZE);
+        let result = match measurer.next_event().await {
+            Ok(Some(Event::Measurement(m))) => Ok(Outcome::Sample(Box::new(analyzer.process(m)))),
+            Ok(Some(Event::Lost(probe))) => Ok(Outcome::Lost(probe)),
+            Ok(None) => Ok(Outcome::Finished),
+            Err(e) => Err(e),
+        };
+        let last = matches!(result, Ok(Outcome::Finished) | Err(_));
+        if tx.send((target.clone(), result)).is_err() || last {
+            return;
+        }
+    }
+}
 
-        socket.send(&payload[..]).await?;
+fn update_refclock(refclock: &mut Refclock, local: Timestamp, offset: f64) {
+    if let Err(e) = refclock.update(local, offset) {
+        warn!("Reference clock update failed: {:#}", e);
+    }
+}
 
-        sleep(interval).await;
+fn print_consensus(sources: &[String], c: &Consensus) {
+    info!(
+        "Consensus offset {:.9} [{:.9}, {:.9}] from {}/{} reflectors{}",
+        c.offset,
+        c.offset_min,
+        c.offset_max,
+        c.truechimers.len(),
+        sources.len(),
+        if c.has_majority() {
+            ""
+        } else {
+            " (no majority)"
+        }
+    );
+    if !c.falsetickers.is_empty() {
+        let names: Vec<_> = c
+            .falsetickers
+            .iter()
+            .map(|&i| sources[i].as_str())
+            .collect();
+        warn!("Falsetickers rejected: {}", names.join(", "));
     }
 }
@@ -0,0 +1,431 @@
+//! Advertising reflectors over multicast DNS (DNS-SD) and browsing for them
+//!
+//! A reflector registers an instance of the `_clockoffset._udp.local`
+//! service: a PTR record to the instance, its SRV record with the probe port
+//! and a TXT record with the version, along with the A records of the host.
+//! Only what finding reflectors needs is implemented: one-shot queries,
+//! answered by unicast, and an announcement when the reflector starts.
+
+use crate::output;
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Instant},
+};
+
+pub const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const PORT: u16 = 5353;
+/// Labels of the service name, `_clockoffset._udp.local`
+const SERVICE: [&str; 3] = ["_clockoffset", "_udp", "local"];
+/// Time to live of the records (seconds)
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on questions asking for a unicast response, and on records of which
+/// this host has the only copy
+const CLASS_FLAG: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+/// Reflector found by browsing
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instance {
+    pub name: String,
+    /// Address of the host, or of the response without an A record, and the
+    /// probe port
+    pub addr: SocketAddr,
+    pub version: Option<String>,
+}
+
+/// Default instance name of a reflector: the host name
+pub fn default_instance() -> String {
+    output::hostname()
+}
+
+/// Records of one reflector instance, answering queries for the service
+#[derive(Clone, Debug)]
+pub struct Advertisement {
+    instance: String,
+    /// First label of the local host name
+    host: String,
+    port: u16,
+}
+
+impl Advertisement {
+    /// Advertise the reflector taking probes on `port` as `instance`
+    pub fn new(instance: &str, port: u16) -> Result<Self> {
+        ensure!(
+            !instance.is_empty() && instance.len() <= 63,
+            "mDNS instance names must have 1 to 63 bytes"
+        );
+        let hostname = output::hostname();
+        let host = hostname.split('.').next().unwrap_or_default();
+        Ok(Self {
+            instance: instance.to_owned(),
+            host: if host.is_empty() { "co" } else { host }.to_owned(),
+            port,
+        })
+    }
+
+    /// Unsolicited response announcing the records
+    pub fn announcement(&self) -> Vec<u8> {
+        self.response(0)
+    }
+
+    /// Response to a query in `packet` for the service or the instance,
+    /// `None` for other queries and for responses
+    pub fn answer(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let message = Message::parse(packet).ok()?;
+        if message.flags & FLAG_RESPONSE != 0 {
+            return None;
+        }
+        let instance = self.instance_name();
+        let asked = message.questions.iter().any(|(name, qtype)| {
+            (names_equal(name, &SERVICE) && matches!(*qtype, TYPE_PTR | TYPE_ANY))
+                || (names_equal(name, &instance)
+                    && matches!(*qtype, TYPE_SRV | TYPE_TXT | TYPE_ANY))
+        });
+        asked.then(|| self.response(message.id))
+    }
+
+    fn instance_name(&self) -> [&str; 4] {
+        [&self.instance, SERVICE[0], SERVICE[1], SERVICE[2]]
+    }
+
+    fn response(&self, id: u16) -> Vec<u8> {
+        let instance = self.instance_name();
+        let host = [self.host.as_str(), "local"];
+        let addrs = local_addrs();
+        let mut buf = Vec::new();
+        push_header(
+            &mut buf,
+            id,
+            FLAG_RESPONSE | FLAG_AUTHORITATIVE,
+            [0, 1, 0, 2 + addrs.len() as u16],
+        );
+
+        push_record(&mut buf, &SERVICE, TYPE_PTR, CLASS_IN, |data| {
+            push_name(data, &instance)
+        });
+        let unique = CLASS_IN | CLASS_FLAG;
+        push_record(&mut buf, &instance, TYPE_SRV, unique, |data| {
+            data.extend_from_slice(&[0, 0, 0, 0]); // priority and weight
+            data.extend_from_slice(&self.port.to_be_bytes());
+            push_name(data, &host);
+        });
+        push_record(&mut buf, &instance, TYPE_TXT, unique, |data| {
+            let version = format!("version={}", env!("CARGO_PKG_VERSION"));
+            data.push(version.len() as u8);
+            data.extend_from_slice(version.as_bytes());
+        });
+        for addr in addrs {
+            push_record(&mut buf, &host, TYPE_A, unique, |data| {
+                data.extend_from_slice(&addr.octets())
+            });
+        }
+        buf
+    }
+}
+
+/// Socket answering queries on the mDNS port of all IPv4 addresses,
+/// shared with other responders of the host where supported
+pub fn bind() -> Result<UdpSocket> {
+    let addr = (Ipv4Addr::UNSPECIFIED, PORT).into();
+    let socket = crate::socket::bind_udp(addr, cfg!(target_os = "linux"))
+        .context("failed to bind the mDNS port")?;
+    socket
+        .join_multicast_v4(GROUP, Ipv4Addr::UNSPECIFIED)
+        .context("failed to join the mDNS group")?;
+    Ok(socket)
+}
+
+/// Where to send the response to a query from `from`: queries from the mDNS
+/// port are answered to the group, others (one-shot queries) directly
+pub fn reply_addr(from: SocketAddr) -> SocketAddr {
+    match from.port() {
+        PORT => (GROUP, PORT).into(),
+        _ => from,
+    }
+}
+
+/// Query for the service and collect the instances answering within `wait`,
+/// once each
+pub async fn browse(wait: Duration) -> Result<Vec<Instance>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    let mut query = Vec::new();
+    push_header(&mut query, 0, 0, [1, 0, 0, 0]);
+    push_name(&mut query, &SERVICE);
+    query.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    query.extend_from_slice(&(CLASS_IN | CLASS_FLAG).to_be_bytes());
+    socket
+        .send_to(&query, (GROUP, PORT))
+        .await
+        .context("failed to send the mDNS query")?;
+
+    let deadline = Instant::now() + wait;
+    let mut instances = Vec::new();
+    let mut buf = [0; 9000];
+    while let Ok(received) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (len, from) = received?;
+        let Ok(message) = Message::parse(&buf[..len]) else {
+            continue;
+        };
+        for instance in message.instances(from.ip()) {
+            if !instances.contains(&instance) {
+                instances.push(instance);
+            }
+        }
+    }
+    Ok(instances)
+}
+
+/// Local IPv4 addresses to answer with, loopback ones only if there are no
+/// others
+#[cfg(unix)]
+fn local_addrs() -> Vec<Ipv4Addr> {
+    let mut addrs: Vec<_> = nix::ifaddrs::getifaddrs()
+        .into_iter()
+        .flatten()
+        .filter_map(|ifaddr| Some(ifaddr.address?.as_sockaddr_in()?.ip()))
+        .collect();
+    addrs.dedup();
+    if addrs.iter().any(|addr| !addr.is_loopback()) {
+        addrs.retain(|addr| !addr.is_loopback());
+    }
+    addrs
+}
+
+/// Browsers fall back to the address the response comes from
+#[cfg(not(unix))]
+fn local_addrs() -> Vec<Ipv4Addr> {
+    Vec::new()
+}
+
+fn names_equal(name: &[String], labels: &[&str]) -> bool {
+    name.len() == labels.len()
+        && name
+            .iter()
+            .zip(labels)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn push_header(buf: &mut Vec<u8>, id: u16, flags: u16, counts: [u16; 4]) {
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(&flags.to_be_bytes());
+    for count in counts {
+        buf.extend_from_slice(&count.to_be_bytes());
+    }
+}
+
+/// Name of `labels`, uncompressed
+fn push_name(buf: &mut Vec<u8>, labels: &[&str]) {
+    for label in labels {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label);
+    }
+    buf.push(0);
+}
+
+/// Resource record with the data written by `data`
+fn push_record(
+    buf: &mut Vec<u8>,
+    name: &[&str],
+    rtype: u16,
+    class: u16,
+    data: impl FnOnce(&mut Vec<u8>),
+) {
+    push_name(buf, name);
+    buf.extend_from_slice(&rtype.to_be_bytes());
+    buf.extend_from_slice(&class.to_be_bytes());
+    buf.extend_from_slice(&TTL.to_be_bytes());
+    let mut rdata = Vec::new();
+    data(&mut rdata);
+    buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&rdata);
+}
+
+/// Data of the record types browsing looks at
+enum Data {
+    Ptr(Vec<String>),
+    Srv { port: u16, target: Vec<String> },
+    Txt(Vec<String>),
+    A(Ipv4Addr),
+    Other,
+}
+
+struct Record {
+    name: Vec<String>,
+    data: Data,
+}
+
+/// Decoded DNS message
+struct Message {
+    id: u16,
+    flags: u16,
+    questions: Vec<(Vec<String>, u16)>,
+    /// Answers, authority and additional records
+    records: Vec<Record>,
+}
+
+impl Message {
+    fn parse(buf: &[u8]) -> Result<Self> {
+        ensure!(buf.len() >= 12, "DNS message too short");
+        let word = |pos: usize| u16::from_be_bytes([buf[pos], buf[pos + 1]]);
+        let (id, flags) = (word(0), word(2));
+        let questions_count = word(4);
+        let records_count = word(6) as usize + word(8) as usize + word(10) as usize;
+        let mut pos = 12;
+        let mut questions = Vec::new();
+        for _ in 0..questions_count {
+            let name = read_name(buf, &mut pos)?;
+            let fields = buf.get(pos..pos + 4).context("truncated question")?;
+            questions.push((name, u16::from_be_bytes([fields[0], fields[1]])));
+            pos += 4;
+        }
+        let mut records = Vec::new();
+        for _ in 0..records_count {
+            let name = read_name(buf, &mut pos)?;
+            let fields = buf.get(pos..pos + 10).context("truncated record")?;
+            let rtype = u16::from_be_bytes([fields[0], fields[1]]);
+            let len = u16::from_be_bytes([fields[8], fields[9]]) as usize;
+            pos += 10;
+            let start = pos;
+            let rdata = buf.get(start..start + len).context("truncated record")?;
+            let data = match rtype {
+                TYPE_PTR => Data::Ptr(read_name(buf, &mut pos)?),
+                TYPE_SRV if len >= 6 => {
+                    pos += 6;
+                    Data::Srv {
+                        port: u16::from_be_bytes([rdata[4], rdata[5]]),
+                        target: read_name(buf, &mut pos)?,
+                    }
+                }
+                TYPE_TXT => {
+                    let mut strings = Vec::new();
+                    let mut rest = rdata;
+                    while let Some((&n, tail)) = rest.split_first() {
+                        let n = (n as usize).min(tail.len());
+                        strings.push(String::from_utf8_lossy(&tail[..n]).into_owned());
+                        rest = &tail[n..];
+                    }
+                    Data::Txt(strings)
+                }
+                TYPE_A if len == 4 => {
+                    Data::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+                }
+                _ => Data::Other,
+            };
+            pos = start + len;
+            records.push(Record { name, data });
+        }
+        Ok(Self {
+            id,
+            flags,
+            questions,
+            records,
+        })
+    }
+
+    /// Data of the records of `name`
+    fn data_of<'a>(&'a self, name: &'a [String]) -> impl Iterator<Item = &'a Data> {
+        self.records
+            .iter()
+            .filter(move |r| {
+                r.name.len() == name.len()
+                    && r.name
+                        .iter()
+                        .zip(name)
+                        .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            })
+            .map(|r| &r.data)
+    }
+
+    /// Instances of the service in a response from `source`
+    fn instances(&self, source: IpAddr) -> Vec<Instance> {
+        if self.flags & FLAG_RESPONSE == 0 {
+            return Vec::new();
+        }
+        let mut instances = Vec::new();
+        for record in &self.records {
+            let Data::Ptr(instance) = &record.data else {
+                continue;
+            };
+            if !names_equal(&record.name, &SERVICE) || instance.is_empty() {
+                continue;
+            }
+            let Some((port, target)) = self.data_of(instance).find_map(|data| match data {
+                Data::Srv { port, target } => Some((*port, target)),
+                _ => None,
+            }) else {
+                continue;
+            };
+            let ip = self
+                .data_of(target)
+                .find_map(|data| match data {
+                    Data::A(ip) => Some(IpAddr::V4(*ip)),
+                    _ => None,
+                })
+                .unwrap_or(source);
+            let version = self
+                .data_of(instance)
+                .filter_map(|data| match data {
+                    Data::Txt(strings) => Some(strings),
+                    _ => None,
+                })
+                .flatten()
+                .find_map(|s| s.strip_prefix("version=").map(str::to_owned));
+            instances.push(Instance {
+                name: instance[0].clone(),
+                addr: SocketAddr::new(ip, port),
+                version,
+            });
+        }
+        instances
+    }
+}
+
+/// Name at `pos`, following compression pointers, and `pos` moved past it
+fn read_name(buf: &[u8], pos: &mut usize) -> Result<Vec<String>> {
+    let mut labels = Vec::new();
+    let mut at = *pos;
+    let mut jumped = false;
+    // Pointers only go backwards in well-formed messages; this bounds loops
+    for _ in 0..128 {
+        let &len = buf.get(at).context("truncated name")?;
+        match len {
+            0 => {
+                if !jumped {
+                    *pos = at + 1;
+                }
+                return Ok(labels);
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let &low = buf.get(at + 1).context("truncated name")?;
+                if !jumped {
+                    *pos = at + 2;
+                    jumped = true;
+                }
+                at = ((len as usize & 0x3f) << 8) | low as usize;
+            }
+            len if len < 64 => {
+                let label = buf
+                    .get(at + 1..at + 1 + len as usize)
+                    .context("truncated name")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                at += 1 + len as usize;
+            }
+            _ => bail!("invalid label length {}", len),
+        }
+    }
+    bail!("name compression loop")
+}
@@ -0,0 +1,191 @@
+use crate::{
+    clock::{nsec_to_sec, Timestamp},
+    socket::Ecn,
+    timestamping::TimestampSource,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::{net::SocketAddr, str::FromStr};
+
+/// Statistics of the burst a measurement was selected from
+#[derive(Clone, Copy, Debug)]
+pub struct BurstStats {
+    /// Probes sent in the burst
+    pub sent: usize,
+    /// Replies received to them
+    pub received: usize,
+    pub rtt_median: f64,
+    pub rtt_max: f64,
+}
+
+/// Known difference between the forward and return one-way delays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Asymmetry {
+    /// Forward minus return delay, in seconds
+    Seconds(f64),
+    /// Share of the round-trip delay spent on the forward path, 0.5 being symmetric
+    Fraction(f64),
+}
+
+impl Asymmetry {
+    /// Offset correction for a measurement with round-trip delay `delay`
+    fn correction(&self, delay: f64) -> f64 {
+        match *self {
+            Asymmetry::Seconds(seconds) => seconds / 2.0,
+            Asymmetry::Fraction(fraction) => (fraction - 0.5) * delay,
+        }
+    }
+}
+
+impl FromStr for Asymmetry {
+    type Err = anyhow::Error;
+
+    /// A signed delay difference with a unit (`ns`, `us`, `ms` or `s`), like
+    /// `-1.5ms`, or a bare forward delay fraction in `[0, 1]`, like `0.7`
+    fn from_str(s: &str) -> Result<Self> {
+        let Some(i) = s.find(|c: char| c.is_ascii_alphabetic()) else {
+            let fraction: f64 = s
+                .parse()
+                .with_context(|| format!("invalid asymmetry {}", s))?;
+            if !(0.0..=1.0).contains(&fraction) {
+                bail!("asymmetry fraction must be in [0, 1], got {}", fraction);
+            }
+            return Ok(Asymmetry::Fraction(fraction));
+        };
+        let (number, unit) = s.split_at(i);
+        let multiplier = match unit {
+            "ns" => 1e-9,
+            "us" => 1e-6,
+            "ms" => 1e-3,
+            "s" => 1.0,
+            _ => return Err(anyhow!("unknown unit '{}' in asymmetry {}", unit, s)),
+        };
+        let value: f64 = number
+            .parse()
+            .with_context(|| format!("invalid asymmetry {}", s))?;
+        Ok(Asymmetry::Seconds(value * multiplier))
+    }
+}
+
+/// Probe left unanswered for longer than the reply timeout
+#[derive(Clone, Copy, Debug)]
+pub struct LostProbe {
+    pub seq: u64,
+    /// Local time the probe was sent
+    pub t1: Timestamp,
+    /// Probes lost so far in this run, including this one
+    pub lost: u64,
+    /// How long after its scheduled slot the probe was sent (seconds)
+    pub send_delay: f64,
+}
+
+/// Single offset sample obtained from one probe/reply exchange
+///
+/// Uses the NTP four-timestamp naming. Offsets are in seconds and are local
+/// clock minus remote clock, so the reflector processing time between `t2`
+/// and `t3` does not widen the offset bounds.
+#[derive(Clone, Copy, Debug)]
+pub struct Measurement {
+    /// Sequence number of the probe
+    pub seq: u64,
+    /// Probes lost so far in this run
+    pub lost: u64,
+    /// Local time the probe was sent
+    pub t1: Timestamp,
+    /// How long after its scheduled slot the probe was sent (seconds), which
+    /// tells local scheduling delays from network ones
+    pub send_delay: f64,
+    /// Remote (reference) time the probe was received
+    pub t2: Timestamp,
+    /// Remote (reference) time the reply was sent
+    pub t3: Timestamp,
+    /// Local time the reply was received
+    pub t4: Timestamp,
+    pub offset_min: f64,
+    pub offset_max: f64,
+    pub offset: f64,
+    /// Round-trip delay excluding the reflector processing time
+    pub delay: f64,
+    /// Round-trip time `t4 - t1`, including the reflector processing time
+    pub rtt: f64,
+    /// Naive one-way delay to the reflector `t2 - t1`, skewed by the clock offset
+    pub forward_delay: f64,
+    /// Naive one-way delay back from the reflector `t4 - t3`, skewed by the clock offset
+    pub return_delay: f64,
+    /// How `t1` was obtained
+    pub t1_source: TimestampSource,
+    /// How `t4` was obtained
+    pub t4_source: TimestampSource,
+    /// Set if this is the lowest-RTT sample of a burst
+    pub burst: Option<BurstStats>,
+    /// Shift applied to the midpoint `offset` for a known path asymmetry
+    pub asymmetry_correction: f64,
+    /// Address the probe was sent to, if it went over the network
+    pub remote: Option<SocketAddr>,
+    /// TTL or hop limit the reply arrived with, if reported
+    pub ttl: Option<u8>,
+    /// ECN code point the reply arrived with, if reported
+    pub ecn: Option<Ecn>,
+}
+
+impl Measurement {
+    pub fn new(seq: u64, t1: Timestamp, t2: Timestamp, t3: Timestamp, t4: Timestamp) -> Self {
+        let t1_nsec = t1.total_nsec();
+        let t2_nsec = t2.total_nsec();
+        let t3_nsec = t3.total_nsec();
+        let t4_nsec = t4.total_nsec();
+
+        Self {
+            seq,
+            lost: 0,
+            t1,
+            send_delay: 0.0,
+            t2,
+            t3,
+            t4,
+            offset_min: nsec_to_sec(t1_nsec - t2_nsec),
+            offset_max: nsec_to_sec(t4_nsec - t3_nsec),
+            offset: nsec_to_sec(((t1_nsec - t2_nsec) + (t4_nsec - t3_nsec)) / 2),
+            delay: nsec_to_sec((t4_nsec - t1_nsec) - (t3_nsec - t2_nsec)),
+            rtt: nsec_to_sec(t4_nsec - t1_nsec),
+            forward_delay: nsec_to_sec(t2_nsec - t1_nsec),
+            return_delay: nsec_to_sec(t4_nsec - t3_nsec),
+            t1_source: TimestampSource::Userspace,
+            t4_source: TimestampSource::Userspace,
+            burst: None,
+            asymmetry_correction: 0.0,
+            remote: None,
+            ttl: None,
+            ecn: None,
+        }
+    }
+
+    /// Narrow the offset bounds by the local timestamping `overhead`, half of
+    /// it being the send path after a userspace `t1` and half the receive
+    /// path before a userspace `t4`
+    pub fn remove_overhead(&mut self, overhead: f64) {
+        let half = (overhead / 2.0).min(self.delay / 2.0).max(0.0);
+        if self.t1_source == TimestampSource::Userspace {
+            self.offset_min += half;
+            self.delay -= half;
+        }
+        if self.t4_source == TimestampSource::Userspace {
+            self.offset_max -= half;
+            self.delay -= half;
+        }
+        self.shift_offset(0.0);
+    }
+
+    /// Correct `offset` for a known path asymmetry
+    pub fn correct_asymmetry(&mut self, asymmetry: Asymmetry) {
+        self.shift_offset(asymmetry.correction(self.delay));
+    }
+
+    /// Move `offset` from the midpoint by `correction` seconds, staying within the bounds
+    pub fn shift_offset(&mut self, correction: f64) {
+        let midpoint = (self.offset_min + self.offset_max) / 2.0;
+        self.asymmetry_correction = correction;
+        self.offset = (midpoint + correction)
+            .max(self.offset_min)
+            .min(self.offset_max);
+    }
+}
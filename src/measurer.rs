@@ -0,0 +1,1168 @@
+use crate::{
+    auth::Key,
+    clock::{Clock, Timestamp},
+    icmp,
+    measurement::{Asymmetry, BurstStats, LostProbe, Measurement},
+    ntp, outlier,
+    poll::PollAdapter,
+    protocol::{self, legacy, ClientId, Cookie, Probe, Reply},
+    quic,
+    random::{self, Rng},
+    roughtime,
+    sequence::{PendingProbe, SequenceTracker},
+    socket::{self, Ecn, Received, SocketOptions},
+    target::{Family, Target},
+    timestamping::{self, Timestamping},
+    transport::{FramedStream, Transport},
+    uring::{IoBackend, Receiver},
+};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::watch,
+    time::{self, sleep_until, Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// What to do when sending falls behind the probe schedule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissedTicks {
+    /// Send the missed probes right away until caught up
+    Burst,
+    /// Restart the schedule one interval after the late probe
+    Delay,
+    /// Drop the missed probes and keep the original schedule
+    Skip,
+}
+
+impl FromStr for MissedTicks {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "burst" => Ok(MissedTicks::Burst),
+            "delay" => Ok(MissedTicks::Delay),
+            "skip" => Ok(MissedTicks::Skip),
+            _ => bail!("unknown missed tick behavior '{}'", s),
+        }
+    }
+}
+
+/// What the measured server speaks
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Probes of this tool, answered by its reflector
+    #[default]
+    Native,
+    /// SNTP client requests to NTP servers, see [`crate::ntp`]
+    Ntp,
+    /// Roughtime requests, with the responses verified against the long-term
+    /// key of the server, see [`crate::roughtime`]
+    Roughtime(roughtime::PublicKey),
+}
+
+impl Protocol {
+    /// Name of the protocol in messages
+    fn name(&self) -> &'static str {
+        match self {
+            Protocol::Native => "probe",
+            Protocol::Ntp => "NTP",
+            Protocol::Roughtime(_) => "Roughtime",
+        }
+    }
+}
+
+/// Measurer settings
+#[derive(Clone, Debug)]
+pub struct MeasurerConfig {
+    /// Probe sending interval, the shortest one with `interval_max`
+    pub interval: Duration,
+    /// Adapt the interval between `interval` and this one to the offset stability
+    pub interval_max: Option<Duration>,
+    /// Randomize each send time within this fraction of the interval either way,
+    /// so probes do not phase-lock with periodic traffic
+    pub jitter: f64,
+    /// How to catch up when probes could not be sent on schedule
+    pub missed_ticks: MissedTicks,
+    /// Address family to use when the target is a hostname
+    pub family: Family,
+    /// Local address to send from, chosen by the system if `None`
+    pub source: Option<SocketAddr>,
+    /// Send probes as datagrams, over a TCP, WebSocket or Unix domain socket
+    /// connection or as QUIC datagrams
+    pub transport: Transport,
+    /// PEM certificates to verify the reflector with over QUIC
+    pub tls_ca: Option<PathBuf>,
+    /// HTTP proxy to open WebSocket connections through with `CONNECT`
+    pub proxy: Option<SocketAddr>,
+    /// How often to re-resolve the target hostname, `None` to resolve only once
+    pub resolve_interval: Option<Duration>,
+    /// Speak the original headerless 16/32-byte format
+    pub legacy: bool,
+    /// Query an NTP or Roughtime server instead of probing a reflector
+    pub protocol: Protocol,
+    /// Local clock `t1` and `t4` are read from
+    pub clock: Clock,
+    /// Source of the reply receive time `t4` (and of `t1` with hardware timestamping)
+    pub timestamping: Timestamping,
+    /// Receive replies on the tokio reactor or on an io_uring thread; probes
+    /// are always sent right after `t1` is read, without waiting for readiness
+    pub io_backend: IoBackend,
+    /// Marking of the probes
+    pub socket: SocketOptions,
+    /// Take `t1` from kernel software transmit timestamps, so it does not include
+    /// the syscall and qdisc latency; falls back to userspace when unsupported
+    pub tx_timestamps: bool,
+    /// Authenticate probes and require authenticated replies
+    pub key: Option<Key>,
+    /// Probes sent back-to-back each interval; only the lowest-RTT reply of
+    /// a burst is reported
+    pub burst: usize,
+    /// Stop after sending this many probes, the warm-up ones included
+    pub count: Option<u64>,
+    /// Probes sent first to prime the path (ARP/ND, route caches, CPU
+    /// frequency), whose replies and losses are not reported
+    pub warmup: u64,
+    /// Stop sending probes this long after connecting
+    pub duration: Option<Duration>,
+    /// Report probes unanswered for this long as lost
+    pub timeout: Option<Duration>,
+    /// While the target does not answer, double the interval with every
+    /// probe up to this one; `None` to keep probing at the full rate
+    pub backoff_max: Option<Duration>,
+    /// Correct offsets for this known path asymmetry
+    pub asymmetry: Option<Asymmetry>,
+    /// Local send and receive overhead included in userspace timestamps
+    /// (seconds), taken off the offset bounds
+    pub overhead: Option<f64>,
+    /// Pad probes to this many bytes of UDP payload, to see the serialization
+    /// delay of real packets
+    pub size: Option<usize>,
+    /// Ask the reflector to pad replies to the size of the probes
+    pub pad_replies: bool,
+    /// Put a random nonce into every probe and drop replies not echoing it;
+    /// reflectors of older versions reject such probes
+    pub nonce: bool,
+    /// Name the client in every probe, for the access log of the reflector;
+    /// reflectors of older versions reject such probes
+    pub client_id: Option<ClientId>,
+    /// Report the TTL (hop limit) and ECN code point every reply arrives
+    /// with (Linux, over UDP)
+    pub ttl_ecn: bool,
+}
+
+impl Default for MeasurerConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            interval_max: None,
+            jitter: 0.0,
+            missed_ticks: MissedTicks::Skip,
+            family: Family::Any,
+            source: None,
+            transport: Transport::Udp,
+            tls_ca: None,
+            proxy: None,
+            resolve_interval: Some(Duration::from_secs(300)),
+            legacy: false,
+            protocol: Protocol::Native,
+            clock: Clock::Realtime,
+            timestamping: Timestamping::Userspace,
+            io_backend: IoBackend::Tokio,
+            socket: SocketOptions::default(),
+            tx_timestamps: false,
+            key: None,
+            burst: 1,
+            count: None,
+            warmup: 0,
+            duration: None,
+            timeout: None,
+            backoff_max: Some(Duration::from_secs(64)),
+            asymmetry: None,
+            overhead: None,
+            size: None,
+            pad_replies: false,
+            nonce: true,
+            client_id: None,
+            ttl_ecn: false,
+        }
+    }
+}
+
+impl MeasurerConfig {
+    /// Whether probes are paced by busy-waiting, for intervals tokio's
+    /// millisecond timer can not keep
+    pub fn high_rate(&self) -> bool {
+        self.interval < HIGH_RATE_INTERVAL
+    }
+}
+
+/// Result of probing
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    Measurement(Measurement),
+    /// Only reported with a reply timeout configured
+    Lost(LostProbe),
+}
+
+/// How long to wait for outstanding replies after the last probe, unless the
+/// reply timeout is longer
+const LINGER: Duration = Duration::from_secs(1);
+
+/// Probes in a row without replies after which the target counts as
+/// unreachable and the interval backs off
+const UNREACHABLE_PROBES: u64 = 8;
+
+/// Shortest interval the tokio timer paces accurately enough
+const HIGH_RATE_INTERVAL: Duration = Duration::from_millis(5);
+/// How long before a high-rate send the timer wakes up, to busy-wait the rest
+///
+/// Covers the millisecond granularity of the timer with some scheduling latency.
+const SPIN_MARGIN: Duration = Duration::from_micros(1500);
+
+/// Settings changed while measuring, see [`Measurer::control`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Control {
+    /// Stop sending probes, still receiving the replies to the sent ones
+    pub paused: bool,
+    /// Probe sending interval, the shortest one with `interval_max`
+    pub interval: Duration,
+}
+
+/// Sends timestamped probes to a reflector and turns the replies into measurements
+///
+/// Probes are only sent while [`Measurer::next_measurement`] is being awaited.
+pub struct Measurer {
+    link: Link,
+    target: Target,
+    remote: SocketAddr,
+    config: MeasurerConfig,
+    /// Scheduled time of the next probe, before jitter is applied
+    tick: Instant,
+    /// Jittered send time of the next probe
+    next_send: Instant,
+    /// Send right away, off schedule, after a challenge
+    resend: bool,
+    next_resolve: Option<Instant>,
+    sequence: SequenceTracker,
+    /// Whether transmit timestamps are delivered on the socket error queue
+    tx_timestamps: bool,
+    /// Sequence number of the first probe sent on the current socket, to map
+    /// error queue transmit timestamp keys back to probes
+    tx_key_base: u64,
+    /// Cookie of the last challenge, echoed in probes to prove reachability
+    cookie: Option<Cookie>,
+    /// End of the `duration` limit
+    deadline: Option<Instant>,
+    /// Set once the probe limits are reached: when to give up on outstanding replies
+    finish_at: Option<Instant>,
+    /// Replies to the current burst
+    burst: Vec<Measurement>,
+    /// Sequence number of the first probe of the current burst
+    burst_start: u64,
+    /// Probes sent in the current burst
+    burst_sent: usize,
+    poll: Option<PollAdapter>,
+    /// Probes, or bursts, sent since the last reply
+    unanswered: u64,
+    /// Interval backed off to while the target does not answer
+    backoff: Option<Duration>,
+    rng: Rng,
+    /// Reply deadlines of the sent probes, oldest first
+    timeouts: VecDeque<(u64, Instant)>,
+    /// Masks the sequence numbers carried in NTP requests, so that spoofed
+    /// responses have to guess it; zero for the other protocols
+    ntp_mask: u64,
+    /// Nonces of the Roughtime requests
+    roughtime: Option<roughtime::Client>,
+    control: Option<watch::Receiver<Control>>,
+    paused: bool,
+    /// Probes lost up to the last warm-up event, left out of the loss counts
+    warmup_lost: u64,
+    buf: [u8; 2048],
+}
+
+/// Wait until `deadline`, busy-waiting the last stretch if `precise`
+///
+/// The busy wait yields to the runtime, so that replies are still received
+/// in the meantime, at the cost of keeping a core busy.
+async fn pace(deadline: Instant, precise: bool) {
+    if !precise {
+        return sleep_until(deadline).await;
+    }
+    if let Some(wake) = deadline.checked_sub(SPIN_MARGIN) {
+        sleep_until(wake).await;
+    }
+    while Instant::now() < deadline {
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Next settings sent on `control`, never if there are none
+async fn changed(control: &mut Option<watch::Receiver<Control>>) -> Control {
+    if let Some(control) = control {
+        if control.changed().await.is_ok() {
+            return *control.borrow_and_update();
+        }
+    }
+    std::future::pending().await
+}
+
+/// Connection to the reflector
+enum Link {
+    Udp {
+        socket: UdpSocket,
+        /// Thread receiving on `socket` with the io_uring backend
+        receiver: Option<Receiver>,
+    },
+    /// TCP, WebSocket or Unix domain socket connection, `None` once lost, until the next probe reconnects
+    Tcp(Option<FramedStream>),
+    Quic {
+        /// Kept across reconnects, to resume the session with 0-RTT
+        endpoint: quic::Endpoint,
+        /// `None` once the connection is lost, until the next probe reconnects
+        connection: Option<quic::Connection>,
+    },
+}
+
+impl Link {
+    /// Connect to `remote` of `target`, also telling if transmit timestamps are enabled
+    async fn connect(
+        remote: SocketAddr,
+        target: &Target,
+        config: &MeasurerConfig,
+    ) -> Result<(Self, bool)> {
+        match config.transport {
+            Transport::Udp => {
+                let (socket, tx_timestamps) = Self::connect_socket(remote, config).await?;
+                let receiver = match config.io_backend {
+                    IoBackend::Tokio => None,
+                    IoBackend::Uring => Some(Receiver::spawn(&socket, config.clock.clone())?),
+                };
+                Ok((Link::Udp { socket, receiver }, tx_timestamps))
+            }
+            Transport::Tcp | Transport::WebSocket | Transport::Unix(_) => {
+                let stream = Self::connect_stream(remote, target, config).await?;
+                Ok((Link::Tcp(Some(stream)), false))
+            }
+            Transport::Quic => {
+                let ca = config.tls_ca.as_deref().ok_or_else(|| {
+                    anyhow!("QUIC needs certificates to verify the reflector with")
+                })?;
+                let local = config
+                    .source
+                    .unwrap_or_else(|| socket::unspecified_for(&remote));
+                let endpoint = quic::Endpoint::client(local, ca, &config.socket)?;
+                let connection = endpoint.connect(remote, &target.host).await?;
+                Ok((
+                    Link::Quic {
+                        endpoint,
+                        connection: Some(connection),
+                    },
+                    false,
+                ))
+            }
+        }
+    }
+
+    async fn connect_stream(
+        remote: SocketAddr,
+        target: &Target,
+        config: &MeasurerConfig,
+    ) -> Result<FramedStream> {
+        match &config.transport {
+            Transport::Unix(path) => FramedStream::connect_unix(path).await,
+            Transport::WebSocket => {
+                FramedStream::connect_websocket(
+                    target,
+                    remote,
+                    config.proxy,
+                    config.source,
+                    &config.socket,
+                )
+                .await
+            }
+            _ => FramedStream::connect(remote, config.source, &config.socket).await,
+        }
+    }
+
+    /// Create a socket connected to `remote`, also telling if transmit timestamps are enabled
+    async fn connect_socket(
+        remote: SocketAddr,
+        config: &MeasurerConfig,
+    ) -> Result<(UdpSocket, bool)> {
+        if !config.timestamping.is_userspace() || config.tx_timestamps {
+            config
+                .clock
+                .check_kernel_timestamps(config.timestamping.is_hardware())?;
+        }
+        let local = match config.source {
+            Some(source) => {
+                ensure!(
+                    source.is_ipv4() == remote.is_ipv4(),
+                    "source address {} can not reach {}",
+                    source,
+                    remote
+                );
+                source
+            }
+            None => socket::unspecified_for(&remote),
+        };
+        // A fixed port is shared by the sockets of all targets, and by the old
+        // and new socket while re-resolving
+        let socket = socket::bind_udp(local, cfg!(unix) && local.port() != 0)?;
+        config.socket.apply(&socket)?;
+        icmp::enable(&socket)?;
+        timestamping::enable(&socket, &config.timestamping, true)?;
+        if config.ttl_ecn {
+            socket::enable_ttl_tos(&socket)?;
+        }
+
+        let tx_timestamps = if config.timestamping.is_hardware() {
+            true
+        } else if config.tx_timestamps {
+            match timestamping::enable_tx_software(&socket) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!(
+                        "Transmit timestamps unavailable, using userspace t1: {:#}",
+                        e
+                    );
+                    false
+                }
+            }
+        } else {
+            false
+        };
+
+        socket.connect(remote).await?;
+        Ok((socket, tx_timestamps))
+    }
+
+    /// Next packet from the reflector
+    async fn recv(&mut self, buf: &mut [u8], config: &MeasurerConfig) -> Result<Received> {
+        match self {
+            Link::Udp {
+                receiver: Some(receiver),
+                ..
+            } => receiver.recv(buf).await,
+            Link::Udp { socket, .. } => {
+                socket::recv(
+                    socket,
+                    buf,
+                    !config.timestamping.is_userspace() || config.ttl_ecn,
+                    &config.clock,
+                )
+                .await
+            }
+            Link::Tcp(Some(stream)) => stream
+                .recv(buf, &config.clock)
+                .await?
+                .ok_or_else(|| anyhow!("connection closed by {}", stream.peer_addr())),
+            Link::Quic {
+                connection: Some(connection),
+                ..
+            } => connection
+                .recv(buf, &config.clock)
+                .await?
+                .ok_or_else(|| anyhow!("connection closed by {}", connection.remote_address())),
+            Link::Tcp(None)
+            | Link::Quic {
+                connection: None, ..
+            } => std::future::pending().await,
+        }
+    }
+
+    /// Drop a lost connection for the next probe to reconnect; `false` for UDP,
+    /// which has none
+    fn disconnect(&mut self) -> bool {
+        match self {
+            Link::Udp { .. } => false,
+            Link::Tcp(stream) => {
+                *stream = None;
+                true
+            }
+            Link::Quic { connection, .. } => {
+                *connection = None;
+                true
+            }
+        }
+    }
+}
+
+impl Measurer {
+    pub async fn connect(target: Target, config: MeasurerConfig) -> Result<Self> {
+        if config.transport != Transport::Udp {
+            let transport = config.transport.name();
+            ensure!(
+                config.timestamping.is_userspace() && !config.tx_timestamps,
+                "kernel timestamps are not supported over {}",
+                transport
+            );
+            ensure!(
+                config.io_backend == IoBackend::Tokio,
+                "the io_uring backend is not supported over {}",
+                transport
+            );
+            ensure!(
+                !config.legacy,
+                "the legacy format is not supported over {}",
+                transport
+            );
+        }
+        if config.protocol != Protocol::Native {
+            let protocol = config.protocol.name();
+            ensure!(
+                config.transport == Transport::Udp,
+                "{} servers are only queried over UDP",
+                protocol
+            );
+            ensure!(
+                config.key.is_none() && !config.legacy,
+                "{} requests can not be authenticated or sent in the legacy format",
+                protocol
+            );
+            ensure!(
+                config.size.is_none(),
+                "{} requests can not be padded",
+                protocol
+            );
+            // NTP and Roughtime timestamps are UTC
+            ensure!(
+                config.clock == Clock::Realtime,
+                "{} servers can only be compared to the realtime clock",
+                protocol
+            );
+        }
+        let (remote, resolve_interval) = match config.transport {
+            // Only loopback is ever reached, whatever the target is called
+            Transport::Unix(_) => (SocketAddr::from((Ipv4Addr::LOCALHOST, target.port)), None),
+            _ => (
+                target.resolve(config.family).await?,
+                config.resolve_interval,
+            ),
+        };
+        let (link, tx_timestamps) = Link::connect(remote, &target, &config).await?;
+        let next_resolve = resolve_interval.map(|period| Instant::now() + period);
+
+        Ok(Self {
+            link,
+            target,
+            remote,
+            tick: Instant::now(),
+            next_send: Instant::now(),
+            resend: false,
+            next_resolve,
+            sequence: SequenceTracker::new(),
+            tx_timestamps,
+            tx_key_base: 0,
+            cookie: None,
+            deadline: config.duration.map(|duration| Instant::now() + duration),
+            finish_at: None,
+            burst: Vec::new(),
+            burst_start: 0,
+            burst_sent: 0,
+            poll: config
+                .interval_max
+                .map(|max| PollAdapter::new(config.interval, max)),
+            unanswered: 0,
+            backoff: None,
+            rng: Rng::new()?,
+            timeouts: VecDeque::new(),
+            ntp_mask: if config.protocol == Protocol::Ntp {
+                Rng::new()?.next_u64()
+            } else {
+                0
+            },
+            roughtime: match &config.protocol {
+                Protocol::Roughtime(key) => Some(roughtime::Client::new(*key)),
+                _ => None,
+            },
+            control: None,
+            paused: false,
+            warmup_lost: 0,
+            buf: [0; 2048],
+            config,
+        })
+    }
+
+    pub fn target(&self) -> &Target {
+        &self.target
+    }
+
+    /// Currently used address of the target
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn config(&self) -> &MeasurerConfig {
+        &self.config
+    }
+
+    /// Current probe interval, which varies with `interval_max` and backs
+    /// off while the target does not answer
+    pub fn interval(&self) -> Duration {
+        let interval = self
+            .poll
+            .as_ref()
+            .map_or(self.config.interval, PollAdapter::interval);
+        self.backoff
+            .map_or(interval, |backoff| backoff.max(interval))
+    }
+
+    /// Follow the settings sent on `control` from now on
+    pub fn control(&mut self, control: watch::Receiver<Control>) {
+        self.control = Some(control);
+    }
+
+    /// Sent/lost/duplicated/reordered probe counters
+    pub fn sequence(&self) -> &SequenceTracker {
+        &self.sequence
+    }
+
+    /// Keep probing until the next valid reply arrives, or the next burst is complete
+    ///
+    /// A burst is complete when all its probes are answered or the next burst is due.
+    ///
+    /// Returns `None` once the `count` or `duration` limit is reached and the
+    /// replies to the last probes arrived or timed out.
+    pub async fn next_measurement(&mut self) -> Result<Option<Measurement>> {
+        loop {
+            match self.next_event().await? {
+                Some(Event::Measurement(m)) => return Ok(Some(m)),
+                Some(Event::Lost(_)) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Like [`Measurer::next_measurement`], but also reporting probes that timed out
+    pub async fn next_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            let mut event = self.receive_event().await?;
+            let (seq, lost) = match &mut event {
+                Some(Event::Measurement(m)) => (m.seq, &mut m.lost),
+                Some(Event::Lost(probe)) => (probe.seq, &mut probe.lost),
+                None => return Ok(None),
+            };
+            if seq < self.config.warmup {
+                self.warmup_lost = *lost;
+                continue;
+            }
+            *lost = lost.saturating_sub(self.warmup_lost);
+            if let (Some(poll), Some(Event::Measurement(m))) = (&mut self.poll, &event) {
+                poll.update(m);
+            }
+            return Ok(event);
+        }
+    }
+
+    async fn receive_event(&mut self) -> Result<Option<Event>> {
+        loop {
+            if self.finish_at.is_none() && self.sending_done() {
+                let linger = self
+                    .config
+                    .timeout
+                    .map_or(LINGER, |timeout| timeout.max(LINGER));
+                self.finish_at = Some(Instant::now() + linger);
+            }
+            if let Some(finish_at) = self.finish_at {
+                if self.sequence.pending() == 0 || Instant::now() >= finish_at {
+                    return Ok(self.finish_burst().map(Event::Measurement));
+                }
+            }
+            let sending = self.finish_at.is_none();
+
+            tokio::select! {
+                control = changed(&mut self.control) => self.apply_control(control),
+                _ = pace(if self.resend { Instant::now() } else { self.next_send }, self.config.high_rate()),
+                    if sending && !self.paused =>
+                {
+                    if self.sending_done() {
+                        continue;
+                    }
+                    self.maybe_reresolve().await?;
+                    let previous = self.finish_burst();
+                    self.send_burst().await?;
+                    if !std::mem::take(&mut self.resend) {
+                        self.back_off();
+                        self.schedule_next();
+                    }
+                    if previous.is_some() {
+                        return Ok(previous.map(Event::Measurement));
+                    }
+                }
+                _ = sleep_until(self.finish_at.unwrap_or(self.tick)), if !sending => {
+                    return Ok(self.finish_burst().map(Event::Measurement));
+                }
+                _ = sleep_until(self.timeouts.front().map_or(self.tick, |&(_, deadline)| deadline)),
+                    if !self.timeouts.is_empty() =>
+                {
+                    if let Some(lost) = self.time_out() {
+                        return Ok(Some(Event::Lost(lost)));
+                    }
+                }
+                received = self.link.recv(&mut self.buf, &self.config) => {
+                    let received = match received {
+                        Ok(received) => received,
+                        // Reconnected with the next probe
+                        Err(e) if self.link.disconnect() => {
+                            warn!("{:#}", e);
+                            continue;
+                        }
+                        // Probes ran into an ICMP error: log it rather than give up
+                        Err(e) if icmp::is_icmp_error(&e) => {
+                            if self.read_error_queue() == 0 {
+                                warn!("{}", e);
+                            }
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if self.tx_timestamps {
+                        self.read_error_queue();
+                    }
+                    match self.handle_challenge(received.len) {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(e) => {
+                            warn!("Invalid packet discarded: {}", e);
+                            continue;
+                        }
+                    }
+                    match self.match_reply(received.len) {
+                        Ok((reply, sent)) => {
+                            self.recover();
+                            let (t1, t1_source) = sent.send_time();
+                            let mut m = Measurement::new(
+                                reply.probe.seq,
+                                t1,
+                                reply.t2,
+                                reply.t3,
+                                received.timestamp,
+                            );
+                            m.lost = self.sequence.lost();
+                            m.send_delay = sent.send_delay;
+                            m.remote = Some(self.remote);
+                            m.t1_source = t1_source;
+                            m.t4_source = received.source;
+                            m.ttl = received.ttl;
+                            m.ecn = received.tos.map(Ecn::from_tos);
+                            if let Some(overhead) = self.config.overhead {
+                                m.remove_overhead(overhead);
+                            }
+                            if let Some(asymmetry) = self.config.asymmetry {
+                                m.correct_asymmetry(asymmetry);
+                            }
+                            if self.config.burst <= 1 {
+                                return Ok(Some(Event::Measurement(m)));
+                            }
+
+                            // Late replies to reported bursts are dropped
+                            if reply.probe.seq >= self.burst_start {
+                                self.burst.push(m);
+                                if self.burst.len() == self.burst_sent {
+                                    return Ok(self.finish_burst().map(Event::Measurement));
+                                }
+                            }
+                        }
+                        Err(e) => warn!("Invalid packet discarded: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Take over changed settings: a new interval applies from the last probe
+    /// sent, and sending resumes right away
+    fn apply_control(&mut self, control: Control) {
+        if control.interval != self.config.interval {
+            let previous = self.tick.checked_sub(self.interval()).unwrap_or(self.tick);
+            self.config.interval = control.interval;
+            if let Some(max) = self.config.interval_max {
+                self.poll = Some(PollAdapter::new(control.interval, max));
+            }
+            self.tick = (previous + self.interval()).max(Instant::now());
+            self.next_send = self.tick;
+        }
+        if self.paused && !control.paused {
+            self.tick = Instant::now();
+            self.next_send = self.tick;
+        }
+        self.paused = control.paused;
+    }
+
+    /// Advance the schedule by one interval from the last tick rather than from
+    /// now, so sending time does not accumulate, and pick the jittered send time
+    fn schedule_next(&mut self) {
+        let interval = self.interval();
+        let now = Instant::now();
+        let mut tick = self.tick + interval;
+        if tick <= now {
+            match self.config.missed_ticks {
+                MissedTicks::Burst => {}
+                MissedTicks::Delay => tick = now + interval,
+                MissedTicks::Skip => {
+                    let missed = (now - tick).as_nanos() / interval.as_nanos().max(1) + 1;
+                    tick += interval * missed as u32;
+                }
+            }
+        }
+        self.tick = tick;
+
+        let spread = interval.mul_f64(self.config.jitter * self.rng.next_f64() * 2.0);
+        let earliest = tick - interval.mul_f64(self.config.jitter);
+        self.next_send = earliest + spread;
+    }
+
+    /// Count an unanswered probe round, doubling the interval once the target
+    /// missed too many of them
+    fn back_off(&mut self) {
+        let Some(max) = self.config.backoff_max else {
+            return;
+        };
+        self.unanswered += 1;
+        // The reply to the probe just sent is still to come
+        if self.unanswered <= UNREACHABLE_PROBES {
+            return;
+        }
+        let interval = self.interval();
+        if self.backoff.is_none() {
+            warn!(
+                "No replies to the last {} probes, backing off from every {} to at most every {} seconds",
+                UNREACHABLE_PROBES,
+                interval.as_secs_f64(),
+                max.max(interval).as_secs_f64()
+            );
+        }
+        self.backoff = Some((interval * 2).min(max).max(interval));
+    }
+
+    /// Note a reply, returning to the normal interval if backed off
+    fn recover(&mut self) {
+        self.unanswered = 0;
+        if self.backoff.take().is_none() {
+            return;
+        }
+        let interval = self.interval();
+        info!(
+            "Replies are back, probing every {} seconds again",
+            interval.as_secs_f64()
+        );
+        self.tick = self.tick.min(Instant::now() + interval);
+        self.next_send = self.next_send.min(self.tick);
+    }
+
+    /// Give up on the oldest probe past its deadline if it is still unanswered
+    fn time_out(&mut self) -> Option<LostProbe> {
+        let (seq, _) = self.timeouts.pop_front()?;
+        let probe = self.sequence.time_out(seq)?;
+        Some(LostProbe {
+            seq,
+            t1: probe.t1,
+            lost: self.sequence.lost(),
+            send_delay: probe.send_delay,
+        })
+    }
+
+    /// Lowest-RTT reply of the current burst, starting a new one
+    fn finish_burst(&mut self) -> Option<Measurement> {
+        let burst = std::mem::take(&mut self.burst);
+        let sent = self.burst_sent;
+        self.burst_start = self.sequence.sent();
+        self.burst_sent = 0;
+
+        let mut best = *burst.iter().min_by(|a, b| a.rtt.total_cmp(&b.rtt))?;
+        let mut rtts: Vec<_> = burst.iter().map(|m| m.rtt).collect();
+        best.burst = Some(BurstStats {
+            sent,
+            received: burst.len(),
+            rtt_median: outlier::median(&mut rtts),
+            rtt_max: rtts[rtts.len() - 1],
+        });
+        Some(best)
+    }
+
+    async fn send_burst(&mut self) -> Result<()> {
+        let remaining = self
+            .config
+            .count
+            .map_or(u64::MAX, |count| count - self.sequence.sent());
+        let size = (self.config.burst.max(1) as u64).min(remaining) as usize;
+        for _ in 0..size {
+            self.send_probe().await?;
+            self.burst_sent += 1;
+        }
+        Ok(())
+    }
+
+    fn sending_done(&self) -> bool {
+        self.config
+            .count
+            .is_some_and(|count| self.sequence.sent() >= count)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Follow DNS changes of the target; resolution failures keep the old address
+    async fn maybe_reresolve(&mut self) -> Result<()> {
+        let (Some(next_resolve), Some(period)) = (self.next_resolve, self.config.resolve_interval)
+        else {
+            return Ok(());
+        };
+        if Instant::now() < next_resolve {
+            return Ok(());
+        }
+        self.next_resolve = Some(Instant::now() + period);
+
+        match self.target.resolve(self.config.family).await {
+            Ok(remote) if remote != self.remote => {
+                info!("{} now resolves to {}", self.target, remote);
+                match Link::connect(remote, &self.target, &self.config).await {
+                    Ok(link) => (self.link, self.tx_timestamps) = link,
+                    // Keeping the old connection, retried at the next re-resolution
+                    Err(e) if self.config.transport != Transport::Udp => {
+                        warn!("{:#}", e);
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                }
+                self.remote = remote;
+                self.tx_key_base = self.sequence.sent();
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Re-resolving {} failed: {:#}", self.target, e),
+        }
+
+        Ok(())
+    }
+
+    /// Take the cookie from a challenge and resend the challenged probe right away
+    ///
+    /// Returns whether the packet was a challenge.
+    fn handle_challenge(&mut self, len: usize) -> Result<bool> {
+        let packet = &self.buf[..len];
+        if self.config.legacy
+            || self.config.protocol != Protocol::Native
+            || !protocol::is_challenge(packet)
+        {
+            return Ok(false);
+        }
+        let packet = match &self.config.key {
+            Some(key) => key.verify(packet)?,
+            None => Cow::Borrowed(packet),
+        };
+        let challenge = protocol::decode_challenge(&packet)?;
+
+        if !self.sequence.forget(challenge.seq) {
+            bail!("challenge for unknown probe {}", challenge.seq);
+        }
+        self.cookie = Some(challenge.cookie);
+        self.resend = true;
+        Ok(true)
+    }
+
+    fn match_reply(&mut self, len: usize) -> Result<(Reply, PendingProbe)> {
+        let reply = self.decode_reply(&self.buf[..len])?;
+        let reordered = self.sequence.reordered();
+        let probe = &reply.probe;
+        let sent = self.sequence.on_reply(probe.seq, probe.t1, probe.nonce)?;
+        if self.sequence.reordered() > reordered {
+            info!("Reordered reply to probe {}", reply.probe.seq);
+        }
+        Ok((reply, sent))
+    }
+
+    /// Match queued transmit timestamps to their probes and log ICMP errors,
+    /// returning how many of them there were
+    fn read_error_queue(&mut self) -> usize {
+        let Link::Udp { socket, .. } = &self.link else {
+            return 0;
+        };
+        let queue = icmp::read_error_queue(socket);
+        for tx in queue.tx_timestamps {
+            let seq = self.tx_key_base + tx.key as u64;
+            self.sequence.on_tx_timestamp(seq, tx.timestamp, tx.source);
+        }
+        for error in &queue.icmp_errors {
+            warn!("{}", error);
+        }
+        queue.icmp_errors.len()
+    }
+
+    fn decode_reply(&self, packet: &[u8]) -> Result<Reply> {
+        if let Some(client) = &self.roughtime {
+            let response = client.verify(packet)?;
+            let t1 = self.sequence.send_time(response.seq).unwrap_or_default();
+            let radius = response.radius;
+            let midpoint = response.midpoint.total_nsec();
+            // Received and sent anywhere within the radius: t2 as late and t3
+            // as early as can be, so the offset bounds take the radius in
+            return Ok(Reply {
+                probe: Probe {
+                    seq: response.seq,
+                    t1,
+                    cookie: None,
+                    pad_reply: false,
+                    nonce: None,
+                    client_id: None,
+                },
+                t2: Timestamp::from_nsec(midpoint + radius),
+                t3: Timestamp::from_nsec(midpoint - radius),
+            });
+        }
+        if self.config.protocol == Protocol::Ntp {
+            let response = ntp::decode_response(packet)?;
+            let seq = response.origin ^ self.ntp_mask;
+            // Requests carry no send time, so the sequence number alone is
+            // checked; unknown ones are counted as duplicates by the tracker
+            let t1 = self.sequence.send_time(seq).unwrap_or_default();
+            return Ok(Reply {
+                probe: Probe {
+                    seq,
+                    t1,
+                    cookie: None,
+                    pad_reply: false,
+                    nonce: None,
+                    client_id: None,
+                },
+                t2: ntp::from_ntp(response.receive, t1),
+                t3: ntp::from_ntp(response.transmit, t1),
+            });
+        }
+        if let Some(key) = &self.config.key {
+            return protocol::decode_reply(&key.verify(packet)?);
+        }
+        if !self.config.legacy {
+            return protocol::decode_reply(packet);
+        }
+
+        // Legacy replies carry no sequence number and no transmit time
+        let (t1, t2) = legacy::decode_reply(packet)?;
+        let seq = self
+            .sequence
+            .find_by_send_time(t1)
+            .ok_or_else(|| anyhow!("reply to unknown probe sent at {}", t1))?;
+        Ok(Reply {
+            probe: Probe {
+                seq,
+                t1,
+                cookie: None,
+                pad_reply: false,
+                nonce: None,
+                client_id: None,
+            },
+            t2,
+            t3: t2,
+        })
+    }
+
+    /// Reconnect a lost stream or QUIC connection; failures are logged, losing the next probe
+    async fn reconnect(&mut self) {
+        let interval = self.interval();
+        let connected = match &mut self.link {
+            Link::Tcp(stream @ None) => {
+                let connect = Link::connect_stream(self.remote, &self.target, &self.config);
+                time::timeout(interval, connect)
+                    .await
+                    .map(|connected| connected.map(|connected| *stream = Some(connected)))
+            }
+            Link::Quic {
+                endpoint,
+                connection: connection @ None,
+            } => time::timeout(interval, endpoint.connect(self.remote, &self.target.host))
+                .await
+                .map(|connected| connected.map(|connected| *connection = Some(connected))),
+            _ => return,
+        };
+        match connected {
+            Ok(Ok(())) => info!("Reconnected"),
+            Ok(Err(e)) => warn!("{:#}", e),
+            Err(_) => warn!("Connecting to {} timed out", self.remote),
+        }
+    }
+
+    async fn send_probe(&mut self) -> Result<()> {
+        // Before t1 is read, so the handshake does not count into the RTT
+        self.reconnect().await;
+        // From the kernel, so that seeing a probe gives nothing away about the
+        // next ones; before t1 is read, as reading it takes a syscall
+        let native = self.config.protocol == Protocol::Native && !self.config.legacy;
+        let nonce = if self.config.nonce && native {
+            let mut nonce = [0; protocol::NONCE_SIZE];
+            random::fill(&mut nonce)?;
+            Some(u64::from_le_bytes(nonce))
+        } else {
+            None
+        };
+        let t1 = self.config.clock.now()?;
+        // The slot stays `next_send` until the burst is sent
+        let send_delay = Instant::now().saturating_duration_since(self.next_send);
+        let seq = self.sequence.on_send(t1, send_delay.as_secs_f64(), nonce);
+        if let Some(timeout) = self.config.timeout {
+            self.timeouts.push_back((seq, Instant::now() + timeout));
+        }
+        let packet = if let Some(client) = &mut self.roughtime {
+            client.request(seq)?
+        } else if self.config.protocol == Protocol::Ntp {
+            ntp::encode_request(seq ^ self.ntp_mask).to_vec()
+        } else if self.config.legacy {
+            legacy::encode_probe(t1).to_vec()
+        } else {
+            let mut packet = protocol::encode_probe(&Probe {
+                seq,
+                t1,
+                cookie: self.cookie,
+                pad_reply: self.config.pad_replies,
+                nonce,
+                client_id: self.config.client_id,
+            });
+            if let Some(size) = self.config.size {
+                // The MAC or the nonce and tag come on top of the padding
+                let overhead = self.config.key.as_ref().map_or(0, Key::overhead);
+                packet = protocol::pad(packet, size.saturating_sub(overhead));
+            }
+            match &self.config.key {
+                Some(key) => key.sign(packet)?,
+                None => packet,
+            }
+        };
+        let sent = match &mut self.link {
+            Link::Udp { socket, .. } => socket.send(&packet).await.map(drop).map_err(Into::into),
+            Link::Tcp(Some(stream)) => stream.send(&packet).await,
+            Link::Quic {
+                connection: Some(connection),
+                ..
+            } => connection.send(&packet),
+            // Not reconnected, the probe is lost
+            Link::Tcp(None)
+            | Link::Quic {
+                connection: None, ..
+            } => Ok(()),
+        };
+        if let Err(e) = sent {
+            if self.link.disconnect() {
+                warn!("{:#}", e);
+            } else if icmp::is_icmp_error(&e) {
+                // An ICMP error for an earlier probe fails the next send, losing this probe
+                if self.read_error_queue() == 0 {
+                    warn!("{}", e);
+                }
+            } else {
+                return Err(e);
+            }
+        }
+        if self.tx_timestamps {
+            self.read_error_queue();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+//! Offsets between every pair of a group of hosts, derived from the offsets
+//! of this host to each of them
+//!
+//! With the offset of this host to hosts `i` and `j` being `o_i` and `o_j`,
+//! the clock of `i` minus the clock of `j` is `o_j - o_i`, within the sum of
+//! both uncertainties. The hosts, this one included, are combined with
+//! Marzullo's algorithm; those outside the agreed interval are inconsistent
+//! with the group.
+
+use crate::{
+    consensus::{self, Consensus},
+    measurement::Measurement,
+};
+use std::fmt;
+
+/// Name of this host in the mesh
+pub const LOCAL: &str = "(this host)";
+
+#[derive(Clone, Debug)]
+struct Host {
+    name: String,
+    /// Offset of this host to the host and its uncertainty, `None` without
+    /// replies
+    offset: Option<(f64, f64)>,
+}
+
+/// Group of hosts with this host first
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    hosts: Vec<Host>,
+    /// With indices of the hosts
+    consensus: Option<Consensus>,
+}
+
+impl Mesh {
+    /// Mesh of the best measurement of this host to each named host
+    pub fn new(measurements: Vec<(String, Option<Measurement>)>) -> Self {
+        let hosts: Vec<_> = [(LOCAL.to_owned(), Some((0.0, 0.0)))]
+            .into_iter()
+            .chain(measurements.into_iter().map(|(name, m)| {
+                let offset = m.map(|m| (m.offset, (m.offset_max - m.offset_min) / 2.0));
+                (name, offset)
+            }))
+            .map(|(name, offset)| Host { name, offset })
+            .collect();
+        let reachable: Vec<_> = (0..hosts.len())
+            .filter(|&i| hosts[i].offset.is_some())
+            .collect();
+        let intervals: Vec<_> = reachable
+            .iter()
+            .filter_map(|&i| hosts[i].offset)
+            .map(|(offset, uncertainty)| (offset - uncertainty, offset + uncertainty))
+            .collect();
+        // The local host always takes part, so there is a consensus
+        let consensus = consensus::marzullo(&intervals).map(|c| Consensus {
+            truechimers: c.truechimers.iter().map(|&i| reachable[i]).collect(),
+            falsetickers: c.falsetickers.iter().map(|&i| reachable[i]).collect(),
+            ..c
+        });
+        Self { hosts, consensus }
+    }
+
+    /// Consensus of the hosts that replied, indices being those of the
+    /// hosts with this host at 0
+    pub fn consensus(&self) -> Option<&Consensus> {
+        self.consensus.as_ref()
+    }
+
+    pub fn name(&self, host: usize) -> &str {
+        &self.hosts[host].name
+    }
+
+    /// Clock of `host` minus the consensus time
+    pub fn deviation(&self, host: usize) -> Option<f64> {
+        let (offset, _) = self.hosts[host].offset?;
+        Some(self.consensus.as_ref()?.offset - offset)
+    }
+
+    /// Hosts outside the agreed interval, or else the one deviating most
+    /// from the consensus, with their deviations
+    pub fn most_inconsistent(&self) -> Vec<(&str, f64)> {
+        let Some(consensus) = &self.consensus else {
+            return Vec::new();
+        };
+        let deviations = |hosts: &[usize]| -> Vec<_> {
+            hosts
+                .iter()
+                .filter_map(|&i| Some((self.name(i), self.deviation(i)?)))
+                .collect()
+        };
+        match consensus.falsetickers.is_empty() {
+            false => deviations(&consensus.falsetickers),
+            true => deviations(&consensus.truechimers)
+                .into_iter()
+                .max_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+/// Table of the hosts, then the matrix of the clock of the row host minus
+/// that of the column host
+impl fmt::Display for Mesh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:>3} {:<39} {:>14} {:>14} {:>14}",
+            "#", "host", "offset", "uncertainty", "deviation"
+        )?;
+        for (i, host) in self.hosts.iter().enumerate() {
+            write!(f, "{:>3} {:<39}", i, host.name)?;
+            match (host.offset, self.deviation(i)) {
+                (Some((offset, uncertainty)), Some(deviation)) => write!(
+                    f,
+                    " {:>14.9} {:>14.9} {:>14.9}",
+                    offset, uncertainty, deviation
+                )?,
+                (Some((offset, uncertainty)), None) => {
+                    write!(f, " {:>14.9} {:>14.9}", offset, uncertainty)?
+                }
+                (None, _) => write!(f, " {:>14}", "no replies")?,
+            }
+            let falseticker = self
+                .consensus
+                .as_ref()
+                .is_some_and(|c| c.falsetickers.contains(&i));
+            writeln!(f, "{}", if falseticker { "  inconsistent" } else { "" })?;
+        }
+        writeln!(f)?;
+        write!(f, "{:>3}", "")?;
+        for i in 0..self.hosts.len() {
+            write!(f, " {:>14}", i)?;
+        }
+        writeln!(f)?;
+        for (i, row) in self.hosts.iter().enumerate() {
+            write!(f, "{:>3}", i)?;
+            for column in &self.hosts {
+                match (row.offset, column.offset) {
+                    (Some((row, _)), Some((column, _))) => write!(f, " {:>14.9}", column - row)?,
+                    _ => write!(f, " {:>14}", "-")?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,372 @@
+//! Prometheus metrics exporter
+
+use crate::{analysis::Sample, clients::ClientTable};
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::warn;
+
+/// Latest values of a measured target
+#[derive(Clone, Copy, Debug, Default)]
+struct TargetMetrics {
+    offset: f64,
+    filtered_offset: f64,
+    rtt: f64,
+    loss_ratio: f64,
+    drift_ppm: Option<f64>,
+    samples: u64,
+    discarded: u64,
+}
+
+/// Packet counters of a reflector
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReflectorCounters {
+    pub received: u64,
+    pub received_bytes: u64,
+    pub replied: u64,
+    pub replied_bytes: u64,
+    pub invalid: u64,
+    pub disallowed: u64,
+    pub rate_limited: u64,
+    pub challenged: u64,
+}
+
+impl fmt::Display for ReflectorCounters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received {} packets ({} bytes), replied {} ({} bytes), {} invalid, {} disallowed, {} rate-limited, {} challenged",
+            self.received,
+            self.received_bytes,
+            self.replied,
+            self.replied_bytes,
+            self.invalid,
+            self.disallowed,
+            self.rate_limited,
+            self.challenged
+        )
+    }
+}
+
+/// Per-target metric family
+struct TargetMetric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&TargetMetrics) -> Option<f64>,
+}
+
+const TARGET_METRICS: [TargetMetric; 7] = [
+    TargetMetric {
+        name: "co_offset_seconds",
+        kind: "gauge",
+        help: "Clock offset (local - remote) of the latest accepted sample",
+        value: |t| Some(t.offset),
+    },
+    TargetMetric {
+        name: "co_filtered_offset_seconds",
+        kind: "gauge",
+        help: "Offset of the minimum-delay recent sample",
+        value: |t| Some(t.filtered_offset),
+    },
+    TargetMetric {
+        name: "co_rtt_seconds",
+        kind: "gauge",
+        help: "Round-trip time of the latest accepted sample",
+        value: |t| Some(t.rtt),
+    },
+    TargetMetric {
+        name: "co_loss_ratio",
+        kind: "gauge",
+        help: "Fraction of probes lost so far",
+        value: |t| Some(t.loss_ratio),
+    },
+    TargetMetric {
+        name: "co_drift_ppm",
+        kind: "gauge",
+        help: "Local clock frequency error relative to the target",
+        value: |t| t.drift_ppm,
+    },
+    TargetMetric {
+        name: "co_samples_total",
+        kind: "counter",
+        help: "Number of samples received",
+        value: |t| Some(t.samples as f64),
+    },
+    TargetMetric {
+        name: "co_discarded_total",
+        kind: "counter",
+        help: "Number of samples discarded as outliers",
+        value: |t| Some(t.discarded as f64),
+    },
+];
+
+#[derive(Debug, Default)]
+struct State {
+    targets: BTreeMap<String, TargetMetrics>,
+    reflector: Option<ReflectorCounters>,
+    /// Clients of the reflector
+    clients: Option<Arc<Mutex<ClientTable>>>,
+}
+
+/// Shared metrics registry, cheap to clone
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    state: Arc<Mutex<State>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a sample of `target`; discarded samples only update the counters
+    pub fn record_sample(&self, target: &str, sample: &Sample) {
+        let mut state = self.state.lock().unwrap();
+        let metrics = state.targets.entry(target.to_owned()).or_default();
+        let m = &sample.measurement;
+
+        metrics.samples += 1;
+        metrics.loss_ratio = m.lost as f64 / (m.seq + 1) as f64;
+        if sample.flags.discarded {
+            metrics.discarded += 1;
+            return;
+        }
+        metrics.offset = m.offset;
+        metrics.filtered_offset = sample.filtered_offset;
+        metrics.rtt = m.rtt;
+        metrics.drift_ppm = sample.drift_ppm;
+    }
+
+    pub(crate) fn reflector_received(&self, bytes: usize) {
+        self.reflector(|r| {
+            r.received += 1;
+            r.received_bytes += bytes as u64;
+        });
+    }
+
+    pub(crate) fn reflector_replied(&self, bytes: usize) {
+        self.reflector(|r| {
+            r.replied += 1;
+            r.replied_bytes += bytes as u64;
+        });
+    }
+
+    pub(crate) fn reflector_invalid(&self) {
+        self.reflector(|r| r.invalid += 1);
+    }
+
+    pub(crate) fn reflector_disallowed(&self) {
+        self.reflector(|r| r.disallowed += 1);
+    }
+
+    pub(crate) fn reflector_rate_limited(&self) {
+        self.reflector(|r| r.rate_limited += 1);
+    }
+
+    pub(crate) fn reflector_challenged(&self) {
+        self.reflector(|r| r.challenged += 1);
+    }
+
+    /// Export the statistics of the clients in `table`
+    pub(crate) fn watch_clients(&self, table: Arc<Mutex<ClientTable>>) {
+        self.state.lock().unwrap().clients = Some(table);
+    }
+
+    /// Reflector counters so far, all zero before the first packet
+    pub fn reflector_counters(&self) -> ReflectorCounters {
+        self.state.lock().unwrap().reflector.unwrap_or_default()
+    }
+
+    fn reflector(&self, update: impl FnOnce(&mut ReflectorCounters)) {
+        update(
+            self.state
+                .lock()
+                .unwrap()
+                .reflector
+                .get_or_insert_with(Default::default),
+        );
+    }
+
+    /// Metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        if !state.targets.is_empty() {
+            for metric in &TARGET_METRICS {
+                write_header(&mut out, metric.name, metric.kind, metric.help);
+                for (target, metrics) in &state.targets {
+                    if let Some(value) = (metric.value)(metrics) {
+                        let _ = writeln!(
+                            out,
+                            "{}{{target=\"{}\"}} {}",
+                            metric.name,
+                            escape_label(target),
+                            value
+                        );
+                    }
+                }
+            }
+        }
+
+        if let Some(reflector) = state.reflector {
+            for (name, help, value) in [
+                (
+                    "co_reflector_received_total",
+                    "Number of packets received by the reflector",
+                    reflector.received,
+                ),
+                (
+                    "co_reflector_received_bytes_total",
+                    "Number of bytes of the packets received by the reflector",
+                    reflector.received_bytes,
+                ),
+                (
+                    "co_reflector_replied_total",
+                    "Number of replies sent by the reflector",
+                    reflector.replied,
+                ),
+                (
+                    "co_reflector_replied_bytes_total",
+                    "Number of bytes of the replies sent by the reflector",
+                    reflector.replied_bytes,
+                ),
+                (
+                    "co_reflector_invalid_total",
+                    "Number of invalid packets discarded by the reflector",
+                    reflector.invalid,
+                ),
+                (
+                    "co_reflector_disallowed_total",
+                    "Number of packets dropped for coming from outside the allowed networks",
+                    reflector.disallowed,
+                ),
+                (
+                    "co_reflector_rate_limited_total",
+                    "Number of packets dropped by the reflector rate limits",
+                    reflector.rate_limited,
+                ),
+                (
+                    "co_reflector_challenged_total",
+                    "Number of probes answered with a reachability challenge",
+                    reflector.challenged,
+                ),
+            ] {
+                write_header(&mut out, name, "counter", help);
+                let _ = writeln!(out, "{} {}", name, value);
+            }
+        }
+
+        if let Some(clients) = &state.clients {
+            let clients = clients.lock().unwrap().snapshot();
+            write_header(
+                &mut out,
+                "co_reflector_clients",
+                "gauge",
+                "Number of distinct clients answered by the reflector",
+            );
+            let _ = writeln!(out, "co_reflector_clients {}", clients.len());
+            if !clients.is_empty() {
+                write_header(
+                    &mut out,
+                    "co_client_packets_total",
+                    "counter",
+                    "Number of probes of the client answered by the reflector",
+                );
+                for (addr, stats) in &clients {
+                    let _ = writeln!(
+                        out,
+                        "co_client_packets_total{{client=\"{}\"}} {}",
+                        addr, stats.packets
+                    );
+                }
+                write_header(
+                    &mut out,
+                    "co_client_apparent_offset_seconds",
+                    "gauge",
+                    "Lower bound on the client clock offset, from its recent probe send times",
+                );
+                for (addr, stats) in &clients {
+                    let _ = writeln!(
+                        out,
+                        "co_client_apparent_offset_seconds{{client=\"{}\"}} {}",
+                        addr, stats.apparent_offset
+                    );
+                }
+            }
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` over HTTP on `addr` forever
+pub async fn serve(addr: SocketAddr, metrics: Metrics) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics endpoint to {}", addr))?;
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_request(stream, &metrics).await {
+                warn!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn handle_request(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    // Only the request line matters, headers and body are ignored
+    let mut buf = [0; 4096];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < buf.len() {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", metrics.render()),
+        ("GET", _) => ("404 Not Found", "Not found\n".to_owned()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_owned()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
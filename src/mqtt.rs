@@ -0,0 +1,205 @@
+//! Publishing samples to an MQTT broker as JSON messages
+//!
+//! A minimal MQTT 3.1.1 client: it connects with a clean session and
+//! without keep-alive, and publishes at QoS 0, reconnecting with the next
+//! sample after a failure.
+
+use crate::{
+    analysis::Sample,
+    output::{self, Field},
+};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    process,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+pub const DEFAULT_TOPIC: &str = "clock-offset/{target}";
+const DEFAULT_PORT: u16 = 1883;
+/// Longest wait for the broker to accept a connection
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const DISCONNECT: u8 = 0xe0;
+
+/// Broker of an `mqtt://[user[:password]@]host[:port]` URL
+#[derive(Clone, Debug)]
+struct Broker {
+    /// `host:port`
+    authority: String,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl Broker {
+    fn parse(url: &str) -> Result<Self> {
+        let Some(rest) = url.strip_prefix("mqtt://") else {
+            bail!("only mqtt:// URLs are supported, got {}", url);
+        };
+        let rest = rest.trim_end_matches('/');
+        let (credentials, host) = match rest.rsplit_once('@') {
+            Some((credentials, host)) => (Some(credentials), host),
+            None => (None, rest),
+        };
+        ensure!(!host.is_empty(), "no broker host in {}", url);
+        let (user, password) = match credentials.map(|c| c.split_once(':')) {
+            Some(Some((user, password))) => (Some(user.to_owned()), Some(password.to_owned())),
+            Some(None) => (credentials.map(str::to_owned), None),
+            None => (None, None),
+        };
+        let has_port = match host.strip_prefix('[') {
+            Some(ipv6) => ipv6.contains("]:"),
+            None => host.contains(':'),
+        };
+        let authority = match has_port {
+            true => host.to_owned(),
+            false => format!("{}:{}", host, DEFAULT_PORT),
+        };
+        Ok(Self {
+            authority,
+            user,
+            password,
+        })
+    }
+
+    fn connect(&self) -> Result<TcpStream> {
+        let addr = self
+            .authority
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("no address found for {}", self.authority))?;
+        let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut flags = 0x02; // clean session
+        let mut payload = string(&format!("co-{}", process::id()));
+        if let Some(user) = &self.user {
+            flags |= 0x80;
+            payload.extend(string(user));
+        }
+        if let Some(password) = &self.password {
+            flags |= 0x40;
+            payload.extend(string(password));
+        }
+        // Protocol name and level 4 (3.1.1), flags and no keep-alive
+        let mut body = string("MQTT");
+        body.extend([4, flags, 0, 0]);
+        body.extend(payload);
+        stream.write_all(&packet(CONNECT, &body))?;
+
+        let mut connack = [0; 4];
+        stream.read_exact(&mut connack)?;
+        ensure!(
+            connack[0] == CONNACK && connack[1] == 2,
+            "unexpected reply to CONNECT"
+        );
+        match connack[3] {
+            0 => Ok(stream),
+            4 | 5 => bail!("broker refused the credentials"),
+            code => bail!("broker refused the connection with code {}", code),
+        }
+    }
+}
+
+/// MQTT UTF-8 string: length and bytes
+fn string(s: &str) -> Vec<u8> {
+    let mut bytes = (s.len() as u16).to_be_bytes().to_vec();
+    bytes.extend(s.as_bytes());
+    bytes
+}
+
+/// Control packet of `kind` with `body`, after its variable-length size
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            bytes.push(byte);
+            break;
+        }
+        bytes.push(byte | 0x80);
+    }
+    bytes.extend(body);
+    bytes
+}
+
+/// Publishes from a background thread, so that a slow broker does not hold
+/// up measuring
+pub struct MqttPublisher {
+    topic: String,
+    fields: Vec<Field>,
+    messages: mpsc::Sender<(String, String)>,
+}
+
+impl MqttPublisher {
+    /// Publish to the broker at `url`, to `topic` with `{target}` replaced
+    /// by the target name, messages of `fields`
+    pub fn new(url: &str, topic: &str, fields: Vec<Field>) -> Result<Self> {
+        let broker = Broker::parse(url).context("invalid MQTT broker URL")?;
+        ensure!(
+            !topic.contains(['+', '#']),
+            "MQTT topics to publish to can not have wildcards"
+        );
+        let (messages, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("mqtt".to_owned())
+            .spawn(move || publish(broker, rx))
+            .context("failed to start publishing to MQTT")?;
+        Ok(Self {
+            topic: topic.to_owned(),
+            fields,
+            messages,
+        })
+    }
+
+    pub fn publish(&self, target: &str, sample: &Sample) {
+        let topic = self.topic.replace("{target}", target);
+        let message = output::sample_json(&self.fields, target, sample);
+        // The thread only stops if it failed to start
+        let _ = self.messages.send((topic, message));
+    }
+}
+
+fn publish(broker: Broker, rx: mpsc::Receiver<(String, String)>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut failing = false;
+    while let Ok((topic, message)) = rx.recv() {
+        let mut body = string(&topic);
+        body.extend(message.as_bytes());
+        let result = match stream.take() {
+            Some(stream) => Ok(stream),
+            None => broker.connect(),
+        }
+        .and_then(|mut s| {
+            s.write_all(&packet(PUBLISH, &body))?;
+            stream = Some(s);
+            Ok(())
+        });
+        match result {
+            Ok(()) if failing => {
+                info!("Publishing to MQTT at {} works again", broker.authority);
+                failing = false;
+            }
+            Ok(()) => {}
+            // Reported once until it recovers, not with every sample
+            Err(e) if !failing => {
+                warn!("Publishing to MQTT at {} failed: {:#}", broker.authority, e);
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+    if let Some(mut stream) = stream {
+        let _ = stream.write_all(&packet(DISCONNECT, &[]));
+    }
+}
@@ -0,0 +1,116 @@
+//! Path MTU probing: probes padded to a size and sent with the
+//! don't-fragment flag, their replies padded alike, either come back whole
+//! or are lost, so the largest size reflected bounds the MTU of the path
+//! both ways
+
+use crate::{measurer::MeasurerConfig, sweep, target::Target, transport::Transport};
+use anyhow::{ensure, Context, Result};
+use std::{fmt, net::SocketAddr, time::Duration};
+use tracing::debug;
+
+/// Largest UDP payload the reflector receives whole
+pub const MAX_SIZE: usize = 2048;
+/// Probes sent at each size, which fails if all of them are lost
+const PROBES: u64 = 3;
+const INTERVAL: Duration = Duration::from_millis(50);
+const UDP_HEADER: usize = 8;
+
+/// Largest probe reflected
+#[derive(Clone, Copy, Debug)]
+pub struct PathMtu {
+    /// UDP payload of the probe, in bytes
+    pub size: usize,
+    pub addr: SocketAddr,
+    /// Probes of the largest size tried were reflected, the path may take more
+    pub at_limit: bool,
+}
+
+impl PathMtu {
+    /// The IP packet size of the probe: the path MTU is at least this
+    pub fn mtu(&self) -> usize {
+        let ip_header = if self.addr.ip().to_canonical().is_ipv4() {
+            20
+        } else {
+            40
+        };
+        self.size + UDP_HEADER + ip_header
+    }
+}
+
+impl fmt::Display for PathMtu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes of UDP payload reflected by {}, a path MTU of {}{}",
+            self.size,
+            self.addr,
+            if self.at_limit { "at least " } else { "" },
+            self.mtu()
+        )
+    }
+}
+
+/// Binary search for the largest probe size from `min` to `max` that
+/// `target` reflects, measuring with `config` otherwise
+///
+/// Probes of `min` bytes must be reflected, or the target is taken to be
+/// unreachable. Sizes are probed with a fresh socket each, so that what the
+/// kernel learned of the path MTU from earlier sizes shows in the sends.
+pub async fn probe(
+    target: &Target,
+    config: &MeasurerConfig,
+    min: usize,
+    max: usize,
+) -> Result<PathMtu> {
+    ensure!(
+        config.transport == Transport::Udp,
+        "the path MTU can only be probed over UDP"
+    );
+    ensure!(
+        min <= max && max <= MAX_SIZE,
+        "probe sizes must go up to at most {} bytes",
+        MAX_SIZE
+    );
+    let reflected = |size| {
+        let mut config = MeasurerConfig {
+            size: Some(size),
+            pad_replies: true,
+            interval: INTERVAL,
+            interval_max: None,
+            jitter: 0.0,
+            burst: 1,
+            count: Some(PROBES),
+            warmup: 0,
+            duration: None,
+            backoff_max: None,
+            ..config.clone()
+        };
+        config.socket.dont_fragment = true;
+        sweep::best(target.clone(), config)
+    };
+    let m = reflected(min)
+        .await
+        .with_context(|| format!("probes of {} bytes not reflected", min))?;
+    let addr = m.remote.context("no address of the reflector")?;
+    // Probes of `lo` bytes are reflected, of `hi` bytes lost
+    let (mut lo, mut hi) = (min, max + 1);
+    while hi - lo > 1 {
+        let size = lo + (hi - lo) / 2;
+        let ok = reflected(size).await.is_ok();
+        debug!(
+            "Probes of {} bytes {}",
+            size,
+            if ok { "reflected" } else { "lost" }
+        );
+        if ok {
+            lo = size;
+        } else {
+            hi = size;
+        }
+    }
+    Ok(PathMtu {
+        size: lo,
+        addr,
+        at_limit: lo == max,
+    })
+}
@@ -0,0 +1,192 @@
+//! NTPv4 packets (RFC 5905), enough to query standard NTP servers as an SNTP
+//! client (RFC 4330) and to answer NTP clients from the reflector
+//!
+//! Integers and timestamps are big-endian. Timestamps count seconds since
+//! 1900 in 32 bits, wrapping around every era of 136 years, and a 32-bit
+//! binary fraction.
+
+use crate::clock::Timestamp;
+use anyhow::{bail, ensure, Result};
+
+/// Well-known port of NTP servers
+pub const PORT: u16 = 123;
+/// Header without extension fields or MAC
+pub const PACKET_SIZE: usize = 48;
+
+/// Seconds from 1900, the start of era 0, to the Unix epoch
+const UNIX_OFFSET: i64 = 2_208_988_800;
+const ERA_SECONDS: i64 = 1 << 32;
+const NANOSECONDS_IN_SECOND: u128 = 1_000_000_000;
+
+const VERSION: u8 = 4;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+/// Leap indicator of servers whose clock is not synchronized
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+/// Precision announced in responses, about a microsecond
+const PRECISION: i8 = -20;
+
+const ORIGIN_OFFSET: usize = 24;
+const RECEIVE_OFFSET: usize = 32;
+const TRANSMIT_OFFSET: usize = 40;
+
+/// NTP side of the reflector
+#[derive(Clone, Debug)]
+pub struct NtpConfig {
+    /// UDP port to answer NTP clients on, usually [`PORT`]
+    pub port: u16,
+    /// Stratum to announce, one more than that of the source the host is
+    /// synchronized to
+    pub stratum: u8,
+}
+
+/// Decoded client request
+#[derive(Clone, Copy, Debug)]
+pub struct Request {
+    pub version: u8,
+    /// Polling interval of the client, as a power of two of seconds
+    pub poll: i8,
+    /// Transmit timestamp of the client, to be echoed back
+    pub transmit: u64,
+}
+
+/// State of the local clock announced in responses
+#[derive(Clone, Copy, Debug)]
+pub struct ServerState {
+    pub synchronized: bool,
+    pub stratum: u8,
+    /// Maximum error of the local clock, in seconds
+    pub root_dispersion: f64,
+}
+
+/// Decoded server response
+#[derive(Clone, Copy, Debug)]
+pub struct Response {
+    pub stratum: u8,
+    /// Transmit timestamp of the request, echoed back
+    pub origin: u64,
+    /// Server receive time of the request
+    pub receive: u64,
+    /// Server transmit time of the response
+    pub transmit: u64,
+}
+
+/// `t` as an NTP timestamp, rounded up so that [`from_ntp`] gives `t` back
+pub fn to_ntp(t: Timestamp) -> u64 {
+    let sec = (t.sec + UNIX_OFFSET).rem_euclid(ERA_SECONDS) as u64;
+    let frac = ((t.nsec as u128) << 32).div_ceil(NANOSECONDS_IN_SECOND) as u64;
+    (sec << 32).wrapping_add(frac)
+}
+
+/// The time of NTP timestamp `ntp` in the era closest to `near`
+pub fn from_ntp(ntp: u64, near: Timestamp) -> Timestamp {
+    let sec = (ntp >> 32) as i64 - UNIX_OFFSET;
+    let era = (near.sec - sec + ERA_SECONDS / 2).div_euclid(ERA_SECONDS);
+    let nsec = ((ntp & 0xffff_ffff) as u128 * NANOSECONDS_IN_SECOND) >> 32;
+    Timestamp::new(sec + era * ERA_SECONDS, nsec as i64)
+}
+
+/// Client request with `transmit` as its transmit timestamp
+///
+/// Servers only echo the transmit timestamp back as the origin one, so it
+/// needs not be the send time; the rest of the request is zero.
+pub fn encode_request(transmit: u64) -> [u8; PACKET_SIZE] {
+    let mut buf = [0; PACKET_SIZE];
+    buf[0] = VERSION << 3 | MODE_CLIENT;
+    buf[TRANSMIT_OFFSET..].copy_from_slice(&transmit.to_be_bytes());
+    buf
+}
+
+/// Decode a client request; extension fields and MACs are ignored
+pub fn decode_request(buf: &[u8]) -> Result<Request> {
+    ensure!(
+        buf.len() >= PACKET_SIZE,
+        "NTP packet of {} bytes is too short",
+        buf.len()
+    );
+    let (version, mode) = (buf[0] >> 3 & 7, buf[0] & 7);
+    ensure!(
+        mode == MODE_CLIENT && (1..=VERSION).contains(&version),
+        "not an NTP client request"
+    );
+    Ok(Request {
+        version,
+        poll: buf[2] as i8,
+        transmit: read_u64(buf, TRANSMIT_OFFSET),
+    })
+}
+
+/// Response to `request` received at `receive` and sent at `transmit`, in the
+/// version of the request
+///
+/// The reference time, when the clock was last set, is taken as `receive`:
+/// the clock is disciplined continuously by the host, not by the reflector.
+pub fn encode_response(
+    request: &Request,
+    server: &ServerState,
+    receive: Timestamp,
+    transmit: Timestamp,
+) -> [u8; PACKET_SIZE] {
+    let mut buf = [0; PACKET_SIZE];
+    let leap = if server.synchronized {
+        0
+    } else {
+        LEAP_UNSYNCHRONIZED
+    };
+    buf[0] = leap << 6 | request.version << 3 | MODE_SERVER;
+    buf[1] = server.stratum;
+    buf[2] = request.poll as u8;
+    buf[3] = PRECISION as u8;
+    // Root delay stays zero, it is unknown to the reflector; the root
+    // dispersion is in 16.16 fixed point seconds
+    let dispersion = (server.root_dispersion * 65536.0).clamp(0.0, u32::MAX as f64) as u32;
+    buf[8..12].copy_from_slice(&dispersion.to_be_bytes());
+    let receive = to_ntp(receive);
+    buf[16..24].copy_from_slice(&receive.to_be_bytes());
+    buf[ORIGIN_OFFSET..RECEIVE_OFFSET].copy_from_slice(&request.transmit.to_be_bytes());
+    buf[RECEIVE_OFFSET..TRANSMIT_OFFSET].copy_from_slice(&receive.to_be_bytes());
+    buf[TRANSMIT_OFFSET..].copy_from_slice(&to_ntp(transmit).to_be_bytes());
+    buf
+}
+
+/// Decode the response of a server, rejecting kiss-o'-death packets and
+/// unsynchronized servers
+pub fn decode_response(buf: &[u8]) -> Result<Response> {
+    ensure!(
+        buf.len() >= PACKET_SIZE,
+        "NTP packet of {} bytes is too short",
+        buf.len()
+    );
+    let (leap, version, mode) = (buf[0] >> 6, buf[0] >> 3 & 7, buf[0] & 7);
+    ensure!(
+        mode == MODE_SERVER && (1..=VERSION).contains(&version),
+        "not an NTP server response"
+    );
+    let stratum = buf[1];
+    if stratum == 0 {
+        // The reference ID holds the ASCII kiss code, such as RATE or DENY
+        bail!(
+            "kiss-o'-death {} from the NTP server",
+            String::from_utf8_lossy(&buf[12..16])
+        );
+    }
+    ensure!(
+        leap != LEAP_UNSYNCHRONIZED,
+        "NTP server clock is not synchronized"
+    );
+    let response = Response {
+        stratum,
+        origin: read_u64(buf, ORIGIN_OFFSET),
+        receive: read_u64(buf, RECEIVE_OFFSET),
+        transmit: read_u64(buf, TRANSMIT_OFFSET),
+    };
+    ensure!(
+        response.receive != 0 && response.transmit != 0,
+        "NTP response without timestamps"
+    );
+    Ok(response)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,146 @@
+//! Export of per-target gauges as OpenTelemetry metrics, posted to an OTLP
+//! collector over HTTP with the JSON encoding
+//!
+//! Every accepted sample adds data points to the gauges `co.offset`,
+//! `co.filtered_offset` and `co.rtt` (seconds), `co.loss_ratio` and, once
+//! estimated, `co.drift` (ppm), with the target in the `target` attribute.
+
+use crate::{analysis::Sample, http::Endpoint};
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, sync::mpsc, thread};
+use tracing::{info, warn};
+
+/// Path of the metrics of an OTLP/HTTP endpoint given without one
+const METRICS_PATH: &str = "/v1/metrics";
+const SERVICE_NAME: &str = "clock-offset";
+
+/// Gauge data point of one sample
+struct Point {
+    name: &'static str,
+    unit: &'static str,
+    target: String,
+    time_nsec: i128,
+    value: f64,
+}
+
+/// Posts data points from a background thread, so that a slow or
+/// unreachable collector does not hold up measuring
+///
+/// Points are batched while a request is in flight.
+pub struct OtlpExporter {
+    points: mpsc::Sender<Vec<Point>>,
+}
+
+impl OtlpExporter {
+    /// Export to the collector at `url`: a base URL such as
+    /// `http://localhost:4318` gets the `/v1/metrics` path, a URL with a path
+    /// is used as it is
+    pub fn new(url: &str) -> Result<Self> {
+        let url = match url
+            .strip_prefix("http://")
+            .map(|rest| rest.trim_end_matches('/'))
+        {
+            Some(authority) if !authority.contains('/') => {
+                format!("http://{}{}", authority, METRICS_PATH)
+            }
+            _ => url.to_owned(),
+        };
+        let endpoint = Endpoint::parse(&url).context("invalid OTLP endpoint")?;
+        let (points, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("otlp".to_owned())
+            .spawn(move || export(endpoint, rx))
+            .context("failed to start exporting to OTLP")?;
+        Ok(Self { points })
+    }
+
+    /// Export the gauges of an accepted sample of `target`
+    pub fn update(&self, target: &str, sample: &Sample) {
+        let m = &sample.measurement;
+        let mut values = vec![
+            ("co.offset", "s", m.offset),
+            ("co.filtered_offset", "s", sample.filtered_offset),
+            ("co.rtt", "s", m.rtt),
+            ("co.loss_ratio", "1", m.lost as f64 / (m.seq + 1) as f64),
+        ];
+        if let Some(drift) = sample.drift_ppm {
+            values.push(("co.drift", "ppm", drift));
+        }
+        let points = values
+            .into_iter()
+            .map(|(name, unit, value)| Point {
+                name,
+                unit,
+                target: target.to_owned(),
+                time_nsec: m.t4.total_nsec(),
+                value,
+            })
+            .collect();
+        // The thread only stops if it failed to start
+        let _ = self.points.send(points);
+    }
+}
+
+/// `ExportMetricsServiceRequest` of `points`, one gauge per metric name
+fn request(points: &[Point]) -> Value {
+    let mut gauges: BTreeMap<&str, (&str, Vec<Value>)> = BTreeMap::new();
+    for point in points {
+        let (_, data_points) = gauges
+            .entry(point.name)
+            .or_insert_with(|| (point.unit, Vec::new()));
+        data_points.push(json!({
+            "attributes": [attribute("target", &point.target)],
+            // 64-bit integers are strings in the JSON encoding
+            "timeUnixNano": point.time_nsec.to_string(),
+            "asDouble": point.value,
+        }));
+    }
+    let metrics: Vec<_> = gauges
+        .into_iter()
+        .map(|(name, (unit, data_points))| {
+            json!({
+                "name": name,
+                "unit": unit,
+                "gauge": { "dataPoints": data_points },
+            })
+        })
+        .collect();
+    json!({
+        "resourceMetrics": [{
+            "resource": { "attributes": [attribute("service.name", SERVICE_NAME)] },
+            "scopeMetrics": [{
+                "scope": { "name": SERVICE_NAME, "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+fn export(endpoint: Endpoint, rx: mpsc::Receiver<Vec<Point>>) {
+    let mut failing = false;
+    while let Ok(mut points) = rx.recv() {
+        points.extend(rx.try_iter().flatten());
+        let body = request(&points).to_string();
+        match endpoint.post("", "application/json", &body) {
+            Ok(()) if failing => {
+                info!("Exporting to OTLP at {} works again", endpoint.authority);
+                failing = false;
+            }
+            Ok(()) => {}
+            // Reported once until it recovers, not with every sample
+            Err(e) if !failing => {
+                warn!(
+                    "Exporting to OTLP at {} failed: {:#}",
+                    endpoint.authority, e
+                );
+                failing = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::measurement::Measurement;
+use std::collections::VecDeque;
+
+/// Samples needed before the adaptive filter starts rejecting
+const MIN_MAD_SAMPLES: usize = 8;
+/// Scales MAD to the standard deviation of normally distributed data
+pub(crate) const MAD_SCALE: f64 = 1.4826;
+
+/// Flags samples with congested round trips, which give unreliable offsets
+#[derive(Debug)]
+pub struct OutlierFilter {
+    max_rtt: Option<f64>,
+    mad_threshold: Option<f64>,
+    window: usize,
+    /// Recent RTTs, including rejected ones so that a lasting RTT increase is adopted
+    rtts: VecDeque<f64>,
+}
+
+impl OutlierFilter {
+    /// Reject samples with RTT above `max_rtt` seconds, or more than `mad_threshold`
+    /// scaled median absolute deviations above the median RTT of the last `window` samples
+    pub fn new(max_rtt: Option<f64>, mad_threshold: Option<f64>, window: usize) -> Self {
+        Self {
+            max_rtt,
+            mad_threshold,
+            window: window.max(MIN_MAD_SAMPLES),
+            rtts: VecDeque::new(),
+        }
+    }
+
+    /// Whether `m` is an outlier
+    pub fn is_outlier(&mut self, m: &Measurement) -> bool {
+        let adaptive_outlier = self.is_adaptive_outlier(m.rtt);
+
+        if self.rtts.len() == self.window {
+            self.rtts.pop_front();
+        }
+        self.rtts.push_back(m.rtt);
+
+        adaptive_outlier || self.max_rtt.is_some_and(|max_rtt| m.rtt > max_rtt)
+    }
+
+    fn is_adaptive_outlier(&self, rtt: f64) -> bool {
+        let Some(threshold) = self.mad_threshold else {
+            return false;
+        };
+        if self.rtts.len() < MIN_MAD_SAMPLES {
+            return false;
+        }
+
+        let mut rtts: Vec<f64> = self.rtts.iter().copied().collect();
+        let center = median(&mut rtts);
+        let mut deviations: Vec<f64> = rtts.iter().map(|r| (r - center).abs()).collect();
+        let mad = median(&mut deviations) * MAD_SCALE;
+
+        rtt > center + threshold * mad
+    }
+}
+
+/// Median of `values`, which are sorted in place
+pub(crate) fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
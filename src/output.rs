@@ -0,0 +1,804 @@
+//! Formatting samples for output
+
+use crate::{
+    analysis::Sample,
+    clock::{DateTime, Timestamp},
+    columnar::{Cell, Kind, ParquetFile},
+    measurement::{LostProbe, Measurement},
+    rotate::{RotatingFile, Rotation},
+    timestamping::TimestampSource,
+};
+use anyhow::{anyhow, bail, Result};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::warn;
+
+/// Output format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Comma-separated values with a header line
+    Csv,
+    /// One JSON object per line
+    Json,
+    /// InfluxDB line protocol
+    Influx,
+    /// Parquet file, with native timestamp columns; only written to files
+    Parquet,
+    /// Layout of chronyd's measurements.log, ignoring the fields
+    Chrony,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            "influx" => Ok(Format::Influx),
+            "parquet" => Ok(Format::Parquet),
+            "chrony" => Ok(Format::Chrony),
+            _ => bail!(
+                "unknown output format '{}', expected csv, json, influx, parquet or chrony",
+                s
+            ),
+        }
+    }
+}
+
+/// How the t1-t4 timestamps are written
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// Seconds and nanoseconds since the Unix epoch
+    Epoch,
+    /// RFC 3339 date and time with nanoseconds; line protocol keeps epoch
+    /// timestamps, as fields are numbers there
+    Iso8601(TimeZone),
+}
+
+impl FromStr for TimeFormat {
+    type Err = anyhow::Error;
+
+    /// `epoch` or `iso8601`, the latter in UTC until a time zone is set
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "epoch" => Ok(TimeFormat::Epoch),
+            "iso8601" | "rfc3339" => Ok(TimeFormat::Iso8601(TimeZone::Utc)),
+            _ => bail!(
+                "unknown timestamp format '{}', expected epoch or iso8601",
+                s
+            ),
+        }
+    }
+}
+
+/// Time zone of ISO 8601 timestamps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeZone {
+    Utc,
+    /// Local time of the system, following its daylight saving time (Unix only)
+    Local,
+    /// Fixed offset from UTC in seconds
+    Fixed(i32),
+}
+
+impl FromStr for TimeZone {
+    type Err = anyhow::Error;
+
+    /// `utc`, `local` or an offset such as `+02:00` or `-0530`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "utc" | "UTC" | "Z" => return Ok(TimeZone::Utc),
+            "local" => {
+                if !cfg!(unix) {
+                    bail!("local time zone is only supported on Unix");
+                }
+                return Ok(TimeZone::Local);
+            }
+            _ => {}
+        }
+        let invalid = || anyhow!("invalid time zone '{}', expected utc, local or +HH:MM", s);
+        let (sign, rest) = match s.as_bytes().first() {
+            Some(b'+') => (1, &s[1..]),
+            Some(b'-') => (-1, &s[1..]),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = rest
+            .split_once(':')
+            .or_else(|| (rest.len() == 4).then(|| rest.split_at(2)))
+            .unwrap_or((rest, "0"));
+        let digits =
+            |part: &str| part.bytes().all(|b| b.is_ascii_digit()) && (1..=2).contains(&part.len());
+        if !digits(hours) || !digits(minutes) {
+            return Err(invalid());
+        }
+        let (hours, minutes): (i32, i32) = (hours.parse()?, minutes.parse()?);
+        if hours > 23 || minutes > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeZone::Fixed(sign * (hours * 3600 + minutes * 60)))
+    }
+}
+
+impl TimeZone {
+    /// Offset from UTC in seconds at `time`
+    fn offset(&self, time: Timestamp) -> i64 {
+        match self {
+            TimeZone::Utc => 0,
+            TimeZone::Local => local_offset(time.sec),
+            TimeZone::Fixed(offset) => *offset as i64,
+        }
+    }
+
+    /// `time` as an RFC 3339 timestamp, e.g. `2022-01-02T03:04:05.123456789+01:00`
+    fn format(&self, time: Timestamp) -> String {
+        match self {
+            TimeZone::Utc => time.to_rfc3339(None),
+            zone => time.to_rfc3339(Some(zone.offset(time))),
+        }
+    }
+}
+
+/// UTC offset of the local time zone at `sec` since the epoch
+#[cfg(unix)]
+fn local_offset(sec: i64) -> i64 {
+    let time = sec as libc::time_t;
+    // Safety: localtime_r() only writes the zeroed struct it is given
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        return 0;
+    }
+    tm.tm_gmtoff as i64
+}
+
+#[cfg(not(unix))]
+fn local_offset(_sec: i64) -> i64 {
+    0
+}
+
+/// Output column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Field {
+    Target,
+    Seq,
+    Lost,
+    T1,
+    T2,
+    T3,
+    T4,
+    OffsetMin,
+    OffsetMax,
+    Offset,
+    Delay,
+    Rtt,
+    ForwardDelay,
+    ReturnDelay,
+    T1Source,
+    T4Source,
+    FilteredOffset,
+    DriftPpm,
+    Flags,
+    SendDelay,
+    BurstReplies,
+    BurstRttMedian,
+    BurstRttMax,
+    OffsetEst,
+    AsymmetryCorrection,
+    KernelFreqPpm,
+    KernelMaxError,
+    KernelEstError,
+    KernelSync,
+    Address,
+    Ttl,
+    Ecn,
+}
+
+impl Field {
+    /// All fields in default output order
+    pub const ALL: &'static [Field] = &[
+        Field::Target,
+        Field::Seq,
+        Field::Lost,
+        Field::T1,
+        Field::T2,
+        Field::T3,
+        Field::T4,
+        Field::OffsetMin,
+        Field::OffsetMax,
+        Field::Offset,
+        Field::Delay,
+        Field::Rtt,
+        Field::ForwardDelay,
+        Field::ReturnDelay,
+        Field::T1Source,
+        Field::T4Source,
+        Field::FilteredOffset,
+        Field::DriftPpm,
+        Field::Flags,
+        Field::SendDelay,
+    ];
+
+    /// Per-burst statistics, appended to the default fields with `--burst`
+    pub const BURST: &'static [Field] = &[
+        Field::BurstReplies,
+        Field::BurstRttMedian,
+        Field::BurstRttMax,
+    ];
+
+    /// Kernel NTP state, appended to the default fields with `--kernel-state`
+    pub const KERNEL: &'static [Field] = &[
+        Field::KernelFreqPpm,
+        Field::KernelMaxError,
+        Field::KernelEstError,
+        Field::KernelSync,
+    ];
+
+    /// Fields added by their own options or hostname targets, besides
+    /// [`Field::BURST`] and [`Field::KERNEL`]
+    const EXTRA: &'static [Field] = &[
+        Field::OffsetEst,
+        Field::AsymmetryCorrection,
+        Field::Address,
+        Field::Ttl,
+        Field::Ecn,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Field::Target => "target",
+            Field::Seq => "seq",
+            Field::Lost => "lost",
+            Field::T1 => "t1",
+            Field::T2 => "t2",
+            Field::T3 => "t3",
+            Field::T4 => "t4",
+            Field::OffsetMin => "offset_min",
+            Field::OffsetMax => "offset_max",
+            Field::Offset => "offset",
+            Field::Delay => "delay",
+            Field::Rtt => "rtt",
+            Field::ForwardDelay => "forward_delay",
+            Field::ReturnDelay => "return_delay",
+            Field::T1Source => "t1_source",
+            Field::T4Source => "t4_source",
+            Field::FilteredOffset => "filtered_offset",
+            Field::DriftPpm => "drift_ppm",
+            Field::Flags => "flags",
+            Field::SendDelay => "send_delay",
+            Field::BurstReplies => "burst_replies",
+            Field::BurstRttMedian => "burst_rtt_median",
+            Field::BurstRttMax => "burst_rtt_max",
+            Field::OffsetEst => "offset_est",
+            Field::AsymmetryCorrection => "asymmetry_correction",
+            Field::KernelFreqPpm => "kernel_freq_ppm",
+            Field::KernelMaxError => "kernel_maxerror",
+            Field::KernelEstError => "kernel_esterror",
+            Field::KernelSync => "kernel_sync",
+            Field::Address => "address",
+            Field::Ttl => "ttl",
+            Field::Ecn => "ecn",
+        }
+    }
+
+    /// Column type in Parquet files
+    fn kind(&self) -> Kind {
+        match self {
+            Field::Target
+            | Field::T1Source
+            | Field::T4Source
+            | Field::Flags
+            | Field::KernelSync
+            | Field::Address
+            | Field::Ecn => Kind::Text,
+            Field::Seq | Field::Lost | Field::BurstReplies | Field::Ttl => Kind::Count,
+            Field::T1 | Field::T2 | Field::T3 | Field::T4 => Kind::Time,
+            _ => Kind::Double,
+        }
+    }
+
+    fn all() -> impl Iterator<Item = &'static Field> {
+        Field::ALL
+            .iter()
+            .chain(Field::BURST)
+            .chain(Field::EXTRA)
+            .chain(Field::KERNEL)
+    }
+
+    fn value(&self, target: &str, sample: &Sample) -> Value {
+        let m = &sample.measurement;
+        match self {
+            Field::Target => Value::Text(target.to_owned()),
+            Field::Seq => Value::Count(m.seq),
+            Field::Lost => Value::Count(m.lost),
+            Field::T1 => Value::Time(m.t1),
+            Field::T2 => Value::Time(m.t2),
+            Field::T3 => Value::Time(m.t3),
+            Field::T4 => Value::Time(m.t4),
+            Field::OffsetMin => Value::Seconds(m.offset_min),
+            Field::OffsetMax => Value::Seconds(m.offset_max),
+            Field::Offset => Value::Seconds(m.offset),
+            Field::Delay => Value::Seconds(m.delay),
+            Field::Rtt => Value::Seconds(m.rtt),
+            Field::ForwardDelay => Value::Seconds(m.forward_delay),
+            Field::ReturnDelay => Value::Seconds(m.return_delay),
+            Field::T1Source => Value::Text(m.t1_source.to_string()),
+            Field::T4Source => Value::Text(m.t4_source.to_string()),
+            Field::FilteredOffset => Value::Seconds(sample.filtered_offset),
+            Field::DriftPpm => sample.drift_ppm.map_or(Value::Missing, Value::Ppm),
+            Field::Flags => Value::Markers(sample.flags.markers()),
+            Field::SendDelay => Value::Seconds(m.send_delay),
+            Field::BurstReplies => m
+                .burst
+                .map_or(Value::Missing, |b| Value::Count(b.received as u64)),
+            Field::BurstRttMedian => m
+                .burst
+                .map_or(Value::Missing, |b| Value::Seconds(b.rtt_median)),
+            Field::BurstRttMax => m
+                .burst
+                .map_or(Value::Missing, |b| Value::Seconds(b.rtt_max)),
+            Field::OffsetEst => sample.offset_est.map_or(Value::Missing, Value::Seconds),
+            Field::AsymmetryCorrection => Value::Seconds(m.asymmetry_correction),
+            Field::KernelFreqPpm => sample
+                .kernel
+                .map_or(Value::Missing, |k| Value::Ppm(k.freq_ppm)),
+            Field::KernelMaxError => sample
+                .kernel
+                .map_or(Value::Missing, |k| Value::Seconds(k.maxerror)),
+            Field::KernelEstError => sample
+                .kernel
+                .map_or(Value::Missing, |k| Value::Seconds(k.esterror)),
+            Field::KernelSync => sample.kernel.map_or(Value::Missing, |k| {
+                Value::Text(if k.synced { "synced" } else { "unsynced" }.to_owned())
+            }),
+            Field::Address => m
+                .remote
+                .map_or(Value::Missing, |addr| Value::Text(addr.to_string())),
+            Field::Ttl => m.ttl.map_or(Value::Missing, |ttl| Value::Count(ttl.into())),
+            Field::Ecn => m
+                .ecn
+                .map_or(Value::Missing, |ecn| Value::Text(ecn.to_string())),
+        }
+    }
+
+    /// Value for a lost probe: only what is known without a reply
+    fn lost_value(&self, target: &str, probe: &LostProbe) -> Value {
+        match self {
+            Field::Target => Value::Text(target.to_owned()),
+            Field::Seq => Value::Count(probe.seq),
+            Field::Lost => Value::Count(probe.lost),
+            Field::T1 => Value::Time(probe.t1),
+            Field::Flags => Value::Markers(vec!["lost"]),
+            Field::SendDelay => Value::Seconds(probe.send_delay),
+            _ => Value::Missing,
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = anyhow::Error;
+
+    /// Field by its column name
+    fn from_str(s: &str) -> Result<Self> {
+        Field::all()
+            .find(|field| field.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<_> = Field::all().map(Field::name).collect();
+                anyhow!(
+                    "unknown field '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Typed field value, rendered per output format
+enum Value {
+    Text(String),
+    Count(u64),
+    Time(Timestamp),
+    Seconds(f64),
+    Ppm(f64),
+    Markers(Vec<&'static str>),
+    Missing,
+}
+
+impl Value {
+    fn to_csv(&self) -> String {
+        match self {
+            Value::Text(text) => text.clone(),
+            Value::Count(count) => count.to_string(),
+            Value::Time(time) => time.to_string(),
+            Value::Seconds(seconds) => format!("{:.9}", seconds),
+            Value::Ppm(ppm) => format!("{:.3}", ppm),
+            Value::Markers(markers) => markers.join("|"),
+            Value::Missing => String::new(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        match self {
+            Value::Text(text) => serde_json::Value::from(text.as_str()).to_string(),
+            Value::Markers(markers) => serde_json::Value::from(markers.clone()).to_string(),
+            Value::Missing => "null".to_owned(),
+            // Timestamps are written as exact decimal numbers
+            value => value.to_csv(),
+        }
+    }
+
+    fn to_cell(&self) -> Cell {
+        match self {
+            Value::Text(text) => Cell::Text(text.clone()),
+            Value::Markers(markers) => Cell::Text(markers.join("|")),
+            Value::Count(count) => Cell::Int(*count as i64),
+            // Nanoseconds fit until 2262
+            Value::Time(time) => Cell::Int(time.total_nsec() as i64),
+            Value::Seconds(value) | Value::Ppm(value) => Cell::Double(*value),
+            Value::Missing => Cell::Null,
+        }
+    }
+
+    /// Line protocol field value, `None` if the field is to be left out
+    fn to_influx(&self) -> Option<String> {
+        match self {
+            Value::Text(text) => Some(influx_string(text)),
+            Value::Markers(markers) => Some(influx_string(&markers.join("|"))),
+            Value::Count(count) => Some(format!("{}i", count)),
+            Value::Missing => None,
+            value => Some(value.to_csv()),
+        }
+    }
+}
+
+/// JSON object of a sample with `fields`, as written by the json format
+/// with epoch timestamps
+pub fn sample_json(fields: &[Field], target: &str, sample: &Sample) -> String {
+    json_object(fields, |field| field.value(target, sample))
+}
+
+fn json_object(fields: &[Field], value: impl Fn(Field) -> Value) -> String {
+    let members: Vec<_> = fields
+        .iter()
+        .map(|field| format!("\"{}\":{}", field.name(), value(*field).to_json()))
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+/// Default separator of CSV columns
+const CSV_DELIMITER: &str = ", ";
+
+/// Line protocol measurement name
+const INFLUX_MEASUREMENT: &str = "clock_offset";
+
+/// Banner of chronyd's measurements.log, between lines of `=`
+const CHRONY_BANNER: &str = "   Date (UTC) Time     IP Address   L St 123 567 ABCD  LP RP Score    Offset  Peer del. Peer disp.  Root del. Root disp. Refid     MTxRx";
+/// Lines after which chronyd repeats the banner
+const CHRONY_BANNER_LINES: u64 = 32;
+
+/// measurements.log line of a sample, with `poll` the log2 of the probe
+/// interval in seconds
+///
+/// The target is taken as a stratum 1 server in basic mode 4 with an unknown
+/// reference id. The offset is that of the target to the local clock, as
+/// chronyd logs it. Dispersion is not estimated and written as zero, and
+/// discarded samples fail test 5, the delay test.
+fn chrony_line(target: &str, sample: &Sample, poll: i32) -> String {
+    let m = &sample.measurement;
+    let t = DateTime::from_unix(m.t4.sec);
+    let address = target
+        .parse::<SocketAddr>()
+        .map_or_else(|_| target.to_owned(), |addr| addr.ip().to_string());
+    let source = |source| match source {
+        TimestampSource::Userspace => 'D',
+        TimestampSource::Kernel => 'K',
+        TimestampSource::Hardware => 'H',
+    };
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {:<15} N  1 111 {}11 1111  {:2} {:2} 1.00 {} {} {} {} {} 00000000 4B {} {}",
+        t.year,
+        t.month,
+        t.day,
+        t.hour,
+        t.minute,
+        t.second,
+        address,
+        u8::from(!sample.flags.discarded),
+        poll,
+        poll,
+        c_exponent(-m.offset),
+        c_exponent(m.delay),
+        c_exponent(0.0),
+        c_exponent(m.delay),
+        c_exponent(0.0),
+        source(m.t1_source),
+        source(m.t4_source)
+    )
+}
+
+/// `value` as printed by C's `%10.3e`, with at least two exponent digits
+fn c_exponent(value: f64) -> String {
+    let formatted = format!("{:.3e}", value);
+    let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let sign = if exponent < 0 { '-' } else { '+' };
+    let text = format!("{}e{}{:02}", mantissa, sign, exponent.abs());
+    format!("{:>10}", text)
+}
+
+fn influx_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn influx_tag(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// Name of the local host for the `host` tag
+#[cfg(unix)]
+pub(crate) fn hostname() -> String {
+    nix::unistd::gethostname()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_owned())
+}
+
+/// Writes samples in the configured format
+enum Destination {
+    Stream(Box<dyn Write + Send>),
+    /// CSV header is repeated at the start of every file
+    File(RotatingFile),
+    /// Created with the columns of the first line written
+    Parquet {
+        path: PathBuf,
+        file: Option<ParquetFile>,
+    },
+}
+
+pub struct OutputWriter {
+    format: Format,
+    fields: Vec<Field>,
+    time_format: TimeFormat,
+    /// Separator of CSV columns
+    delimiter: String,
+    /// Whether CSV output starts with a header line
+    header: bool,
+    out: Destination,
+    header_pending: bool,
+    /// `host` tag of line protocol output
+    host: String,
+    /// Lines written since the chrony banner
+    banner_lines: u64,
+    /// Sequence number and `t1` of the latest sample of every target, for
+    /// the chrony poll interval
+    previous: HashMap<String, (u64, Timestamp)>,
+}
+
+impl OutputWriter {
+    pub fn new(format: Format, out: Box<dyn Write + Send>) -> Self {
+        Self::with_destination(format, Destination::Stream(out))
+    }
+
+    pub fn stdout(format: Format) -> Self {
+        Self::new(format, Box::new(io::stdout()))
+    }
+
+    /// Append to the file at `path`, rotating it as requested; Parquet files
+    /// are replaced and not rotated
+    pub fn file(format: Format, path: &Path, rotation: Rotation) -> Result<Self> {
+        if format == Format::Parquet {
+            if rotation != Rotation::Never {
+                bail!("Parquet output can not be rotated");
+            }
+            let path = path.to_owned();
+            return Ok(Self::with_destination(
+                format,
+                Destination::Parquet { path, file: None },
+            ));
+        }
+        let file = RotatingFile::open(path, rotation)?;
+        Ok(Self::with_destination(format, Destination::File(file)))
+    }
+
+    /// Write these fields instead of [`Field::ALL`]
+    pub fn set_fields(&mut self, fields: Vec<Field>) {
+        self.fields = fields;
+    }
+
+    /// Write timestamps in `format` instead of [`TimeFormat::Epoch`]
+    pub fn set_time_format(&mut self, format: TimeFormat) {
+        self.time_format = format;
+    }
+
+    /// Separate CSV columns with `delimiter` instead of `, `
+    pub fn set_delimiter(&mut self, delimiter: String) {
+        self.delimiter = delimiter;
+    }
+
+    /// Leave out the CSV header line
+    pub fn no_header(&mut self) {
+        self.header = false;
+        self.header_pending = false;
+    }
+
+    fn with_destination(format: Format, out: Destination) -> Self {
+        Self {
+            format,
+            fields: Field::ALL.to_vec(),
+            time_format: TimeFormat::Epoch,
+            delimiter: CSV_DELIMITER.to_owned(),
+            header: true,
+            out,
+            header_pending: matches!(format, Format::Csv | Format::Chrony),
+            host: if format == Format::Influx {
+                hostname()
+            } else {
+                String::new()
+            },
+            banner_lines: 0,
+            previous: HashMap::new(),
+        }
+    }
+
+    pub fn write_sample(&mut self, target: &str, sample: &Sample) -> io::Result<()> {
+        if self.format == Format::Chrony {
+            let poll = self.poll(target, &sample.measurement);
+            return self.write_text(&chrony_line(target, sample, poll));
+        }
+        self.write_line(
+            target,
+            |field| field.value(target, sample),
+            sample.measurement.t4,
+        )
+    }
+
+    /// Write a line for a probe that timed out, leaving the reply fields empty
+    pub fn write_lost(&mut self, target: &str, probe: &LostProbe) -> io::Result<()> {
+        // chronyd does not log lost packets either
+        if self.format == Format::Chrony {
+            return Ok(());
+        }
+        self.write_line(target, |field| field.lost_value(target, probe), probe.t1)
+    }
+
+    /// Write one line with `value` of every field, `time` being the line protocol timestamp
+    fn write_line(
+        &mut self,
+        target: &str,
+        value: impl Fn(Field) -> Value,
+        time: Timestamp,
+    ) -> io::Result<()> {
+        if let Destination::Parquet { path, file } = &mut self.out {
+            let file = match file {
+                Some(file) => file,
+                None => {
+                    let columns: Vec<_> =
+                        self.fields.iter().map(|f| (f.name(), f.kind())).collect();
+                    file.insert(ParquetFile::create(path, &columns)?)
+                }
+            };
+            let row = self.fields.iter().map(|f| value(*f).to_cell()).collect();
+            return file.push(row);
+        }
+
+        let value = |field: Field| match (value(field), self.time_format) {
+            (Value::Time(time), TimeFormat::Iso8601(zone)) if self.format != Format::Influx => {
+                Value::Text(zone.format(time))
+            }
+            (value, _) => value,
+        };
+
+        let line = match self.format {
+            Format::Csv => self
+                .fields
+                .iter()
+                .map(|field| value(*field).to_csv())
+                .collect::<Vec<_>>()
+                .join(&self.delimiter),
+            Format::Json => json_object(&self.fields, value),
+            Format::Influx => {
+                let fields: Vec<_> = self
+                    .fields
+                    .iter()
+                    .filter(|&&field| field != Field::Target)
+                    .filter_map(|field| {
+                        let value = value(*field).to_influx()?;
+                        Some(format!("{}={}", field.name(), value))
+                    })
+                    .collect();
+                format!(
+                    "{},host={},target={} {} {}",
+                    INFLUX_MEASUREMENT,
+                    influx_tag(&self.host),
+                    influx_tag(target),
+                    fields.join(","),
+                    time.total_nsec()
+                )
+            }
+            Format::Parquet => unreachable!("Parquet output to a stream"),
+            Format::Chrony => unreachable!("chrony output of the fields"),
+        };
+        self.write_text(&line)
+    }
+
+    /// Write `line` to the stream or file, after the header if it is due
+    fn write_text(&mut self, line: &str) -> io::Result<()> {
+        let out: &mut dyn Write = match &mut self.out {
+            Destination::Stream(stream) => stream,
+            Destination::File(file) => {
+                let new_file = file.prepare_write()? && self.header;
+                match self.format {
+                    Format::Csv => self.header_pending = new_file,
+                    // chronyd writes the banner to appended files too
+                    Format::Chrony => self.header_pending |= new_file,
+                    _ => {}
+                }
+                file
+            }
+            Destination::Parquet { .. } => unreachable!("text output to a Parquet file"),
+        };
+
+        if self.header_pending {
+            match self.format {
+                Format::Chrony => {
+                    let rule = "=".repeat(CHRONY_BANNER.len());
+                    writeln!(out, "{}\n{}\n{}", rule, CHRONY_BANNER, rule)?;
+                    self.banner_lines = 0;
+                }
+                _ => {
+                    let names: Vec<_> = self.fields.iter().map(Field::name).collect();
+                    writeln!(out, "{}", names.join(&self.delimiter))?;
+                }
+            }
+            self.header_pending = false;
+        }
+
+        writeln!(out, "{}", line)?;
+        if self.format == Format::Chrony && self.header {
+            self.banner_lines += 1;
+            self.header_pending = self.banner_lines == CHRONY_BANNER_LINES;
+        }
+        out.flush()
+    }
+
+    /// log2 of the probe interval of `target` in seconds, from the time
+    /// since its previous sample, 0 for the first one
+    fn poll(&mut self, target: &str, m: &Measurement) -> i32 {
+        let poll = match self.previous.get(target) {
+            Some(&(seq, t1)) if m.seq > seq => {
+                let interval = (m.t1.total_nsec() - t1.total_nsec()) as f64 * 1e-9;
+                (interval / (m.seq - seq) as f64).log2().round() as i32
+            }
+            _ => 0,
+        };
+        self.previous.insert(target.to_owned(), (m.seq, m.t1));
+        poll
+    }
+}
+
+impl Drop for OutputWriter {
+    /// Parquet files are only readable once closed
+    fn drop(&mut self) {
+        if let Destination::Parquet {
+            path,
+            file: Some(file),
+        } = &mut self.out
+        {
+            if let Err(e) = file.close() {
+                warn!("Closing {} failed: {}", path.display(), e);
+            }
+        }
+    }
+}
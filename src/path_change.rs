@@ -0,0 +1,70 @@
+use crate::measurement::Measurement;
+use std::collections::VecDeque;
+
+/// Samples in each of the two windows whose minimum delays are compared
+const WINDOW: usize = 8;
+
+/// Detects changes of the network path, which shift the delays and their
+/// asymmetry and so the offset, without either clock stepping
+#[derive(Debug)]
+pub struct PathChangeDetector {
+    delay_threshold: Option<f64>,
+    last_ttl: Option<u8>,
+    /// Delays of the recent samples, the older window first
+    delays: VecDeque<f64>,
+}
+
+impl PathChangeDetector {
+    /// Flag replies arriving with another TTL than the previous one, and
+    /// minimum delay changes of more than `delay_threshold` seconds if given
+    pub fn new(delay_threshold: Option<f64>) -> Self {
+        Self {
+            delay_threshold,
+            last_ttl: None,
+            delays: VecDeque::new(),
+        }
+    }
+
+    /// Whether the path apparently changed between the previous sample and `m`
+    ///
+    /// The minimum delay of the last samples is compared with that of the
+    /// samples before them: a lower one shows right away, a higher one once
+    /// a whole window of samples has it. The delays are learned anew after
+    /// a change.
+    pub fn is_change(&mut self, m: &Measurement) -> bool {
+        let ttl_change = match (self.last_ttl, m.ttl) {
+            (Some(last), Some(ttl)) => ttl != last,
+            _ => false,
+        };
+        if m.ttl.is_some() {
+            self.last_ttl = m.ttl;
+        }
+
+        if self.delays.len() == 2 * WINDOW {
+            self.delays.pop_front();
+        }
+        self.delays.push_back(m.delay);
+        let delay_change = self.delay_threshold.is_some_and(|threshold| {
+            self.delays.len() == 2 * WINDOW && {
+                let before = self
+                    .delays
+                    .range(..WINDOW)
+                    .copied()
+                    .fold(f64::INFINITY, f64::min);
+                let recent = self
+                    .delays
+                    .range(WINDOW..)
+                    .copied()
+                    .fold(f64::INFINITY, f64::min);
+                (recent - before).abs() > threshold
+            }
+        });
+
+        let change = ttl_change || delay_change;
+        if change {
+            self.delays.clear();
+            self.delays.push_back(m.delay);
+        }
+        change
+    }
+}
@@ -0,0 +1,81 @@
+//! Adaptive probe interval, in the spirit of NTP poll adjustment
+
+use crate::{clock::Timestamp, measurement::Measurement};
+use tokio::time::Duration;
+
+/// Consecutive consistent measurements needed before the interval doubles
+const STABLE_SAMPLES: i32 = 4;
+/// Deviations from the prediction beyond this many uncertainties count as a step
+const STEP_THRESHOLD: f64 = 4.0;
+
+/// Doubles the interval while offsets follow their trend within their
+/// uncertainty, falls back to the minimum on steps and halves it on loss
+#[derive(Debug)]
+pub struct PollAdapter {
+    min: Duration,
+    max: Duration,
+    current: Duration,
+    /// Positive: consecutive consistent samples, negative: inconsistent ones
+    score: i32,
+    lost: u64,
+    /// Time and offset of the last measurement
+    last: Option<(Timestamp, f64)>,
+    /// Offset change per second between the last two measurements
+    slope: f64,
+}
+
+impl PollAdapter {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max: max.max(min),
+            current: min,
+            score: 0,
+            lost: 0,
+            last: None,
+            slope: 0.0,
+        }
+    }
+
+    /// Interval until the next probe
+    pub fn interval(&self) -> Duration {
+        self.current
+    }
+
+    pub fn update(&mut self, m: &Measurement) {
+        if m.lost > self.lost {
+            self.lost = m.lost;
+            self.score = 0;
+            self.current = (self.current / 2).max(self.min);
+        }
+
+        let Some((time, offset)) = self.last.replace((m.t1, m.offset)) else {
+            return;
+        };
+        let dt = (m.t1.total_nsec() - time.total_nsec()) as f64 * 1e-9;
+        if dt <= 0.0 {
+            return;
+        }
+        let deviation = (m.offset - (offset + self.slope * dt)).abs();
+        self.slope = (m.offset - offset) / dt;
+        // Half the round trip bounds the offset error from path asymmetry
+        let uncertainty = m.rtt / 2.0;
+
+        if deviation > STEP_THRESHOLD * uncertainty {
+            self.score = 0;
+            self.current = self.min;
+        } else if deviation <= uncertainty {
+            self.score = self.score.max(0) + 1;
+            if self.score >= STABLE_SAMPLES {
+                self.score = 0;
+                self.current = (self.current * 2).min(self.max);
+            }
+        } else {
+            self.score = self.score.min(0) - 1;
+            if self.score <= -STABLE_SAMPLES {
+                self.score = 0;
+                self.current = (self.current / 2).max(self.min);
+            }
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! Wire format
+//!
+//! Every packet starts with a header of magic bytes, protocol version,
+//! packet type and flags. Integers and timestamps are little-endian.
+
+use crate::clock::Timestamp;
+use anyhow::{anyhow, bail, ensure, Result};
+use std::{fmt, str::FromStr};
+
+pub const MAGIC: [u8; 4] = *b"CLKO";
+pub const VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 8;
+/// Position of the flags byte in the header
+pub const FLAGS_OFFSET: usize = 6;
+/// The packet is followed by a MAC, see [`crate::auth`]
+pub const FLAG_AUTHENTICATED: u8 = 1;
+/// The packet is padded, see [`pad`]
+pub const FLAG_PADDED: u8 = 2;
+/// The probe asks for a reply padded to its own size
+pub const FLAG_PAD_REPLY: u8 = 4;
+/// Everything after the header is encrypted, see [`crate::auth`]; set along
+/// with [`FLAG_AUTHENTICATED`]
+pub const FLAG_ENCRYPTED: u8 = 8;
+/// The probe or reply ends with a random nonce, see [`Probe::nonce`]
+pub const FLAG_NONCE: u8 = 16;
+pub const NONCE_SIZE: usize = 8;
+/// The probe carries a client ID, see [`ClientId`]
+pub const FLAG_CLIENT_ID: u8 = 32;
+pub const MAX_CLIENT_ID_SIZE: usize = 32;
+
+/// Probe: header, sequence number and the local send time, optionally followed by a cookie
+pub const PAYLOAD_SIZE: usize = HEADER_SIZE + 24;
+/// Reply: the probe fields followed by the reflector receive and transmit times
+pub const REFLECTED_PAYLOAD_SIZE: usize = PAYLOAD_SIZE + 32;
+/// Challenge: header, probe sequence number and a cookie to echo in further probes
+pub const CHALLENGE_SIZE: usize = HEADER_SIZE + 8 + COOKIE_SIZE;
+pub const COOKIE_SIZE: usize = 16;
+/// Discovery request and announcement: header, the nonce of the request,
+/// the probe port and the version of the reflector, so that requests are
+/// as large as the announcements they draw
+pub const DISCOVERY_SIZE: usize = HEADER_SIZE + 8 + 2 + MAX_VERSION_SIZE;
+pub const MAX_VERSION_SIZE: usize = 16;
+
+/// Proof of return reachability handed out by a reflector
+pub type Cookie = [u8; COOKIE_SIZE];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum PacketType {
+    Probe = 1,
+    Reply = 2,
+    Challenge = 3,
+    Discover = 4,
+    Announce = 5,
+}
+
+/// Decoded probe
+#[derive(Clone, Copy, Debug)]
+pub struct Probe {
+    pub seq: u64,
+    pub t1: Timestamp,
+    /// Cookie from the last challenge of the reflector
+    pub cookie: Option<Cookie>,
+    /// Ask for the reply to be padded to the size of the probe
+    pub pad_reply: bool,
+    /// Random value echoed in the reply, so that off-path hosts can not fake
+    /// replies by guessing the sequence number and the port alone
+    pub nonce: Option<u64>,
+    /// Name of the client for the access log of the reflector; not echoed
+    pub client_id: Option<ClientId>,
+}
+
+/// Name a client gives itself in its probes: up to [`MAX_CLIENT_ID_SIZE`]
+/// bytes of UTF-8 without whitespace or control characters
+///
+/// It travels after the cookie as its bytes followed by their count.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientId {
+    len: u8,
+    bytes: [u8; MAX_CLIENT_ID_SIZE],
+}
+
+impl ClientId {
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a valid string
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl FromStr for ClientId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ensure!(
+            !s.is_empty() && s.len() <= MAX_CLIENT_ID_SIZE,
+            "client ID must have 1 to {} bytes",
+            MAX_CLIENT_ID_SIZE
+        );
+        ensure!(
+            !s.chars().any(|c| c.is_whitespace() || c.is_control()),
+            "client ID can not have whitespace or control characters"
+        );
+        let mut bytes = [0; MAX_CLIENT_ID_SIZE];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(Self {
+            len: s.len() as u8,
+            bytes,
+        })
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Decoded reply
+#[derive(Clone, Copy, Debug)]
+pub struct Reply {
+    pub probe: Probe,
+    pub t2: Timestamp,
+    pub t3: Timestamp,
+}
+
+/// Answer to a probe from a source that has not proven it receives replies
+///
+/// It is no larger than the probe, so a spoofed source can not be used
+/// for amplification.
+#[derive(Clone, Copy, Debug)]
+pub struct Challenge {
+    /// Sequence number of the challenged probe
+    pub seq: u64,
+    pub cookie: Cookie,
+}
+
+fn encode_header(buf: &mut Vec<u8>, packet_type: PacketType) {
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.push(packet_type as u8);
+    buf.push(0); // flags
+    buf.push(0); // reserved
+}
+
+fn decode_header(buf: &[u8], packet_type: PacketType) -> Result<()> {
+    ensure!(
+        buf.len() >= HEADER_SIZE && buf[..4] == MAGIC,
+        "not a clock-offset packet"
+    );
+    ensure!(buf[4] == VERSION, "unsupported protocol version {}", buf[4]);
+    ensure!(
+        buf[5] == packet_type as u8,
+        "unexpected packet type {}",
+        buf[5]
+    );
+    Ok(())
+}
+
+fn ensure_size(buf: &[u8], size: usize) -> Result<()> {
+    ensure!(
+        !is_authenticated(buf) || buf.len() != size + crate::auth::MAC_SIZE,
+        "authenticated packet, but no key configured"
+    );
+    ensure!(buf.len() == size, "payload size {} != {}", buf.len(), size);
+    Ok(())
+}
+
+fn encode_probe_fields(buf: &mut Vec<u8>, probe: &Probe) {
+    buf.extend_from_slice(&probe.seq.to_le_bytes());
+    buf.extend_from_slice(&probe.t1.to_le_bytes());
+}
+
+fn decode_probe_fields(buf: &[u8]) -> Result<Probe> {
+    Ok(Probe {
+        seq: u64::from_le_bytes(buf[..8].try_into()?),
+        t1: Timestamp::from_le_bytes(&buf[8..24])?,
+        cookie: None,
+        pad_reply: false,
+        nonce: None,
+        client_id: None,
+    })
+}
+
+/// Append the nonce, if any, flagging it in the header
+fn encode_nonce(buf: &mut Vec<u8>, nonce: Option<u64>) {
+    if let Some(nonce) = nonce {
+        buf[FLAGS_OFFSET] |= FLAG_NONCE;
+        buf.extend_from_slice(&nonce.to_le_bytes());
+    }
+}
+
+/// `buf` without the nonce at its end and the nonce, if flagged; the padding
+/// must be stripped first
+fn split_nonce(buf: &[u8]) -> Result<(&[u8], Option<u64>)> {
+    if buf[FLAGS_OFFSET] & FLAG_NONCE == 0 {
+        return Ok((buf, None));
+    }
+    ensure!(
+        buf.len() >= HEADER_SIZE + NONCE_SIZE,
+        "packet with a nonce too short"
+    );
+    let (buf, nonce) = buf.split_at(buf.len() - NONCE_SIZE);
+    Ok((buf, Some(u64::from_le_bytes(nonce.try_into()?))))
+}
+
+/// `buf` without the client ID at its end and the client ID, if flagged; the
+/// nonce must be stripped first
+fn split_client_id(buf: &[u8]) -> Result<(&[u8], Option<ClientId>)> {
+    if buf[FLAGS_OFFSET] & FLAG_CLIENT_ID == 0 {
+        return Ok((buf, None));
+    }
+    let len = match buf.last() {
+        Some(&len) if buf.len() > HEADER_SIZE + len as usize => len as usize,
+        _ => bail!("packet with a client ID too short"),
+    };
+    let (buf, id) = buf[..buf.len() - 1].split_at(buf.len() - 1 - len);
+    let id = std::str::from_utf8(id).map_err(|_| anyhow!("client ID not UTF-8"))?;
+    Ok((buf, Some(id.parse()?)))
+}
+
+pub fn encode_probe(probe: &Probe) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(PAYLOAD_SIZE + COOKIE_SIZE);
+    encode_header(&mut buf, PacketType::Probe);
+    if probe.pad_reply {
+        buf[FLAGS_OFFSET] |= FLAG_PAD_REPLY;
+    }
+    encode_probe_fields(&mut buf, probe);
+    if let Some(cookie) = &probe.cookie {
+        buf.extend_from_slice(cookie);
+    }
+    if let Some(id) = &probe.client_id {
+        buf[FLAGS_OFFSET] |= FLAG_CLIENT_ID;
+        buf.extend_from_slice(id.as_str().as_bytes());
+        buf.push(id.len);
+    }
+    encode_nonce(&mut buf, probe.nonce);
+    buf
+}
+
+pub fn decode_probe(buf: &[u8]) -> Result<Probe> {
+    decode_header(buf, PacketType::Probe)?;
+    let (buf, nonce) = split_nonce(unpad(buf)?)?;
+    let (buf, client_id) = split_client_id(buf)?;
+    let mut probe = if buf.len() == PAYLOAD_SIZE + COOKIE_SIZE {
+        let mut probe = decode_probe_fields(&buf[HEADER_SIZE..])?;
+        probe.cookie = Some(buf[PAYLOAD_SIZE..].try_into()?);
+        probe
+    } else {
+        ensure_size(buf, PAYLOAD_SIZE)?;
+        decode_probe_fields(&buf[HEADER_SIZE..])?
+    };
+    probe.pad_reply = buf[FLAGS_OFFSET] & FLAG_PAD_REPLY != 0;
+    probe.nonce = nonce;
+    probe.client_id = client_id;
+    Ok(probe)
+}
+
+pub fn encode_reply(reply: &Reply) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(REFLECTED_PAYLOAD_SIZE);
+    encode_header(&mut buf, PacketType::Reply);
+    encode_probe_fields(&mut buf, &reply.probe);
+    buf.extend_from_slice(&reply.t2.to_le_bytes());
+    buf.extend_from_slice(&reply.t3.to_le_bytes());
+    encode_nonce(&mut buf, reply.probe.nonce);
+    buf
+}
+
+pub fn decode_reply(buf: &[u8]) -> Result<Reply> {
+    decode_header(buf, PacketType::Reply)?;
+    let (buf, nonce) = split_nonce(unpad(buf)?)?;
+    ensure_size(buf, REFLECTED_PAYLOAD_SIZE)?;
+    let fields = &buf[HEADER_SIZE..];
+    Ok(Reply {
+        probe: Probe {
+            nonce,
+            ..decode_probe_fields(fields)?
+        },
+        t2: Timestamp::from_le_bytes(&fields[24..40])?,
+        t3: Timestamp::from_le_bytes(&fields[40..56])?,
+    })
+}
+
+pub fn encode_challenge(challenge: &Challenge) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(CHALLENGE_SIZE);
+    encode_header(&mut buf, PacketType::Challenge);
+    buf.extend_from_slice(&challenge.seq.to_le_bytes());
+    buf.extend_from_slice(&challenge.cookie);
+    buf
+}
+
+pub fn decode_challenge(buf: &[u8]) -> Result<Challenge> {
+    decode_header(buf, PacketType::Challenge)?;
+    ensure_size(buf, CHALLENGE_SIZE)?;
+    Ok(Challenge {
+        seq: u64::from_le_bytes(buf[HEADER_SIZE..HEADER_SIZE + 8].try_into()?),
+        cookie: buf[HEADER_SIZE + 8..].try_into()?,
+    })
+}
+
+/// Reflector answering a discovery request, see [`crate::discovery`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Announcement {
+    /// Nonce of the request
+    pub nonce: u64,
+    /// Port the reflector takes probes on
+    pub port: u16,
+    /// Version of the reflector software, at most [`MAX_VERSION_SIZE`] bytes
+    pub version: String,
+}
+
+/// Discovery request with a `nonce` for the announcements to echo
+pub fn encode_discover(nonce: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(DISCOVERY_SIZE);
+    encode_header(&mut buf, PacketType::Discover);
+    buf.extend_from_slice(&nonce.to_le_bytes());
+    buf.resize(DISCOVERY_SIZE, 0);
+    buf
+}
+
+/// Nonce of a discovery request
+pub fn decode_discover(buf: &[u8]) -> Result<u64> {
+    decode_header(buf, PacketType::Discover)?;
+    ensure_size(buf, DISCOVERY_SIZE)?;
+    Ok(u64::from_le_bytes(
+        buf[HEADER_SIZE..HEADER_SIZE + 8].try_into()?,
+    ))
+}
+
+/// Announcement, the version cut to [`MAX_VERSION_SIZE`] bytes
+pub fn encode_announcement(announcement: &Announcement) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(DISCOVERY_SIZE);
+    encode_header(&mut buf, PacketType::Announce);
+    buf.extend_from_slice(&announcement.nonce.to_le_bytes());
+    buf.extend_from_slice(&announcement.port.to_le_bytes());
+    let version = announcement.version.as_bytes();
+    buf.extend_from_slice(&version[..version.len().min(MAX_VERSION_SIZE)]);
+    buf.resize(DISCOVERY_SIZE, 0);
+    buf
+}
+
+pub fn decode_announcement(buf: &[u8]) -> Result<Announcement> {
+    decode_header(buf, PacketType::Announce)?;
+    ensure_size(buf, DISCOVERY_SIZE)?;
+    let fields = &buf[HEADER_SIZE..];
+    let version = &fields[10..];
+    let len = version
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(version.len());
+    Ok(Announcement {
+        nonce: u64::from_le_bytes(fields[..8].try_into()?),
+        port: u16::from_le_bytes(fields[8..10].try_into()?),
+        version: String::from_utf8_lossy(&version[..len]).into_owned(),
+    })
+}
+
+/// Original headerless format: a 16-byte probe carrying only `t1`, answered
+/// with the probe followed by the reflector receive time
+pub mod legacy {
+    use super::ensure_size;
+    use crate::clock::Timestamp;
+    use anyhow::Result;
+
+    pub const PAYLOAD_SIZE: usize = 16;
+    pub const REFLECTED_PAYLOAD_SIZE: usize = 32;
+
+    pub fn encode_probe(t1: Timestamp) -> [u8; PAYLOAD_SIZE] {
+        t1.to_le_bytes()
+    }
+
+    pub fn decode_probe(buf: &[u8]) -> Result<Timestamp> {
+        ensure_size(buf, PAYLOAD_SIZE)?;
+        Timestamp::from_le_bytes(buf)
+    }
+
+    pub fn encode_reply(t1: Timestamp, t2: Timestamp) -> [u8; REFLECTED_PAYLOAD_SIZE] {
+        let mut buf = [0; REFLECTED_PAYLOAD_SIZE];
+        buf[..16].copy_from_slice(&t1.to_le_bytes());
+        buf[16..].copy_from_slice(&t2.to_le_bytes());
+        buf
+    }
+
+    /// Decode a reply into `(t1, t2)`
+    pub fn decode_reply(buf: &[u8]) -> Result<(Timestamp, Timestamp)> {
+        ensure_size(buf, REFLECTED_PAYLOAD_SIZE)?;
+        Ok((
+            Timestamp::from_le_bytes(&buf[..16])?,
+            Timestamp::from_le_bytes(&buf[16..])?,
+        ))
+    }
+}
+
+/// Pad `packet` with zeros to `size` bytes, if it is shorter by enough to end
+/// with the `u16` padding length, which counts itself
+///
+/// The padding goes before the MAC, so packets are padded before being signed.
+pub fn pad(mut packet: Vec<u8>, size: usize) -> Vec<u8> {
+    if packet.len() + 2 > size {
+        return packet;
+    }
+    let padding = size - packet.len();
+    packet[FLAGS_OFFSET] |= FLAG_PADDED;
+    packet.resize(size - 2, 0);
+    packet.extend_from_slice(&(padding as u16).to_le_bytes());
+    packet
+}
+
+/// `buf` without its padding, if any; the MAC must be stripped first
+fn unpad(buf: &[u8]) -> Result<&[u8]> {
+    if buf[FLAGS_OFFSET] & FLAG_PADDED == 0 {
+        return Ok(buf);
+    }
+    ensure!(buf.len() >= HEADER_SIZE + 2, "padded packet too short");
+    let padding = u16::from_le_bytes([buf[buf.len() - 2], buf[buf.len() - 1]]) as usize;
+    ensure!(
+        (2..=buf.len() - HEADER_SIZE).contains(&padding),
+        "invalid padding length {}",
+        padding
+    );
+    Ok(&buf[..buf.len() - padding])
+}
+
+/// Whether `buf` looks like a packet of the current protocol
+pub fn has_magic(buf: &[u8]) -> bool {
+    buf.len() >= HEADER_SIZE && buf[..4] == MAGIC
+}
+
+/// Whether `buf` is a challenge rather than a reply
+pub fn is_challenge(buf: &[u8]) -> bool {
+    has_magic(buf) && buf[5] == PacketType::Challenge as u8
+}
+
+/// Whether `buf` is flagged as carrying a MAC
+pub fn is_authenticated(buf: &[u8]) -> bool {
+    has_magic(buf) && buf[FLAGS_OFFSET] & FLAG_AUTHENTICATED != 0
+}
+
+/// Whether `buf` is flagged as encrypted
+pub fn is_encrypted(buf: &[u8]) -> bool {
+    has_magic(buf) && buf[FLAGS_OFFSET] & FLAG_ENCRYPTED != 0
+}
@@ -0,0 +1,265 @@
+//! Probes and replies as QUIC datagrams (RFC 9221): encrypted, authenticated
+//! with TLS certificates, and resumed with 0-RTT after reconnecting
+//!
+//! Only built with the `quic` feature; otherwise connecting and binding fail.
+
+use std::path::PathBuf;
+
+/// QUIC side of the reflector
+#[derive(Clone, Debug)]
+pub struct QuicConfig {
+    /// UDP port to accept QUIC connections on, separate from the plain probes
+    pub port: u16,
+    /// PEM certificate chain of the reflector
+    pub cert: PathBuf,
+    /// PEM private key of the certificate
+    pub key: PathBuf,
+}
+
+pub use sys::{Connection, Endpoint, Incoming};
+
+#[cfg(feature = "quic")]
+mod sys {
+    use super::QuicConfig;
+    use crate::{
+        clock::Clock,
+        socket::{self, Received, SocketOptions},
+        timestamping::TimestampSource,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use quinn::{
+        crypto::rustls::{QuicClientConfig, QuicServerConfig},
+        ConnectionError, TokioRuntime, TransportConfig,
+    };
+    use rustls::{
+        crypto::ring,
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        version::TLS13,
+        RootCertStore,
+    };
+    use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+    /// ALPN protocol of the probes
+    const ALPN: &[u8] = b"co";
+    /// Keeps connections from idling out between probes at long intervals
+    const KEEP_ALIVE: Duration = Duration::from_secs(5);
+
+    /// QUIC endpoint on its own UDP socket
+    pub struct Endpoint(quinn::Endpoint);
+
+    impl Endpoint {
+        /// Endpoint to connect from, verifying reflectors with the certificates in `ca`
+        ///
+        /// Sessions are remembered, so that reconnecting through the same
+        /// endpoint sends the first probes with 0-RTT.
+        pub fn client(local: SocketAddr, ca: &Path, options: &SocketOptions) -> Result<Self> {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(ca)
+                .with_context(|| format!("failed to read {}", ca.display()))?
+            {
+                roots.add(
+                    cert.with_context(|| format!("invalid certificate in {}", ca.display()))?,
+                )?;
+            }
+            let mut tls =
+                rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_protocol_versions(&[&TLS13])?
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+            tls.alpn_protocols = vec![ALPN.to_vec()];
+            tls.enable_early_data = true;
+
+            let mut transport = TransportConfig::default();
+            transport.keep_alive_interval(Some(KEEP_ALIVE));
+            let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls)?));
+            config.transport_config(Arc::new(transport));
+
+            let mut endpoint = Self::bind(local, None, options)?;
+            endpoint.0.set_default_client_config(config);
+            Ok(endpoint)
+        }
+
+        /// Endpoint accepting connections with the certificate of `config`
+        pub fn server(
+            addr: SocketAddr,
+            config: &QuicConfig,
+            options: &SocketOptions,
+        ) -> Result<Self> {
+            let certs = CertificateDer::pem_file_iter(&config.cert)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("failed to read {}", config.cert.display()))?;
+            let key = PrivateKeyDer::from_pem_file(&config.key)
+                .with_context(|| format!("failed to read {}", config.key.display()))?;
+            let mut tls =
+                rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                    .with_protocol_versions(&[&TLS13])?
+                    .with_no_client_auth()
+                    .with_single_cert(certs, key)?;
+            tls.alpn_protocols = vec![ALPN.to_vec()];
+            // Replayed probes only get replies again
+            tls.max_early_data_size = u32::MAX;
+
+            let server =
+                quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?));
+            Self::bind(addr, Some(server), options)
+        }
+
+        fn bind(
+            addr: SocketAddr,
+            server: Option<quinn::ServerConfig>,
+            options: &SocketOptions,
+        ) -> Result<Self> {
+            let socket = socket::bind_udp(addr, false)?;
+            options.apply(&socket)?;
+            let endpoint = quinn::Endpoint::new(
+                Default::default(),
+                server,
+                socket.into_std()?,
+                Arc::new(TokioRuntime),
+            )?;
+            Ok(Self(endpoint))
+        }
+
+        pub fn local_addr(&self) -> Result<SocketAddr> {
+            Ok(self.0.local_addr()?)
+        }
+
+        /// Connect to the reflector at `remote`, whose certificate must be for
+        /// `server_name`; with a remembered session, right away with 0-RTT
+        pub async fn connect(&self, remote: SocketAddr, server_name: &str) -> Result<Connection> {
+            let connecting = self.0.connect(remote, server_name)?;
+            let connection = match connecting.into_0rtt() {
+                Ok((connection, _)) => connection,
+                Err(connecting) => connecting
+                    .await
+                    .with_context(|| format!("failed to connect to {}", remote))?,
+            };
+            Ok(Connection(connection))
+        }
+
+        /// Next incoming connection attempt
+        pub async fn accept(&self) -> Result<Incoming> {
+            let incoming = self
+                .0
+                .accept()
+                .await
+                .ok_or_else(|| anyhow!("QUIC endpoint closed"))?;
+            Ok(Incoming(incoming))
+        }
+    }
+
+    /// Connection attempt of a client
+    pub struct Incoming(quinn::Incoming);
+
+    impl Incoming {
+        /// Complete the handshake, 0-RTT probes of resumed sessions are read right away
+        pub async fn accept(self) -> Result<Connection> {
+            let connection = match self.0.accept()?.into_0rtt() {
+                Ok((connection, _)) => connection,
+                Err(connecting) => connecting.await?,
+            };
+            Ok(Connection(connection))
+        }
+    }
+
+    /// QUIC connection exchanging a datagram per probe and reply
+    pub struct Connection(quinn::Connection);
+
+    impl Connection {
+        pub fn remote_address(&self) -> SocketAddr {
+            self.0.remote_address()
+        }
+
+        /// Next datagram, copied into `buf` and timestamped with `clock`; `None`
+        /// once the peer closed the connection
+        ///
+        /// Cancel safe.
+        pub async fn recv(&self, buf: &mut [u8], clock: &Clock) -> Result<Option<Received>> {
+            let datagram = match self.0.read_datagram().await {
+                Ok(datagram) => datagram,
+                Err(ConnectionError::ApplicationClosed(_)) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let timestamp = clock.now()?;
+            let len = datagram.len().min(buf.len());
+            buf[..len].copy_from_slice(&datagram[..len]);
+            Ok(Some(Received {
+                len,
+                from: self.remote_address(),
+                timestamp,
+                source: TimestampSource::Userspace,
+                dest: None,
+                ttl: None,
+                tos: None,
+            }))
+        }
+
+        pub fn send(&self, packet: &[u8]) -> Result<()> {
+            self.0.send_datagram(packet.to_vec().into())?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(feature = "quic"))]
+mod sys {
+    use super::QuicConfig;
+    use crate::{
+        clock::Clock,
+        socket::{Received, SocketOptions},
+    };
+    use anyhow::{bail, Result};
+    use std::{net::SocketAddr, path::Path};
+
+    pub enum Endpoint {}
+
+    impl Endpoint {
+        pub fn client(_local: SocketAddr, _ca: &Path, _options: &SocketOptions) -> Result<Self> {
+            bail!("QUIC support is not built in, rebuild with the quic feature")
+        }
+
+        pub fn server(
+            _addr: SocketAddr,
+            _config: &QuicConfig,
+            _options: &SocketOptions,
+        ) -> Result<Self> {
+            bail!("QUIC support is not built in, rebuild with the quic feature")
+        }
+
+        pub fn local_addr(&self) -> Result<SocketAddr> {
+            match *self {}
+        }
+
+        pub async fn connect(&self, _remote: SocketAddr, _server_name: &str) -> Result<Connection> {
+            match *self {}
+        }
+
+        pub async fn accept(&self) -> Result<Incoming> {
+            match *self {}
+        }
+    }
+
+    pub enum Incoming {}
+
+    impl Incoming {
+        pub async fn accept(self) -> Result<Connection> {
+            match self {}
+        }
+    }
+
+    pub enum Connection {}
+
+    impl Connection {
+        pub fn remote_address(&self) -> SocketAddr {
+            match *self {}
+        }
+
+        pub async fn recv(&self, _buf: &mut [u8], _clock: &Clock) -> Result<Option<Received>> {
+            match *self {}
+        }
+
+        pub fn send(&self, _packet: &[u8]) -> Result<()> {
+            match *self {}
+        }
+    }
+}
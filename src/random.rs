@@ -0,0 +1,37 @@
+//! Randomness from the operating system and a small generator seeded from it
+
+use anyhow::{anyhow, Result};
+
+/// Fill `buf` with random bytes from the operating system
+pub fn fill(buf: &mut [u8]) -> Result<()> {
+    getrandom::fill(buf).map_err(|e| anyhow!("failed to get random bytes: {}", e))
+}
+
+/// SplitMix64 generator, fine for scheduling but not for secrets
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new() -> Result<Self> {
+        let mut seed = [0; 8];
+        fill(&mut seed)?;
+        Ok(Self {
+            state: u64::from_le_bytes(seed),
+        })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,90 @@
+//! Token bucket rate limiting of reflector replies
+
+use std::{collections::HashMap, net::IpAddr, time::Instant};
+
+/// Bucket capacity, in seconds worth of the rate
+const BURST_SECONDS: f64 = 2.0;
+/// Number of tracked sources above which idle ones are forgotten
+const MAX_SOURCES: usize = 65536;
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        let capacity = (rate * BURST_SECONDS).max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Per-source and global packets per second limits
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    per_source: Option<f64>,
+    sources: HashMap<IpAddr, TokenBucket>,
+    global: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_source: Option<f64>, global: Option<f64>) -> Self {
+        Self {
+            per_source,
+            sources: HashMap::new(),
+            global: global.map(|rate| TokenBucket::new(rate, Instant::now())),
+        }
+    }
+
+    /// Whether a reply to `source` may be sent now
+    pub fn allow(&mut self, source: IpAddr) -> bool {
+        let now = Instant::now();
+
+        if let Some(rate) = self.per_source {
+            if self.sources.len() >= MAX_SOURCES && !self.sources.contains_key(&source) {
+                self.forget_idle(now);
+            }
+            let bucket = self
+                .sources
+                .entry(source)
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            if !bucket.take(now) {
+                return false;
+            }
+        }
+
+        self.global.as_mut().is_none_or(|bucket| bucket.take(now))
+    }
+
+    /// Drop sources whose buckets have refilled completely
+    fn forget_idle(&mut self, now: Instant) {
+        self.sources.retain(|_, bucket| {
+            bucket.refill(now);
+            bucket.tokens < bucket.capacity
+        });
+    }
+}
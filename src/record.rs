@@ -0,0 +1,180 @@
+//! Reading back measurement logs written in the CSV or JSON output formats
+
+use crate::{measurement::Measurement, socket::Ecn, timestamping::TimestampSource};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+
+/// Target name used for logs without a `target` column
+const UNNAMED_TARGET: &str = "-";
+/// Columns a measurement is recomputed from
+const REQUIRED: [&str; 5] = ["seq", "t1", "t2", "t3", "t4"];
+
+/// Measurement read from a log, with the target it was taken against
+#[derive(Clone, Debug)]
+pub struct Record {
+    pub target: String,
+    pub measurement: Measurement,
+}
+
+/// Parse a CSV (with header) or JSON lines log, detected from its first line
+///
+/// Derived columns are recomputed from the four timestamps; repeated CSV
+/// headers, as written at the start of every rotated file, and lost probes
+/// are skipped. The CSV delimiter is that of the header.
+pub fn parse_log(contents: &str) -> Result<Vec<Record>> {
+    let mut lines = contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let Some((_, first)) = lines.clone().next() else {
+        return Ok(Vec::new());
+    };
+
+    if first.trim_start().starts_with('{') {
+        let value: serde_json::Value = serde_json::from_str(first).context("line 1")?;
+        let object = value
+            .as_object()
+            .ok_or_else(|| anyhow!("line 1: not a JSON object"))?;
+        check_columns(&object.keys().map(String::as_str).collect::<Vec<_>>())?;
+        lines
+            .filter_map(|(i, line)| {
+                parse_json_line(line)
+                    .with_context(|| format!("line {}", i + 1))
+                    .transpose()
+            })
+            .collect()
+    } else {
+        let (_, header) = lines.next().unwrap_or_default();
+        let delimiter = delimiter(header);
+        let columns: Vec<_> = header.split(delimiter).map(str::trim).collect();
+        check_columns(&columns)?;
+        lines
+            .filter(|(_, line)| *line != header)
+            .filter_map(|(i, line)| {
+                parse_csv_line(&columns, delimiter, line)
+                    .with_context(|| format!("line {}", i + 1))
+                    .transpose()
+            })
+            .collect()
+    }
+}
+
+/// Separator of the column names in `header`, without surrounding spaces
+/// unless it is whitespace; a comma for a single column
+fn delimiter(header: &str) -> &str {
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let rest = header.trim().trim_start_matches(is_name);
+    let separator = &rest[..rest.find(is_name).unwrap_or(rest.len())];
+    match separator.trim() {
+        "" if separator.is_empty() => ",",
+        "" => separator,
+        trimmed => trimmed,
+    }
+}
+
+/// Fail early for logs written with `--fields` leaving out what measurements
+/// are recomputed from
+fn check_columns(columns: &[&str]) -> Result<()> {
+    let missing: Vec<_> = REQUIRED
+        .iter()
+        .filter(|name| !columns.contains(name))
+        .copied()
+        .collect();
+    if !missing.is_empty() {
+        bail!(
+            "the log has no {} columns, which are needed to analyze it",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn parse_csv_line(columns: &[&str], delimiter: &str, line: &str) -> Result<Option<Record>> {
+    let values: Vec<_> = line.split(delimiter).map(str::trim).collect();
+    if values.len() != columns.len() {
+        bail!("{} values for {} columns", values.len(), columns.len());
+    }
+    let fields = columns
+        .iter()
+        .zip(values)
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(&column, value)| (column, value.to_owned()))
+        .collect();
+    to_record(&fields)
+}
+
+fn parse_json_line(line: &str) -> Result<Option<Record>> {
+    let value: serde_json::Value = serde_json::from_str(line)?;
+    let object = value
+        .as_object()
+        .ok_or_else(|| anyhow!("not a JSON object"))?;
+    let fields = object
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Number(n) => n.to_string(),
+                _ => return None,
+            };
+            Some((key.as_str(), value))
+        })
+        .collect();
+    to_record(&fields)
+}
+
+/// `None` for lost probes, which have no reply timestamps
+fn to_record(fields: &HashMap<&str, String>) -> Result<Option<Record>> {
+    if ["t2", "t3", "t4"]
+        .iter()
+        .all(|name| !fields.contains_key(name))
+    {
+        return Ok(None);
+    }
+
+    let get = |name: &str| fields.get(name).ok_or_else(|| anyhow!("missing {}", name));
+    let timestamp = |name: &str| {
+        get(name)?
+            .parse()
+            .with_context(|| format!("invalid {}", name))
+    };
+
+    let mut m = Measurement::new(
+        get("seq")?.parse().context("invalid seq")?,
+        timestamp("t1")?,
+        timestamp("t2")?,
+        timestamp("t3")?,
+        timestamp("t4")?,
+    );
+    if let Some(lost) = fields.get("lost") {
+        m.lost = lost.parse().context("invalid lost")?;
+    }
+    if let Some(source) = fields.get("t1_source") {
+        m.t1_source = source.parse::<TimestampSource>()?;
+    }
+    if let Some(source) = fields.get("t4_source") {
+        m.t4_source = source.parse::<TimestampSource>()?;
+    }
+    if let Some(delay) = fields.get("send_delay") {
+        m.send_delay = delay.parse().context("invalid send_delay")?;
+    }
+    if let Some(addr) = fields.get("address") {
+        m.remote = Some(addr.parse().context("invalid address")?);
+    }
+    if let Some(ttl) = fields.get("ttl") {
+        m.ttl = Some(ttl.parse().context("invalid ttl")?);
+    }
+    if let Some(ecn) = fields.get("ecn") {
+        m.ecn = Some(ecn.parse::<Ecn>()?);
+    }
+    if let Some(correction) = fields.get("asymmetry_correction") {
+        m.shift_offset(correction.parse().context("invalid asymmetry_correction")?);
+    }
+
+    Ok(Some(Record {
+        target: fields
+            .get("target")
+            .cloned()
+            .unwrap_or_else(|| UNNAMED_TARGET.to_owned()),
+        measurement: m,
+    }))
+}
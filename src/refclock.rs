@@ -0,0 +1,220 @@
+//! Feeding offsets to chronyd or ntpd as a reference clock
+
+use crate::clock::Timestamp;
+#[cfg(unix)]
+use anyhow::anyhow;
+use anyhow::{bail, Context, Result};
+#[cfg(unix)]
+use std::{
+    io, mem,
+    os::unix::net::UnixDatagram,
+    ptr,
+    sync::atomic::{fence, Ordering},
+};
+use std::{path::PathBuf, str::FromStr};
+
+/// Base key of the NTP SHM segments (`"NTP0"`), the unit number is added to it
+#[cfg(unix)]
+const SHM_KEY_BASE: libc::key_t = 0x4e545030;
+/// Magic of chrony SOCK refclock samples (`"SOCK"`)
+#[cfg(unix)]
+const SOCK_MAGIC: libc::c_int = 0x534f434b;
+/// Advertised precision, log2 seconds (about 1 µs)
+#[cfg(unix)]
+const PRECISION: libc::c_int = -20;
+
+/// Reference clock interface to feed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefclockSpec {
+    /// NTP shared memory segment `shm:<unit>`, as read by `refclock SHM <unit>`
+    Shm(u32),
+    /// chrony SOCK refclock `sock:<path>`
+    Sock(PathBuf),
+}
+
+impl FromStr for RefclockSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("shm", unit)) => Ok(RefclockSpec::Shm(
+                unit.parse()
+                    .with_context(|| format!("invalid SHM unit in {}", s))?,
+            )),
+            Some(("sock", path)) if !path.is_empty() => Ok(RefclockSpec::Sock(path.into())),
+            _ => bail!(
+                "invalid refclock '{}', expected shm:<unit> or sock:<path>",
+                s
+            ),
+        }
+    }
+}
+
+/// `struct shmTime` of ntpd
+#[cfg(unix)]
+#[repr(C)]
+struct ShmTime {
+    mode: libc::c_int,
+    count: libc::c_int,
+    clock_sec: libc::time_t,
+    clock_usec: libc::c_int,
+    receive_sec: libc::time_t,
+    receive_usec: libc::c_int,
+    leap: libc::c_int,
+    precision: libc::c_int,
+    nsamples: libc::c_int,
+    valid: libc::c_int,
+    clock_nsec: libc::c_uint,
+    receive_nsec: libc::c_uint,
+    dummy: [libc::c_int; 8],
+}
+
+/// `struct sock_sample` of chrony
+#[cfg(unix)]
+#[repr(C)]
+struct SockSample {
+    tv: libc::timeval,
+    offset: f64,
+    pulse: libc::c_int,
+    leap: libc::c_int,
+    _pad: libc::c_int,
+    magic: libc::c_int,
+}
+
+#[cfg(unix)]
+enum Sink {
+    Shm(*mut ShmTime),
+    Sock { socket: UnixDatagram, path: PathBuf },
+}
+
+/// Attached reference clock
+#[cfg(unix)]
+pub struct Refclock {
+    sink: Sink,
+}
+
+#[cfg(unix)]
+// The SHM segment is only written through `&mut self`
+unsafe impl Send for Refclock {}
+
+#[cfg(unix)]
+impl Refclock {
+    pub fn open(spec: &RefclockSpec) -> Result<Self> {
+        let sink = match spec {
+            RefclockSpec::Shm(unit) => {
+                // Units 0 and 1 are only readable by root, as in ntpd
+                let mode = if *unit < 2 { 0o600 } else { 0o666 };
+                let key = SHM_KEY_BASE + *unit as libc::key_t;
+                let id =
+                    unsafe { libc::shmget(key, mem::size_of::<ShmTime>(), libc::IPC_CREAT | mode) };
+                if id < 0 {
+                    return Err(io::Error::last_os_error())
+                        .with_context(|| format!("shmget() of SHM unit {} failed", unit));
+                }
+                let shm = unsafe { libc::shmat(id, ptr::null(), 0) };
+                if shm as isize == -1 {
+                    return Err(io::Error::last_os_error())
+                        .with_context(|| format!("shmat() of SHM unit {} failed", unit));
+                }
+                Sink::Shm(shm as *mut ShmTime)
+            }
+            RefclockSpec::Sock(path) => Sink::Sock {
+                socket: UnixDatagram::unbound()?,
+                path: path.clone(),
+            },
+        };
+        Ok(Self { sink })
+    }
+
+    /// Report that the true time was `local - offset` at local system time `local`
+    ///
+    /// `offset` follows the sign convention of the measurements (local − remote).
+    pub fn update(&mut self, local: Timestamp, offset: f64) -> Result<()> {
+        match &mut self.sink {
+            Sink::Shm(shm) => {
+                let reference = Timestamp::from_nsec(local.total_nsec() - (offset * 1e9) as i128);
+                unsafe { write_shm(*shm, local, reference) };
+                Ok(())
+            }
+            Sink::Sock { socket, path } => {
+                let sample = SockSample {
+                    tv: libc::timeval {
+                        tv_sec: local.sec,
+                        tv_usec: (local.nsec / 1000) as _,
+                    },
+                    offset: -offset,
+                    pulse: 0,
+                    leap: 0,
+                    _pad: 0,
+                    magic: SOCK_MAGIC,
+                };
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(
+                        &sample as *const _ as *const u8,
+                        mem::size_of::<SockSample>(),
+                    )
+                };
+                socket
+                    .send_to(bytes, &path)
+                    .map_err(|e| anyhow!("sending to {} failed: {}", path.display(), e))?;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Refclock {
+    fn drop(&mut self) {
+        if let Sink::Shm(shm) = self.sink {
+            unsafe { libc::shmdt(shm as *const libc::c_void) };
+        }
+    }
+}
+
+/// Write a sample with the `count` protocol (mode 1), so readers can detect torn reads
+///
+/// # Safety
+/// `shm` must point to an attached segment of at least `ShmTime` size.
+#[cfg(unix)]
+unsafe fn write_shm(shm: *mut ShmTime, receive: Timestamp, clock: Timestamp) {
+    let count = ptr::addr_of_mut!((*shm).count);
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).mode), 1);
+    ptr::write_volatile(count, ptr::read_volatile(count).wrapping_add(1));
+    fence(Ordering::SeqCst);
+
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).clock_sec), clock.sec);
+    ptr::write_volatile(
+        ptr::addr_of_mut!((*shm).clock_usec),
+        (clock.nsec / 1000) as _,
+    );
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).clock_nsec), clock.nsec as _);
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).receive_sec), receive.sec);
+    ptr::write_volatile(
+        ptr::addr_of_mut!((*shm).receive_usec),
+        (receive.nsec / 1000) as _,
+    );
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).receive_nsec), receive.nsec as _);
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).leap), 0);
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).precision), PRECISION);
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).nsamples), 0);
+
+    fence(Ordering::SeqCst);
+    ptr::write_volatile(count, ptr::read_volatile(count).wrapping_add(1));
+    ptr::write_volatile(ptr::addr_of_mut!((*shm).valid), 1);
+}
+
+/// Reference clock placeholder; the chronyd and ntpd interfaces are Unix-only
+#[cfg(not(unix))]
+pub struct Refclock;
+
+#[cfg(not(unix))]
+impl Refclock {
+    pub fn open(_spec: &RefclockSpec) -> Result<Self> {
+        bail!("reference clocks are only supported on Unix")
+    }
+
+    pub fn update(&mut self, _local: Timestamp, _offset: f64) -> Result<()> {
+        Ok(())
+    }
+}
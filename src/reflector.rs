@@ -0,0 +1,678 @@
+#[cfg(target_os = "linux")]
+use crate::batch::{self, Outgoing, RecvBatch};
+use crate::{
+    access_log::{AccessLog, AccessLogConfig},
+    auth::Key,
+    cidr::Cidr,
+    clients::{ClientStats, ClientTable},
+    clock::{Clock, Timestamp},
+    cookie::CookieJar,
+    discovery,
+    kernel_state::KernelState,
+    mdns::{self, Advertisement},
+    metrics::{Metrics, ReflectorCounters},
+    ntp::{self, NtpConfig, ServerState},
+    protocol::{self, legacy, Challenge, ClientId, Reply},
+    quic::{self, QuicConfig},
+    ratelimit::RateLimiter,
+    socket::{self, Received, SocketOptions},
+    systemd,
+    timestamping::{self, Timestamping},
+    transport::{FramedStream, UnixListener},
+    uring::{IoBackend, Ring, Stop},
+};
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    borrow::Cow,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinSet,
+    time::{self, Instant},
+};
+use tracing::warn;
+
+/// Reflector settings
+#[derive(Clone, Debug, Default)]
+pub struct ReflectorConfig {
+    /// Also answer headerless probes of the original 16/32-byte format
+    pub legacy: bool,
+    /// Local clock `t2` and `t3` are read from
+    pub clock: Clock,
+    /// Source of the probe receive time `t2`
+    pub timestamping: Timestamping,
+    /// Serve probes on the tokio reactor or on io_uring threads
+    pub io_backend: IoBackend,
+    /// Marking of the replies
+    pub socket: SocketOptions,
+    /// Only answer probes authenticated with this key, authenticating the replies
+    pub key: Option<Key>,
+    /// Only answer sources in these networks, all if empty
+    pub allow: Vec<Cidr>,
+    /// Maximum replies per second to a single source address
+    pub rate_limit: Option<f64>,
+    /// Maximum replies per second overall
+    pub max_pps: Option<f64>,
+    /// Answer sources with a challenge until they echo its cookie, proving
+    /// they receive replies; legacy probes are not answered in this mode
+    pub challenge: bool,
+    /// Packet counters for the metrics exporter
+    pub metrics: Option<Metrics>,
+    /// Sockets sharing the port with `SO_REUSEPORT`, each served by its own
+    /// task; 0 is taken as 1
+    pub workers: usize,
+    /// Datagrams read with one `recvmmsg()` call and answered with one
+    /// `sendmmsg()` (Linux only); 0 and 1 handle them one by one
+    pub batch: usize,
+    /// Also accept probes over TCP connections on the same port, see
+    /// [`crate::Transport::Tcp`]
+    pub tcp: bool,
+    /// Also accept probes as QUIC datagrams, see [`crate::Transport::Quic`]
+    pub quic: Option<QuicConfig>,
+    /// Also accept probes over WebSocket connections on this TCP port, see
+    /// [`crate::Transport::WebSocket`]
+    pub websocket_port: Option<u16>,
+    /// Also accept probes over connections to the Unix domain socket at this
+    /// path, see [`crate::Transport::Unix`]
+    pub unix: Option<PathBuf>,
+    /// Also answer NTP clients, such as chronyd, on a port of their own
+    pub ntp: Option<NtpConfig>,
+    /// Log the probe rate of every client and client ID
+    pub access_log: Option<AccessLogConfig>,
+    /// Answer discovery requests on this port, see [`crate::discovery`]
+    pub discovery_port: Option<u16>,
+    /// Advertise the reflector over mDNS as this instance, see
+    /// [`crate::mdns`]
+    pub mdns: Option<String>,
+    /// Serve the sockets passed by systemd instead of binding the UDP socket
+    /// and the TCP listener: UDP sockets as workers and a TCP listener as
+    /// with `tcp`
+    pub socket_activation: bool,
+}
+
+/// Answers probes with the local receive and transmit timestamps
+pub struct Reflector {
+    /// One per worker, all bound to the same address
+    sockets: Vec<Arc<UdpSocket>>,
+    tcp: Option<Arc<TcpListener>>,
+    websocket: Option<Arc<TcpListener>>,
+    quic: Option<Arc<quic::Endpoint>>,
+    unix: Option<Arc<UnixListener>>,
+    ntp: Option<Arc<UdpSocket>>,
+    discovery: Option<Arc<UdpSocket>>,
+    mdns: Option<(Arc<UdpSocket>, Advertisement)>,
+    shared: Arc<Shared>,
+}
+
+/// State of the reflector common to all workers
+struct Shared {
+    config: ReflectorConfig,
+    cookies: Option<CookieJar>,
+    /// Shared with the metrics exporter
+    clients: Arc<Mutex<ClientTable>>,
+    limiter: Mutex<RateLimiter>,
+    /// Reply from the address each probe was sent to
+    pktinfo: bool,
+    /// Port of the probe sockets, announced to discovery requests
+    port: u16,
+    access_log: Option<AccessLog>,
+}
+
+/// Reply to a packet received at `t2` from an address, of one protocol
+type ReplyFn = fn(&Shared, &[u8], Timestamp, &SocketAddr) -> Result<Vec<u8>>;
+
+impl Reflector {
+    /// Bind the reflector on `addr`; `[::]` also accepts IPv4 probes where supported
+    pub async fn bind(addr: SocketAddr, config: ReflectorConfig) -> Result<Self> {
+        if !config.timestamping.is_userspace() {
+            config
+                .clock
+                .check_kernel_timestamps(config.timestamping.is_hardware())?;
+        }
+        let workers = config.workers.max(1);
+        // Elsewhere SO_REUSEPORT does not spread datagrams over the sockets
+        if workers > 1 && !cfg!(target_os = "linux") {
+            bail!("multiple workers are only supported on Linux");
+        }
+        if config.batch > 1 && !cfg!(target_os = "linux") {
+            bail!("batched I/O is only supported on Linux");
+        }
+        if config.batch > 1 && config.io_backend == IoBackend::Uring {
+            bail!("batched I/O is not supported with the io_uring backend");
+        }
+        let mut sockets = Vec::with_capacity(workers);
+        let mut passed_tcp = None;
+        let mut addr = addr;
+        if config.socket_activation {
+            for socket in systemd::listen_sockets()? {
+                match socket {
+                    systemd::Socket::Udp(socket) => sockets.push(UdpSocket::from_std(socket)?),
+                    systemd::Socket::Tcp(listener) if passed_tcp.is_none() => {
+                        passed_tcp = Some(TcpListener::from_std(listener)?)
+                    }
+                    systemd::Socket::Tcp(_) => bail!("systemd passed more than one TCP listener"),
+                }
+            }
+            ensure!(!sockets.is_empty(), "systemd passed no UDP socket");
+            addr = sockets[0].local_addr()?;
+        } else {
+            for _ in 0..workers {
+                let socket = socket::bind_udp(addr, workers > 1)?;
+                // The other workers join the port picked for the first one
+                addr = socket.local_addr()?;
+                sockets.push(socket);
+            }
+        }
+        let mut pktinfo = false;
+        for socket in &sockets {
+            config.socket.apply(socket)?;
+            // Transmit timestamps can not be put into the reply they are taken for
+            timestamping::enable(socket, &config.timestamping, false)?;
+            // With several local addresses, the route may pick another reply source
+            pktinfo = socket.local_addr()?.ip().is_unspecified() && socket::enable_pktinfo(socket)?;
+        }
+        let tcp = match (passed_tcp, config.tcp) {
+            (Some(listener), _) => Some(Arc::new(listener)),
+            (None, true) => Some(Arc::new(listen(addr).await?)),
+            (None, false) => None,
+        };
+        let websocket = match config.websocket_port {
+            Some(port) => Some(Arc::new(listen(SocketAddr::new(addr.ip(), port)).await?)),
+            None => None,
+        };
+        let quic = match &config.quic {
+            Some(quic) => {
+                let addr = SocketAddr::new(addr.ip(), quic.port);
+                Some(Arc::new(quic::Endpoint::server(
+                    addr,
+                    quic,
+                    &config.socket,
+                )?))
+            }
+            None => None,
+        };
+        let unix = match &config.unix {
+            Some(path) => Some(Arc::new(UnixListener::bind(path)?)),
+            None => None,
+        };
+        let ntp = match &config.ntp {
+            Some(ntp) => {
+                // NTP timestamps are UTC
+                if config.clock != Clock::Realtime {
+                    bail!("NTP clients can only be answered with the realtime clock");
+                }
+                let socket = socket::bind_udp(SocketAddr::new(addr.ip(), ntp.port), false)?;
+                config.socket.apply(&socket)?;
+                timestamping::enable(&socket, &config.timestamping, false)?;
+                if pktinfo {
+                    socket::enable_pktinfo(&socket)?;
+                }
+                Some(Arc::new(socket))
+            }
+            None => None,
+        };
+        let discovery = config
+            .discovery_port
+            .map(discovery::bind)
+            .transpose()?
+            .map(Arc::new);
+        let mdns = match &config.mdns {
+            Some(instance) => Some((
+                Arc::new(mdns::bind()?),
+                Advertisement::new(instance, addr.port())?,
+            )),
+            None => None,
+        };
+        let cookies = config.challenge.then(CookieJar::new).transpose()?;
+        let clients = Arc::new(Mutex::new(ClientTable::default()));
+        if let Some(metrics) = &config.metrics {
+            metrics.watch_clients(clients.clone());
+        }
+        let limiter = Mutex::new(RateLimiter::new(config.rate_limit, config.max_pps));
+        let access_log = config
+            .access_log
+            .as_ref()
+            .map(AccessLog::open)
+            .transpose()?;
+        Ok(Self {
+            sockets: sockets.into_iter().map(Arc::new).collect(),
+            tcp,
+            websocket,
+            quic,
+            unix,
+            ntp,
+            discovery,
+            mdns,
+            shared: Arc::new(Shared {
+                config,
+                cookies,
+                clients,
+                limiter,
+                pktinfo,
+                port: addr.port(),
+                access_log,
+            }),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.sockets[0].local_addr()?)
+    }
+
+    /// Statistics of the clients answered so far, ordered by address
+    pub fn clients(&self) -> Vec<(IpAddr, ClientStats)> {
+        self.shared.clients.lock().unwrap().snapshot()
+    }
+
+    /// Number of distinct clients answered so far
+    pub fn client_count(&self) -> usize {
+        self.shared.clients.lock().unwrap().len()
+    }
+
+    /// Packet counters so far, kept if the reflector has metrics
+    pub fn counters(&self) -> Option<ReflectorCounters> {
+        self.shared
+            .config
+            .metrics
+            .as_ref()
+            .map(Metrics::reflector_counters)
+    }
+
+    /// Reflect packets forever, with a task (or an io_uring thread) per worker
+    pub async fn run(&self) -> Result<()> {
+        // Raised when this future is dropped, as threads can not be aborted
+        let stop = Stop::default();
+        let mut workers = JoinSet::new();
+        for socket in &self.sockets {
+            let (socket, shared) = (socket.clone(), self.shared.clone());
+            match self.shared.config.io_backend {
+                IoBackend::Tokio => workers.spawn(async move { shared.serve(&socket).await }),
+                IoBackend::Uring => {
+                    let mut ring = Ring::new(stop.flag())?;
+                    let thread =
+                        tokio::task::spawn_blocking(move || shared.serve_uring(&socket, &mut ring));
+                    workers.spawn(async move { thread.await? })
+                }
+            };
+        }
+        for (listener, websocket) in [(&self.tcp, false), (&self.websocket, true)] {
+            if let Some(listener) = listener {
+                let (listener, shared) = (listener.clone(), self.shared.clone());
+                workers.spawn(async move { shared.serve_tcp(&listener, websocket).await });
+            }
+        }
+        if let Some(endpoint) = &self.quic {
+            let (endpoint, shared) = (endpoint.clone(), self.shared.clone());
+            workers.spawn(async move { shared.serve_quic(&endpoint).await });
+        }
+        if let Some(socket) = &self.ntp {
+            let (socket, shared) = (socket.clone(), self.shared.clone());
+            workers.spawn(async move { shared.serve_ntp(&socket).await });
+        }
+        if let Some(socket) = &self.discovery {
+            let (socket, shared) = (socket.clone(), self.shared.clone());
+            workers.spawn(async move { shared.serve_discovery(&socket).await });
+        }
+        if let Some((socket, advertisement)) = &self.mdns {
+            let (socket, advertisement) = (socket.clone(), advertisement.clone());
+            let shared = self.shared.clone();
+            workers.spawn(async move { shared.serve_mdns(&socket, &advertisement).await });
+        }
+        if let Some(listener) = &self.unix {
+            let (listener, shared) = (listener.clone(), self.shared.clone());
+            workers.spawn(async move { shared.serve_unix(&listener).await });
+        }
+        if let Some(config) = &self.shared.config.access_log {
+            let (interval, shared) = (config.interval, self.shared.clone());
+            workers.spawn(async move {
+                let mut timer = time::interval_at(Instant::now() + interval, interval);
+                loop {
+                    timer.tick().await;
+                    if let Some(log) = &shared.access_log {
+                        log.flush();
+                    }
+                }
+            });
+        }
+        // Dropping the set on return stops the remaining workers
+        match workers.join_next().await {
+            Some(result) => result?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    async fn serve(&self, socket: &UdpSocket) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.config.batch > 1 {
+            return self.serve_batched(socket).await;
+        }
+
+        let mut buf = [0; 2048]; // should be enough for MTU 1500
+        loop {
+            let received = socket::recv(
+                socket,
+                &mut buf,
+                self.pktinfo || !self.config.timestamping.is_userspace(),
+                &self.config.clock,
+            )
+            .await?;
+            if let Some(reply) = self.handle(&buf[..received.len], &received) {
+                socket::send_to(socket, &reply, received.from, received.dest).await?;
+                self.count(|m| m.reflector_replied(reply.len()));
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    async fn serve_batched(&self, socket: &UdpSocket) -> Result<()> {
+        let mut batch = RecvBatch::new(self.config.batch);
+        let mut replies = Vec::with_capacity(self.config.batch);
+        loop {
+            batch.recv(socket, &self.config.clock).await?;
+            replies.clear();
+            for (packet, received) in batch.iter() {
+                if let Some(reply) = self.handle(packet, received) {
+                    replies.push(Outgoing {
+                        buf: reply,
+                        to: received.from,
+                        from: received.dest,
+                    });
+                }
+            }
+            batch::send(socket, &replies).await?;
+            for reply in &replies {
+                self.count(|m| m.reflector_replied(reply.buf.len()));
+            }
+        }
+    }
+
+    /// Accept TCP connections, serving each in a task of its own; with
+    /// `websocket`, HTTP connections to upgrade
+    async fn serve_tcp(self: &Arc<Self>, listener: &TcpListener, websocket: bool) -> Result<()> {
+        // Dropped with the listener task, closing the connections
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted?;
+                    let shared = self.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = shared.serve_stream(stream, websocket).await {
+                            warn!("TCP connection from {} closed: {:#}", addr, e);
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+
+    async fn serve_stream(&self, stream: TcpStream, websocket: bool) -> Result<()> {
+        let stream = match websocket {
+            true => FramedStream::accept_websocket(stream).await?,
+            false => FramedStream::new(stream)?,
+        };
+        self.serve_framed(stream).await
+    }
+
+    /// Accept Unix domain socket connections, serving each in a task of its own
+    async fn serve_unix(self: &Arc<Self>, listener: &UnixListener) -> Result<()> {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let (stream, shared) = (accepted?, self.clone());
+                    connections.spawn(async move {
+                        if let Err(e) = shared.serve_framed(stream).await {
+                            warn!("Unix domain socket connection closed: {:#}", e);
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+
+    async fn serve_framed(&self, mut stream: FramedStream) -> Result<()> {
+        let mut buf = [0; 2048];
+        while let Some(received) = stream.recv(&mut buf, &self.config.clock).await? {
+            if let Some(reply) = self.handle(&buf[..received.len], &received) {
+                stream.send(&reply).await?;
+                self.count(|m| m.reflector_replied(reply.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Accept QUIC connections, serving each in a task of its own
+    async fn serve_quic(self: &Arc<Self>, endpoint: &quic::Endpoint) -> Result<()> {
+        let mut connections = JoinSet::new();
+        loop {
+            tokio::select! {
+                incoming = endpoint.accept() => {
+                    let (incoming, shared) = (incoming?, self.clone());
+                    connections.spawn(async move {
+                        // Failed handshakes are only logged, like closed connections
+                        let connection = match incoming.accept().await {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                warn!("QUIC connection failed: {:#}", e);
+                                return;
+                            }
+                        };
+                        let addr = connection.remote_address();
+                        if let Err(e) = shared.serve_connection(connection).await {
+                            warn!("QUIC connection from {} closed: {:#}", addr, e);
+                        }
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+    }
+
+    async fn serve_connection(&self, connection: quic::Connection) -> Result<()> {
+        let mut buf = [0; 2048];
+        while let Some(received) = connection.recv(&mut buf, &self.config.clock).await? {
+            if let Some(reply) = self.handle(&buf[..received.len], &received) {
+                connection.send(&reply)?;
+                self.count(|m| m.reflector_replied(reply.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Answer NTP clients, with the same access and rate limits as probes
+    async fn serve_ntp(&self, socket: &UdpSocket) -> Result<()> {
+        let mut buf = [0; 2048];
+        loop {
+            let received = socket::recv(
+                socket,
+                &mut buf,
+                self.pktinfo || !self.config.timestamping.is_userspace(),
+                &self.config.clock,
+            )
+            .await?;
+            if let Some(reply) = self.answer(&buf[..received.len], &received, Self::reply_ntp) {
+                socket::send_to(socket, &reply, received.from, received.dest).await?;
+                self.count(|m| m.reflector_replied(reply.len()));
+            }
+        }
+    }
+
+    /// Answer discovery requests, with the same access and rate limits as
+    /// probes
+    async fn serve_discovery(&self, socket: &UdpSocket) -> Result<()> {
+        let mut buf = [0; 2048];
+        loop {
+            let received = socket::recv(socket, &mut buf, false, &self.config.clock).await?;
+            if let Some(reply) = self.answer(&buf[..received.len], &received, Self::reply_discovery)
+            {
+                // From the address the route picks, not the group the request went to
+                socket::send_to(socket, &reply, received.from, None).await?;
+                self.count(|m| m.reflector_replied(reply.len()));
+            }
+        }
+    }
+
+    /// Announce the advertisement, then answer mDNS queries for it from
+    /// allowed addresses; the port sees all mDNS traffic of the network, so
+    /// the rest is neither counted nor logged
+    async fn serve_mdns(&self, socket: &UdpSocket, advertisement: &Advertisement) -> Result<()> {
+        let group = (mdns::GROUP, mdns::PORT).into();
+        socket::send_to(socket, &advertisement.announcement(), group, None).await?;
+        let mut buf = [0; 9000];
+        loop {
+            let received = socket::recv(socket, &mut buf, false, &self.config.clock).await?;
+            if !self.is_allowed(&received.from) {
+                continue;
+            }
+            if let Some(reply) = advertisement.answer(&buf[..received.len]) {
+                let to = mdns::reply_addr(received.from);
+                socket::send_to(socket, &reply, to, None).await?;
+            }
+        }
+    }
+
+    /// Blocking loop of a worker on the io_uring backend, until `ring` is stopped
+    fn serve_uring(&self, socket: &UdpSocket, ring: &mut Ring) -> Result<()> {
+        let mut buf = [0; 2048]; // should be enough for MTU 1500
+        while let Some(received) = ring.recv(socket, &mut buf, &self.config.clock)? {
+            if let Some(reply) = self.handle(&buf[..received.len], &received) {
+                if !ring.send_to(socket, &reply, received.from, received.dest)? {
+                    break;
+                }
+                self.count(|m| m.reflector_replied(reply.len()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reply to a received datagram, `None` if it is not to be answered
+    fn handle(&self, packet: &[u8], received: &Received) -> Option<Vec<u8>> {
+        self.answer(packet, received, Self::reply_to)
+    }
+
+    /// Reply to a packet allowed through the limits with `reply_to`
+    fn answer(&self, packet: &[u8], received: &Received, reply_to: ReplyFn) -> Option<Vec<u8>> {
+        let addr = received.from;
+        self.count(|m| m.reflector_received(packet.len()));
+        if !self.is_allowed(&addr) {
+            self.count(Metrics::reflector_disallowed);
+            return None;
+        }
+        if !self.limiter.lock().unwrap().allow(addr.ip()) {
+            self.count(Metrics::reflector_rate_limited);
+            return None;
+        }
+
+        match reply_to(self, packet, received.timestamp, &addr) {
+            Ok(reply) => Some(reply),
+            Err(e) => {
+                warn!("Invalid packet from {} discarded: {}", addr, e);
+                self.count(Metrics::reflector_invalid);
+                None
+            }
+        }
+    }
+
+    fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        self.config.allow.is_empty() || self.config.allow.iter().any(|net| net.contains(&addr.ip()))
+    }
+
+    fn log_access(&self, from: &SocketAddr, id: Option<ClientId>) {
+        if let Some(log) = &self.access_log {
+            log.record(from.ip(), id);
+        }
+    }
+
+    fn count(&self, counter: impl FnOnce(&Metrics)) {
+        if let Some(metrics) = &self.config.metrics {
+            counter(metrics);
+        }
+    }
+
+    /// NTP clients are not added to the client table: their transmit
+    /// timestamps may be random rather than their send times
+    fn reply_ntp(&self, packet: &[u8], t2: Timestamp, _from: &SocketAddr) -> Result<Vec<u8>> {
+        let request = ntp::decode_request(packet)?;
+        let stratum = self.config.ntp.as_ref().map_or(0, |ntp| ntp.stratum);
+        // Without the kernel state, as outside Linux, the clock is assumed synchronized
+        let server = match KernelState::read() {
+            Ok(state) => ServerState {
+                synchronized: state.synced,
+                stratum,
+                root_dispersion: state.maxerror,
+            },
+            Err(_) => ServerState {
+                synchronized: true,
+                stratum,
+                root_dispersion: 0.0,
+            },
+        };
+        let t3 = self.config.clock.now()?;
+        Ok(ntp::encode_response(&request, &server, t2, t3).to_vec())
+    }
+
+    fn reply_discovery(
+        &self,
+        packet: &[u8],
+        _t2: Timestamp,
+        _from: &SocketAddr,
+    ) -> Result<Vec<u8>> {
+        discovery::announce(packet, self.port)
+    }
+
+    fn reply_to(&self, packet: &[u8], t2: Timestamp, from: &SocketAddr) -> Result<Vec<u8>> {
+        let plain = self.config.key.is_none() && self.cookies.is_none();
+        if plain && self.config.legacy && !protocol::has_magic(packet) {
+            let t1 = legacy::decode_probe(packet)?;
+            self.clients.lock().unwrap().add(from.ip(), t1, t2);
+            self.log_access(from, None);
+            return Ok(legacy::encode_reply(t1, t2).to_vec());
+        }
+
+        let packet = match &self.config.key {
+            Some(key) => key.verify(packet)?,
+            None => Cow::Borrowed(packet),
+        };
+        let probe = protocol::decode_probe(&packet)?;
+
+        let reply = match &self.cookies {
+            Some(cookies) if !probe.cookie.is_some_and(|c| cookies.check(from, &c)) => {
+                self.count(Metrics::reflector_challenged);
+                protocol::encode_challenge(&Challenge {
+                    seq: probe.seq,
+                    cookie: cookies.issue(from),
+                })
+            }
+            _ => {
+                self.clients.lock().unwrap().add(from.ip(), probe.t1, t2);
+                self.log_access(from, probe.client_id);
+                let t3 = self.config.clock.now()?;
+                let reply = protocol::encode_reply(&Reply { probe, t2, t3 });
+                if probe.pad_reply {
+                    // As large as the probe, so padding never amplifies
+                    protocol::pad(reply, packet.len())
+                } else {
+                    reply
+                }
+            }
+        };
+        Ok(match &self.config.key {
+            Some(key) => key.sign(reply)?,
+            None => reply,
+        })
+    }
+}
+
+async fn listen(addr: SocketAddr) -> Result<TcpListener> {
+    TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on TCP {}", addr))
+}
@@ -0,0 +1,168 @@
+use crate::clock::DateTime;
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// When to start a new output file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+    /// When the file reaches this many bytes
+    Size(u64),
+}
+
+impl Rotation {
+    /// Start of the rotation period containing `sec`
+    fn period_start(&self, sec: i64) -> Option<i64> {
+        match self {
+            Rotation::Hourly => Some(sec - sec.rem_euclid(3600)),
+            Rotation::Daily => Some(sec - sec.rem_euclid(86400)),
+            Rotation::Never | Rotation::Size(_) => None,
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = anyhow::Error;
+
+    /// `never`, `hourly`, `daily` or a size like `100M`
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "never" => return Ok(Rotation::Never),
+            "hourly" => return Ok(Rotation::Hourly),
+            "daily" => return Ok(Rotation::Daily),
+            _ => {}
+        }
+
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+            _ => (s, 1),
+        };
+        let size: u64 = number
+            .parse()
+            .with_context(|| format!("invalid rotation '{}'", s))?;
+        if size == 0 {
+            bail!("rotation size must be positive");
+        }
+        Ok(Rotation::Size(size * multiplier))
+    }
+}
+
+/// Append-only file that is renamed aside and restarted according to a [`Rotation`]
+///
+/// Rotated files get the UTC start time of their contents appended to the name.
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    /// Time the current file was started
+    started: i64,
+}
+
+impl RotatingFile {
+    /// Open `path` for appending; an existing file is continued if still within its period
+    pub fn open(path: &Path, rotation: Rotation) -> Result<Self> {
+        let file = open_append(path)?;
+        let metadata = file.metadata()?;
+        let started = metadata
+            .modified()
+            .ok()
+            .filter(|_| metadata.len() > 0)
+            .map_or_else(now, unix_secs);
+
+        let mut rotating = Self {
+            path: path.to_owned(),
+            rotation,
+            file,
+            size: metadata.len(),
+            started,
+        };
+        rotating.rotate_if_due()?;
+        Ok(rotating)
+    }
+
+    /// Rotate if due, returning whether the current file is empty
+    pub fn prepare_write(&mut self) -> io::Result<bool> {
+        self.rotate_if_due()
+            .map_err(|e| io::Error::other(format!("{:#}", e)))?;
+        Ok(self.size == 0)
+    }
+
+    fn rotate_if_due(&mut self) -> Result<()> {
+        if self.size == 0 {
+            return Ok(());
+        }
+
+        let now = now();
+        let due = match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(limit) => self.size >= limit,
+            rotation => rotation.period_start(now) != rotation.period_start(self.started),
+        };
+        if !due {
+            return Ok(());
+        }
+
+        let started = DateTime::from_unix(self.started);
+        let suffix = format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}",
+            started.year, started.month, started.day, started.hour, started.minute, started.second
+        );
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".");
+        rotated.push(&suffix);
+        // Several size-based rotations can happen within one second
+        let mut n = 1;
+        while Path::new(&rotated).exists() {
+            rotated = self.path.clone().into_os_string();
+            rotated.push(format!(".{}.{}", suffix, n));
+            n += 1;
+        }
+        fs::rename(&self.path, &rotated)
+            .with_context(|| format!("failed to rotate {}", self.path.display()))?;
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.started = now;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))
+}
+
+fn unix_secs(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+fn now() -> i64 {
+    unix_secs(SystemTime::now())
+}
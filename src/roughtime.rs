@@ -0,0 +1,265 @@
+//! Roughtime client (the original Google protocol, as served by Cloudflare
+//! and others): every response carries the server time signed by a key the
+//! server's long-term key delegates to, proving where the timestamp came from
+//!
+//! Messages are maps of tags to values: the number of tags, the offsets of
+//! all values but the first and the sorted tags as little-endian `u32`s, then
+//! the values themselves.
+
+use crate::{base64, clock::Timestamp, random};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
+use std::{collections::BTreeMap, str::FromStr};
+
+/// Usual port of Roughtime servers
+pub const PORT: u16 = 2002;
+/// Requests are padded to this size, so that replies never amplify
+const REQUEST_SIZE: usize = 1024;
+const NONCE_SIZE: usize = 64;
+const HASH_SIZE: usize = 64;
+/// Nonces remembered for unanswered requests
+const PENDING_WINDOW: usize = 1024;
+
+const TAG_NONC: &[u8; 4] = b"NONC";
+const TAG_PAD: &[u8; 4] = b"PAD\xff";
+const TAG_SIG: &[u8; 4] = b"SIG\0";
+const TAG_CERT: &[u8; 4] = b"CERT";
+const TAG_DELE: &[u8; 4] = b"DELE";
+const TAG_PUBK: &[u8; 4] = b"PUBK";
+const TAG_MINT: &[u8; 4] = b"MINT";
+const TAG_MAXT: &[u8; 4] = b"MAXT";
+const TAG_SREP: &[u8; 4] = b"SREP";
+const TAG_ROOT: &[u8; 4] = b"ROOT";
+const TAG_MIDP: &[u8; 4] = b"MIDP";
+const TAG_RADI: &[u8; 4] = b"RADI";
+const TAG_PATH: &[u8; 4] = b"PATH";
+const TAG_INDX: &[u8; 4] = b"INDX";
+
+/// Prefixes of the signed certificate and response
+const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+
+type Nonce = [u8; NONCE_SIZE];
+
+/// Long-term Ed25519 public key of a server, given in base64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicKey(VerifyingKey);
+
+impl FromStr for PublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let key: [u8; 32] = base64::decode(s)?
+            .try_into()
+            .map_err(|_| anyhow!("Roughtime public keys are 32 bytes"))?;
+        Ok(Self(VerifyingKey::from_bytes(&key)?))
+    }
+}
+
+/// Verified server time
+#[derive(Clone, Copy, Debug)]
+pub struct Response {
+    /// Sequence number of the answered request
+    pub seq: u64,
+    /// Server time somewhere between receiving the request and responding
+    pub midpoint: Timestamp,
+    /// Uncertainty of `midpoint` either way, in nanoseconds
+    pub radius: i128,
+}
+
+/// Builds requests and verifies the responses of a single server
+pub struct Client {
+    key: PublicKey,
+    /// Nonces of the unanswered requests by sequence number
+    nonces: BTreeMap<u64, Nonce>,
+}
+
+impl Client {
+    pub fn new(key: PublicKey) -> Self {
+        Self {
+            key,
+            nonces: BTreeMap::new(),
+        }
+    }
+
+    /// Request of probe `seq`, with a random nonce of its own
+    pub fn request(&mut self, seq: u64) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_SIZE];
+        random::fill(&mut nonce)?;
+        self.nonces.insert(seq, nonce);
+        if self.nonces.len() > PENDING_WINDOW {
+            self.nonces.pop_first();
+        }
+        // Header of two tags, with the offset of the second value
+        let padding = REQUEST_SIZE - 16 - NONCE_SIZE;
+        Ok(encode(&[(TAG_NONC, &nonce), (TAG_PAD, &vec![0; padding])]))
+    }
+
+    /// Verify the signatures of a response and find the request it answers
+    ///
+    /// Servers answer batches of requests at once, proving that a nonce was
+    /// signed with its path in a Merkle tree of the batch.
+    pub fn verify(&self, packet: &[u8]) -> Result<Response> {
+        let response = Message::parse(packet)?;
+        let cert = Message::parse(response.get(TAG_CERT)?)?;
+        let delegation = cert.get(TAG_DELE)?;
+        verify_signature(
+            &self.key.0,
+            DELEGATION_CONTEXT,
+            delegation,
+            cert.get(TAG_SIG)?,
+        )
+        .context("invalid Roughtime delegation")?;
+
+        let delegation = Message::parse(delegation)?;
+        let delegated: [u8; 32] = delegation.get(TAG_PUBK)?.try_into()?;
+        let signed = response.get(TAG_SREP)?;
+        verify_signature(
+            &VerifyingKey::from_bytes(&delegated)?,
+            RESPONSE_CONTEXT,
+            signed,
+            response.get(TAG_SIG)?,
+        )
+        .context("invalid Roughtime response signature")?;
+
+        let signed = Message::parse(signed)?;
+        let midpoint = read_u64(signed.get(TAG_MIDP)?)?;
+        let (min, max) = (
+            read_u64(delegation.get(TAG_MINT)?)?,
+            read_u64(delegation.get(TAG_MAXT)?)?,
+        );
+        ensure!(
+            (min..=max).contains(&midpoint),
+            "Roughtime delegation is not valid at the response time"
+        );
+
+        let root = signed.get(TAG_ROOT)?;
+        let index = u32::from_le_bytes(response.get(TAG_INDX)?.try_into()?);
+        let path = response.get(TAG_PATH)?;
+        ensure!(
+            path.len().is_multiple_of(HASH_SIZE),
+            "invalid Roughtime Merkle path"
+        );
+        let seq = self
+            .nonces
+            .iter()
+            .find_map(|(&seq, nonce)| (tree_root(nonce, index, path) == root).then_some(seq))
+            .ok_or_else(|| anyhow!("Roughtime response to an unknown request"))?;
+
+        // Times are in microseconds since the Unix epoch
+        let radius = u32::from_le_bytes(signed.get(TAG_RADI)?.try_into()?);
+        Ok(Response {
+            seq,
+            midpoint: Timestamp::from_nsec(midpoint as i128 * 1000),
+            radius: radius as i128 * 1000,
+        })
+    }
+}
+
+fn verify_signature(
+    key: &VerifyingKey,
+    context: &[u8],
+    data: &[u8],
+    signature: &[u8],
+) -> Result<()> {
+    let signature = Signature::from_slice(signature)?;
+    let message = [context, data].concat();
+    Ok(key.verify_strict(&message, &signature)?)
+}
+
+/// Root of the Merkle tree with the leaf of `nonce` at `index`, given the
+/// hashes of the siblings on the way up
+fn tree_root(nonce: &Nonce, mut index: u32, path: &[u8]) -> Vec<u8> {
+    let mut hash = Sha512::new()
+        .chain_update([0])
+        .chain_update(nonce)
+        .finalize();
+    for sibling in path.chunks(HASH_SIZE) {
+        let node = Sha512::new().chain_update([1]);
+        hash = if index & 1 == 0 {
+            node.chain_update(hash).chain_update(sibling).finalize()
+        } else {
+            node.chain_update(sibling).chain_update(hash).finalize()
+        };
+        index >>= 1;
+    }
+    hash.to_vec()
+}
+
+fn read_u64(value: &[u8]) -> Result<u64> {
+    Ok(u64::from_le_bytes(value.try_into()?))
+}
+
+/// Message of tags in increasing order, with values whose sizes are multiples of 4
+fn encode(values: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(REQUEST_SIZE);
+    buf.extend_from_slice(&(values.len() as u32).to_le_bytes());
+    let mut offset = 0;
+    for (_, value) in &values[..values.len() - 1] {
+        offset += value.len() as u32;
+        buf.extend_from_slice(&offset.to_le_bytes());
+    }
+    for (tag, _) in values {
+        buf.extend_from_slice(*tag);
+    }
+    for (_, value) in values {
+        buf.extend_from_slice(value);
+    }
+    buf
+}
+
+/// Parsed message, borrowing the values
+struct Message<'a> {
+    values: Vec<(u32, &'a [u8])>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(buf: &'a [u8]) -> Result<Self> {
+        let word = |i: usize| -> Result<u32> {
+            let bytes = buf
+                .get(4 * i..4 * i + 4)
+                .ok_or_else(|| anyhow!("truncated Roughtime message"))?;
+            Ok(u32::from_le_bytes(bytes.try_into()?))
+        };
+        let count = word(0)? as usize;
+        if count == 0 {
+            return Ok(Self { values: Vec::new() });
+        }
+        ensure!(count <= buf.len() / 8, "truncated Roughtime message");
+        let body = &buf[8 * count..];
+
+        let mut values = Vec::with_capacity(count);
+        let mut start = 0;
+        for i in 0..count {
+            let tag = word(count + i)?;
+            let end = match i + 1 < count {
+                true => word(1 + i)? as usize,
+                false => body.len(),
+            };
+            if values.last().is_some_and(|&(last, _)| last >= tag) {
+                bail!("Roughtime message tags are not in order");
+            }
+            ensure!(
+                start <= end && end <= body.len() && end.is_multiple_of(4),
+                "invalid Roughtime message offsets"
+            );
+            values.push((tag, &body[start..end]));
+            start = end;
+        }
+        Ok(Self { values })
+    }
+
+    fn get(&self, tag: &[u8; 4]) -> Result<&'a [u8]> {
+        let tag_value = u32::from_le_bytes(*tag);
+        self.values
+            .iter()
+            .find_map(|&(t, value)| (t == tag_value).then_some(value))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Roughtime message without {}",
+                    String::from_utf8_lossy(tag).trim_end_matches(['\0', '\u{fffd}'])
+                )
+            })
+    }
+}
@@ -0,0 +1,111 @@
+//! Real-time scheduling, CPU pinning and memory locking of the process threads
+
+use anyhow::{bail, Result};
+
+/// Scheduling settings; the defaults leave the process as it is
+#[derive(Clone, Debug, Default)]
+pub struct SchedulingConfig {
+    /// `SCHED_FIFO` priority, 1 to 99
+    pub rt_priority: Option<i32>,
+    /// CPUs to run on, all if empty
+    pub cpus: Vec<usize>,
+    /// Lock all current and future memory, so page faults do not delay timestamps
+    pub lock_memory: bool,
+}
+
+impl SchedulingConfig {
+    fn is_default(&self) -> bool {
+        self.rt_priority.is_none() && self.cpus.is_empty() && !self.lock_memory
+    }
+
+    /// Apply to the calling thread, and so to the threads it starts afterwards
+    pub fn apply(&self) -> Result<()> {
+        if self.is_default() {
+            return Ok(());
+        }
+        if !cfg!(target_os = "linux") {
+            bail!("real-time scheduling options are only supported on Linux");
+        }
+        if let Some(priority) = self.rt_priority {
+            sys::set_fifo(priority)?;
+        }
+        if !self.cpus.is_empty() {
+            sys::set_affinity(&self.cpus)?;
+        }
+        if self.lock_memory {
+            sys::lock_memory()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use anyhow::{ensure, Context, Result};
+    use std::{io, mem};
+
+    pub fn set_fifo(priority: i32) -> Result<()> {
+        let (min, max) = unsafe {
+            (
+                libc::sched_get_priority_min(libc::SCHED_FIFO),
+                libc::sched_get_priority_max(libc::SCHED_FIFO),
+            )
+        };
+        ensure!(
+            (min..=max).contains(&priority),
+            "real-time priority must be between {} and {}",
+            min,
+            max
+        );
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        // Thread 0 is the calling one
+        if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } != 0 {
+            return Err(io::Error::last_os_error())
+                .context("sched_setscheduler() call failed (needs CAP_SYS_NICE)");
+        }
+        Ok(())
+    }
+
+    pub fn set_affinity(cpus: &[usize]) -> Result<()> {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for &cpu in cpus {
+            ensure!(
+                cpu < libc::CPU_SETSIZE as usize,
+                "CPU {} is out of range",
+                cpu
+            );
+            unsafe { libc::CPU_SET(cpu, &mut set) };
+        }
+        if unsafe { libc::sched_setaffinity(0, mem::size_of_val(&set), &set) } != 0 {
+            return Err(io::Error::last_os_error()).context("sched_setaffinity() call failed");
+        }
+        Ok(())
+    }
+
+    pub fn lock_memory() -> Result<()> {
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+            return Err(io::Error::last_os_error())
+                .context("mlockall() call failed (needs CAP_IPC_LOCK or a higher RLIMIT_MEMLOCK)");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use anyhow::Result;
+
+    pub fn set_fifo(_priority: i32) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn set_affinity(_cpus: &[usize]) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn lock_memory() -> Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+use crate::{clock::Timestamp, timestamping::TimestampSource};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+
+/// How many unanswered probes are remembered before they are considered lost for good
+const PENDING_WINDOW: usize = 1024;
+
+/// Unanswered probe
+#[derive(Clone, Copy, Debug)]
+pub struct PendingProbe {
+    /// Send time carried in the probe
+    pub t1: Timestamp,
+    /// How long after its scheduled slot the probe was sent (seconds)
+    pub send_delay: f64,
+    /// Transmit timestamp reported by the kernel or the NIC after sending
+    pub tx_timestamp: Option<(Timestamp, TimestampSource)>,
+    /// Nonce the reply has to echo
+    pub nonce: Option<u64>,
+}
+
+impl PendingProbe {
+    /// Most accurate known send time
+    pub fn send_time(&self) -> (Timestamp, TimestampSource) {
+        self.tx_timestamp
+            .unwrap_or((self.t1, TimestampSource::Userspace))
+    }
+}
+
+/// Matches replies to sent probes and keeps loss/duplication/reordering counters
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    next_seq: u64,
+    pending: BTreeMap<u64, PendingProbe>,
+    highest_received: Option<u64>,
+    /// Probes that fell out of the pending window unanswered
+    expired: u64,
+    /// Probes given up on after the reply timeout
+    timed_out: u64,
+    duplicates: u64,
+    reordered: u64,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a probe sent at `t1`, `send_delay` after its slot, with
+    /// `nonce`, returning its sequence number
+    pub fn on_send(&mut self, t1: Timestamp, send_delay: f64, nonce: Option<u64>) -> u64 {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        self.pending.insert(
+            seq,
+            PendingProbe {
+                t1,
+                send_delay,
+                tx_timestamp: None,
+                nonce,
+            },
+        );
+        if self.pending.len() > PENDING_WINDOW {
+            self.pending.pop_first();
+            self.expired += 1;
+        }
+
+        seq
+    }
+
+    /// Record the transmit timestamp of a sent probe
+    ///
+    /// Hardware timestamps take precedence over software ones reported for the same probe.
+    pub fn on_tx_timestamp(&mut self, seq: u64, timestamp: Timestamp, source: TimestampSource) {
+        if let Some(probe) = self.pending.get_mut(&seq) {
+            if !matches!(probe.tx_timestamp, Some((_, TimestampSource::Hardware))) {
+                probe.tx_timestamp = Some((timestamp, source));
+            }
+        }
+    }
+
+    /// Match a reply to its probe
+    ///
+    /// Replies with a wrong send time or nonce leave the probe pending, in
+    /// case they are forged and the real reply is still to come.
+    pub fn on_reply(
+        &mut self,
+        seq: u64,
+        t1: Timestamp,
+        nonce: Option<u64>,
+    ) -> Result<PendingProbe> {
+        let Some(sent) = self.pending.get(&seq).copied() else {
+            if seq < self.next_seq {
+                self.duplicates += 1;
+                bail!("duplicate or expired reply to probe {}", seq);
+            } else {
+                bail!("reply to probe {} that was never sent", seq);
+            }
+        };
+        if sent.t1 != t1 {
+            bail!("reply to probe {} does not match the sent timestamp", seq);
+        }
+        if sent.nonce.is_some() && sent.nonce != nonce {
+            bail!("reply to probe {} with an unknown nonce", seq);
+        }
+        self.pending.remove(&seq);
+
+        match self.highest_received {
+            Some(highest) if seq < highest => self.reordered += 1,
+            _ => self.highest_received = Some(seq),
+        }
+
+        Ok(sent)
+    }
+
+    /// Send time of the probe `seq` if it is still unanswered
+    pub fn send_time(&self, seq: u64) -> Option<Timestamp> {
+        self.pending.get(&seq).map(|sent| sent.t1)
+    }
+
+    /// Stop waiting for a reply to a pending probe without counting it as lost
+    ///
+    /// Returns whether the probe was pending.
+    pub fn forget(&mut self, seq: u64) -> bool {
+        self.pending.remove(&seq).is_some()
+    }
+
+    /// Give up on a pending probe, counting it as lost
+    ///
+    /// Returns the probe if it was still pending.
+    pub fn time_out(&mut self, seq: u64) -> Option<PendingProbe> {
+        let probe = self.pending.remove(&seq)?;
+        self.timed_out += 1;
+        Some(probe)
+    }
+
+    /// Sequence number of the unanswered probe sent at `t1`
+    pub fn find_by_send_time(&self, t1: Timestamp) -> Option<u64> {
+        self.pending
+            .iter()
+            .find_map(|(&seq, sent)| (sent.t1 == t1).then_some(seq))
+    }
+
+    /// Probes not answered while a later probe was
+    pub fn lost(&self) -> u64 {
+        let overtaken = match self.highest_received {
+            Some(highest) => self.pending.range(..highest).count() as u64,
+            None => 0,
+        };
+        self.expired + self.timed_out + overtaken
+    }
+
+    /// Number of probes still waiting for a reply
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    pub fn reordered(&self) -> u64 {
+        self.reordered
+    }
+}
@@ -0,0 +1,143 @@
+//! Smoothed offset estimates tracking slow drift while rejecting per-packet noise
+
+use crate::{clock::Timestamp, measurement::Measurement};
+use anyhow::{bail, Context, Result};
+use std::str::FromStr;
+
+/// Phase variance added per second by white frequency noise (s²/s), about 1e-8 ADEV at 1 s
+const WHITE_FM_NOISE: f64 = 1e-16;
+/// Frequency variance added per second by random-walk frequency noise (1/s)
+const RANDOM_WALK_FM_NOISE: f64 = 1e-18;
+/// Initial frequency variance, about 100 ppm
+const INITIAL_FREQUENCY_VARIANCE: f64 = 1e-8;
+
+/// Kind of smoother producing the `offset_est` estimate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmoothingFilter {
+    /// Exponentially weighted moving average with this weight of the newest sample
+    Ewma(f64),
+    /// Two-state (offset, frequency) Kalman filter
+    Kalman,
+}
+
+impl FromStr for SmoothingFilter {
+    type Err = anyhow::Error;
+
+    /// `ewma:ALPHA` with `ALPHA` in `(0, 1]`, or `kalman`
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "kalman" {
+            return Ok(SmoothingFilter::Kalman);
+        }
+        let Some(alpha) = s.strip_prefix("ewma:") else {
+            bail!("unknown filter '{}', expected ewma:ALPHA or kalman", s);
+        };
+        let alpha: f64 = alpha
+            .parse()
+            .with_context(|| format!("invalid EWMA weight in {}", s))?;
+        if !(alpha > 0.0 && alpha <= 1.0) {
+            bail!("EWMA weight must be in (0, 1], got {}", alpha);
+        }
+        Ok(SmoothingFilter::Ewma(alpha))
+    }
+}
+
+/// State of a [`SmoothingFilter`]
+#[derive(Debug)]
+pub(crate) enum Smoother {
+    Ewma { alpha: f64, estimate: Option<f64> },
+    Kalman(Option<Kalman>),
+}
+
+impl Smoother {
+    pub fn new(filter: SmoothingFilter) -> Self {
+        match filter {
+            SmoothingFilter::Ewma(alpha) => Smoother::Ewma {
+                alpha,
+                estimate: None,
+            },
+            SmoothingFilter::Kalman => Smoother::Kalman(None),
+        }
+    }
+
+    /// Update with an accepted measurement
+    pub fn add(&mut self, m: &Measurement) {
+        match self {
+            Smoother::Ewma { alpha, estimate } => {
+                *estimate = Some(match *estimate {
+                    Some(estimate) => *alpha * m.offset + (1.0 - *alpha) * estimate,
+                    None => m.offset,
+                });
+            }
+            Smoother::Kalman(Some(kalman)) => kalman.update(m),
+            Smoother::Kalman(state) => *state = Some(Kalman::new(m)),
+        }
+    }
+
+    pub fn estimate(&self) -> Option<f64> {
+        match self {
+            Smoother::Ewma { estimate, .. } => *estimate,
+            Smoother::Kalman(kalman) => kalman.as_ref().map(|k| k.offset),
+        }
+    }
+}
+
+/// Offset and frequency estimate with their covariance
+///
+/// Each measurement observes the offset with a standard deviation of half its
+/// round-trip time, the widest error the asymmetry can introduce.
+#[derive(Debug)]
+pub(crate) struct Kalman {
+    time: Timestamp,
+    offset: f64,
+    /// Rate of change of the offset (s/s)
+    frequency: f64,
+    /// Covariance of `(offset, frequency)`
+    p: [[f64; 2]; 2],
+}
+
+impl Kalman {
+    fn new(m: &Measurement) -> Self {
+        Self {
+            time: m.t1,
+            offset: m.offset,
+            frequency: 0.0,
+            p: [
+                [measurement_variance(m), 0.0],
+                [0.0, INITIAL_FREQUENCY_VARIANCE],
+            ],
+        }
+    }
+
+    fn update(&mut self, m: &Measurement) {
+        let dt = ((m.t1.total_nsec() - self.time.total_nsec()) as f64 * 1e-9).max(0.0);
+        self.time = m.t1;
+
+        // Predict
+        let offset = self.offset + self.frequency * dt;
+        let [[p00, p01], [p10, p11]] = self.p;
+        let q00 = WHITE_FM_NOISE * dt + RANDOM_WALK_FM_NOISE * dt.powi(3) / 3.0;
+        let q01 = RANDOM_WALK_FM_NOISE * dt * dt / 2.0;
+        let q11 = RANDOM_WALK_FM_NOISE * dt;
+        let p00 = p00 + dt * (p10 + p01) + dt * dt * p11 + q00;
+        let p01 = p01 + dt * p11 + q01;
+        let p10 = p10 + dt * p11 + q01;
+        let p11 = p11 + q11;
+
+        // Correct with the measured offset
+        let innovation = m.offset - offset;
+        let s = p00 + measurement_variance(m);
+        let (k0, k1) = (p00 / s, p10 / s);
+        self.offset = offset + k0 * innovation;
+        self.frequency += k1 * innovation;
+        self.p = [
+            [(1.0 - k0) * p00, (1.0 - k0) * p01],
+            [p10 - k1 * p00, p11 - k1 * p01],
+        ];
+    }
+}
+
+fn measurement_variance(m: &Measurement) -> f64 {
+    // Keep zero-RTT measurements from making the filter overconfident
+    let sigma = (m.rtt / 2.0).max(1e-9);
+    sigma * sigma
+}
@@ -0,0 +1,851 @@
+#[cfg(target_os = "linux")]
+use crate::timestamping;
+use crate::{
+    clock::{Clock, Timestamp},
+    timestamping::TimestampSource,
+};
+#[cfg(unix)]
+use anyhow::anyhow;
+use anyhow::{bail, Context, Result};
+#[cfg(unix)]
+use nix::sys::socket::{
+    bind, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrStorage,
+};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+#[cfg(unix)]
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6},
+    os::unix::io::AsRawFd,
+};
+#[cfg(unix)]
+use tokio::io::Interest;
+use tokio::net::{TcpSocket, UdpSocket};
+#[cfg(unix)]
+use tracing::warn;
+
+/// Bind a non-blocking UDP socket
+///
+/// Sockets bound to the IPv6 unspecified address `[::]` are made dual-stack,
+/// so they also accept IPv4 traffic as v4-mapped addresses. With `reuse_port`,
+/// several sockets can be bound to the same address.
+#[cfg(unix)]
+pub fn bind_udp(addr: SocketAddr, reuse_port: bool) -> Result<UdpSocket> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    // Apple systems have no SOCK_NONBLOCK/SOCK_CLOEXEC, the flags are set afterwards
+    #[cfg(not(target_vendor = "apple"))]
+    let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+    #[cfg(target_vendor = "apple")]
+    let flags = SockFlag::empty();
+    let fd = socket(family, SockType::Datagram, flags, None).context("socket() call failed")?;
+    let std_socket = std::net::UdpSocket::from(fd);
+    #[cfg(target_vendor = "apple")]
+    {
+        use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+        std_socket.set_nonblocking(true)?;
+        fcntl(
+            std_socket.as_raw_fd(),
+            FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC),
+        )
+        .context("fcntl() call failed")?;
+    }
+
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        // Not fatal: some systems do not support dual-stack sockets
+        if let Err(e) = setsockopt(&std_socket, sockopt::Ipv6V6Only, &false) {
+            warn!("Failed to enable dual-stack socket: {}", e);
+        }
+    }
+
+    if reuse_port {
+        setsockopt(&std_socket, sockopt::ReusePort, &true)
+            .context("failed to enable SO_REUSEPORT")?;
+    }
+    bind(std_socket.as_raw_fd(), &SockaddrStorage::from(addr))
+        .with_context(|| format!("failed to bind to {}", addr))?;
+
+    Ok(UdpSocket::from_std(std_socket)?)
+}
+
+/// Bind a non-blocking UDP socket
+///
+/// IPv6 sockets keep the platform default of not accepting IPv4 traffic.
+#[cfg(not(unix))]
+pub fn bind_udp(addr: SocketAddr, reuse_port: bool) -> Result<UdpSocket> {
+    if reuse_port {
+        bail!("SO_REUSEPORT is not supported on this platform");
+    }
+    let socket =
+        std::net::UdpSocket::bind(addr).with_context(|| format!("failed to bind to {}", addr))?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket)?)
+}
+
+/// Differentiated Services code point, the upper six bits of the IPv4 TOS
+/// and IPv6 traffic class byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dscp(u8);
+
+/// Standard code point names: class selectors, lower effort, assured
+/// forwarding classes, voice admit and expedited forwarding
+const DSCP_NAMES: &[(&str, u8)] = &[
+    ("CS0", 0),
+    ("LE", 1),
+    ("CS1", 8),
+    ("AF11", 10),
+    ("AF12", 12),
+    ("AF13", 14),
+    ("CS2", 16),
+    ("AF21", 18),
+    ("AF22", 20),
+    ("AF23", 22),
+    ("CS3", 24),
+    ("AF31", 26),
+    ("AF32", 28),
+    ("AF33", 30),
+    ("CS4", 32),
+    ("AF41", 34),
+    ("AF42", 36),
+    ("AF43", 38),
+    ("CS5", 40),
+    ("VA", 44),
+    ("EF", 46),
+    ("CS6", 48),
+    ("CS7", 56),
+];
+
+impl Dscp {
+    /// The code point shifted into a TOS/traffic class byte, ECN bits clear
+    pub fn tos(&self) -> u8 {
+        self.0 << 2
+    }
+}
+
+impl fmt::Display for Dscp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match DSCP_NAMES.iter().find(|(_, value)| *value == self.0) {
+            Some((name, _)) => f.write_str(name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl FromStr for Dscp {
+    type Err = anyhow::Error;
+
+    /// A code point name such as `EF` or `AF41`, or a number from 0 to 63
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((_, value)) = DSCP_NAMES
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        {
+            return Ok(Dscp(*value));
+        }
+        match s.parse::<u8>() {
+            Ok(value) if value < 64 => Ok(Dscp(value)),
+            _ => bail!(
+                "invalid DSCP '{}', expected a name such as EF or AF41, or a number from 0 to 63",
+                s
+            ),
+        }
+    }
+}
+
+/// Explicit Congestion Notification code point, the lower two bits of the
+/// IPv4 TOS and IPv6 traffic class byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    /// Congestion experienced, marked by a router on the path
+    Ce,
+}
+
+impl Ecn {
+    pub fn from_tos(tos: u8) -> Self {
+        match tos & 3 {
+            0 => Ecn::NotEct,
+            1 => Ecn::Ect1,
+            2 => Ecn::Ect0,
+            _ => Ecn::Ce,
+        }
+    }
+}
+
+impl fmt::Display for Ecn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Ecn::NotEct => "not-ect",
+            Ecn::Ect1 => "ect1",
+            Ecn::Ect0 => "ect0",
+            Ecn::Ce => "ce",
+        })
+    }
+}
+
+impl FromStr for Ecn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "not-ect" => Ok(Ecn::NotEct),
+            "ect1" => Ok(Ecn::Ect1),
+            "ect0" => Ok(Ecn::Ect0),
+            "ce" => Ok(Ecn::Ce),
+            _ => bail!("unknown ECN code point '{}'", s),
+        }
+    }
+}
+
+/// Options applied to the sockets probes and replies are sent on
+#[derive(Clone, Debug, Default)]
+pub struct SocketOptions {
+    /// DSCP marking, so packets get the treatment of a traffic class
+    pub dscp: Option<Dscp>,
+    /// `SO_PRIORITY` (Linux), selecting the queueing discipline band
+    pub priority: Option<u32>,
+    /// Only send and receive through this network interface (`SO_BINDTODEVICE`, Linux)
+    pub interface: Option<String>,
+    /// IPv4 TTL or IPv6 hop limit of sent packets
+    pub ttl: Option<u32>,
+    /// Set the don't-fragment flag, and never fragment locally: packets
+    /// larger than the path MTU are lost (Linux)
+    pub dont_fragment: bool,
+}
+
+/// Socket [`SocketOptions`] can be applied to: UDP sockets, and TCP ones
+/// before they connect
+#[cfg(unix)]
+pub trait Configurable: AsRawFd {
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+#[cfg(not(unix))]
+pub trait Configurable {
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+    fn set_ttl(&self, ttl: u32) -> std::io::Result<()>;
+}
+
+impl Configurable for UdpSocket {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    #[cfg(not(unix))]
+    fn set_ttl(&self, ttl: u32) -> std::io::Result<()> {
+        UdpSocket::set_ttl(self, ttl)
+    }
+}
+
+impl Configurable for TcpSocket {
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpSocket::local_addr(self)
+    }
+
+    #[cfg(not(unix))]
+    fn set_ttl(&self, _ttl: u32) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl SocketOptions {
+    #[cfg(unix)]
+    pub fn apply(&self, socket: &impl Configurable) -> Result<()> {
+        if let Some(dscp) = self.dscp {
+            let tos = dscp.tos() as libc::c_int;
+            if socket.local_addr()?.is_ipv6() {
+                set_int_option(
+                    socket,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_TCLASS,
+                    tos,
+                    "IPV6_TCLASS",
+                )?;
+                // IPv4 packets of dual-stack sockets are marked with IP_TOS
+                #[cfg(target_os = "linux")]
+                set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos, "IP_TOS")?;
+            } else {
+                set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos, "IP_TOS")?;
+            }
+        }
+        if let Some(ttl) = self.ttl {
+            let ttl = ttl as libc::c_int;
+            if socket.local_addr()?.is_ipv6() {
+                set_int_option(
+                    socket,
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_UNICAST_HOPS,
+                    ttl,
+                    "IPV6_UNICAST_HOPS",
+                )?;
+                #[cfg(target_os = "linux")]
+                set_int_option(socket, libc::IPPROTO_IP, libc::IP_TTL, ttl, "IP_TTL")?;
+            } else {
+                set_int_option(socket, libc::IPPROTO_IP, libc::IP_TTL, ttl, "IP_TTL")?;
+            }
+        }
+        if let Some(priority) = self.priority {
+            #[cfg(target_os = "linux")]
+            set_int_option(
+                socket,
+                libc::SOL_SOCKET,
+                libc::SO_PRIORITY,
+                priority as libc::c_int,
+                "SO_PRIORITY",
+            )?;
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = priority;
+                bail!("socket priorities are only supported on Linux");
+            }
+        }
+        if self.dont_fragment {
+            #[cfg(target_os = "linux")]
+            {
+                if socket.local_addr()?.is_ipv6() {
+                    set_int_option(
+                        socket,
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_MTU_DISCOVER,
+                        libc::IPV6_PMTUDISC_DO,
+                        "IPV6_MTU_DISCOVER",
+                    )?;
+                }
+                set_int_option(
+                    socket,
+                    libc::IPPROTO_IP,
+                    libc::IP_MTU_DISCOVER,
+                    libc::IP_PMTUDISC_DO,
+                    "IP_MTU_DISCOVER",
+                )?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            bail!("the don't-fragment flag is only supported on Linux");
+        }
+        if let Some(interface) = &self.interface {
+            #[cfg(target_os = "linux")]
+            {
+                let ret = unsafe {
+                    libc::setsockopt(
+                        socket.as_raw_fd(),
+                        libc::SOL_SOCKET,
+                        libc::SO_BINDTODEVICE,
+                        interface.as_ptr() as *const libc::c_void,
+                        interface.len() as libc::socklen_t,
+                    )
+                };
+                if ret != 0 {
+                    return Err(io::Error::last_os_error())
+                        .with_context(|| format!("failed to bind to interface {}", interface));
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                let _ = interface;
+                bail!("binding to an interface is only supported on Linux");
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, socket: &impl Configurable) -> Result<()> {
+        if self.dscp.is_some()
+            || self.priority.is_some()
+            || self.interface.is_some()
+            || self.dont_fragment
+        {
+            bail!("socket options are not supported on this platform");
+        }
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl).context("failed to set the TTL")?;
+        }
+        Ok(())
+    }
+}
+
+/// Unspecified local address of the same family as `remote`
+pub fn unspecified_for(remote: &SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+/// Received datagram
+pub struct Received {
+    pub len: usize,
+    pub from: SocketAddr,
+    /// Local receive time
+    pub timestamp: Timestamp,
+    pub source: TimestampSource,
+    /// Address the datagram was sent to, with packet info enabled
+    pub dest: Option<PacketInfo>,
+    /// IPv4 TTL or IPv6 hop limit, see [`enable_ttl_tos`]
+    pub ttl: Option<u8>,
+    /// IPv4 TOS or IPv6 traffic class byte, see [`enable_ttl_tos`]
+    pub tos: Option<u8>,
+}
+
+/// Local address and interface a datagram arrived on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PacketInfo {
+    pub addr: IpAddr,
+    pub interface: u32,
+}
+
+/// Receive a datagram, with `recvmsg()` if `ancillary` is set to read kernel
+/// timestamps and packet info
+///
+/// The receive time is taken from `clock` if the kernel did not provide one.
+pub async fn recv(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    ancillary: bool,
+    clock: &Clock,
+) -> Result<Received> {
+    if !ancillary {
+        let (len, from) = socket.recv_from(buf).await?;
+        return Ok(Received {
+            len,
+            from,
+            timestamp: clock.now()?,
+            source: TimestampSource::Userspace,
+            dest: None,
+            ttl: None,
+            tos: None,
+        });
+    }
+    recv_timestamped(socket, buf, clock).await
+}
+
+#[cfg(unix)]
+async fn recv_timestamped(socket: &UdpSocket, buf: &mut [u8], clock: &Clock) -> Result<Received> {
+    let msg = loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || {
+            recvmsg(socket, buf, libc::MSG_DONTWAIT)
+        }) {
+            Ok(msg) => break msg,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    let (timestamp, source) = match msg.timestamp {
+        Some(timestamp) => timestamp,
+        None => (clock.now()?, TimestampSource::Userspace),
+    };
+    Ok(Received {
+        len: msg.len,
+        from: msg
+            .from
+            .ok_or_else(|| anyhow!("recvmsg() returned no source address"))?,
+        timestamp,
+        source,
+        dest: msg.dest,
+        ttl: msg.ttl,
+        tos: msg.tos,
+    })
+}
+
+#[cfg(not(unix))]
+async fn recv_timestamped(
+    _socket: &UdpSocket,
+    _buf: &mut [u8],
+    _clock: &Clock,
+) -> Result<Received> {
+    anyhow::bail!("kernel receive timestamps are not supported on this platform")
+}
+
+/// Report the destination address of every datagram, so replies can be sent from it;
+/// tells whether the platform supports it for the socket
+///
+/// Useful on sockets bound to an unspecified address of a multihomed host, where
+/// the kernel would otherwise pick the reply source address by route.
+#[cfg(unix)]
+pub fn enable_pktinfo(socket: &UdpSocket) -> Result<bool> {
+    if socket.local_addr()?.is_ipv6() {
+        set_int_option(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVPKTINFO,
+            1,
+            "IPV6_RECVPKTINFO",
+        )?;
+        // IPv4 destinations on dual-stack sockets are reported with IP_PKTINFO
+        #[cfg(target_os = "linux")]
+        set_int_option(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1, "IP_PKTINFO")?;
+        return Ok(true);
+    }
+    #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+    {
+        set_int_option(socket, libc::IPPROTO_IP, libc::IP_PKTINFO, 1, "IP_PKTINFO")?;
+        Ok(true)
+    }
+    #[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+    Ok(false)
+}
+
+#[cfg(not(unix))]
+pub fn enable_pktinfo(_socket: &UdpSocket) -> Result<bool> {
+    Ok(false)
+}
+
+/// Report the TTL (hop limit) and the TOS (traffic class) byte of every
+/// datagram received, read with ancillary data
+#[cfg(target_os = "linux")]
+pub fn enable_ttl_tos(socket: &UdpSocket) -> Result<()> {
+    if socket.local_addr()?.is_ipv6() {
+        set_int_option(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVHOPLIMIT,
+            1,
+            "IPV6_RECVHOPLIMIT",
+        )?;
+        set_int_option(
+            socket,
+            libc::IPPROTO_IPV6,
+            libc::IPV6_RECVTCLASS,
+            1,
+            "IPV6_RECVTCLASS",
+        )?;
+    }
+    // Also for IPv4 packets of dual-stack sockets
+    set_int_option(socket, libc::IPPROTO_IP, libc::IP_RECVTTL, 1, "IP_RECVTTL")?;
+    set_int_option(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1, "IP_RECVTOS")?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_ttl_tos(_socket: &UdpSocket) -> Result<()> {
+    bail!("the TTL and TOS of received packets are only reported on Linux")
+}
+
+/// Send a datagram to `to`, from the local address in `from` if given
+pub async fn send_to(
+    socket: &UdpSocket,
+    buf: &[u8],
+    to: SocketAddr,
+    from: Option<PacketInfo>,
+) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(from) = from {
+        loop {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || sendmsg_from(socket, buf, to, from)) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = from;
+    socket.send_to(buf, to).await?;
+    Ok(())
+}
+
+/// Non-async `sendmsg()` with an `IP_PKTINFO` or `IPV6_PKTINFO` source address
+#[cfg(unix)]
+fn sendmsg_from(
+    socket: &UdpSocket,
+    buf: &[u8],
+    to: SocketAddr,
+    from: PacketInfo,
+) -> io::Result<()> {
+    use nix::sys::socket::SockaddrLike;
+
+    let addr = SockaddrStorage::from(to);
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut cmsg_buf = [0u64; 8];
+    let mut mhdr: libc::msghdr = unsafe { mem::zeroed() };
+    mhdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+    mhdr.msg_namelen = addr.len();
+    mhdr.msg_iov = &mut iov;
+    mhdr.msg_iovlen = 1;
+    mhdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    unsafe { write_pktinfo(&mut mhdr, from) };
+
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &mhdr, libc::MSG_DONTWAIT) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the source address of a message to send
+///
+/// # Safety
+/// `mhdr.msg_control` must point to an aligned buffer of at least 64 bytes.
+#[cfg(unix)]
+pub(crate) unsafe fn write_pktinfo(mhdr: &mut libc::msghdr, from: PacketInfo) {
+    let written = match from.addr {
+        #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+        IpAddr::V4(addr) => {
+            let mut info: libc::in_pktinfo = mem::zeroed();
+            info.ipi_spec_dst.s_addr = u32::from(addr).to_be();
+            write_cmsg(mhdr, libc::IPPROTO_IP, libc::IP_PKTINFO, info)
+        }
+        #[cfg(not(any(target_os = "linux", target_vendor = "apple")))]
+        IpAddr::V4(_) => 0,
+        IpAddr::V6(addr) => {
+            let mut info: libc::in6_pktinfo = mem::zeroed();
+            info.ipi6_addr.s6_addr = addr.octets();
+            // Needed for link-local addresses
+            info.ipi6_ifindex = from.interface as _;
+            write_cmsg(mhdr, libc::IPPROTO_IPV6, libc::IPV6_PKTINFO, info)
+        }
+    };
+    mhdr.msg_controllen = written as _;
+    if written == 0 {
+        mhdr.msg_control = std::ptr::null_mut();
+    }
+}
+
+/// Put `value` as the only control message of `mhdr`, returning the control length
+///
+/// # Safety
+/// `mhdr.msg_control` must point to an aligned buffer of at least
+/// `CMSG_SPACE(size_of::<T>())` bytes.
+#[cfg(unix)]
+unsafe fn write_cmsg<T>(
+    mhdr: &mut libc::msghdr,
+    level: libc::c_int,
+    kind: libc::c_int,
+    value: T,
+) -> usize {
+    let space = libc::CMSG_SPACE(mem::size_of::<T>() as _) as usize;
+    mhdr.msg_controllen = space as _;
+    let cmsg = libc::CMSG_FIRSTHDR(mhdr);
+    (*cmsg).cmsg_level = level;
+    (*cmsg).cmsg_type = kind;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<T>() as _) as _;
+    std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut T, value);
+    space
+}
+
+#[cfg(unix)]
+pub(crate) fn set_int_option(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+    what: &str,
+) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error()).with_context(|| format!("failed to set {}", what));
+    }
+    Ok(())
+}
+
+/// Datagram or error queue entry read with `recvmsg()`
+#[cfg(unix)]
+pub(crate) struct Message {
+    pub len: usize,
+    pub from: Option<SocketAddr>,
+    /// Timestamp from `SCM_TIMESTAMPNS` or `SCM_TIMESTAMPING` (Linux), or
+    /// `SCM_TIMESTAMP` (other systems)
+    pub timestamp: Option<(Timestamp, TimestampSource)>,
+    /// `IP_RECVERR`/`IPV6_RECVERR` error queue entry
+    #[cfg(target_os = "linux")]
+    pub extended_err: Option<libc::sock_extended_err>,
+    /// Address of the host that sent the ICMP error of `extended_err`
+    #[cfg(target_os = "linux")]
+    pub offender: Option<IpAddr>,
+    /// `IP_PKTINFO` or `IPV6_PKTINFO` destination
+    pub dest: Option<PacketInfo>,
+    /// `IP_TTL` or `IPV6_HOPLIMIT`
+    pub ttl: Option<u8>,
+    /// `IP_TOS` or `IPV6_TCLASS`
+    pub tos: Option<u8>,
+}
+
+/// Non-async `recvmsg()` with the control messages of interest parsed
+#[cfg(unix)]
+pub(crate) fn recvmsg(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    flags: libc::c_int,
+) -> io::Result<Message> {
+    let mut cmsg_buf = [0u64; 64];
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut mhdr: libc::msghdr = unsafe { mem::zeroed() };
+    mhdr.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+    mhdr.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+    mhdr.msg_iov = &mut iov;
+    mhdr.msg_iovlen = 1;
+    mhdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+    mhdr.msg_controllen = mem::size_of_val(&cmsg_buf) as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut mhdr, flags) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { parse_message(&mhdr, &addr, len as usize) })
+}
+
+/// Source address and control messages of interest of a received message
+///
+/// # Safety
+/// `mhdr` must have been filled in by a successful `recvmsg()` or `recvmmsg()`,
+/// with `addr` as its name buffer.
+#[cfg(unix)]
+pub(crate) unsafe fn parse_message(
+    mhdr: &libc::msghdr,
+    addr: &libc::sockaddr_storage,
+    len: usize,
+) -> Message {
+    let mut msg = Message {
+        len,
+        from: (mhdr.msg_namelen > 0)
+            .then(|| to_socket_addr(addr))
+            .flatten(),
+        timestamp: None,
+        #[cfg(target_os = "linux")]
+        extended_err: None,
+        #[cfg(target_os = "linux")]
+        offender: None,
+        dest: None,
+        ttl: None,
+        tos: None,
+    };
+
+    let mut cmsg = libc::CMSG_FIRSTHDR(mhdr);
+    while let Some(hdr) = unsafe { cmsg.as_ref() } {
+        let data = unsafe {
+            let data = libc::CMSG_DATA(hdr);
+            // `cmsg_len` is `u32` on Apple systems
+            #[allow(clippy::unnecessary_cast)]
+            let len = hdr.cmsg_len as usize - (data as usize - cmsg as usize);
+            std::slice::from_raw_parts(data, len)
+        };
+
+        match (hdr.cmsg_level, hdr.cmsg_type) {
+            #[cfg(target_os = "linux")]
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMPNS) => {
+                let ts: libc::timespec = unsafe { read_cmsg(data) };
+                msg.timestamp = Some((
+                    Timestamp::new(ts.tv_sec, ts.tv_nsec),
+                    TimestampSource::Kernel,
+                ));
+            }
+            #[cfg(target_os = "linux")]
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMPING) => {
+                msg.timestamp = timestamping::parse_scm_timestamping(data);
+            }
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IP, libc::IP_RECVERR) | (libc::IPPROTO_IPV6, libc::IPV6_RECVERR) => {
+                msg.extended_err = Some(unsafe { read_cmsg(data) });
+                // The offender address follows the error, `AF_UNSPEC` if unknown
+                let offender = &data[mem::size_of::<libc::sock_extended_err>()..];
+                let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+                let len = offender.len().min(mem::size_of_val(&addr));
+                std::ptr::copy_nonoverlapping(
+                    offender.as_ptr(),
+                    &mut addr as *mut _ as *mut u8,
+                    len,
+                );
+                msg.offender = to_socket_addr(&addr).map(|addr| addr.ip());
+            }
+            #[cfg(any(target_os = "linux", target_vendor = "apple"))]
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info: libc::in_pktinfo = unsafe { read_cmsg(data) };
+                msg.dest = Some(PacketInfo {
+                    addr: Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into(),
+                    interface: info.ipi_ifindex as u32,
+                });
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info: libc::in6_pktinfo = unsafe { read_cmsg(data) };
+                msg.dest = Some(PacketInfo {
+                    addr: Ipv6Addr::from(info.ipi6_addr.s6_addr).into(),
+                    interface: info.ipi6_ifindex as u32,
+                });
+            }
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IP, libc::IP_TTL) | (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) => {
+                let ttl: libc::c_int = unsafe { read_cmsg(data) };
+                msg.ttl = u8::try_from(ttl).ok();
+            }
+            // A single byte for IPv4, an `int` for IPv6
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IP, libc::IP_TOS) => msg.tos = data.first().copied(),
+            #[cfg(target_os = "linux")]
+            (libc::IPPROTO_IPV6, libc::IPV6_TCLASS) => {
+                let tclass: libc::c_int = unsafe { read_cmsg(data) };
+                msg.tos = u8::try_from(tclass).ok();
+            }
+            #[cfg(not(target_os = "linux"))]
+            (libc::SOL_SOCKET, libc::SCM_TIMESTAMP) => {
+                let tv: libc::timeval = unsafe { read_cmsg(data) };
+                msg.timestamp = Some((
+                    Timestamp::new(tv.tv_sec as i64, tv.tv_usec as i64 * 1000),
+                    TimestampSource::Kernel,
+                ));
+            }
+            _ => {}
+        }
+
+        cmsg = libc::CMSG_NXTHDR(mhdr, cmsg);
+    }
+
+    msg
+}
+
+/// Unaligned read of a control message payload
+///
+/// # Safety
+/// `T` must be a plain C struct valid for any bit pattern.
+#[cfg(unix)]
+unsafe fn read_cmsg<T>(data: &[u8]) -> T {
+    assert!(data.len() >= mem::size_of::<T>());
+    std::ptr::read_unaligned(data.as_ptr() as *const T)
+}
+
+#[cfg(unix)]
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match addr.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
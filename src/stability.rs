@@ -0,0 +1,109 @@
+//! Oscillator stability statistics over offset (phase) series
+
+use std::fmt;
+
+/// Overlapping Allan deviation at `tau = m * tau0` of phase samples taken every `tau0` seconds
+///
+/// `None` if the series is too short for `m`.
+pub fn adev(phase: &[f64], tau0: f64, m: usize) -> Option<f64> {
+    if m == 0 || phase.len() <= 2 * m {
+        return None;
+    }
+
+    let terms = phase.len() - 2 * m;
+    let sum: f64 = (0..terms)
+        .map(|i| {
+            let d = second_difference(phase, i, m);
+            d * d
+        })
+        .sum();
+    let tau = m as f64 * tau0;
+    Some((sum / (2.0 * tau * tau * terms as f64)).sqrt())
+}
+
+/// Time deviation at `tau = m * tau0`, in seconds
+///
+/// Computed from the modified Allan variance as `TVAR = tau^2 / 3 * MVAR`, which
+/// leaves `tau0` out. `None` if the series is too short for `m`.
+pub fn tdev(phase: &[f64], m: usize) -> Option<f64> {
+    if m == 0 || phase.len() < 3 * m {
+        return None;
+    }
+
+    let terms = phase.len() - 3 * m + 1;
+    // Sliding sum of m consecutive second differences
+    let mut window: f64 = (0..m).map(|i| second_difference(phase, i, m)).sum();
+    let mut sum = window * window;
+    for j in 1..terms {
+        window += second_difference(phase, j + m - 1, m) - second_difference(phase, j - 1, m);
+        sum += window * window;
+    }
+    let m = m as f64;
+    Some((sum / (6.0 * m * m * terms as f64)).sqrt())
+}
+
+fn second_difference(phase: &[f64], i: usize, m: usize) -> f64 {
+    phase[i + 2 * m] - 2.0 * phase[i + m] + phase[i]
+}
+
+/// Averaging factors 1, 2, 4, ... that [`adev`] accepts for `n` samples
+pub fn octave_factors(n: usize) -> Vec<usize> {
+    std::iter::successors(Some(1usize), |m| m.checked_mul(2))
+        .take_while(|m| n > 2 * m)
+        .collect()
+}
+
+/// Median spacing of sample times, taken as the nominal sampling interval
+pub fn sample_interval(times: &[f64]) -> Option<f64> {
+    let mut steps: Vec<_> = times.windows(2).map(|w| w[1] - w[0]).collect();
+    if steps.is_empty() {
+        return None;
+    }
+    Some(crate::outlier::median(&mut steps))
+}
+
+/// Deviations at one averaging time
+#[derive(Clone, Copy, Debug)]
+pub struct StabilityPoint {
+    /// Averaging time (seconds)
+    pub tau: f64,
+    pub adev: f64,
+    /// Missing at the longest averaging times, which need more samples than ADEV
+    pub tdev: Option<f64>,
+}
+
+/// ADEV and TDEV at octave-spaced averaging times
+#[derive(Clone, Debug, Default)]
+pub struct Stability {
+    pub points: Vec<StabilityPoint>,
+}
+
+impl Stability {
+    /// Analyze phase samples taken every `tau0` seconds
+    pub fn of(phase: &[f64], tau0: f64) -> Self {
+        let points = octave_factors(phase.len())
+            .into_iter()
+            .filter_map(|m| {
+                Some(StabilityPoint {
+                    tau: m as f64 * tau0,
+                    adev: adev(phase, tau0, m)?,
+                    tdev: tdev(phase, m),
+                })
+            })
+            .collect();
+        Self { points }
+    }
+}
+
+impl fmt::Display for Stability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for p in &self.points {
+            write!(f, "  tau {:>10.3} s  adev {:.3e}", p.tau, p.adev)?;
+            match p.tdev {
+                Some(tdev) => writeln!(f, "  tdev {:.3e} s", tdev)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{
+    measurement::Measurement,
+    outlier::{median, MAD_SCALE},
+};
+use std::collections::VecDeque;
+
+/// Offset changes needed before steps are detected
+const MIN_STEP_SAMPLES: usize = 8;
+/// Lower bound of the noise floor, so noiseless series do not flag every nanosecond
+const MIN_NOISE: f64 = 1e-9;
+
+/// Detects discontinuities of the local or remote clock, such as NTP stepping it
+#[derive(Debug)]
+pub struct StepDetector {
+    threshold: f64,
+    window: usize,
+    last_offset: Option<f64>,
+    /// Recent changes of the offset between consecutive accepted samples
+    changes: VecDeque<f64>,
+}
+
+impl StepDetector {
+    /// Flag offset changes of more than `threshold` times the noise floor, the
+    /// median plus scaled MAD of the last `window` changes
+    pub fn new(threshold: f64, window: usize) -> Self {
+        Self {
+            threshold,
+            window: window.max(MIN_STEP_SAMPLES),
+            last_offset: None,
+            changes: VecDeque::new(),
+        }
+    }
+
+    /// Whether the clocks stepped between the previous accepted sample and `m`
+    ///
+    /// A step also has to put the previous offset outside the offset bounds of
+    /// `m`, so that delay asymmetry alone can not explain it. The noise floor
+    /// is learned anew after a step.
+    pub fn is_step(&mut self, m: &Measurement) -> bool {
+        let Some(last_offset) = self.last_offset.replace(m.offset) else {
+            return false;
+        };
+        let change = (m.offset - last_offset).abs();
+        let step = self.changes.len() >= MIN_STEP_SAMPLES
+            && change > self.threshold * self.noise_floor()
+            && !(m.offset_min..=m.offset_max).contains(&last_offset);
+
+        if step {
+            self.changes.clear();
+        } else {
+            if self.changes.len() == self.window {
+                self.changes.pop_front();
+            }
+            self.changes.push_back(change);
+        }
+        step
+    }
+
+    /// Forget the offsets so far, for an offset change that is not a step
+    pub fn reset(&mut self) {
+        self.last_offset = None;
+        self.changes.clear();
+    }
+
+    fn noise_floor(&self) -> f64 {
+        let mut changes: Vec<f64> = self.changes.iter().copied().collect();
+        let center = median(&mut changes);
+        let mut deviations: Vec<f64> = changes.iter().map(|c| (c - center).abs()).collect();
+        (center + median(&mut deviations) * MAD_SCALE).max(MIN_NOISE)
+    }
+}
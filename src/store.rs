@@ -0,0 +1,272 @@
+//! Storage of the samples of `measure` in an SQLite database, for SQL
+//! queries over long-term data and for `analyze`
+//!
+//! Every run adds a row to `runs`, with its targets, command line and the
+//! version of the tool, and its samples to `measurements` and lost probes to
+//! `lost_probes`. Timestamps are integer nanoseconds since the epoch,
+//! offsets and delays seconds.
+//!
+//! Only built with the `sqlite` feature; otherwise opening a store fails.
+
+use anyhow::{bail, Result};
+use std::{fmt, path::PathBuf, str::FromStr};
+
+pub use sys::{load, Store};
+
+/// Where samples are stored, `sqlite:PATH` on the command line
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StoreSpec {
+    Sqlite(PathBuf),
+}
+
+impl FromStr for StoreSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            Some(("sqlite", path)) if !path.is_empty() => Ok(StoreSpec::Sqlite(path.into())),
+            _ => bail!("unknown store '{}', expected sqlite:PATH", s),
+        }
+    }
+}
+
+impl fmt::Display for StoreSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreSpec::Sqlite(path) => write!(f, "sqlite:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sys {
+    use super::StoreSpec;
+    use crate::{
+        analysis::Sample,
+        clock::Timestamp,
+        measurement::{LostProbe, Measurement},
+        record::Record,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use rusqlite::{params, Connection};
+    use std::env;
+
+    const SCHEMA: &str = "
+        PRAGMA journal_mode = WAL;
+        PRAGMA synchronous = NORMAL;
+        CREATE TABLE IF NOT EXISTS runs (
+            id INTEGER PRIMARY KEY,
+            started INTEGER NOT NULL,
+            version TEXT NOT NULL,
+            targets TEXT NOT NULL,
+            command_line TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS measurements (
+            run INTEGER NOT NULL REFERENCES runs(id),
+            target TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            lost INTEGER NOT NULL,
+            t1 INTEGER NOT NULL,
+            t2 INTEGER NOT NULL,
+            t3 INTEGER NOT NULL,
+            t4 INTEGER NOT NULL,
+            offset REAL NOT NULL,
+            delay REAL NOT NULL,
+            rtt REAL NOT NULL,
+            filtered_offset REAL NOT NULL,
+            drift_ppm REAL,
+            discarded INTEGER NOT NULL,
+            send_delay REAL NOT NULL,
+            t1_source TEXT NOT NULL,
+            t4_source TEXT NOT NULL,
+            asymmetry_correction REAL NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS measurements_run ON measurements (run, target, t1);
+        CREATE TABLE IF NOT EXISTS lost_probes (
+            run INTEGER NOT NULL REFERENCES runs(id),
+            target TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            lost INTEGER NOT NULL,
+            t1 INTEGER NOT NULL
+        );
+    ";
+
+    /// Database the samples of one run are written to
+    pub struct Store {
+        connection: Connection,
+        run: i64,
+    }
+
+    impl Store {
+        /// Open or create the database of `spec`, starting a run of `targets`
+        pub fn create(spec: &StoreSpec, targets: &[String]) -> Result<Self> {
+            let connection = open(spec)?;
+            let command_line: Vec<_> = env::args().collect();
+            connection
+                .execute(
+                    "INSERT INTO runs (started, version, targets, command_line) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        nsec(Timestamp::now()?),
+                        env!("CARGO_PKG_VERSION"),
+                        targets.join(","),
+                        serde_json::Value::from(command_line).to_string()
+                    ],
+                )
+                .with_context(|| format!("failed to start a run in {}", spec))?;
+            let run = connection.last_insert_rowid();
+            Ok(Self { connection, run })
+        }
+
+        /// Id of the run in the `runs` table
+        pub fn run(&self) -> i64 {
+            self.run
+        }
+
+        pub fn add_sample(&mut self, target: &str, sample: &Sample) -> Result<()> {
+            let m = &sample.measurement;
+            self.connection
+                .prepare_cached(
+                    "INSERT INTO measurements VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18)",
+                )?
+                .execute(params![
+                    self.run,
+                    target,
+                    m.seq as i64,
+                    m.lost as i64,
+                    nsec(m.t1),
+                    nsec(m.t2),
+                    nsec(m.t3),
+                    nsec(m.t4),
+                    m.offset,
+                    m.delay,
+                    m.rtt,
+                    sample.filtered_offset,
+                    sample.drift_ppm,
+                    sample.flags.discarded,
+                    m.send_delay,
+                    m.t1_source.to_string(),
+                    m.t4_source.to_string(),
+                    m.asymmetry_correction
+                ])
+                .context("failed to store a sample")?;
+            Ok(())
+        }
+
+        pub fn add_lost(&mut self, target: &str, probe: &LostProbe) -> Result<()> {
+            self.connection
+                .prepare_cached("INSERT INTO lost_probes VALUES (?1, ?2, ?3, ?4, ?5)")?
+                .execute(params![
+                    self.run,
+                    target,
+                    probe.seq as i64,
+                    probe.lost as i64,
+                    nsec(probe.t1)
+                ])
+                .context("failed to store a lost probe")?;
+            Ok(())
+        }
+    }
+
+    /// Measurements of `run` in the database of `spec`, of the latest run
+    /// without one, with the derived values recomputed as for logs
+    pub fn load(spec: &StoreSpec, run: Option<i64>) -> Result<Vec<Record>> {
+        let connection = open(spec)?;
+        let run = match run {
+            Some(run) => run,
+            None => connection
+                .query_row("SELECT max(id) FROM runs", [], |row| {
+                    row.get::<_, Option<i64>>(0)
+                })?
+                .ok_or_else(|| anyhow!("no runs in {}", spec))?,
+        };
+        let mut statement = connection.prepare(
+            "SELECT target, seq, lost, t1, t2, t3, t4, send_delay, t1_source, t4_source, asymmetry_correction
+             FROM measurements WHERE run = ?1 ORDER BY rowid",
+        )?;
+        let rows = statement.query_map([run], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                [row.get::<_, i64>(1)?, row.get(2)?],
+                [row.get::<_, i64>(3)?, row.get(4)?, row.get(5)?, row.get(6)?],
+                row.get::<_, f64>(7)?,
+                [row.get::<_, String>(8)?, row.get(9)?],
+                row.get::<_, f64>(10)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (
+                target,
+                [seq, lost],
+                [t1, t2, t3, t4],
+                send_delay,
+                [t1_source, t4_source],
+                correction,
+            ) = row?;
+            let timestamp = |nsec: i64| Timestamp::from_nsec(nsec.into());
+            let mut m = Measurement::new(
+                seq as u64,
+                timestamp(t1),
+                timestamp(t2),
+                timestamp(t3),
+                timestamp(t4),
+            );
+            m.lost = lost as u64;
+            m.send_delay = send_delay;
+            m.t1_source = t1_source.parse()?;
+            m.t4_source = t4_source.parse()?;
+            m.shift_offset(correction);
+            Ok(Record {
+                target,
+                measurement: m,
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+        .with_context(|| format!("failed to read run {} from {}", run, spec))
+    }
+
+    fn open(spec: &StoreSpec) -> Result<Connection> {
+        let StoreSpec::Sqlite(path) = spec;
+        let connection =
+            Connection::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        connection
+            .execute_batch(SCHEMA)
+            .with_context(|| format!("failed to set up {}", path.display()))?;
+        Ok(connection)
+    }
+
+    /// Nanoseconds since the epoch, which fit an SQLite integer until 2262
+    fn nsec(t: Timestamp) -> i64 {
+        t.total_nsec() as i64
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+mod sys {
+    use super::StoreSpec;
+    use crate::{analysis::Sample, measurement::LostProbe, record::Record};
+    use anyhow::{bail, Result};
+
+    pub enum Store {}
+
+    impl Store {
+        pub fn create(_spec: &StoreSpec, _targets: &[String]) -> Result<Self> {
+            bail!("SQLite support is not built in, rebuild with the sqlite feature")
+        }
+
+        pub fn run(&self) -> i64 {
+            match *self {}
+        }
+
+        pub fn add_sample(&mut self, _target: &str, _sample: &Sample) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn add_lost(&mut self, _target: &str, _probe: &LostProbe) -> Result<()> {
+            match *self {}
+        }
+    }
+
+    pub fn load(_spec: &StoreSpec, _run: Option<i64>) -> Result<Vec<Record>> {
+        bail!("SQLite support is not built in, rebuild with the sqlite feature")
+    }
+}
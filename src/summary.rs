@@ -0,0 +1,306 @@
+//! End-of-run statistics
+
+use crate::{
+    analysis::Sample,
+    clock::Timestamp,
+    histogram::{DurationHistogram, Percentiles},
+    measurement::LostProbe,
+};
+use serde_json::{json, Value};
+use std::fmt;
+
+/// Statistics of the samples of one target
+#[derive(Clone, Debug, Default)]
+pub struct TargetSummary {
+    pub samples: u64,
+    pub discarded: u64,
+    /// Clock steps detected
+    pub steps: u64,
+    /// Network path changes detected
+    pub path_changes: u64,
+    /// Probes sent up to the latest answered one
+    pub sent: u64,
+    pub lost: u64,
+    /// Offsets of the accepted samples
+    pub offsets: Vec<f64>,
+    /// Round-trip times of the accepted samples
+    pub rtts: Vec<f64>,
+    /// Send times of the accepted samples (seconds), which space the offsets
+    pub times: Vec<f64>,
+    pub offset_histogram: DurationHistogram,
+    pub rtt_histogram: DurationHistogram,
+    /// Latest drift estimate
+    pub drift_ppm: Option<f64>,
+    /// Latest filtered offset
+    pub filtered_offset: Option<f64>,
+    /// Probes sent and lost before the last reset, not counted in `sent` and `lost`
+    sent_before: u64,
+    lost_before: u64,
+    /// Sequence number and send time of the first and the latest probe reported
+    first_probe: Option<(u64, Timestamp)>,
+    last_probe: Option<(u64, Timestamp)>,
+}
+
+impl TargetSummary {
+    pub fn add(&mut self, sample: &Sample) {
+        let m = &sample.measurement;
+        self.samples += 1;
+        self.sent = self.sent.max((m.seq + 1).saturating_sub(self.sent_before));
+        self.lost = m.lost.saturating_sub(self.lost_before);
+        self.add_probe(m.seq, m.t1);
+        if sample.flags.path_change {
+            self.path_changes += 1;
+        }
+        if sample.flags.discarded {
+            self.discarded += 1;
+            return;
+        }
+        if sample.flags.step {
+            self.steps += 1;
+        }
+        self.offsets.push(m.offset);
+        self.rtts.push(m.rtt);
+        self.times.push(m.t1.total_nsec() as f64 * 1e-9);
+        self.offset_histogram.record(m.offset);
+        self.rtt_histogram.record(m.rtt);
+        if sample.drift_ppm.is_some() {
+            self.drift_ppm = sample.drift_ppm;
+        }
+        self.filtered_offset = Some(sample.filtered_offset);
+    }
+
+    pub fn add_lost(&mut self, probe: &LostProbe) {
+        self.sent = self
+            .sent
+            .max((probe.seq + 1).saturating_sub(self.sent_before));
+        self.lost = probe.lost.saturating_sub(self.lost_before);
+        self.add_probe(probe.seq, probe.t1);
+    }
+
+    fn add_probe(&mut self, seq: u64, t1: Timestamp) {
+        if self.first_probe.is_none_or(|(first, _)| seq < first) {
+            self.first_probe = Some((seq, t1));
+        }
+        if self.last_probe.is_none_or(|(last, _)| seq > last) {
+            self.last_probe = Some((seq, t1));
+        }
+    }
+
+    /// Probes sent per second, from the send times of the reported probes
+    pub fn probe_rate(&self) -> Option<f64> {
+        let ((first, t1_first), (last, t1_last)) = (self.first_probe?, self.last_probe?);
+        let elapsed = (t1_last.total_nsec() - t1_first.total_nsec()) as f64 * 1e-9;
+        (elapsed > 0.0).then(|| (last - first) as f64 / elapsed)
+    }
+
+    /// Start counting afresh, keeping the latest estimates
+    pub fn reset(&mut self) {
+        *self = Self {
+            drift_ppm: self.drift_ppm,
+            filtered_offset: self.filtered_offset,
+            sent_before: self.sent_before + self.sent,
+            lost_before: self.lost_before + self.lost,
+            ..Self::default()
+        };
+    }
+
+    /// Offset and round-trip time percentiles, one line each
+    pub fn write_percentiles(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        if let Some(offset) = self.offset_histogram.percentiles() {
+            writeln!(f, "  offset {}: {}", Percentiles::LABEL, offset)?;
+        }
+        if let Some(rtt) = self.rtt_histogram.percentiles() {
+            writeln!(f, "  rtt    {}: {}", Percentiles::LABEL, rtt)?;
+        }
+        Ok(())
+    }
+
+    pub fn loss_ratio(&self) -> f64 {
+        if self.sent == 0 {
+            0.0
+        } else {
+            self.lost as f64 / self.sent as f64
+        }
+    }
+}
+
+/// Minimum, median and 95th percentile
+#[derive(Clone, Copy, Debug)]
+pub struct Spread {
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+}
+
+impl Spread {
+    pub fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(f64::total_cmp);
+        Some(Self {
+            min: sorted[0],
+            median: percentile(&sorted, 0.5),
+            p95: percentile(&sorted, 0.95),
+        })
+    }
+}
+
+impl fmt::Display for Spread {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.9}/{:.9}/{:.9}", self.min, self.median, self.p95)
+    }
+}
+
+/// Minimum, percentiles and maximum of `values` as a JSON object, `null`
+/// without values
+fn distribution_json(values: &[f64]) -> Value {
+    if values.is_empty() {
+        return Value::Null;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    json!({
+        "min": sorted[0],
+        "p50": percentile(&sorted, 0.5),
+        "p90": percentile(&sorted, 0.9),
+        "p95": percentile(&sorted, 0.95),
+        "p99": percentile(&sorted, 0.99),
+        "p99_9": percentile(&sorted, 0.999),
+        "max": sorted[sorted.len() - 1],
+    })
+}
+
+/// Nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Statistics of all targets, in the order they first reported
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    targets: Vec<(String, TargetSummary)>,
+    /// Warm-up probes of every target, not counted as sent
+    warmup: u64,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Leave the first `warmup` probes of every target out of the sent counts
+    pub fn with_warmup(warmup: u64) -> Self {
+        Self {
+            warmup,
+            ..Self::default()
+        }
+    }
+
+    pub fn add(&mut self, target: &str, sample: &Sample) {
+        self.target(target).add(sample);
+    }
+
+    pub fn add_lost(&mut self, target: &str, probe: &LostProbe) {
+        self.target(target).add_lost(probe);
+    }
+
+    fn target(&mut self, target: &str) -> &mut TargetSummary {
+        let index = match self.targets.iter().position(|(name, _)| name == target) {
+            Some(index) => index,
+            None => {
+                let summary = TargetSummary {
+                    sent_before: self.warmup,
+                    ..TargetSummary::default()
+                };
+                self.targets.push((target.to_owned(), summary));
+                self.targets.len() - 1
+            }
+        };
+        &mut self.targets[index].1
+    }
+
+    pub fn targets(&self) -> &[(String, TargetSummary)] {
+        &self.targets
+    }
+
+    /// Statistics of every target as a JSON object keyed by target,
+    /// with exact percentiles of the accepted samples
+    pub fn to_json(&self) -> Value {
+        let targets: serde_json::Map<_, _> = self
+            .targets
+            .iter()
+            .map(|(target, t)| {
+                let summary = json!({
+                    "samples": t.samples,
+                    "discarded": t.discarded,
+                    "steps": t.steps,
+                    "path_changes": t.path_changes,
+                    "sent": t.sent,
+                    "lost": t.lost,
+                    "loss_ratio": t.loss_ratio(),
+                    "probe_rate": t.probe_rate(),
+                    "offset": distribution_json(&t.offsets),
+                    "rtt": distribution_json(&t.rtts),
+                    "filtered_offset": t.filtered_offset,
+                    "drift_ppm": t.drift_ppm,
+                });
+                (target.clone(), summary)
+            })
+            .collect();
+        json!({ "targets": targets })
+    }
+
+    /// Start counting afresh for all targets, see [`TargetSummary::reset`]
+    pub fn reset(&mut self) {
+        for (_, t) in &mut self.targets {
+            t.reset();
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (target, t) in &self.targets {
+            writeln!(
+                f,
+                "{}: {} samples ({} discarded{}{}), {}/{} probes lost ({:.1}%)",
+                target,
+                t.samples,
+                t.discarded,
+                match t.steps {
+                    0 => String::new(),
+                    1 => ", 1 clock step".to_owned(),
+                    steps => format!(", {} clock steps", steps),
+                },
+                match t.path_changes {
+                    0 => String::new(),
+                    1 => ", 1 path change".to_owned(),
+                    changes => format!(", {} path changes", changes),
+                },
+                t.lost,
+                t.sent,
+                t.loss_ratio() * 100.0
+            )?;
+            if let Some(rate) = t.probe_rate() {
+                writeln!(f, "  probe rate: {:.1}/s", rate)?;
+            }
+            if let Some(offset) = Spread::of(&t.offsets) {
+                writeln!(f, "  offset min/median/p95: {}", offset)?;
+            }
+            if let Some(rtt) = Spread::of(&t.rtts) {
+                writeln!(f, "  rtt    min/median/p95: {}", rtt)?;
+            }
+            t.write_percentiles(f)?;
+            if let Some(offset) = t.filtered_offset {
+                writeln!(f, "  filtered offset: {:.9}", offset)?;
+            }
+            if let Some(drift) = t.drift_ppm {
+                writeln!(f, "  drift: {:.3} ppm", drift)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+//! Short measurements of many reflectors at once, for a snapshot of a group
+//! of hosts rather than offset series
+
+use crate::{
+    clock_filter::ClockFilter,
+    measurement::Measurement,
+    measurer::{Measurer, MeasurerConfig},
+    target::Target,
+};
+use anyhow::{anyhow, ensure, Result};
+use std::{fmt, sync::Arc};
+use tokio::sync::Semaphore;
+use tracing::{error_span, Instrument};
+
+/// Measure every target with `config`, at most `parallel` of them at a
+/// time, and keep the minimum-delay measurement of each
+///
+/// The config must have a count, so that measuring a target ends. Results
+/// are in the order of the targets, an error for a target that could not
+/// be reached or did not reply.
+pub async fn sweep(
+    targets: &[Target],
+    config: &MeasurerConfig,
+    parallel: usize,
+) -> Result<Vec<Result<Measurement>>> {
+    ensure!(config.count.is_some(), "sweeps need a count of probes");
+    ensure!(
+        parallel > 0,
+        "at least one target must be measured at a time"
+    );
+    let permits = Arc::new(Semaphore::new(parallel));
+    let handles: Vec<_> = targets
+        .iter()
+        .map(|target| {
+            let (target, config, permits) = (target.clone(), config.clone(), permits.clone());
+            // At the error level, so that filtering by level keeps the target in the messages
+            let span = error_span!("measure", remote = %target);
+            let measuring = async move {
+                let _permit = permits.acquire_owned().await?;
+                best(target, config).await
+            };
+            tokio::spawn(measuring.instrument(span))
+        })
+        .collect();
+    let mut results = Vec::with_capacity(handles.len());
+    for handle in handles {
+        results.push(handle.await.unwrap_or_else(|e| Err(e.into())));
+    }
+    Ok(results)
+}
+
+pub(crate) async fn best(target: Target, config: MeasurerConfig) -> Result<Measurement> {
+    let probes = config.count.unwrap_or_default() as usize;
+    let mut measurer = Measurer::connect(target.clone(), config).await?;
+    let mut filter = ClockFilter::new(probes);
+    while let Some(m) = measurer.next_measurement().await? {
+        filter.add(m);
+    }
+    filter
+        .best()
+        .cloned()
+        .ok_or_else(|| anyhow!("no replies from {}", target))
+}
+
+/// Best measurements of a sweep by host, sorted by offset, hosts without
+/// replies last
+#[derive(Clone, Debug)]
+pub struct Survey(Vec<(String, Option<Measurement>)>);
+
+impl Survey {
+    pub fn new(mut measurements: Vec<(String, Option<Measurement>)>) -> Self {
+        measurements.sort_by(|(_, a), (_, b)| match (a, b) {
+            (Some(a), Some(b)) => a.offset.total_cmp(&b.offset),
+            (a, b) => b.is_some().cmp(&a.is_some()),
+        });
+        Self(measurements)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Number of hosts that replied
+    pub fn replied(&self) -> usize {
+        self.0.iter().filter(|(_, m)| m.is_some()).count()
+    }
+
+    /// Lowest and highest offset of the hosts that replied
+    pub fn spread(&self) -> Option<(f64, f64)> {
+        let mut offsets = self.0.iter().filter_map(|(_, m)| Some(m.as_ref()?.offset));
+        let first = offsets.next()?;
+        Some(offsets.fold((first, first), |(min, max), o| (min.min(o), max.max(o))))
+    }
+}
+
+/// Table of the hosts with their offset, its uncertainty (half the delay)
+/// and the round-trip time
+impl fmt::Display for Survey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<39} {:>14}   {:<12} {:>12}",
+            "host", "offset", "uncertainty", "rtt"
+        )?;
+        for (host, m) in &self.0 {
+            match m {
+                Some(m) => writeln!(
+                    f,
+                    "{:<39} {:>14.9} \u{b1} {:<12.9} {:>12.9}",
+                    host,
+                    m.offset,
+                    (m.offset_max - m.offset_min) / 2.0,
+                    m.rtt
+                )?,
+                None => writeln!(f, "{:<39} {:>14}", host, "no replies")?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! systemd service integration: sockets passed by socket activation
+//! (`LISTEN_FDS`) and `sd_notify()` state notifications, without libsystemd
+//!
+//! Both are no-ops where the variables systemd sets are missing, as when not
+//! run as a systemd service.
+
+#[cfg(unix)]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use std::{env, time::Duration};
+
+/// Socket passed by systemd
+pub enum Socket {
+    Udp(std::net::UdpSocket),
+    Tcp(std::net::TcpListener),
+}
+
+/// Send `state` (e.g. `READY=1`) to the service manager, if it asked for
+/// notifications with `NOTIFY_SOCKET`
+#[cfg(unix)]
+pub fn notify(state: &str) -> Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+    // Names starting with @ are in the abstract namespace
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        bail!("abstract notification socket {} outside Linux", name);
+    } else {
+        socket
+            .send_to(state.as_bytes(), &*path)
+            .with_context(|| format!("failed to notify systemd on {}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> Result<()> {
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`, half the watchdog timeout of the
+/// service, if it has one
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != std::process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+/// Take the sockets passed by socket activation, in the order of the socket
+/// unit; they can only be taken once
+#[cfg(unix)]
+pub fn listen_sockets() -> Result<Vec<Socket>> {
+    use nix::{
+        fcntl::{fcntl, FcntlArg, FdFlag},
+        sys::socket::{getsockopt, sockopt, SockType},
+    };
+    use std::{
+        os::fd::{AsRawFd, FromRawFd, OwnedFd},
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    /// Passed descriptors start after stdin, stdout and stderr
+    const FIRST_FD: i32 = 3;
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let pid = env::var("LISTEN_PID").context("no sockets passed by systemd (LISTEN_PID)")?;
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        bail!("sockets passed by systemd are for process {}", pid);
+    }
+    let count: i32 = env::var("LISTEN_FDS")
+        .context("no sockets passed by systemd (LISTEN_FDS)")?
+        .parse()
+        .context("invalid LISTEN_FDS")?;
+    if TAKEN.swap(true, Ordering::Relaxed) {
+        bail!("sockets passed by systemd are already taken");
+    }
+    (FIRST_FD..FIRST_FD + count)
+        .map(|fd| {
+            // Safety: systemd hands the descriptors over to this process,
+            // and TAKEN makes sure they are owned once
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .context("fcntl() call failed")?;
+            match getsockopt(&fd, sockopt::SockType).context("passed descriptor is no socket")? {
+                SockType::Datagram => {
+                    let socket = std::net::UdpSocket::from(fd);
+                    socket.set_nonblocking(true)?;
+                    Ok(Socket::Udp(socket))
+                }
+                SockType::Stream => {
+                    let listener = std::net::TcpListener::from(fd);
+                    listener.set_nonblocking(true)?;
+                    Ok(Socket::Tcp(listener))
+                }
+                other => bail!("passed socket of unsupported type {:?}", other),
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn listen_sockets() -> Result<Vec<Socket>> {
+    bail!("socket activation is only supported on Unix");
+}
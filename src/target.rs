@@ -0,0 +1,146 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+};
+use tokio::net::lookup_host;
+
+/// Address family preference used when resolving a target
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Family {
+    Any,
+    V4,
+    V6,
+}
+
+impl Family {
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::V4 => addr.is_ipv4(),
+            Family::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Family::Any => write!(f, "IP"),
+            Family::V4 => write!(f, "IPv4"),
+            Family::V6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// Reflector host (name or address) and port
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    pub fn new(host: impl Into<String>, port: u16) -> Self {
+        Self {
+            host: host.into(),
+            port,
+        }
+    }
+
+    /// Parse `host`, `host:port`, `ipv6` or `[ipv6]:port`
+    pub fn parse(s: &str, default_port: u16) -> Result<Self> {
+        if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("missing ']' in {}", s))?;
+            let port = match rest.strip_prefix(':') {
+                Some(port) => port
+                    .parse()
+                    .with_context(|| format!("invalid port in {}", s))?,
+                None if rest.is_empty() => default_port,
+                None => bail!("unexpected characters after ']' in {}", s),
+            };
+            return Ok(Self::new(host, port));
+        }
+
+        match s.split_once(':') {
+            // More than one colon: a bare IPv6 address
+            Some((_, rest)) if rest.contains(':') => Ok(Self::new(s, default_port)),
+            Some((host, port)) => Ok(Self::new(
+                host,
+                port.parse()
+                    .with_context(|| format!("invalid port in {}", s))?,
+            )),
+            None => Ok(Self::new(s, default_port)),
+        }
+    }
+
+    /// Parse a targets file: one target per line, `#` starts a comment
+    pub fn parse_list(contents: &str, default_port: u16) -> Result<Vec<Self>> {
+        contents
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| Self::parse(line, default_port))
+            .collect()
+    }
+
+    /// Whether the host is an IP address rather than a name
+    pub fn is_address(&self) -> bool {
+        self.host.parse::<IpAddr>().is_ok()
+    }
+
+    /// Resolve to the first address of the requested family
+    pub async fn resolve(&self, family: Family) -> Result<SocketAddr> {
+        let mut addrs = lookup_host((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("failed to resolve {}", self.host))?;
+
+        addrs
+            .find(|addr| family.matches(addr))
+            .ok_or_else(|| anyhow!("no {} address found for {}", family, self.host))
+    }
+
+    /// Resolve to every address of the requested family, in the order of
+    /// the resolver and without duplicates
+    pub async fn resolve_all(&self, family: Family) -> Result<Vec<SocketAddr>> {
+        let addrs = lookup_host((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("failed to resolve {}", self.host))?;
+        Self::distinct(addrs, family, &self.host)
+    }
+
+    fn distinct(
+        addrs: impl Iterator<Item = SocketAddr>,
+        family: Family,
+        host: &str,
+    ) -> Result<Vec<SocketAddr>> {
+        let mut distinct = Vec::new();
+        for addr in addrs.filter(|addr| family.matches(addr)) {
+            if !distinct.contains(&addr) {
+                distinct.push(addr);
+            }
+        }
+        if distinct.is_empty() {
+            bail!("no {} address found for {}", family, host);
+        }
+        Ok(distinct)
+    }
+}
+
+impl From<SocketAddr> for Target {
+    fn from(addr: SocketAddr) -> Self {
+        Self::new(addr.ip().to_string(), addr.port())
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
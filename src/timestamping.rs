@@ -0,0 +1,282 @@
+//! Kernel and NIC hardware packet timestamping
+
+use crate::clock::Timestamp;
+#[cfg(unix)]
+use crate::socket;
+#[cfg(target_os = "linux")]
+use anyhow::Context;
+use anyhow::{bail, Result};
+use std::{fmt, str::FromStr};
+#[cfg(target_os = "linux")]
+use std::{mem, os::unix::io::AsRawFd};
+use tokio::net::UdpSocket;
+#[cfg(unix)]
+use tracing::warn;
+
+// Not exported by the libc version in use
+#[cfg(target_os = "linux")]
+const SOF_TIMESTAMPING_OPT_ID: libc::c_uint = 1 << 7;
+#[cfg(target_os = "linux")]
+const SOF_TIMESTAMPING_OPT_TSONLY: libc::c_uint = 1 << 11;
+#[cfg(target_os = "linux")]
+const SIOCSHWTSTAMP: libc::c_ulong = 0x89b0;
+#[cfg(target_os = "linux")]
+const HWTSTAMP_TX_ON: libc::c_int = 1;
+#[cfg(target_os = "linux")]
+const HWTSTAMP_FILTER_ALL: libc::c_int = 1;
+
+/// Where a packet timestamp came from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampSource {
+    /// `clock_gettime()` right before sending or after receiving
+    #[default]
+    Userspace,
+    /// Kernel software timestamp
+    Kernel,
+    /// NIC hardware timestamp
+    Hardware,
+}
+
+impl fmt::Display for TimestampSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimestampSource::Userspace => write!(f, "user"),
+            TimestampSource::Kernel => write!(f, "kernel"),
+            TimestampSource::Hardware => write!(f, "hardware"),
+        }
+    }
+}
+
+impl FromStr for TimestampSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "user" => Ok(TimestampSource::Userspace),
+            "kernel" => Ok(TimestampSource::Kernel),
+            "hardware" => Ok(TimestampSource::Hardware),
+            _ => bail!("unknown timestamp source '{}'", s),
+        }
+    }
+}
+
+/// Packet timestamping mode of a socket
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Timestamping {
+    /// Timestamps taken in userspace
+    #[default]
+    Userspace,
+    /// Kernel software receive timestamps (`SO_TIMESTAMPNS`, `SO_TIMESTAMP` outside Linux)
+    Kernel,
+    /// NIC hardware timestamps, falling back to kernel software ones
+    ///
+    /// Hardware timestamps are in the time base of the NIC clock, which should
+    /// be kept in sync with the system clock (e.g. by `phc2sys`).
+    Hardware { interface: String },
+}
+
+impl Timestamping {
+    pub fn is_userspace(&self) -> bool {
+        *self == Timestamping::Userspace
+    }
+
+    pub fn is_hardware(&self) -> bool {
+        matches!(self, Timestamping::Hardware { .. })
+    }
+}
+
+/// Transmit timestamp read from the socket error queue
+#[derive(Clone, Copy, Debug)]
+pub struct TxTimestamp {
+    /// Index of the sent packet on this socket, counting from 0
+    pub key: u32,
+    pub timestamp: Timestamp,
+    pub source: TimestampSource,
+}
+
+/// Configure `socket` for the requested timestamping
+///
+/// Transmit timestamps are only requested if `tx` is set, as they have to be
+/// drained from the error queue. Kernel receive timestamps fall back to
+/// userspace ones if the system refuses them.
+#[cfg(unix)]
+pub fn enable(socket: &UdpSocket, mode: &Timestamping, tx: bool) -> Result<()> {
+    match mode {
+        Timestamping::Userspace => Ok(()),
+        Timestamping::Kernel => {
+            if let Err(e) = enable_kernel_rx(socket) {
+                warn!(
+                    "Kernel timestamps unavailable, using userspace ones: {:#}",
+                    e
+                );
+            }
+            Ok(())
+        }
+        Timestamping::Hardware { interface } => enable_hardware(socket, interface, tx),
+    }
+}
+
+/// Nanosecond kernel receive timestamps (`SO_TIMESTAMPNS`)
+#[cfg(target_os = "linux")]
+fn enable_kernel_rx(socket: &UdpSocket) -> Result<()> {
+    socket::set_int_option(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_TIMESTAMPNS,
+        1,
+        "SO_TIMESTAMPNS",
+    )
+}
+
+/// Microsecond kernel receive timestamps (`SO_TIMESTAMP`), the portable BSD option
+#[cfg(all(unix, not(target_os = "linux")))]
+fn enable_kernel_rx(socket: &UdpSocket) -> Result<()> {
+    socket::set_int_option(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_TIMESTAMP,
+        1,
+        "SO_TIMESTAMP",
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn enable_hardware(socket: &UdpSocket, interface: &str, tx: bool) -> Result<()> {
+    if let Err(e) = enable_nic_timestamping(socket, interface) {
+        warn!(
+            "Failed to enable hardware timestamping on {}: {:#}",
+            interface, e
+        );
+    }
+
+    let mut flags = libc::SOF_TIMESTAMPING_RX_HARDWARE
+        | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE;
+    if tx {
+        flags |= libc::SOF_TIMESTAMPING_TX_HARDWARE
+            | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+            | SOF_TIMESTAMPING_OPT_ID
+            | SOF_TIMESTAMPING_OPT_TSONLY;
+    }
+    socket::set_int_option(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_TIMESTAMPING,
+        flags as libc::c_int,
+        "SO_TIMESTAMPING",
+    )
+}
+
+/// `SO_TIMESTAMPING` is Linux-only: fall back to kernel software timestamps
+#[cfg(all(unix, not(target_os = "linux")))]
+fn enable_hardware(socket: &UdpSocket, interface: &str, _tx: bool) -> Result<()> {
+    warn!(
+        "Hardware timestamping on {} is only supported on Linux, using kernel timestamps",
+        interface
+    );
+    enable(socket, &Timestamping::Kernel, false)
+}
+
+/// Request kernel software transmit timestamps only (`SOF_TIMESTAMPING_TX_SOFTWARE`)
+///
+/// Receive timestamping configured with `SO_TIMESTAMPNS` is not affected.
+#[cfg(target_os = "linux")]
+pub fn enable_tx_software(socket: &UdpSocket) -> Result<()> {
+    let flags = libc::SOF_TIMESTAMPING_TX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_SOFTWARE
+        | SOF_TIMESTAMPING_OPT_ID
+        | SOF_TIMESTAMPING_OPT_TSONLY;
+    socket::set_int_option(
+        socket,
+        libc::SOL_SOCKET,
+        libc::SO_TIMESTAMPING,
+        flags as libc::c_int,
+        "SO_TIMESTAMPING",
+    )
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct HwtstampConfig {
+    flags: libc::c_int,
+    tx_type: libc::c_int,
+    rx_filter: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct IfreqData {
+    ifr_name: [libc::c_char; libc::IFNAMSIZ],
+    ifr_data: *mut libc::c_void,
+    _pad: [u8; 16],
+}
+
+/// Turn on timestamping in the NIC driver (`SIOCSHWTSTAMP`, needs `CAP_NET_ADMIN`)
+#[cfg(target_os = "linux")]
+fn enable_nic_timestamping(socket: &UdpSocket, interface: &str) -> Result<()> {
+    if interface.len() >= libc::IFNAMSIZ {
+        bail!("interface name too long");
+    }
+
+    let mut config = HwtstampConfig {
+        flags: 0,
+        tx_type: HWTSTAMP_TX_ON,
+        rx_filter: HWTSTAMP_FILTER_ALL,
+    };
+    let mut ifreq = IfreqData {
+        ifr_name: [0; libc::IFNAMSIZ],
+        ifr_data: &mut config as *mut _ as *mut libc::c_void,
+        _pad: [0; 16],
+    };
+    for (dst, src) in ifreq.ifr_name.iter_mut().zip(interface.bytes()) {
+        *dst = src as libc::c_char;
+    }
+
+    let ret = unsafe { libc::ioctl(socket.as_raw_fd(), SIOCSHWTSTAMP as _, &mut ifreq) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).context("SIOCSHWTSTAMP failed");
+    }
+    Ok(())
+}
+
+/// Pick the best timestamp out of a `SCM_TIMESTAMPING` control message
+#[cfg(target_os = "linux")]
+pub(crate) fn parse_scm_timestamping(data: &[u8]) -> Option<(Timestamp, TimestampSource)> {
+    const TIMESPEC_SIZE: usize = mem::size_of::<libc::timespec>();
+    if data.len() < 3 * TIMESPEC_SIZE {
+        return None;
+    }
+
+    // ts[0] is the software timestamp, ts[2] the raw hardware one
+    let timespec = |index: usize| {
+        let ts: libc::timespec =
+            unsafe { std::ptr::read_unaligned(data[index * TIMESPEC_SIZE..].as_ptr() as *const _) };
+        Timestamp::new(ts.tv_sec, ts.tv_nsec)
+    };
+    let hardware = timespec(2);
+    let software = timespec(0);
+
+    if hardware != Timestamp::default() {
+        Some((hardware, TimestampSource::Hardware))
+    } else if software != Timestamp::default() {
+        Some((software, TimestampSource::Kernel))
+    } else {
+        None
+    }
+}
+
+/// Configure `socket` for the requested timestamping; only userspace timestamps
+/// are available on this platform
+#[cfg(not(unix))]
+pub fn enable(_socket: &UdpSocket, mode: &Timestamping, _tx: bool) -> Result<()> {
+    match mode {
+        Timestamping::Userspace => Ok(()),
+        _ => bail!("only userspace timestamps are supported on this platform"),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn enable_tx_software(_socket: &UdpSocket) -> Result<()> {
+    bail!("transmit timestamps are only supported on Linux")
+}
@@ -0,0 +1,384 @@
+//! Transports probes and replies are exchanged over
+
+use crate::{
+    clock::Clock,
+    random::Rng,
+    socket::{Received, SocketOptions},
+    target::Target,
+    timestamping::TimestampSource,
+    websocket,
+};
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    fmt,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpSocket, TcpStream},
+};
+
+/// How probes reach the reflector
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// A datagram per probe and reply
+    #[default]
+    Udp,
+    /// Length-prefixed packets over a TCP connection, for networks that block
+    /// UDP; results are noisier, with delayed ACKs and retransmissions holding
+    /// packets back, but the offset stays bounded by the RTT interval as over UDP
+    Tcp,
+    /// QUIC datagrams, encrypted and authenticated with TLS, for middleboxes
+    /// that mangle plain UDP; see [`crate::quic`]
+    Quic,
+    /// Binary messages over a WebSocket connection, optionally through an HTTP
+    /// proxy, for hosts that may only reach the web; as noisy as TCP
+    WebSocket,
+    /// Length-prefixed packets over a Unix domain socket, to measure the
+    /// overhead of the tool itself on a single host, with no network and a real
+    /// offset of zero; the target only names the reflector in the output
+    Unix(PathBuf),
+}
+
+impl Transport {
+    /// Name of the transport in messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Udp => "UDP",
+            Transport::Tcp => "TCP",
+            Transport::Quic => "QUIC",
+            Transport::WebSocket => "WebSocket",
+            Transport::Unix(_) => "Unix domain sockets",
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Udp => f.write_str("udp"),
+            Transport::Tcp => f.write_str("tcp"),
+            Transport::Quic => f.write_str("quic"),
+            Transport::WebSocket => f.write_str("websocket"),
+            Transport::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "udp" => Ok(Transport::Udp),
+            "tcp" => Ok(Transport::Tcp),
+            "quic" => Ok(Transport::Quic),
+            "websocket" => Ok(Transport::WebSocket),
+            _ => match s.strip_prefix("unix:") {
+                Some(path) if !path.is_empty() => Ok(Transport::Unix(path.into())),
+                _ => bail!(
+                    "unknown transport '{}', expected udp, tcp, quic, websocket or unix:PATH",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// Largest packet carried over a stream, as large as the datagram buffers
+const MAX_PACKET_SIZE: usize = 2048;
+/// Each packet is preceded by its `u16` little-endian length
+const LENGTH_SIZE: usize = 2;
+/// Room for the longest frame header
+const MAX_HEADER_SIZE: usize = websocket::MAX_HEADER_SIZE;
+/// Stands in for the address of peers on Unix domain sockets, which have none
+const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Byte stream packets are framed on
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// How packets are delimited on a stream
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    /// Length prefix of [`Transport::Tcp`]
+    Length,
+    /// WebSocket frames, masked by clients
+    WebSocket { client: bool },
+}
+
+/// Packets over a TCP connection or a Unix domain socket
+pub struct FramedStream {
+    stream: Box<dyn Stream>,
+    peer: SocketAddr,
+    framing: Framing,
+    /// Received bytes not yet returned, at most one packet with its header
+    buf: Box<[u8; MAX_HEADER_SIZE + MAX_PACKET_SIZE]>,
+    filled: usize,
+    /// WebSocket masks
+    rng: Rng,
+}
+
+impl FramedStream {
+    /// Length-prefixed packets on `stream`
+    pub fn new(stream: TcpStream) -> Result<Self> {
+        Self::with_framing(stream, Framing::Length)
+    }
+
+    fn with_framing(stream: TcpStream, framing: Framing) -> Result<Self> {
+        // Probes are sent right after t1 is read, not when Nagle's algorithm sees fit
+        stream.set_nodelay(true)?;
+        let peer = stream.peer_addr()?;
+        Self::with_stream(Box::new(stream), peer, framing)
+    }
+
+    fn with_stream(stream: Box<dyn Stream>, peer: SocketAddr, framing: Framing) -> Result<Self> {
+        Ok(Self {
+            peer,
+            stream,
+            framing,
+            buf: Box::new([0; MAX_HEADER_SIZE + MAX_PACKET_SIZE]),
+            filled: 0,
+            rng: Rng::new()?,
+        })
+    }
+
+    /// Connect to `remote`, from `source` if given
+    pub async fn connect(
+        remote: SocketAddr,
+        source: Option<SocketAddr>,
+        options: &SocketOptions,
+    ) -> Result<Self> {
+        Self::new(Self::connect_tcp(remote, source, options).await?)
+    }
+
+    /// Open a WebSocket connection to `target` at `remote`, or through the HTTP
+    /// proxy at `proxy`
+    pub async fn connect_websocket(
+        target: &Target,
+        remote: SocketAddr,
+        proxy: Option<SocketAddr>,
+        source: Option<SocketAddr>,
+        options: &SocketOptions,
+    ) -> Result<Self> {
+        let mut stream = Self::connect_tcp(proxy.unwrap_or(remote), source, options).await?;
+        let host = target.to_string();
+        if proxy.is_some() {
+            websocket::connect_proxy(&mut stream, &host).await?;
+        }
+        let mut framed = Self::with_framing(stream, Framing::WebSocket { client: true })?;
+        websocket::connect(&mut framed.stream, &host, &mut framed.rng).await?;
+        Ok(framed)
+    }
+
+    /// Connect to the reflector listening on the Unix domain socket at `path`
+    pub async fn connect_unix(path: &Path) -> Result<Self> {
+        let stream = sys::connect(path)
+            .await
+            .with_context(|| format!("failed to connect to {}", path.display()))?;
+        Self::with_stream(stream, UNIX_PEER, Framing::Length)
+    }
+
+    /// Accept the WebSocket upgrade of an incoming HTTP connection
+    pub async fn accept_websocket(mut stream: TcpStream) -> Result<Self> {
+        websocket::accept(&mut stream).await?;
+        Self::with_framing(stream, Framing::WebSocket { client: false })
+    }
+
+    async fn connect_tcp(
+        remote: SocketAddr,
+        source: Option<SocketAddr>,
+        options: &SocketOptions,
+    ) -> Result<TcpStream> {
+        let socket = match remote {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        options.apply(&socket)?;
+        if let Some(source) = source {
+            socket
+                .bind(source)
+                .with_context(|| format!("failed to bind to {}", source))?;
+        }
+        socket
+            .connect(remote)
+            .await
+            .with_context(|| format!("failed to connect to {}", remote))
+    }
+
+    /// Address of the peer, loopback for Unix domain sockets
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Next packet, copied into `buf` and timestamped with `clock` once complete;
+    /// `None` once the peer closed the connection between packets
+    ///
+    /// Cancel safe: a partly received packet stays buffered for the next call.
+    pub async fn recv(&mut self, buf: &mut [u8], clock: &Clock) -> Result<Option<Received>> {
+        loop {
+            if let Some(frame) = self.frame()? {
+                if frame.close {
+                    return Ok(None);
+                }
+                let timestamp = clock.now()?;
+                let copied = frame.payload.len().min(buf.len());
+                if !frame.control {
+                    buf[..copied].copy_from_slice(&self.buf[frame.payload.start..][..copied]);
+                }
+                self.buf.copy_within(frame.end..self.filled, 0);
+                self.filled -= frame.end;
+                // Pings of proxies keeping the connection alive go unanswered
+                if frame.control {
+                    continue;
+                }
+                return Ok(Some(Received {
+                    len: copied,
+                    from: self.peer,
+                    timestamp,
+                    source: TimestampSource::Userspace,
+                    dest: None,
+                    ttl: None,
+                    tos: None,
+                }));
+            }
+            let read = self.stream.read(&mut self.buf[self.filled..]).await?;
+            if read == 0 {
+                ensure!(self.filled == 0, "connection closed within a packet");
+                return Ok(None);
+            }
+            self.filled += read;
+        }
+    }
+
+    /// The complete frame at the start of the buffer, if any
+    fn frame(&mut self) -> Result<Option<websocket::Frame>> {
+        let buf = &mut self.buf[..self.filled];
+        match self.framing {
+            Framing::Length => {
+                if buf.len() < LENGTH_SIZE {
+                    return Ok(None);
+                }
+                let len = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+                ensure!(
+                    len <= MAX_PACKET_SIZE,
+                    "packet of {} bytes is too large",
+                    len
+                );
+                let end = LENGTH_SIZE + len;
+                Ok((buf.len() >= end).then_some(websocket::Frame {
+                    payload: LENGTH_SIZE..end,
+                    end,
+                    close: false,
+                    control: false,
+                }))
+            }
+            Framing::WebSocket { .. } => websocket::decode(buf, MAX_PACKET_SIZE),
+        }
+    }
+
+    /// Send a packet in a frame of its own, with a single write
+    pub async fn send(&mut self, packet: &[u8]) -> Result<()> {
+        ensure!(
+            packet.len() <= MAX_PACKET_SIZE,
+            "packet of {} bytes is too large",
+            packet.len()
+        );
+        let frame = match self.framing {
+            Framing::Length => {
+                let mut frame = Vec::with_capacity(LENGTH_SIZE + packet.len());
+                frame.extend_from_slice(&(packet.len() as u16).to_le_bytes());
+                frame.extend_from_slice(packet);
+                frame
+            }
+            Framing::WebSocket { client } => {
+                let mask = client.then(|| (self.rng.next_u64() as u32).to_le_bytes());
+                websocket::encode(packet, mask)
+            }
+        };
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+/// Listener for [`Transport::Unix`] connections
+pub struct UnixListener(sys::UnixListener);
+
+impl UnixListener {
+    /// Listen on `path`, replacing the socket a previous reflector left behind
+    pub fn bind(path: &Path) -> Result<Self> {
+        sys::UnixListener::bind(path)
+            .map(Self)
+            .with_context(|| format!("failed to listen on {}", path.display()))
+    }
+
+    /// Next connection, with length-prefixed packets
+    pub async fn accept(&self) -> Result<FramedStream> {
+        FramedStream::with_stream(self.accept_stream().await?, UNIX_PEER, Framing::Length)
+    }
+
+    /// Next connection, as a plain byte stream
+    pub(crate) async fn accept_stream(&self) -> Result<Box<dyn Stream>> {
+        Ok(self.0.accept().await?)
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use super::Stream;
+    use std::{fs, io, os::unix::fs::FileTypeExt, path::Path};
+    use tokio::net::UnixStream;
+
+    pub async fn connect(path: &Path) -> io::Result<Box<dyn Stream>> {
+        Ok(Box::new(UnixStream::connect(path).await?))
+    }
+
+    pub struct UnixListener(tokio::net::UnixListener);
+
+    impl UnixListener {
+        pub fn bind(path: &Path) -> io::Result<Self> {
+            // Only sockets are removed, never a file given by mistake
+            if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                fs::remove_file(path)?;
+            }
+            tokio::net::UnixListener::bind(path).map(Self)
+        }
+
+        pub async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+            let (stream, _) = self.0.accept().await?;
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use super::Stream;
+    use std::{io, path::Path};
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix domain sockets are only supported on Unix",
+        )
+    }
+
+    pub async fn connect(_path: &Path) -> io::Result<Box<dyn Stream>> {
+        Err(unsupported())
+    }
+
+    pub enum UnixListener {}
+
+    impl UnixListener {
+        pub fn bind(_path: &Path) -> io::Result<Self> {
+            Err(unsupported())
+        }
+
+        pub async fn accept(&self) -> io::Result<Box<dyn Stream>> {
+            match *self {}
+        }
+    }
+}
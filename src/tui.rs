@@ -0,0 +1,242 @@
+//! Live terminal dashboard of `measure`: a table of the targets, with the
+//! recent offsets and the round-trip time distribution of the selected one
+//!
+//! Keys: Up/Down or Tab select the target, q, Esc or Ctrl-C quit.
+
+use crate::{
+    logging::LogBuffer,
+    summary::{Spread, Summary, TargetSummary},
+};
+use anyhow::{Context, Result};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Modifier, Style},
+    widgets::{BarChart, Block, Cell, Paragraph, Row, Sparkline, Table},
+    DefaultTerminal, Frame,
+};
+use std::{future, thread};
+use tokio::sync::mpsc;
+
+/// Round-trip times making up the histogram
+const RTT_WINDOW: usize = 1000;
+/// Log lines shown below the charts
+const LOG_LINES: u16 = 6;
+
+/// The dashboard, drawn on the alternate screen until dropped
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    log: LogBuffer,
+    keys: mpsc::UnboundedReceiver<KeyEvent>,
+    /// Index of the target the charts are of
+    selected: usize,
+}
+
+impl Dashboard {
+    /// Take over the terminal, showing the lines logged to `log`
+    pub fn start(log: LogBuffer) -> Result<Self> {
+        let (tx, keys) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("tui-input".to_owned())
+            .spawn(move || {
+                while let Ok(event) = event::read() {
+                    if let Event::Key(key) = event {
+                        if key.kind == KeyEventKind::Press && tx.send(key).is_err() {
+                            return;
+                        }
+                    }
+                }
+            })
+            .context("failed to start reading the keyboard")?;
+        let terminal = ratatui::try_init().context("failed to set up the terminal")?;
+        Ok(Self {
+            terminal,
+            log,
+            keys,
+            selected: 0,
+        })
+    }
+
+    /// Wait for a key press, returning whether it asks to quit
+    pub async fn input(&mut self) -> bool {
+        let Some(key) = self.keys.recv().await else {
+            // Without a keyboard, only signals stop measuring
+            return future::pending().await;
+        };
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => true,
+            KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+            KeyCode::Down | KeyCode::Tab => {
+                self.selected = self.selected.wrapping_add(1);
+                false
+            }
+            KeyCode::Up | KeyCode::BackTab => {
+                self.selected = self.selected.wrapping_sub(1);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    pub fn draw(&mut self, summary: &Summary) -> Result<()> {
+        let targets = summary.targets();
+        if !targets.is_empty() {
+            self.selected = self.selected.min(targets.len() - 1);
+        }
+        let log = self.log.latest(LOG_LINES as usize);
+        let selected = self.selected;
+        self.terminal
+            .draw(|frame| render(frame, targets, selected, &log))
+            .context("failed to draw the dashboard")?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        ratatui::restore();
+        self.log.release();
+    }
+}
+
+fn render(frame: &mut Frame, targets: &[(String, TargetSummary)], selected: usize, log: &[String]) {
+    let rows = targets.len().max(1) as u16 + 3;
+    let [table_area, charts_area, log_area] = Layout::vertical([
+        Constraint::Length(rows),
+        Constraint::Min(8),
+        Constraint::Length(LOG_LINES + 2),
+    ])
+    .areas(frame.area());
+    frame.render_widget(targets_table(targets, selected), table_area);
+
+    let [offsets_area, rtts_area] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .areas(charts_area);
+    match targets.get(selected) {
+        Some((name, t)) => {
+            render_offsets(frame, offsets_area, name, &t.offsets);
+            render_rtts(frame, rtts_area, &t.rtts);
+        }
+        None => frame.render_widget(
+            Paragraph::new("Waiting for replies...").block(Block::bordered()),
+            charts_area,
+        ),
+    }
+
+    frame.render_widget(
+        Paragraph::new(log.join("\n")).block(Block::bordered().title(" Log ")),
+        log_area,
+    );
+}
+
+fn targets_table(targets: &[(String, TargetSummary)], selected: usize) -> Table<'_> {
+    let header = Row::new([
+        "target",
+        "samples",
+        "loss",
+        "offset",
+        "filtered",
+        "rtt median",
+        "drift",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+    let rows = targets.iter().enumerate().map(|(i, (name, t))| {
+        let rtt = Spread::of(&t.rtts).map(|rtt| rtt.median);
+        let row = Row::new([
+            Cell::from(name.as_str()),
+            Cell::from(t.samples.to_string()),
+            Cell::from(format!("{:.1}%", t.loss_ratio() * 100.0)),
+            Cell::from(t.offsets.last().copied().map(seconds).unwrap_or_default()),
+            Cell::from(t.filtered_offset.map(seconds).unwrap_or_default()),
+            Cell::from(rtt.map(seconds).unwrap_or_default()),
+            Cell::from(
+                t.drift_ppm
+                    .map(|drift| format!("{:.3} ppm", drift))
+                    .unwrap_or_default(),
+            ),
+        ]);
+        match i == selected {
+            true => row.style(Style::new().add_modifier(Modifier::REVERSED)),
+            false => row,
+        }
+    });
+    let widths = [
+        Constraint::Fill(3),
+        Constraint::Fill(1),
+        Constraint::Fill(1),
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+    ];
+    Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title(" Targets (Up/Down select, q quits) "))
+}
+
+/// Sparkline of the latest offsets that fit, drawn from their minimum
+fn render_offsets(frame: &mut Frame, area: Rect, name: &str, offsets: &[f64]) {
+    let width = area.width.saturating_sub(2) as usize;
+    let recent = &offsets[offsets.len().saturating_sub(width)..];
+    let min = recent.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = recent.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    let title = match recent.is_empty() {
+        true => format!(" Offset to {} ", name),
+        false => format!(" Offset to {}: {} to {} ", name, seconds(min), seconds(max)),
+    };
+    // Nanoseconds above the minimum, the minimum itself shown as a dot
+    let data: Vec<u64> = recent
+        .iter()
+        .map(|offset| ((offset - min) * 1e9) as u64 + 1)
+        .collect();
+    frame.render_widget(
+        Sparkline::default()
+            .block(Block::bordered().title(title))
+            .data(&data),
+        area,
+    );
+}
+
+/// Histogram of the latest round-trip times, in buckets as wide as fit
+fn render_rtts(frame: &mut Frame, area: Rect, rtts: &[f64]) {
+    let block = Block::bordered().title(" RTT histogram ");
+    let recent = &rtts[rtts.len().saturating_sub(RTT_WINDOW)..];
+    let min = recent.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = recent.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    // Bars of width 7 with a gap, for labels like 1.234ms
+    let buckets = (area.width.saturating_sub(2) as usize / 8).max(1);
+    if recent.is_empty() {
+        frame.render_widget(block, area);
+        return;
+    }
+    let width = (max - min) / buckets as f64;
+    let mut counts = vec![0; buckets];
+    for rtt in recent {
+        let bucket = match width > 0.0 {
+            true => ((rtt - min) / width) as usize,
+            false => 0,
+        };
+        counts[bucket.min(buckets - 1)] += 1;
+    }
+    let labels: Vec<_> = (0..buckets)
+        .map(|i| seconds(min + width * i as f64))
+        .collect();
+    let data: Vec<_> = labels.iter().map(String::as_str).zip(counts).collect();
+    frame.render_widget(
+        BarChart::default()
+            .block(block)
+            .bar_width(7)
+            .bar_gap(1)
+            .data(data.as_slice()),
+        area,
+    );
+}
+
+/// Seconds in the unit reading best
+fn seconds(value: f64) -> String {
+    match value.abs() {
+        v if v < 1e-3 => format!("{:.1}us", value * 1e6),
+        v if v < 1.0 => format!("{:.3}ms", value * 1e3),
+        _ => format!("{:.3}s", value),
+    }
+}
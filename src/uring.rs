@@ -0,0 +1,358 @@
+//! Datagram I/O through io_uring on dedicated threads, bypassing the tokio reactor
+
+use crate::{clock::Clock, socket::Received};
+use anyhow::{bail, Result};
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::net::UdpSocket;
+
+/// How datagrams are sent and received
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoBackend {
+    /// Readiness-based I/O on the tokio reactor (epoll, kqueue)
+    #[default]
+    Tokio,
+    /// Completion-based I/O with io_uring on threads of its own (Linux only);
+    /// receive times are read by that thread as soon as the kernel completes the
+    /// receive, without waiting for an async task to be woken
+    Uring,
+}
+
+impl fmt::Display for IoBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IoBackend::Tokio => "tokio",
+            IoBackend::Uring => "uring",
+        })
+    }
+}
+
+impl FromStr for IoBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tokio" => Ok(IoBackend::Tokio),
+            "uring" => Ok(IoBackend::Uring),
+            _ => bail!("unknown I/O backend '{}', expected tokio or uring", s),
+        }
+    }
+}
+
+/// Flag telling the I/O threads to give up, raised when dropped
+#[derive(Default)]
+pub struct Stop(Arc<AtomicBool>);
+
+impl Stop {
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.0.clone()
+    }
+}
+
+impl Drop for Stop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Datagrams of a receive thread, up to the error that stopped it
+type Datagrams = tokio::sync::mpsc::UnboundedReceiver<Result<(Received, Vec<u8>)>>;
+
+/// Receives the datagrams of a socket on a thread of its own
+pub struct Receiver {
+    rx: Datagrams,
+    _stop: Stop,
+}
+
+impl Receiver {
+    /// Start receiving on `socket`, timestamping with `clock` when the kernel does not
+    pub fn spawn(socket: &UdpSocket, clock: Clock) -> Result<Self> {
+        let stop = Stop::default();
+        let rx = sys::spawn_receiver(socket, clock, stop.flag())?;
+        Ok(Self { rx, _stop: stop })
+    }
+
+    /// Next datagram, copied into `buf`
+    pub async fn recv(&mut self, buf: &mut [u8]) -> Result<Received> {
+        let Some(received) = self.rx.recv().await else {
+            bail!("io_uring receive thread stopped");
+        };
+        let (mut received, data) = received?;
+        received.len = data.len().min(buf.len());
+        buf[..received.len].copy_from_slice(&data[..received.len]);
+        Ok(received)
+    }
+}
+
+pub use sys::Ring;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use super::Datagrams;
+    use crate::{
+        clock::Clock,
+        icmp,
+        socket::{self, PacketInfo, Received},
+        timestamping::TimestampSource,
+    };
+    use anyhow::{anyhow, Context, Result};
+    use io_uring::{opcode, squeue, types, IoUring};
+    use nix::sys::socket::{SockaddrLike, SockaddrStorage};
+    use std::{
+        io, mem,
+        net::SocketAddr,
+        os::unix::io::{AsFd, AsRawFd, RawFd},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+    use tokio::{net::UdpSocket, sync::mpsc};
+
+    /// How often a thread waiting for a completion checks whether to stop
+    const STOP_CHECK: types::Timespec = types::Timespec::new().nsec(100_000_000);
+
+    /// io_uring of a single thread, which blocks on every operation
+    pub struct Ring {
+        ring: IoUring,
+        stop: Arc<AtomicBool>,
+    }
+
+    /// `user_data` of the operations, to tell their completions apart
+    const OPERATION: u64 = 0;
+    const CANCEL: u64 = 1;
+
+    impl Ring {
+        pub fn new(stop: Arc<AtomicBool>) -> Result<Self> {
+            let ring = IoUring::new(8).context("failed to set up io_uring")?;
+            Ok(Self { ring, stop })
+        }
+
+        /// Receive a datagram, `None` once stopped
+        pub fn recv(
+            &mut self,
+            socket: &UdpSocket,
+            buf: &mut [u8],
+            clock: &Clock,
+        ) -> Result<Option<Received>> {
+            self.recv_fd(socket.as_raw_fd(), buf, clock)
+        }
+
+        fn recv_fd(
+            &mut self,
+            fd: RawFd,
+            buf: &mut [u8],
+            clock: &Clock,
+        ) -> Result<Option<Received>> {
+            let mut control = [0u64; 64];
+            let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let mut mhdr: libc::msghdr = unsafe { mem::zeroed() };
+            mhdr.msg_name = &mut addr as *mut _ as *mut libc::c_void;
+            mhdr.msg_namelen = mem::size_of_val(&addr) as libc::socklen_t;
+            mhdr.msg_iov = &mut iov;
+            mhdr.msg_iovlen = 1;
+            mhdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            mhdr.msg_controllen = mem::size_of_val(&control) as _;
+
+            let entry = opcode::RecvMsg::new(types::Fd(fd), &mut mhdr).build();
+            let Some(len) = (unsafe { self.complete(entry)? }) else {
+                return Ok(None);
+            };
+            let msg = unsafe { socket::parse_message(&mhdr, &addr, len as usize) };
+
+            let (timestamp, source) = match msg.timestamp {
+                Some(timestamp) => timestamp,
+                None => (clock.now()?, TimestampSource::Userspace),
+            };
+            Ok(Some(Received {
+                len: msg.len,
+                from: msg
+                    .from
+                    .ok_or_else(|| anyhow!("recvmsg() returned no source address"))?,
+                timestamp,
+                source,
+                dest: msg.dest,
+                ttl: msg.ttl,
+                tos: msg.tos,
+            }))
+        }
+
+        /// Send a datagram to `to`, from the local address in `from` if given;
+        /// `false` once stopped
+        pub fn send_to(
+            &mut self,
+            socket: &UdpSocket,
+            buf: &[u8],
+            to: SocketAddr,
+            from: Option<PacketInfo>,
+        ) -> Result<bool> {
+            let addr = SockaddrStorage::from(to);
+            let mut control = [0u64; 8];
+            let mut iov = libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let mut mhdr: libc::msghdr = unsafe { mem::zeroed() };
+            mhdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+            mhdr.msg_namelen = addr.len();
+            mhdr.msg_iov = &mut iov;
+            mhdr.msg_iovlen = 1;
+            if let Some(from) = from {
+                mhdr.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+                unsafe { socket::write_pktinfo(&mut mhdr, from) };
+            }
+
+            let entry = opcode::SendMsg::new(types::Fd(socket.as_raw_fd()), &mhdr).build();
+            Ok(unsafe { self.complete(entry)? }.is_some())
+        }
+
+        /// Run a single operation to completion, returning its non-negative
+        /// result, or `None` if stopped before it completed
+        ///
+        /// # Safety
+        /// The buffers of `entry` must stay valid until this returns; a stopped
+        /// operation is cancelled and waited for first.
+        unsafe fn complete(&mut self, entry: squeue::Entry) -> Result<Option<u32>> {
+            let entry = entry.user_data(OPERATION);
+            self.push(entry.clone())?;
+            let args = types::SubmitArgs::new().timespec(&STOP_CHECK);
+            let mut cancelled = false;
+            loop {
+                let submitted = if cancelled {
+                    self.ring.submit_and_wait(1)
+                } else {
+                    self.ring.submitter().submit_with_args(1, &args)
+                };
+                match submitted {
+                    Ok(_) => {}
+                    Err(e) if matches!(e.raw_os_error(), Some(libc::ETIME | libc::EINTR)) => {}
+                    Err(e) => return Err(e).context("io_uring_enter() call failed"),
+                }
+
+                let result = self
+                    .ring
+                    .completion()
+                    .find(|cqe| cqe.user_data() == OPERATION)
+                    .map(|cqe| cqe.result());
+                match result {
+                    Some(_) if cancelled => return Ok(None),
+                    // Woken by an error queue entry rather than a datagram
+                    Some(result) if result == -libc::EAGAIN => self.push(entry.clone())?,
+                    Some(result) if result < 0 => {
+                        return Err(io::Error::from_raw_os_error(-result).into())
+                    }
+                    Some(result) => return Ok(Some(result as u32)),
+                    None => {}
+                }
+                if !cancelled && self.stop.load(Ordering::Relaxed) {
+                    self.push(
+                        opcode::AsyncCancel::new(OPERATION)
+                            .build()
+                            .user_data(CANCEL),
+                    )?;
+                    cancelled = true;
+                }
+            }
+        }
+
+        unsafe fn push(&mut self, entry: squeue::Entry) -> Result<()> {
+            self.ring
+                .submission()
+                .push(&entry)
+                .map_err(|_| anyhow!("io_uring submission queue is full"))
+        }
+    }
+
+    pub fn spawn_receiver(
+        socket: &UdpSocket,
+        clock: Clock,
+        stop: Arc<AtomicBool>,
+    ) -> Result<Datagrams> {
+        // A descriptor of its own, so a closed socket is not mistaken for a later one
+        let fd = socket.as_fd().try_clone_to_owned()?;
+        let mut ring = Ring::new(stop)?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        std::thread::Builder::new()
+            .name("co-uring-recv".into())
+            .spawn(move || {
+                let mut buf = [0; 2048]; // should be enough for MTU 1500
+                loop {
+                    let received = match ring.recv_fd(fd.as_raw_fd(), &mut buf, &clock) {
+                        Ok(Some(received)) => {
+                            let data = buf[..received.len].to_vec();
+                            Ok((received, data))
+                        }
+                        Ok(None) => return,
+                        Err(e) => Err(e),
+                    };
+                    // ICMP errors are for the measurer to log, later datagrams still arrive
+                    let failed = received.as_ref().is_err_and(|e| !icmp::is_icmp_error(e));
+                    if tx.send(received).is_err() || failed {
+                        return;
+                    }
+                }
+            })
+            .context("failed to start the io_uring receive thread")?;
+        Ok(rx)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use super::Datagrams;
+    use crate::{
+        clock::Clock,
+        socket::{PacketInfo, Received},
+    };
+    use anyhow::{bail, Result};
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::AtomicBool, Arc},
+    };
+    use tokio::net::UdpSocket;
+
+    pub enum Ring {}
+
+    impl Ring {
+        pub fn new(_stop: Arc<AtomicBool>) -> Result<Self> {
+            bail!("the io_uring backend is only supported on Linux")
+        }
+
+        pub fn recv(
+            &mut self,
+            _socket: &UdpSocket,
+            _buf: &mut [u8],
+            _clock: &Clock,
+        ) -> Result<Option<Received>> {
+            match *self {}
+        }
+
+        pub fn send_to(
+            &mut self,
+            _socket: &UdpSocket,
+            _buf: &[u8],
+            _to: SocketAddr,
+            _from: Option<PacketInfo>,
+        ) -> Result<bool> {
+            match *self {}
+        }
+    }
+
+    pub fn spawn_receiver(
+        _socket: &UdpSocket,
+        _clock: Clock,
+        _stop: Arc<AtomicBool>,
+    ) -> Result<Datagrams> {
+        bail!("the io_uring backend is only supported on Linux")
+    }
+}
@@ -0,0 +1,129 @@
+//! Embedded web UI of `measure`: a page plotting the offsets and round-trip
+//! times of all targets live, fed by server-sent events
+//!
+//! `GET /` is the page, `GET /events` the stream, with one JSON object per
+//! sample.
+
+use crate::analysis::Sample;
+use anyhow::{Context, Result};
+use serde_json::json;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+use tracing::{info, warn};
+
+const PAGE: &str = include_str!("web.html");
+/// Longest HTTP request head read
+const MAX_REQUEST_SIZE: usize = 4096;
+/// Samples buffered for slow viewers, which skip the older ones
+const EVENT_QUEUE: usize = 256;
+
+/// Web UI server, streaming the samples published to it
+pub struct WebUi {
+    events: broadcast::Sender<Arc<str>>,
+}
+
+impl WebUi {
+    /// Serve the UI on `addr`
+    pub async fn bind(addr: SocketAddr) -> Result<Self> {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind the web UI to {}", addr))?;
+        info!("Serving the web UI on http://{}/", listener.local_addr()?);
+        let (events, _) = broadcast::channel(EVENT_QUEUE);
+        let sender = events.clone();
+        tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        warn!("Web UI failed to accept a connection: {}", e);
+                        continue;
+                    }
+                };
+                let events = sender.subscribe();
+                tokio::spawn(async move {
+                    if let Err(e) = handle(stream, events).await {
+                        warn!("Web UI request failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(Self { events })
+    }
+
+    /// Send a sample of `target` to the connected viewers
+    pub fn publish(&self, target: &str, sample: &Sample) {
+        let m = &sample.measurement;
+        let event = json!({
+            "target": target,
+            "time": m.t4.total_nsec() as f64 * 1e-9,
+            "offset": m.offset,
+            "filtered_offset": sample.filtered_offset,
+            "rtt": m.rtt,
+            "discarded": sample.flags.discarded,
+        });
+        // Without viewers there is nobody to send to
+        let _ = self.events.send(event.to_string().into());
+    }
+}
+
+async fn handle(mut stream: TcpStream, mut events: broadcast::Receiver<Arc<str>>) -> Result<()> {
+    // Only the request line matters, headers and body are ignored
+    let mut buf = [0; MAX_REQUEST_SIZE];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") && len < buf.len() {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/") => {
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                PAGE.len(),
+                PAGE
+            );
+            stream.write_all(response.as_bytes()).await?;
+        }
+        ("GET", "/events") => {
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+                .await?;
+            loop {
+                let event = match events.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                // A closed page ends the stream here
+                if stream
+                    .write_all(format!("data: {}\n\n", event).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+        }
+        _ => {
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await?;
+        }
+    }
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,205 @@
+//! WebSocket (RFC 6455) handshakes and framing, to tunnel probes through
+//! proxies that only pass web traffic
+
+use crate::{base64, random::Rng};
+use anyhow::{bail, ensure, Context, Result};
+use sha1::{Digest, Sha1};
+use std::ops::Range;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Appended to the client key to derive `Sec-WebSocket-Accept`
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Longest request or response head read during a handshake
+const MAX_HEAD_SIZE: usize = 8192;
+/// Longest frame header: flags and length, 64-bit extended length and mask
+pub const MAX_HEADER_SIZE: usize = 14;
+
+const OPCODE_BINARY: u8 = 2;
+const OPCODE_CLOSE: u8 = 8;
+
+/// Frame found at the start of a receive buffer
+pub struct Frame {
+    /// Where the payload is in the buffer, unmasked
+    pub payload: Range<usize>,
+    /// Where the next frame starts
+    pub end: usize,
+    /// The peer is closing the connection
+    pub close: bool,
+    /// Control frame to skip, such as a ping
+    pub control: bool,
+}
+
+/// Ask an HTTP proxy on `stream` to tunnel the connection to `target`
+pub async fn connect_proxy(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    target: &str,
+) -> Result<()> {
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    stream.write_all(request.as_bytes()).await?;
+    let response = read_head(stream).await?;
+    let status = response.lines().next().unwrap_or_default();
+    ensure!(
+        status.split(' ').nth(1) == Some("200"),
+        "proxy refused to connect to {}: {}",
+        target,
+        status
+    );
+    Ok(())
+}
+
+/// Client handshake, upgrading the HTTP connection to `host` on `stream`
+pub async fn connect(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    host: &str,
+    rng: &mut Rng,
+) -> Result<()> {
+    let mut nonce = [0; 16];
+    nonce[..8].copy_from_slice(&rng.next_u64().to_le_bytes());
+    nonce[8..].copy_from_slice(&rng.next_u64().to_le_bytes());
+    let key = base64::encode(&nonce);
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        host, key
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let response = read_head(stream).await?;
+    let status = response.lines().next().unwrap_or_default();
+    ensure!(
+        status.split(' ').nth(1) == Some("101"),
+        "WebSocket upgrade refused: {}",
+        status
+    );
+    ensure!(
+        header(&response, "sec-websocket-accept") == Some(accept_key(&key).as_str()),
+        "invalid Sec-WebSocket-Accept in the upgrade response"
+    );
+    Ok(())
+}
+
+/// Server handshake, accepting the upgrade request on `stream`; any path is accepted
+pub async fn accept(stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> Result<()> {
+    let request = read_head(stream).await?;
+    let Some(key) = header(&request, "sec-websocket-key") else {
+        stream
+            .write_all(b"HTTP/1.1 426 Upgrade Required\r\nConnection: close\r\n\r\n")
+            .await?;
+        bail!("not a WebSocket upgrade request");
+    };
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Binary frame of `packet`, masked with `mask` as frames of clients must be
+pub fn encode(packet: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(MAX_HEADER_SIZE + packet.len());
+    frame.push(0x80 | OPCODE_BINARY);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match packet.len() {
+        len @ 0..=125 => frame.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(mask_bit | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(mask_bit | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            frame.extend_from_slice(&mask);
+            frame.extend(packet.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => frame.extend_from_slice(packet),
+    }
+    frame
+}
+
+/// The frame at the start of `buf`, unmasking its payload in place; `None`
+/// until it is complete
+///
+/// Payloads longer than `max_payload` and fragmented messages are rejected.
+pub fn decode(buf: &mut [u8], max_payload: usize) -> Result<Option<Frame>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let (fin, opcode) = (buf[0] & 0x80 != 0, buf[0] & 0x0f);
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut start) = match buf[1] & 0x7f {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into()?), 10),
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    ensure!(
+        len <= max_payload as u64,
+        "WebSocket frame of {} bytes is too large",
+        len
+    );
+    ensure!(
+        fin && opcode != 0,
+        "fragmented WebSocket messages are not supported"
+    );
+    let mask = if masked {
+        let Some(mask) = buf.get(start..start + 4) else {
+            return Ok(None);
+        };
+        let mask: [u8; 4] = mask.try_into()?;
+        start += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    let end = start + len as usize;
+    if buf.len() < end {
+        return Ok(None);
+    }
+    if let Some(mask) = mask {
+        for (i, b) in buf[start..end].iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    Ok(Some(Frame {
+        payload: start..end,
+        end,
+        close: opcode == OPCODE_CLOSE,
+        control: opcode != OPCODE_BINARY,
+    }))
+}
+
+/// Read an HTTP request or response head, byte by byte so that nothing after it is consumed
+async fn read_head(stream: &mut (impl AsyncRead + Unpin)) -> Result<String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        ensure!(head.len() < MAX_HEAD_SIZE, "HTTP head too long");
+        head.push(
+            stream
+                .read_u8()
+                .await
+                .context("connection closed during the handshake")?,
+        );
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Value of the header `name` (lowercase) in an HTTP head
+fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.lines().skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+    })
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(GUID.as_bytes());
+    base64::encode(&sha1.finalize())
+}